@@ -0,0 +1,143 @@
+use std::collections::HashMap;
+
+use crate::{path_components, Policy};
+
+/// Identifies a single path glob of an endpoint within a `Policy`
+#[derive(Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord, Debug)]
+pub struct GlobRef {
+    pub endpoint: usize,
+    pub glob: usize,
+}
+
+/// Endpoint path globs bucketed by hostname, so a lookup only tests globs that could match.
+/// Must be rebuilt whenever the policy changes.
+#[derive(Default, Debug)]
+pub struct EndpointIndex {
+    by_host: HashMap<String, Vec<GlobRef>>,
+    any_host: Vec<GlobRef>,
+}
+
+impl EndpointIndex {
+    pub fn new(policy: &Policy) -> Self {
+        let mut index = EndpointIndex::default();
+        for (endpoint_index, endpoint) in policy.endpoints.iter().enumerate() {
+            for (glob_index, glob) in endpoint.matches.iter().enumerate() {
+                let glob_ref = GlobRef {
+                    endpoint: endpoint_index,
+                    glob: glob_index,
+                };
+                match glob.literal_host() {
+                    Some(host) => index
+                        .by_host
+                        .entry(host.to_string())
+                        .or_default()
+                        .push(glob_ref),
+                    None => index.any_host.push(glob_ref),
+                }
+            }
+        }
+        index
+    }
+
+    /// returns the globs matching `path` in policy order.
+    /// equal results resolve to the same configuration in `Policy::resolve_path_config`.
+    pub fn matching_globs(&self, policy: &Policy, path: &str) -> Vec<GlobRef> {
+        let components = path_components(path);
+        let host_globs = components
+            .first()
+            .and_then(|host| self.by_host.get(*host))
+            .map(|x| &x[..])
+            .unwrap_or_default();
+        let mut globs = host_globs
+            .iter()
+            .chain(&self.any_host)
+            .filter(|x| {
+                policy.endpoints[x.endpoint].matches[x.glob]
+                    .matches_components(components.iter().copied())
+            })
+            .copied()
+            .collect::<Vec<_>>();
+        globs.sort();
+        globs
+    }
+
+    /// returns the distinct glob combinations matched by an example path of each glob, and by paths matching no glob.
+    /// this covers nested globs, but paths where otherwise unrelated globs overlap (i.e. `*/api/users` and
+    /// `example.com/**`) or that only match through regex components can resolve to other combinations.
+    pub fn example_combinations(&self, policy: &Policy) -> Vec<Vec<GlobRef>> {
+        let mut combinations = vec![vec![]];
+        for endpoint in &policy.endpoints {
+            for glob in &endpoint.matches {
+                let globs = match glob.example_path() {
+                    Some(path) => self.matching_globs(policy, &path),
+                    None => continue,
+                };
+                if !combinations.contains(&globs) {
+                    combinations.push(globs);
+                }
+            }
+        }
+        combinations
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_endpoint_index() {
+        let policy: Policy = serde_yaml::from_str(
+            r#"
+categories: {}
+endpoints:
+  - matches: "**"
+  - matches:
+      - "example.com/api/**"
+      - "other.com/**"
+    config:
+      a: {}
+  - matches: "*/api/users"
+  - matches: "example.com/api/users"
+    config:
+      b: {}
+"#,
+        )
+        .unwrap();
+        let index = EndpointIndex::new(&policy);
+        let glob = |endpoint, glob| GlobRef { endpoint, glob };
+
+        assert_eq!(
+            index.matching_globs(&policy, "example.com/api/users?id=1"),
+            vec![glob(0, 0), glob(1, 0), glob(2, 0), glob(3, 0)]
+        );
+        assert_eq!(
+            index.matching_globs(&policy, "other.com/api/users"),
+            vec![glob(0, 0), glob(1, 1), glob(2, 0)]
+        );
+        assert_eq!(
+            index.matching_globs(&policy, "unknown.com/"),
+            vec![glob(0, 0)]
+        );
+        assert_eq!(
+            index.example_combinations(&policy),
+            vec![
+                vec![],
+                vec![glob(0, 0)],
+                vec![glob(0, 0), glob(1, 0)],
+                vec![glob(0, 0), glob(1, 1)],
+                vec![glob(0, 0), glob(2, 0)],
+                vec![glob(0, 0), glob(1, 0), glob(2, 0), glob(3, 0)],
+            ]
+        );
+        for path in ["example.com/api/users", "other.com/x", "a.com/api/users"] {
+            let indexed = policy.resolve_path_config(&index.matching_globs(&policy, path));
+            let linear = policy.get_path_config(path);
+            assert_eq!(indexed.policy_path, linear.policy_path);
+            assert_eq!(
+                indexed.configuration.keys().collect::<Vec<_>>(),
+                linear.configuration.keys().collect::<Vec<_>>()
+            );
+        }
+    }
+}
@@ -0,0 +1,253 @@
+use std::{fmt, str::FromStr};
+
+use anyhow::{bail, Context, Result};
+use serde::{de::Unexpected, Deserialize, Deserializer, Serialize, Serializer};
+
+/// A step from a json value into one of its children
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum JsonSegment {
+    Key(String),
+    Index(usize),
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+enum Selector {
+    Key(String),
+    Index(usize),
+    Any,
+}
+
+impl Selector {
+    fn matches(&self, segment: &JsonSegment) -> bool {
+        match (self, segment) {
+            (Selector::Any, _) => true,
+            (Selector::Key(key), JsonSegment::Key(target)) => key == target,
+            (Selector::Index(index), JsonSegment::Index(target)) => index == target,
+            _ => false,
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+enum Step {
+    Child(Selector),
+    Descendant(Selector),
+}
+
+/// A subset of JSONPath, selecting subtrees of a json document:
+/// `$`: the root document
+/// `.key` or `['key']`: a key of an object
+/// `[3]`: an index of an array
+/// `.*` or `[*]`: any key or index
+/// `..key`, `..*`, `..[3]`: a descendant at any depth
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct JsonPath {
+    source: String,
+    steps: Vec<Step>,
+}
+
+impl<'de> Deserialize<'de> for JsonPath {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let raw = String::deserialize(deserializer)?;
+        raw.parse().map_err(|e| {
+            serde::de::Error::invalid_value(
+                Unexpected::Str(&raw),
+                &&*format!("invalid JsonPath: {}", e),
+            )
+        })
+    }
+}
+
+impl Serialize for JsonPath {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        self.source.serialize(serializer)
+    }
+}
+
+fn parse_bracket(chars: &mut std::iter::Peekable<std::str::Chars>) -> Result<Selector> {
+    let selector = match chars.peek() {
+        Some('*') => {
+            chars.next();
+            Selector::Any
+        }
+        Some(quote @ ('\'' | '"')) => {
+            let quote = *quote;
+            chars.next();
+            let mut key = String::new();
+            loop {
+                match chars.next() {
+                    Some(c) if c == quote => break,
+                    Some('\\') => key.push(chars.next().context("unterminated key")?),
+                    Some(c) => key.push(c),
+                    None => bail!("unterminated key"),
+                }
+            }
+            Selector::Key(key)
+        }
+        _ => {
+            let mut index = String::new();
+            while let Some(c) = chars.peek().filter(|c| c.is_ascii_digit()) {
+                index.push(*c);
+                chars.next();
+            }
+            Selector::Index(
+                index
+                    .parse()
+                    .context("expected an index, `*` or quoted key")?,
+            )
+        }
+    };
+    if chars.next() != Some(']') {
+        bail!("expected `]`");
+    }
+    Ok(selector)
+}
+
+fn parse_dotted(chars: &mut std::iter::Peekable<std::str::Chars>) -> Result<Selector> {
+    if chars.peek() == Some(&'[') {
+        chars.next();
+        return parse_bracket(chars);
+    }
+    let mut key = String::new();
+    while let Some(c) = chars.peek().filter(|c| !matches!(c, '.' | '[' | ']')) {
+        key.push(*c);
+        chars.next();
+    }
+    Ok(match &*key {
+        "" => bail!("expected a key after `.`"),
+        "*" => Selector::Any,
+        _ => Selector::Key(key),
+    })
+}
+
+impl FromStr for JsonPath {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let mut chars = s.trim().chars().peekable();
+        if chars.next() != Some('$') {
+            bail!("must start with `$`");
+        }
+        let mut steps = vec![];
+        while let Some(c) = chars.next() {
+            let step = match c {
+                '.' if chars.peek() == Some(&'.') => {
+                    chars.next();
+                    Step::Descendant(parse_dotted(&mut chars)?)
+                }
+                '.' => Step::Child(parse_dotted(&mut chars)?),
+                '[' => Step::Child(parse_bracket(&mut chars)?),
+                c => bail!("unexpected character {c:?}"),
+            };
+            steps.push(step);
+        }
+        Ok(Self {
+            source: s.to_string(),
+            steps,
+        })
+    }
+}
+
+impl fmt::Display for JsonPath {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.source)
+    }
+}
+
+fn contains_steps(steps: &[Step], path: &[JsonSegment]) -> bool {
+    let (step, remaining) = match steps.split_first() {
+        Some(x) => x,
+        None => return true,
+    };
+    match step {
+        Step::Child(selector) => match path.split_first() {
+            Some((segment, path)) => selector.matches(segment) && contains_steps(remaining, path),
+            None => false,
+        },
+        Step::Descendant(selector) => (0..path.len())
+            .any(|i| selector.matches(&path[i]) && contains_steps(remaining, &path[i + 1..])),
+    }
+}
+
+impl JsonPath {
+    /// returns true if the value at `path` is within a subtree selected by this `JsonPath`
+    pub fn contains(&self, path: &[JsonSegment]) -> bool {
+        contains_steps(&self.steps, path)
+    }
+}
+
+/// Limits matching within json bodies to selected subtrees
+#[derive(Clone, Debug, Serialize, Deserialize, Default, PartialEq, Eq)]
+pub struct JsonPathScope {
+    /// if empty, the entire document is included
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub include: Vec<JsonPath>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub exclude: Vec<JsonPath>,
+}
+
+impl JsonPathScope {
+    pub fn is_empty(&self) -> bool {
+        self.include.is_empty() && self.exclude.is_empty()
+    }
+
+    pub fn contains(&self, path: &[JsonSegment]) -> bool {
+        (self.include.is_empty() || self.include.iter().any(|x| x.contains(path)))
+            && !self.exclude.iter().any(|x| x.contains(path))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn path(segments: &[&str]) -> Vec<JsonSegment> {
+        segments
+            .iter()
+            .map(|x| match x.parse() {
+                Ok(index) => JsonSegment::Index(index),
+                Err(_) => JsonSegment::Key(x.to_string()),
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_json_path() {
+        let customer: JsonPath = "$.data[*].customer".parse().unwrap();
+        assert!(customer.contains(&path(&["data", "0", "customer"])));
+        assert!(customer.contains(&path(&["data", "3", "customer", "email"])));
+        assert!(!customer.contains(&path(&["data", "0"])));
+        assert!(customer.contains(&path(&["data", "x", "customer"])));
+        assert!(!customer.contains(&path(&["meta", "0", "customer"])));
+
+        let quoted: JsonPath = "$['a.b'][2]".parse().unwrap();
+        assert!(quoted.contains(&path(&["a.b", "2"])));
+        assert!(!quoted.contains(&path(&["a", "b", "2"])));
+        assert!(!quoted.contains(&path(&["a.b", "x"])));
+
+        let descendant: JsonPath = "$..email".parse().unwrap();
+        assert!(descendant.contains(&path(&["email"])));
+        assert!(descendant.contains(&path(&["a", "1", "email"])));
+        assert!(!descendant.contains(&path(&["a", "1"])));
+
+        assert!("$".parse::<JsonPath>().unwrap().contains(&[]));
+        for invalid in ["data", "$.", "$[x]", "$['a'", "$.a]"] {
+            assert!(invalid.parse::<JsonPath>().is_err(), "{invalid}");
+        }
+    }
+
+    #[test]
+    fn test_json_path_scope() {
+        let scope: JsonPathScope = serde_yaml::from_str(
+            r#"
+include: ["$.data"]
+exclude: ["$.data.meta"]
+"#,
+        )
+        .unwrap();
+        assert!(scope.contains(&path(&["data", "name"])));
+        assert!(!scope.contains(&path(&["data", "meta", "id"])));
+        assert!(!scope.contains(&path(&["other"])));
+        assert!(JsonPathScope::default().contains(&path(&["other"])));
+    }
+}
@@ -3,14 +3,20 @@ use std::{
     sync::Arc,
 };
 
-use anyhow::Result;
+use anyhow::{bail, Result};
 use fancy_regex::Regex;
 use indexmap::{IndexMap, IndexSet};
 use serde::{Deserialize, Serialize};
+use serde_single_or_vec2::SingleOrVec;
 
 mod path_glob;
 pub use path_glob::PathGlob;
-use serde_single_or_vec2::SingleOrVec;
+mod endpoint_index;
+pub use endpoint_index::{EndpointIndex, GlobRef};
+mod validate;
+pub use validate::{Diagnostic, ValidationReport};
+mod json_path;
+pub use json_path::{JsonPath, JsonPathScope, JsonSegment};
 
 mod regex_serde {
     use std::borrow::Cow;
@@ -38,6 +44,15 @@ pub fn parse_policy(policy: &str) -> Result<Policy> {
         }
     };
 
+    let mut report = parsed.validate();
+    report.locate(policy, &[]);
+    for warning in &report.warnings {
+        log::warn!("policy warning: {warning}");
+    }
+    if !report.is_valid() {
+        bail!("invalid policy:\n{report}");
+    }
+
     // recur_fillin_endpoint(&mut parsed.root_endpoint, "/");
     Ok(parsed)
 }
@@ -51,15 +66,16 @@ impl PartialEq for RegexWrapper {
     }
 }
 
-#[derive(Clone, Copy, Debug, Serialize, Deserialize, PartialEq, Eq)]
-#[serde(rename_all = "snake_case")]
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "snake_case", tag = "action")]
 pub enum PolicyAction {
     Ignore,
     Alert,
-    // Mask {
-    //     #[serde(default)]
-    //     mask_replacement: String,
-    // },
+    Mask {
+        /// if empty, each character of the matched value is replaced by `*`
+        #[serde(default, skip_serializing_if = "String::is_empty")]
+        mask_replacement: String,
+    },
     Block,
 }
 
@@ -76,16 +92,83 @@ pub enum ContentType {
     Json,
     #[serde(alias = "Html")]
     Html,
+    #[serde(alias = "Xml")]
+    Xml,
+    #[serde(alias = "Text")]
+    Text,
+    #[serde(alias = "Csv")]
+    Csv,
+    #[serde(alias = "Form")]
+    Form,
+    #[serde(alias = "Grpc")]
+    Grpc,
+    /// jpeg, png and webp images, whose metadata is scanned
+    #[serde(alias = "Image")]
+    Image,
 }
 
-#[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
+#[derive(Clone, Copy, Debug, Serialize, Deserialize, PartialEq, Eq)]
 #[serde(rename_all = "snake_case")]
 pub enum MatchContext {
+    /// json object keys, xml element names, csv headers, form field names and protobuf field names
     Keys,
+    /// json values, xml text and attribute values, csv cells, form field contents, protobuf string and number fields
+    /// and image metadata values
     Values,
+    /// html text nodes
+    Text,
+    /// html attribute values
+    Attributes,
+    /// html comments
+    Comments,
+    /// contents of html `<script>` blocks
+    Scripts,
+}
+
+impl MatchContext {
+    /// contexts of json, xml, csv, form, grpc and image bodies
+    pub const STRUCTURED: [MatchContext; 2] = [MatchContext::Keys, MatchContext::Values];
+    pub const HTML: [MatchContext; 4] = [
+        MatchContext::Text,
+        MatchContext::Attributes,
+        MatchContext::Comments,
+        MatchContext::Scripts,
+    ];
+}
+
+/// Type of a JSON value, as seen by the `values` context
+#[derive(Clone, Copy, Debug, Serialize, Deserialize, PartialEq, Eq, Hash)]
+#[serde(rename_all = "snake_case")]
+pub enum JsonValueType {
+    String,
+    Number,
+    Boolean,
+    Null,
 }
 
-#[derive(Clone, Debug, Serialize, Deserialize, Default, PartialEq)]
+/// Encoding undone on candidate spans of a body before they are matched again
+#[derive(Clone, Copy, Debug, Serialize, Deserialize, PartialEq, Eq, Hash)]
+#[serde(rename_all = "snake_case")]
+pub enum Decoder {
+    /// percent-encoding, i.e. `%31%32`
+    Url,
+    /// numeric and common named character references, i.e. `&#49;&#x32;`
+    HtmlEntities,
+    /// standard or url-safe base64, with or without padding
+    Base64,
+    /// json string escapes, i.e. `\u0031`
+    JsonEscapes,
+}
+
+/// Direction of traffic through the proxy, scanned bodies are either sent by the client or by the upstream service
+#[derive(Clone, Copy, Debug, Serialize, Deserialize, PartialEq, Eq, Hash)]
+#[serde(rename_all = "snake_case")]
+pub enum Direction {
+    Request,
+    Response,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize, Default, PartialEq, Eq)]
 pub struct AlertConfig {
     #[serde(skip_serializing_if = "Option::is_none")]
     pub per_request: Option<usize>,
@@ -152,6 +235,18 @@ pub struct ConfiguredPolicyAction {
     /// interpretation is define by the content_type
     #[serde(default, skip_serializing_if = "Vec::is_empty")]
     pub contexts: SingleOrVec<'static, MatchContext>,
+    /// limits matching in json to the selected subtrees
+    #[serde(default, skip_serializing_if = "JsonPathScope::is_empty")]
+    pub paths: JsonPathScope,
+    /// if empty, only strings are matched in the `values` context of json
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub value_types: SingleOrVec<'static, JsonValueType>,
+    /// if empty, inherits the directions of the endpoint
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub directions: SingleOrVec<'static, Direction>,
+    /// matched values are also searched for in the decoded text of these encodings
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub decode: SingleOrVec<'static, Decoder>,
     #[serde(default, skip_serializing_if = "AlertConfig::is_empty")]
     pub alert: AlertConfig,
     #[serde(default, skip_serializing_if = "HashSet::is_empty")]
@@ -164,6 +259,17 @@ fn is_zero(x: &usize) -> bool {
     *x == 0
 }
 
+/// names of the native matchers that may be referenced from `MatchGroup::direct`
+pub const DIRECT_MATCHERS: &[&str] = &[
+    "ssn",
+    "credit_card",
+    "iban",
+    "email",
+    "phone_e164",
+    "aws_access_key",
+    "jwt",
+];
+
 #[derive(Default, Serialize, Deserialize, Clone, Debug, PartialEq)]
 pub struct MatchGroup {
     #[serde(default, skip_serializing_if = "Vec::is_empty")]
@@ -179,15 +285,16 @@ pub struct MatchGroup {
 }
 
 #[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+/// `Ref` must come first, since every field of an inline `MatchGroup` is optional
 #[serde(untagged)]
 pub enum MatchGroupRef {
+    Ref {
+        name: String,
+    },
     Inline {
         #[serde(flatten)]
         match_group: MatchGroup,
     },
-    Ref {
-        name: String,
-    },
 }
 
 impl MatchGroupRef {
@@ -228,11 +335,21 @@ pub enum Category {
         target: MatchGroupRef,
         rematcher: MatchGroupRef,
     },
-    // Jpeg {
-    //     /// https://docs.rs/kamadak-exif/0.5.4/src/exif/tag.rs.html#252
-    //     exif_tags: Vec<String>,
-    //     drop_xmp: Option<bool>,
-    // },
+    /// matches json values under keys, and csv cells under headers, matching any of `keys`
+    Keyed {
+        keys: SingleOrVec<'static, RegexWrapper>,
+        /// if None, any non-empty value is matched in full
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        values: Option<MatchGroupRef>,
+    },
+    /// matches image metadata fields whose tag matches any of `tags`, i.e. `GPSLatitude` (EXIF), `dc:creator` (XMP),
+    /// `IPTC:By-line` (IPTC) or `Comment` (PNG text). masking strips the field from the image
+    ImageMetadata {
+        tags: SingleOrVec<'static, RegexWrapper>,
+        /// if None, any non-empty value is matched in full
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        values: Option<MatchGroupRef>,
+    },
 }
 
 #[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
@@ -265,6 +382,12 @@ pub struct EndpointConfig {
     pub token_extractor: Option<Arc<TokenExtractionConfig>>,
     #[serde(flatten, default, skip_serializing_if = "Option::is_none")]
     pub report_style: Option<DataReportStyle>,
+    /// directions scanned for categories configured here, if empty, only responses are scanned
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub directions: SingleOrVec<'static, Direction>,
+    /// overrides the policy's body limits for matching paths
+    #[serde(default, skip_serializing_if = "BodyLimits::is_default")]
+    pub limits: BodyLimits,
 }
 
 fn collected_request_headers_default() -> IndexSet<String> {
@@ -310,6 +433,93 @@ fn report_style_is_default(style: &DataReportStyle) -> bool {
     *style == DataReportStyle::Raw
 }
 
+fn default_embedded_json_depth() -> usize {
+    2
+}
+
+fn embedded_json_depth_is_default(depth: &usize) -> bool {
+    *depth == default_embedded_json_depth()
+}
+
+fn default_block_status_code() -> u32 {
+    403
+}
+
+/// A serialized protobuf `FileDescriptorSet`, describing the messages of grpc bodies
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum DescriptorSet {
+    /// base64 encoded descriptor set
+    Inline(String),
+    /// name of a descriptor set sent by the policy server along with the policy
+    Reference(String),
+    /// a `Reference` replaced by the descriptor set it names, once received
+    #[serde(skip)]
+    Resolved(Vec<u8>),
+}
+
+/// Describes the response that replaces a blocked response
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
+pub struct BlockConfig {
+    #[serde(default = "default_block_status_code")]
+    pub status_code: u32,
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    pub body: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub content_type: Option<String>,
+    /// maximum number of response body bytes held back while scanning for blocked categories.
+    /// if exceeded, the response is released and a later block can only truncate it.
+    /// if None, the entire response is held back.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_hold_bytes: Option<usize>,
+}
+
+impl Default for BlockConfig {
+    fn default() -> Self {
+        Self {
+            status_code: default_block_status_code(),
+            body: String::new(),
+            content_type: None,
+            max_hold_bytes: None,
+        }
+    }
+}
+
+impl BlockConfig {
+    pub fn is_default(&self) -> bool {
+        *self == Self::default()
+    }
+}
+
+/// Bounds the work done on a single body. Once a limit is hit, the rest of the body is passed through unscanned
+#[derive(Clone, Debug, Default, Serialize, Deserialize, PartialEq)]
+pub struct BodyLimits {
+    /// maximum number of bytes a compressed body is decompressed to
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_decompressed_bytes: Option<usize>,
+    /// maximum ratio of decompressed to compressed bytes of a compressed body
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_compression_ratio: Option<f64>,
+    /// maximum number of (decompressed) body bytes scanned
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_scanned_bytes: Option<usize>,
+}
+
+impl BodyLimits {
+    pub fn is_default(&self) -> bool {
+        *self == Self::default()
+    }
+
+    /// returns these limits with the ones set in `other` taking precedence
+    pub fn overridden_by(&self, other: &BodyLimits) -> BodyLimits {
+        BodyLimits {
+            max_decompressed_bytes: other.max_decompressed_bytes.or(self.max_decompressed_bytes),
+            max_compression_ratio: other.max_compression_ratio.or(self.max_compression_ratio),
+            max_scanned_bytes: other.max_scanned_bytes.or(self.max_scanned_bytes),
+        }
+    }
+}
+
 #[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
 pub struct Policy {
     pub categories: IndexMap<String, Category>,
@@ -334,49 +544,134 @@ pub struct Policy {
         skip_serializing_if = "report_style_is_default"
     )]
     pub report_style: DataReportStyle,
+    /// response sent in place of blocked responses
+    #[serde(default, skip_serializing_if = "BlockConfig::is_default")]
+    pub block: BlockConfig,
+    /// levels of json documents embedded in json strings or html script blocks that are parsed, 0 disables
+    #[serde(
+        default = "default_embedded_json_depth",
+        skip_serializing_if = "embedded_json_depth_is_default"
+    )]
+    pub embedded_json_depth: usize,
+    /// describe the messages of grpc bodies, whose string fields are otherwise scanned without field names
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub grpc_descriptor_sets: Vec<DescriptorSet>,
+    /// limits on the bodies of every path, unless overridden by an endpoint
+    #[serde(default, skip_serializing_if = "BodyLimits::is_default")]
+    pub limits: BodyLimits,
+    /// media types scanned as another media type, i.e. `application/x-amz-json-1.1: application/json`
+    #[serde(default, skip_serializing_if = "IndexMap::is_empty")]
+    pub content_type_aliases: IndexMap<String, String>,
 }
 
 pub struct PathPolicy {
     pub policy_path: String,
     pub configuration: IndexMap<Arc<String>, PathConfiguration>,
     pub token_extractor: Option<Arc<TokenExtractionConfig>>,
+    pub limits: BodyLimits,
 }
 
 pub struct PathConfiguration {
     pub matcher_path: String,
     pub category_config: Arc<ConfiguredPolicyAction>,
     pub report_style: DataReportStyle,
+    pub directions: Vec<Direction>,
 }
 
-impl Policy {
-    pub fn get_path_config<'a>(&'a self, path: &str) -> PathPolicy {
-        let path = if let Some((left, _)) = path.split_once('?') {
-            left
+impl PathConfiguration {
+    pub fn scans(&self, direction: Direction) -> bool {
+        self.directions.contains(&direction)
+    }
+
+    /// returns true if the category is matched in `direction` bodies of `content_type`,
+    /// which it is unless it's ignored or its `content_types` don't list `content_type`
+    pub fn scans_content(&self, direction: Direction, content_type: &ContentType) -> bool {
+        let config = &self.category_config;
+        self.scans(direction)
+            && (config.content_types.is_empty() || config.content_types.contains(content_type))
+            && !matches!(config.action, Some(PolicyAction::Ignore))
+    }
+
+    /// returns true if `context` of a parser with the contexts `parser_contexts` is matched,
+    /// which is all of them if none of `parser_contexts` are configured
+    pub fn matches_context(&self, context: MatchContext, parser_contexts: &[MatchContext]) -> bool {
+        let contexts = &self.category_config.contexts;
+        contexts.contains(&context) || !parser_contexts.iter().any(|x| contexts.contains(x))
+    }
+
+    pub fn matches_value_type(&self, value_type: JsonValueType) -> bool {
+        if self.category_config.value_types.is_empty() {
+            value_type == JsonValueType::String
         } else {
-            path
-        };
+            self.category_config.value_types.contains(&value_type)
+        }
+    }
+}
 
-        let components = path
-            .split('/')
-            .map(|x| x.trim())
-            .filter(|x| !x.is_empty())
-            .collect::<Vec<_>>();
-        let mut output = IndexMap::new();
+impl PathPolicy {
+    /// returns true if any category is scanned in `direction`
+    pub fn scans(&self, direction: Direction) -> bool {
+        self.configuration
+            .values()
+            .any(|config| config.scans(direction))
+    }
+}
 
-        let mut policy_paths: BTreeMap<&PathGlob, Vec<&EndpointConfig>> = BTreeMap::new();
+/// splits a request path (with hostname prefix) into glob-matchable components, ignoring the query string
+pub(crate) fn path_components(path: &str) -> Vec<&str> {
+    let path = if let Some((left, _)) = path.split_once('?') {
+        left
+    } else {
+        path
+    };
+
+    path.split('/')
+        .map(|x| x.trim())
+        .filter(|x| !x.is_empty())
+        .collect()
+}
 
-        for endpoint in self.endpoints.iter() {
-            for path in &*endpoint.matches {
-                if path.matches_components(components.iter().copied()) {
-                    policy_paths.entry(path).or_default().push(endpoint);
+impl Policy {
+    pub fn get_path_config(&self, path: &str) -> PathPolicy {
+        let components = path_components(path);
+        let mut globs = vec![];
+        for (endpoint_index, endpoint) in self.endpoints.iter().enumerate() {
+            for (glob_index, glob) in endpoint.matches.iter().enumerate() {
+                if glob.matches_components(components.iter().copied()) {
+                    globs.push(GlobRef {
+                        endpoint: endpoint_index,
+                        glob: glob_index,
+                    });
                 }
             }
         }
+        self.resolve_path_config(&globs)
+    }
+
+    /// builds the configuration of a path matching `globs`, which are in policy order (see `EndpointIndex::matching_globs`)
+    pub fn resolve_path_config(&self, globs: &[GlobRef]) -> PathPolicy {
+        let mut output = IndexMap::new();
+
+        let mut policy_paths: BTreeMap<&PathGlob, Vec<&EndpointConfig>> = BTreeMap::new();
+
+        for glob in globs {
+            let endpoint = &self.endpoints[glob.endpoint];
+            policy_paths
+                .entry(&endpoint.matches[glob.glob])
+                .or_default()
+                .push(endpoint);
+        }
 
         let mut token_extractor = None;
+        let mut limits = self.limits.clone();
 
         for (path, configs) in policy_paths.iter().rev() {
             for config in configs {
+                let endpoint_directions = if config.directions.is_empty() {
+                    &[Direction::Response][..]
+                } else {
+                    &config.directions[..]
+                };
                 for (category, config) in &config.config {
                     output.insert(
                         category.clone(),
@@ -387,12 +682,18 @@ impl Policy {
                                 .report_style
                                 .or(config.report_style)
                                 .unwrap_or(self.report_style),
+                            directions: if config.directions.is_empty() {
+                                endpoint_directions.to_vec()
+                            } else {
+                                config.directions.to_vec()
+                            },
                         },
                     );
                 }
                 if let Some(extractor) = &config.token_extractor {
                     token_extractor = Some(extractor.clone());
                 }
+                limits = limits.overridden_by(&config.limits);
             }
         }
 
@@ -404,6 +705,74 @@ impl Policy {
                 .unwrap_or_else(|| String::new()),
             configuration: output,
             token_extractor,
+            limits,
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_path_directions() {
+        let policy: Policy = serde_yaml::from_str(
+            r#"
+categories: {}
+endpoints:
+  - matches: "**"
+    config:
+      a: {}
+      b:
+        directions: request
+  - matches: "example.com/upload"
+    directions: [request, response]
+    config:
+      c: {}
+"#,
+        )
+        .unwrap();
+        let config = policy.get_path_config("example.com/upload");
+        let directions = |category: &str| {
+            config.configuration[&Arc::new(category.to_string())]
+                .directions
+                .clone()
+        };
+        assert_eq!(directions("a"), vec![Direction::Response]);
+        assert_eq!(directions("b"), vec![Direction::Request]);
+        assert_eq!(
+            directions("c"),
+            vec![Direction::Request, Direction::Response]
+        );
+        assert!(config.configuration[&Arc::new("a".to_string())].scans(Direction::Response));
+        assert!(config.scans(Direction::Request));
+    }
+
+    #[test]
+    fn test_path_limits() {
+        let policy: Policy = serde_yaml::from_str(
+            r#"
+categories: {}
+limits:
+  max_decompressed_bytes: 1000
+  max_compression_ratio: 50
+endpoints:
+  - matches: "**"
+  - matches: "example.com/export/**"
+    limits:
+      max_decompressed_bytes: 5000
+      max_scanned_bytes: 2000
+"#,
+        )
+        .unwrap();
+        assert_eq!(policy.get_path_config("example.com/").limits, policy.limits);
+        assert_eq!(
+            policy.get_path_config("example.com/export/all").limits,
+            BodyLimits {
+                max_decompressed_bytes: Some(5000),
+                max_compression_ratio: Some(50.0),
+                max_scanned_bytes: Some(2000),
+            }
+        );
+    }
+}
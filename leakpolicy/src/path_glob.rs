@@ -279,6 +279,65 @@ impl PathGlob {
     pub fn matches(&self, target: &str) -> bool {
         self.matches_components(target.split('/'))
     }
+
+    /// the first component, if it only matches a single literal value (i.e. a specific hostname)
+    pub(crate) fn literal_host(&self) -> Option<&str> {
+        match self.components.first()? {
+            GlobComponent::Literal(s) => Some(&**s),
+            _ => None,
+        }
+    }
+
+    /// a path matched by this glob, if one can be derived without inverting a regex component
+    pub(crate) fn example_path(&self) -> Option<String> {
+        let mut components = vec![];
+        for component in &self.components {
+            match component {
+                GlobComponent::AnyOne => components.push("_"),
+                GlobComponent::AnyMany => (),
+                GlobComponent::Regex(..) => return None,
+                GlobComponent::Contains(s) => components.push(&s[1..s.len() - 1]),
+                GlobComponent::Prefix(s) => components.push(&s[..s.len() - 1]),
+                GlobComponent::Suffix(s) => components.push(&s[1..]),
+                GlobComponent::Literal(s) => components.push(&**s),
+            }
+        }
+        let path = components.join("/");
+        self.matches(&path).then_some(path)
+    }
+
+    /// describes components that parse, but can't match as intended
+    pub(crate) fn problems(&self) -> Vec<String> {
+        let mut problems = vec![];
+        if self.components.is_empty() {
+            problems.push("path glob has no components and matches nothing".to_string());
+        }
+        for (i, component) in self.components.iter().enumerate() {
+            match component {
+                GlobComponent::AnyMany
+                    if matches!(self.components.get(i + 1), Some(GlobComponent::AnyMany)) =>
+                {
+                    problems.push("consecutive `**` components are not supported".to_string());
+                }
+                GlobComponent::Regex(..) | GlobComponent::AnyOne | GlobComponent::AnyMany => (),
+                component => {
+                    let text = match component {
+                        GlobComponent::Contains(s) => &s[1..s.len() - 1],
+                        GlobComponent::Prefix(s) => &s[..s.len() - 1],
+                        GlobComponent::Suffix(s) => &s[1..],
+                        other => other.as_ref(),
+                    };
+                    if text.is_empty() || text.contains('*') {
+                        problems.push(format!(
+                            "`{}`: `*` is only a wildcard as an entire component, or at the start or end of one",
+                            component.as_ref()
+                        ));
+                    }
+                }
+            }
+        }
+        problems
+    }
 }
 
 #[cfg(test)]
@@ -0,0 +1,616 @@
+use std::{collections::HashMap, fmt};
+
+use yaml_rust::{
+    parser::{Event, MarkedEventReceiver, Parser},
+    scanner::Marker,
+};
+
+use crate::{
+    BodyLimits, Category, ConfiguredPolicyAction, ContentType, DataReportStyle, MatchContext,
+    MatchGroup, MatchGroupRef, Policy, DIRECT_MATCHERS,
+};
+
+/// A single problem found in a policy
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Diagnostic {
+    /// mapping keys and sequence indices leading to the offending value, i.e. `["endpoints", "0", "config"]`
+    pub path: Vec<String>,
+    pub message: String,
+    /// 1-based (line, column) of the offending value in the YAML source, if located
+    pub location: Option<(usize, usize)>,
+}
+
+impl fmt::Display for Diagnostic {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.path.join("."))?;
+        if let Some((line, column)) = self.location {
+            write!(f, " (line {line}, column {column})")?;
+        }
+        write!(f, ": {}", self.message)
+    }
+}
+
+/// Errors make a policy invalid, warnings are likely mistakes that don't prevent it from being used
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct ValidationReport {
+    pub errors: Vec<Diagnostic>,
+    pub warnings: Vec<Diagnostic>,
+}
+
+impl fmt::Display for ValidationReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for error in &self.errors {
+            writeln!(f, "error: {error}")?;
+        }
+        for warning in &self.warnings {
+            writeln!(f, "warning: {warning}")?;
+        }
+        Ok(())
+    }
+}
+
+impl ValidationReport {
+    pub fn is_valid(&self) -> bool {
+        self.errors.is_empty()
+    }
+
+    fn error(&mut self, path: &[&str], message: impl Into<String>) {
+        self.errors.push(Diagnostic {
+            path: path.iter().map(|x| x.to_string()).collect(),
+            message: message.into(),
+            location: None,
+        });
+    }
+
+    fn warning(&mut self, path: &[&str], message: impl Into<String>) {
+        self.warnings.push(Diagnostic {
+            path: path.iter().map(|x| x.to_string()).collect(),
+            message: message.into(),
+            location: None,
+        });
+    }
+
+    /// fills in the location of each diagnostic from the YAML (or JSON) source the policy was parsed from.
+    /// `root` is the path to the policy within the source, i.e. `["local_policy"]` for a plugin configuration.
+    /// diagnostics for values not literally present in the source (i.e. defaults) point at their closest parent.
+    pub fn locate(&mut self, source: &str, root: &[&str]) {
+        let mut locator = Locator::default();
+        if let Err(e) = Parser::new(source.chars()).load(&mut locator, false) {
+            log::debug!("failed to locate policy diagnostics: {e}");
+            return;
+        }
+        for diagnostic in self.errors.iter_mut().chain(self.warnings.iter_mut()) {
+            let mut path = root.iter().map(|x| x.to_string()).collect::<Vec<_>>();
+            path.extend(diagnostic.path.iter().cloned());
+            while !path.is_empty() {
+                if let Some(marker) = locator.locations.get(&path) {
+                    diagnostic.location = Some((marker.line(), marker.col() + 1));
+                    break;
+                }
+                path.pop();
+            }
+        }
+    }
+}
+
+enum Frame {
+    Mapping { key: Option<String> },
+    Sequence { index: usize },
+}
+
+/// records the location of every value (or mapping key) by its path
+#[derive(Default)]
+struct Locator {
+    stack: Vec<Frame>,
+    locations: HashMap<Vec<String>, Marker>,
+}
+
+impl Locator {
+    fn path(&self) -> Vec<String> {
+        self.stack
+            .iter()
+            .filter_map(|frame| match frame {
+                Frame::Mapping { key } => key.clone(),
+                Frame::Sequence { index } => Some(index.to_string()),
+            })
+            .collect()
+    }
+
+    /// returns true if the event is a mapping key
+    fn is_key(&self) -> bool {
+        matches!(self.stack.last(), Some(Frame::Mapping { key: None }))
+    }
+
+    fn end_value(&mut self) {
+        match self.stack.last_mut() {
+            Some(Frame::Mapping { key }) => *key = None,
+            Some(Frame::Sequence { index }) => *index += 1,
+            None => (),
+        }
+    }
+
+    fn start_value(&mut self, marker: Marker) {
+        let path = self.path();
+        self.locations.entry(path).or_insert(marker);
+    }
+}
+
+impl MarkedEventReceiver for Locator {
+    fn on_event(&mut self, event: Event, marker: Marker) {
+        match event {
+            Event::Scalar(value, ..) if self.is_key() => {
+                if let Some(Frame::Mapping { key }) = self.stack.last_mut() {
+                    *key = Some(value);
+                }
+                self.start_value(marker);
+            }
+            Event::Scalar(..) | Event::Alias(_) => {
+                self.start_value(marker);
+                self.end_value();
+            }
+            Event::MappingStart(_) => {
+                self.start_value(marker);
+                self.stack.push(Frame::Mapping { key: None });
+            }
+            Event::SequenceStart(_) => {
+                self.start_value(marker);
+                self.stack.push(Frame::Sequence { index: 0 });
+            }
+            Event::MappingEnd | Event::SequenceEnd => {
+                self.stack.pop();
+                self.end_value();
+            }
+            _ => (),
+        }
+    }
+}
+
+fn validate_report_style(
+    report: &mut ValidationReport,
+    path: &[&str],
+    report_style: Option<DataReportStyle>,
+) {
+    if let Some(DataReportStyle::PartialSha256 { report_bits }) = report_style {
+        if report_bits == 0 || report_bits > 256 {
+            let mut path = path.to_vec();
+            path.push("report_bits");
+            report.error(&path, "partial_sha256 report_bits must be within [1, 256]");
+        }
+    }
+}
+
+fn validate_limits(report: &mut ValidationReport, path: &[&str], limits: &BodyLimits) {
+    let mut path = path.to_vec();
+    path.push("limits");
+    if let Some(ratio) = limits.max_compression_ratio {
+        if ratio.is_nan() || ratio < 1.0 {
+            let mut path = path.clone();
+            path.push("max_compression_ratio");
+            report.error(&path, "max_compression_ratio must be at least 1");
+        }
+    }
+    for (field, limit) in [
+        ("max_decompressed_bytes", limits.max_decompressed_bytes),
+        ("max_scanned_bytes", limits.max_scanned_bytes),
+    ] {
+        if limit == Some(0) {
+            let mut path = path.clone();
+            path.push(field);
+            report.warning(&path, format!("{field} of 0 disables scanning"));
+        }
+    }
+}
+
+/// returns true for a `type/subtype` media type without parameters
+fn is_media_type(value: &str) -> bool {
+    match value.split_once('/') {
+        Some((kind, subtype)) => {
+            !kind.is_empty()
+                && !subtype.is_empty()
+                && !subtype.contains('/')
+                && !value.contains(|c: char| c == ';' || c.is_whitespace())
+        }
+        None => false,
+    }
+}
+
+fn validate_match_group(report: &mut ValidationReport, path: &[&str], match_group: &MatchGroup) {
+    if match_group.raw.is_empty() && match_group.regexes.is_empty() && match_group.direct.is_empty()
+    {
+        report.warning(path, "match group has no raw, regexes, or direct matchers");
+    }
+    if match_group.regex_strip > 0 && match_group.regexes.is_empty() {
+        report.warning(path, "regex_strip is set, but there are no regexes");
+    }
+    for (i, name) in match_group.direct.iter().enumerate() {
+        if !DIRECT_MATCHERS.contains(&&**name) {
+            let i = i.to_string();
+            let mut path = path.to_vec();
+            path.extend(["direct", &*i]);
+            report.error(
+                &path,
+                format!(
+                    "unknown direct matcher '{name}', expected one of: {}",
+                    DIRECT_MATCHERS.join(", ")
+                ),
+            );
+        }
+    }
+}
+
+impl Policy {
+    /// checks for mistakes that parsing alone doesn't catch
+    pub fn validate(&self) -> ValidationReport {
+        let mut report = ValidationReport::default();
+
+        for (name, category) in &self.categories {
+            let path = ["categories", &**name];
+            match category {
+                Category::Matchers { match_group } => {
+                    validate_match_group(
+                        &mut report,
+                        &["categories", name, "Matchers"],
+                        match_group,
+                    );
+                }
+                Category::Correlate { group1, group2, .. } => {
+                    self.validate_group_ref(&mut report, &path, "Correlate", "group1", group1);
+                    self.validate_group_ref(&mut report, &path, "Correlate", "group2", group2);
+                }
+                Category::Rematch { target, rematcher } => {
+                    self.validate_group_ref(&mut report, &path, "Rematch", "target", target);
+                    self.validate_group_ref(&mut report, &path, "Rematch", "rematcher", rematcher);
+                }
+                Category::Keyed { keys, values } => {
+                    if keys.is_empty() {
+                        report.error(&["categories", name, "Keyed", "keys"], "no key regexes");
+                    }
+                    if let Some(values) = values {
+                        self.validate_group_ref(&mut report, &path, "Keyed", "values", values);
+                    }
+                }
+                Category::ImageMetadata { tags, values } => {
+                    if tags.is_empty() {
+                        report.error(
+                            &["categories", name, "ImageMetadata", "tags"],
+                            "no tag regexes",
+                        );
+                    }
+                    if let Some(values) = values {
+                        self.validate_group_ref(
+                            &mut report,
+                            &path,
+                            "ImageMetadata",
+                            "values",
+                            values,
+                        );
+                    }
+                }
+            }
+            let used = self
+                .endpoints
+                .iter()
+                .any(|endpoint| endpoint.config.contains_key(name));
+            if !used {
+                report.warning(&path, "category is not used by any endpoint");
+            }
+        }
+
+        for (i, endpoint) in self.endpoints.iter().enumerate() {
+            let i = i.to_string();
+            let path = ["endpoints", &*i];
+            if endpoint.matches.is_empty() {
+                report.warning(&path, "endpoint has no path globs in `matches`");
+            }
+            for (j, glob) in endpoint.matches.iter().enumerate() {
+                let j = j.to_string();
+                for problem in glob.problems() {
+                    report.error(&["endpoints", &*i, "matches", &*j], problem);
+                }
+            }
+            validate_report_style(&mut report, &path, endpoint.report_style);
+            validate_limits(&mut report, &path, &endpoint.limits);
+            for (category_name, config) in &endpoint.config {
+                let path = ["endpoints", &*i, "config", &***category_name];
+                if !self.categories.contains_key(&**category_name) {
+                    report.error(&path, format!("no category named '{category_name}'"));
+                }
+                validate_report_style(&mut report, &path, config.report_style);
+                if let Some(Category::Keyed { .. }) = self.categories.get(&**category_name) {
+                    self.validate_keyed_config(&mut report, &path, config);
+                }
+            }
+        }
+
+        validate_report_style(&mut report, &["report_style"], Some(self.report_style));
+        validate_limits(&mut report, &[], &self.limits);
+        for (media_type, alias) in &self.content_type_aliases {
+            if !is_media_type(media_type) || !is_media_type(alias) {
+                report.error(
+                    &["content_type_aliases", media_type],
+                    "content type aliases must map a media type (i.e. `application/x-json`) to another",
+                );
+            }
+        }
+        if !(0.0..=1.0).contains(&self.body_collection_rate) {
+            report.warning(
+                &["body_collection_rate"],
+                "body_collection_rate should be within [0, 1]",
+            );
+        }
+        if !(100..=599).contains(&self.block.status_code) {
+            report.error(
+                &["block", "status_code"],
+                "block status_code must be a valid HTTP status code",
+            );
+        }
+
+        report
+    }
+
+    /// Keyed categories only run on json values (including json scripts of html bodies) and csv cells,
+    /// warns if `config` leaves them nothing to match
+    fn validate_keyed_config(
+        &self,
+        report: &mut ValidationReport,
+        path: &[&str],
+        config: &ConfiguredPolicyAction,
+    ) {
+        let keyed_content_type = config.content_types.is_empty()
+            || config.content_types.iter().any(|x| match x {
+                ContentType::Json | ContentType::Csv => true,
+                ContentType::Html => self.embedded_json_depth > 0,
+                _ => false,
+            });
+        if !keyed_content_type {
+            report.warning(
+                &[path, &["content_types"]].concat(),
+                "Keyed categories only match JSON values and CSV cells, this category never runs",
+            );
+        }
+        let contexts = &config.contexts;
+        if contexts
+            .iter()
+            .any(|x| MatchContext::STRUCTURED.contains(x))
+            && !contexts.contains(&MatchContext::Values)
+        {
+            report.warning(
+                &[path, &["contexts"]].concat(),
+                "Keyed categories only match in the `values` context, this category never runs",
+            );
+        }
+    }
+
+    fn validate_group_ref(
+        &self,
+        report: &mut ValidationReport,
+        path: &[&str],
+        kind: &str,
+        field: &str,
+        group: &MatchGroupRef,
+    ) {
+        let mut path = path.to_vec();
+        path.extend([kind, field]);
+        match group {
+            MatchGroupRef::Inline { match_group } => {
+                validate_match_group(report, &path, match_group);
+            }
+            MatchGroupRef::Ref { name } => match self.categories.get(name) {
+                None => report.error(&path, format!("no category named '{name}'")),
+                Some(Category::Matchers { .. }) => (),
+                Some(_) => report.error(
+                    &path,
+                    format!("'{name}' must be a Matchers category to be used in a {kind} category"),
+                ),
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn validate(source: &str) -> ValidationReport {
+        let policy: Policy = serde_yaml::from_str(source).unwrap();
+        let mut report = policy.validate();
+        report.locate(source, &[]);
+        report
+    }
+
+    fn error_locations(report: &ValidationReport) -> Vec<(String, Option<(usize, usize)>)> {
+        report
+            .errors
+            .iter()
+            .map(|x| (x.path.join("."), x.location))
+            .collect()
+    }
+
+    #[test]
+    fn test_validate() {
+        let report = validate(
+            r#"
+categories:
+  ssn:
+    Matchers:
+      regexes:
+        - "\\d{3}-\\d{2}-\\d{4}"
+  ssn_near_label:
+    Correlate:
+      group1:
+        name: ssn
+      group2:
+        name: missing
+      max_distance: 64
+  rematched:
+    Rematch:
+      target:
+        name: ssn_near_label
+      rematcher:
+        raw:
+          - "123"
+endpoints:
+  - matches: "**"
+    config:
+      ssn:
+        report_style: partial_sha256
+        report_bits: 0
+      ssn_near_label: {}
+      rematched: {}
+      unknown: {}
+"#,
+        );
+        assert_eq!(
+            error_locations(&report),
+            vec![
+                (
+                    "categories.ssn_near_label.Correlate.group2".to_string(),
+                    Some((11, 7))
+                ),
+                (
+                    "categories.rematched.Rematch.target".to_string(),
+                    Some((16, 7))
+                ),
+                (
+                    "endpoints.0.config.ssn.report_bits".to_string(),
+                    Some((26, 9))
+                ),
+                ("endpoints.0.config.unknown".to_string(), Some((29, 7))),
+            ]
+        );
+        assert!(report.warnings.is_empty());
+    }
+
+    #[test]
+    fn test_validate_keyed() {
+        let report = validate(
+            r#"
+categories:
+  ssn_field:
+    Keyed:
+      keys: "^ssn$"
+endpoints:
+  - matches: "**"
+    config:
+      ssn_field:
+        content_types: [xml, form]
+  - matches: "/api/**"
+    config:
+      ssn_field:
+        contexts: keys
+  - matches: "/csv/**"
+    config:
+      ssn_field:
+        content_types: [csv, xml]
+"#,
+        );
+        assert!(report.is_valid());
+        assert_eq!(
+            report
+                .warnings
+                .iter()
+                .map(|x| x.path.join("."))
+                .collect::<Vec<_>>(),
+            vec![
+                "endpoints.0.config.ssn_field.content_types",
+                "endpoints.1.config.ssn_field.contexts",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_validate_limits() {
+        let report = validate(
+            r#"
+categories: {}
+limits:
+  max_compression_ratio: 0.5
+endpoints:
+  - matches: "**"
+    limits:
+      max_scanned_bytes: 0
+"#,
+        );
+        assert_eq!(
+            error_locations(&report),
+            vec![("limits.max_compression_ratio".to_string(), Some((4, 3)))]
+        );
+        assert_eq!(
+            report.warnings[0].path.join("."),
+            "endpoints.0.limits.max_scanned_bytes"
+        );
+    }
+
+    #[test]
+    fn test_validate_content_type_aliases() {
+        let report = validate(
+            r#"
+categories: {}
+endpoints: []
+content_type_aliases:
+  application/x-amz-json-1.1: application/json
+  json: application/json
+  text/x-csv: "text/csv; charset=utf-8"
+"#,
+        );
+        assert_eq!(
+            error_locations(&report),
+            vec![
+                ("content_type_aliases.json".to_string(), Some((6, 3))),
+                ("content_type_aliases.text/x-csv".to_string(), Some((7, 3))),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_validate_globs() {
+        let report = validate(
+            r#"
+categories: {}
+endpoints:
+  - matches:
+      - "example.com/**/**"
+      - "example.com/a*b"
+"#,
+        );
+        assert_eq!(
+            error_locations(&report),
+            vec![
+                ("endpoints.0.matches.0".to_string(), Some((5, 9))),
+                ("endpoints.0.matches.1".to_string(), Some((6, 9))),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_validate_direct() {
+        let report = validate(
+            r#"
+categories:
+  payment_data:
+    Matchers:
+      direct:
+        - credit_card
+        - credit_crad
+endpoints:
+  - matches: "**"
+    config:
+      payment_data: {}
+"#,
+        );
+        assert_eq!(
+            error_locations(&report),
+            vec![(
+                "categories.payment_data.Matchers.direct.1".to_string(),
+                Some((7, 11))
+            )]
+        );
+    }
+
+    #[test]
+    fn test_validate_example() {
+        let source = include_str!("../../examples/policies/simple_policy.yaml");
+        let policy: Policy = serde_yaml::from_str(source).unwrap();
+        assert!(policy.validate().is_valid());
+    }
+}
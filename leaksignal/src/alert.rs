@@ -0,0 +1,406 @@
+use std::{
+    collections::{hash_map::DefaultHasher, HashMap, HashSet},
+    hash::{Hash, Hasher},
+    time::SystemTime,
+};
+
+use log::error;
+use proxy_wasm::{
+    hostcalls,
+    types::{MetricType, Status},
+};
+use rand::{thread_rng, Rng};
+use sha2::{Digest, Sha256};
+
+use crate::{evaluator::MatcherMetadata, metric::Metric, policy::DataReportStyle};
+
+const WINDOW_SECS: u64 = 300;
+const CAS_RETRIES: usize = 8;
+/// number of shared data keys per category for each of ip and token windows
+const WINDOW_SLOTS: u64 = 1024;
+/// number of consecutive slots searched for a window, the oldest of them is evicted if none is free
+const SLOT_PROBES: u64 = 8;
+
+/// Tracks matches against the `AlertConfig` thresholds of their categories for a single response.
+/// 5 minute windows are kept in envoy shared data, so all worker threads count towards the same window.
+/// Envoy never frees shared data, so windows live in a fixed set of slots per category that are reused once expired
+/// or evicted, and clients are only identified in them by a hash.
+#[derive(Default)]
+pub struct AlertTracker {
+    ip: Option<String>,
+    token: Option<String>,
+    /// hashes of unique matches per category within this response
+    request_matches: HashMap<String, HashSet<u64>>,
+    /// categories whose thresholds were already met within this response
+    escalated: HashSet<String>,
+}
+
+impl AlertTracker {
+    pub fn new(ip: String, token: Option<String>) -> Self {
+        Self {
+            ip: Some(ip).filter(|x| !x.is_empty()),
+            token: token.filter(|x| !x.is_empty()),
+            ..Default::default()
+        }
+    }
+
+    /// records a match of `value`, returns true if its category has met any of its alert thresholds
+    pub fn record(&mut self, metadata: &MatcherMetadata, value: &str) -> bool {
+        let config = &metadata.alert;
+        if config.is_empty() {
+            return true;
+        }
+        let category_name = &metadata.category_name;
+        if self.escalated.contains(category_name) {
+            return true;
+        }
+        let hash = match_hash(metadata.local_report_style, value);
+
+        let mut escalated = false;
+        if let Some(threshold) = config.per_request {
+            let request_matches = self
+                .request_matches
+                .entry(category_name.clone())
+                .or_default();
+            request_matches.insert(hash);
+            escalated |= request_matches.len() >= threshold;
+        }
+        if let (Some(threshold), Some(ip)) = (config.per_5min_by_ip, &self.ip) {
+            escalated |= record_shared(
+                &format!("leaksignal_alert.{category_name}.ip"),
+                identity_hash(ip),
+                hash,
+                threshold,
+            );
+        }
+        if let (Some(threshold), Some(token)) = (config.per_5min_by_token, &self.token) {
+            escalated |= record_shared(
+                &format!("leaksignal_alert.{category_name}.token"),
+                identity_hash(token),
+                hash,
+                threshold,
+            );
+        }
+
+        if escalated {
+            self.escalated.insert(category_name.clone());
+        }
+        escalated
+    }
+}
+
+/// matches that aren't reported are all considered unique
+fn match_hash(report_style: DataReportStyle, value: &str) -> u64 {
+    if matches!(report_style, DataReportStyle::None) {
+        return thread_rng().gen();
+    }
+    let mut hasher = DefaultHasher::new();
+    value.hash(&mut hasher);
+    hasher.finish()
+}
+
+/// clients are identified by a hash of their ip or token, so neither is exposed in shared data keys
+fn identity_hash(identity: &str) -> u64 {
+    let digest = Sha256::digest(identity.as_bytes());
+    u64::from_le_bytes(digest[..8].try_into().unwrap())
+}
+
+fn read_u64(window: &[u8], index: usize) -> Option<u64> {
+    window
+        .get(index * 8..index * 8 + 8)
+        .map(|x| u64::from_le_bytes(x.try_into().unwrap()))
+}
+
+/// returns the identity owning `window` if it hasn't expired
+fn window_owner(window: &[u8], now: u64) -> Option<u64> {
+    let identity = read_u64(window, 0)?;
+    let start = read_u64(window, 1)?;
+    (now < start + WINDOW_SECS).then_some(identity)
+}
+
+/// A window is encoded as the identity it counts for and its start time in seconds, followed by the hashes of its
+/// unique matches. Hashes stop being collected once `threshold` is met, bounding the size of each window.
+/// `window` is replaced if it expired or belongs to another identity.
+/// Returns the updated window if it changed, and whether the threshold is met.
+fn update_window(
+    window: Option<&[u8]>,
+    identity: u64,
+    now: u64,
+    hash: u64,
+    threshold: usize,
+) -> (Option<Vec<u8>>, bool) {
+    let mut window = match window {
+        Some(window) if window_owner(window, now) == Some(identity) => window.to_vec(),
+        _ => {
+            let mut window = identity.to_le_bytes().to_vec();
+            window.extend_from_slice(&now.to_le_bytes());
+            window
+        }
+    };
+    let count = (window.len() - 16) / 8;
+    if count >= threshold {
+        return (None, true);
+    }
+    let hash = hash.to_le_bytes();
+    if window[16..].chunks_exact(8).any(|x| x == hash) {
+        return (None, false);
+    }
+    window.extend_from_slice(&hash);
+    (Some(window), count + 1 >= threshold)
+}
+
+/// Envoy shared data holding the alert windows
+trait SharedData {
+    fn get(&self, key: &str) -> Result<(Option<Vec<u8>>, Option<u32>), Status>;
+    fn set(&self, key: &str, value: &[u8], cas: Option<u32>) -> Result<(), Status>;
+}
+
+struct HostSharedData;
+
+impl SharedData for HostSharedData {
+    fn get(&self, key: &str) -> Result<(Option<Vec<u8>>, Option<u32>), Status> {
+        hostcalls::get_shared_data(key)
+    }
+
+    fn set(&self, key: &str, value: &[u8], cas: Option<u32>) -> Result<(), Status> {
+        hostcalls::set_shared_data(key, Some(value), cas)
+    }
+}
+
+/// shared data key, window and cas of a window slot
+type Slot = (String, Option<Vec<u8>>, Option<u32>);
+
+/// finds the slot holding the live window of `identity`, or else the first free slot, among the slots it probes.
+/// if all probed slots hold live windows of other identities, the oldest of them is returned to be evicted.
+/// returns the slot and whether it is evicted.
+fn find_slot(
+    shared: &impl SharedData,
+    prefix: &str,
+    identity: u64,
+    now: u64,
+) -> Result<(Slot, bool), Status> {
+    let mut free = None;
+    let mut oldest: Option<(u64, Slot)> = None;
+    for probe in 0..SLOT_PROBES {
+        let key = format!("{prefix}.{}", identity.wrapping_add(probe) % WINDOW_SLOTS);
+        let (window, cas) = shared.get(&key)?;
+        match window.as_deref().and_then(|x| window_owner(x, now)) {
+            Some(owner) if owner == identity => return Ok(((key, window, cas), false)),
+            Some(_) => {
+                let start = window.as_deref().and_then(|x| read_u64(x, 1)).unwrap_or(0);
+                if !matches!(&oldest, Some((oldest, _)) if *oldest <= start) {
+                    oldest = Some((start, (key, window, cas)));
+                }
+            }
+            None => {
+                free.get_or_insert((key, window, cas));
+            }
+        }
+    }
+    match free {
+        Some(slot) => Ok((slot, false)),
+        None => Ok((oldest.expect("no slots probed").1, true)),
+    }
+}
+
+/// Outcome of counting a match in a shared window
+#[derive(Debug, PartialEq, Eq)]
+enum WindowUpdate {
+    Counted {
+        /// set if the threshold is met
+        met: bool,
+        /// set if the window of another identity was evicted for it
+        evicted: bool,
+    },
+    /// the window couldn't be read or written
+    Failed,
+}
+
+/// counts a match in the shared window of `identity` at `now` (in seconds)
+fn update_shared(
+    shared: &impl SharedData,
+    prefix: &str,
+    identity: u64,
+    now: u64,
+    hash: u64,
+    threshold: usize,
+) -> WindowUpdate {
+    for _ in 0..CAS_RETRIES {
+        let ((key, window, cas), evicted) = match find_slot(shared, prefix, identity, now) {
+            Ok(x) => x,
+            Err(e) => {
+                error!("failed to read alert window '{prefix}': {:?}", e);
+                return WindowUpdate::Failed;
+            }
+        };
+        let (window, met) = update_window(window.as_deref(), identity, now, hash, threshold);
+        let window = match window {
+            Some(x) => x,
+            None => {
+                return WindowUpdate::Counted {
+                    met,
+                    evicted: false,
+                }
+            }
+        };
+        match shared.set(&key, &window[..], cas) {
+            Ok(()) => return WindowUpdate::Counted { met, evicted },
+            Err(Status::CasMismatch) => continue,
+            Err(e) => {
+                error!("failed to write alert window '{key}': {:?}", e);
+                return WindowUpdate::Failed;
+            }
+        }
+    }
+    error!("alert window '{prefix}' is too contended");
+    WindowUpdate::Failed
+}
+
+/// counts a match in the shared window of `identity`, returns true if `threshold` is met.
+/// if the window can't be read or written, the threshold is considered met so enforcement fails closed.
+fn record_shared(prefix: &str, identity: u64, hash: u64, threshold: usize) -> bool {
+    let now = hostcalls::get_current_time()
+        .unwrap()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs();
+    match update_shared(&HostSharedData, prefix, identity, now, hash, threshold) {
+        WindowUpdate::Counted { met, evicted } => {
+            if evicted {
+                Metric::lookup_or_define("ls.alert_window.evicted.count", MetricType::Counter)
+                    .increment(1);
+            }
+            met
+        }
+        WindowUpdate::Failed => {
+            Metric::lookup_or_define("ls.alert_window.failed.count", MetricType::Counter)
+                .increment(1);
+            true
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cell::RefCell;
+
+    use super::*;
+
+    #[test]
+    fn test_update_window() {
+        let (window, met) = update_window(None, 7, 1000, 1, 2);
+        assert!(!met);
+        let window = window.unwrap();
+        assert_eq!(window.len(), 24);
+        assert_eq!(window_owner(&window, 1010), Some(7));
+
+        // duplicate match
+        assert_eq!(update_window(Some(&window), 7, 1010, 1, 2), (None, false));
+
+        let (window, met) = update_window(Some(&window), 7, 1010, 2, 2);
+        assert!(met);
+        let window = window.unwrap();
+        assert_eq!(update_window(Some(&window), 7, 1020, 3, 2), (None, true));
+
+        // window expired
+        assert_eq!(window_owner(&window, 1000 + WINDOW_SECS), None);
+        let (expired, met) = update_window(Some(&window), 7, 1000 + WINDOW_SECS, 3, 2);
+        assert!(!met);
+        assert_eq!(expired.unwrap().len(), 24);
+
+        // window of another identity is replaced
+        let (replaced, met) = update_window(Some(&window), 8, 1020, 3, 2);
+        assert!(!met);
+        assert_eq!(window_owner(&replaced.unwrap(), 1020), Some(8));
+    }
+
+    #[derive(Default)]
+    struct TestSharedData(RefCell<HashMap<String, (Vec<u8>, u32)>>);
+
+    impl SharedData for TestSharedData {
+        fn get(&self, key: &str) -> Result<(Option<Vec<u8>>, Option<u32>), Status> {
+            Ok(match self.0.borrow().get(key) {
+                Some((value, cas)) => (Some(value.clone()), Some(*cas)),
+                None => (None, None),
+            })
+        }
+
+        fn set(&self, key: &str, value: &[u8], cas: Option<u32>) -> Result<(), Status> {
+            let mut data = self.0.borrow_mut();
+            let current = data.get(key).map(|x| x.1);
+            if cas.is_some() && cas != current {
+                return Err(Status::CasMismatch);
+            }
+            data.insert(key.to_string(), (value.to_vec(), current.unwrap_or(0) + 1));
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_full_slots() {
+        let shared = TestSharedData::default();
+        let now: u64 = 100_000;
+        let prefix = "leaksignal_alert.test_full.ip";
+        let identity = 5;
+        // every probed slot holds a live window of another identity, the one at `identity + 3` being the oldest
+        for probe in 0..SLOT_PROBES {
+            let start = if probe == 3 { now - 200 } else { now - 100 };
+            let mut window = (1000 + probe).to_le_bytes().to_vec();
+            window.extend_from_slice(&start.to_le_bytes());
+            let key = format!("{prefix}.{}", identity + probe);
+            shared.set(&key, &window, None).unwrap();
+        }
+
+        assert_eq!(
+            update_shared(&shared, prefix, identity, now, 1, 2),
+            WindowUpdate::Counted {
+                met: false,
+                evicted: true
+            }
+        );
+        let key = format!("{prefix}.{}", identity + 3);
+        let window = shared.get(&key).unwrap().0.unwrap();
+        assert_eq!(window_owner(&window, now), Some(identity));
+        // the evicted slot keeps counting for the new identity
+        assert_eq!(
+            update_shared(&shared, prefix, identity, now, 2, 2),
+            WindowUpdate::Counted {
+                met: true,
+                evicted: false
+            }
+        );
+    }
+
+    #[test]
+    fn test_failed_update() {
+        struct FailingSharedData;
+
+        impl SharedData for FailingSharedData {
+            fn get(&self, _key: &str) -> Result<(Option<Vec<u8>>, Option<u32>), Status> {
+                Ok((None, Some(1)))
+            }
+
+            fn set(&self, _key: &str, _value: &[u8], _cas: Option<u32>) -> Result<(), Status> {
+                Err(Status::CasMismatch)
+            }
+        }
+
+        assert_eq!(
+            update_shared(
+                &FailingSharedData,
+                "leaksignal_alert.test_failed.ip",
+                5,
+                0,
+                1,
+                2
+            ),
+            WindowUpdate::Failed
+        );
+    }
+
+    #[test]
+    fn test_identity_hash() {
+        assert_eq!(identity_hash("token"), identity_hash("token"));
+        assert_ne!(identity_hash("token"), identity_hash("other token"));
+    }
+}
@@ -0,0 +1,365 @@
+use anyhow::{bail, Result};
+use encoding_rs::{Decoder, Encoding, UTF_16BE, UTF_16LE, UTF_8};
+
+/// length of the longest byte order mark, the start of a body is held until this much is available
+const MAX_BOM_LENGTH: usize = 3;
+
+/// returns the encoding named by the `charset` parameter of a content-type value
+pub fn charset_from_content_type(value: &str) -> Option<&'static Encoding> {
+    value
+        .split(';')
+        .skip(1)
+        .filter_map(|x| x.split_once('='))
+        .find(|(name, _)| name.trim().eq_ignore_ascii_case("charset"))
+        .and_then(|(_, label)| Encoding::for_label(label.trim().trim_matches('"').as_bytes()))
+}
+
+/// A run of characters with the same encoded and decoded lengths
+struct Run {
+    output_start: usize,
+    input_start: usize,
+    count: usize,
+    output_length: usize,
+    input_length: usize,
+}
+
+/// Maps offsets of a transcoded body back to the body it was decoded from
+#[derive(Default)]
+struct OffsetMap {
+    runs: Vec<Run>,
+    output_length: usize,
+    input_length: usize,
+}
+
+impl OffsetMap {
+    /// records the next decoded character
+    fn push(&mut self, input_start: usize, input_length: usize, output_length: usize) {
+        match self.runs.last_mut() {
+            Some(run)
+                if run.input_length == input_length
+                    && run.output_length == output_length
+                    && run.input_start + run.count * run.input_length == input_start =>
+            {
+                run.count += 1;
+            }
+            _ => self.runs.push(Run {
+                output_start: self.output_length,
+                input_start,
+                count: 1,
+                output_length,
+                input_length,
+            }),
+        }
+        self.output_length += output_length;
+        self.input_length = self.input_length.max(input_start + input_length);
+    }
+
+    fn map(&self, offset: usize) -> usize {
+        if offset >= self.output_length {
+            return self.input_length;
+        }
+        let index = self.runs.partition_point(|run| run.output_start <= offset);
+        match index.checked_sub(1).map(|index| &self.runs[index]) {
+            Some(run) => {
+                run.input_start + (offset - run.output_start) / run.output_length * run.input_length
+            }
+            None => offset,
+        }
+    }
+}
+
+enum State {
+    /// the start of the body, held until its byte order mark can be detected
+    Sniffing(Vec<u8>),
+    /// utf-8 without a byte order mark passes through
+    Passthrough,
+    Decoding(Decoder),
+}
+
+/// Streaming transcoder of a body to utf-8, by its byte order mark or declared charset
+pub struct Transcoder {
+    declared: &'static Encoding,
+    state: State,
+    /// encoding of a transcoded body, None if it passes through
+    encoding: Option<&'static Encoding>,
+    bom_length: usize,
+    /// offset of the next input byte
+    input_offset: usize,
+    /// offset of the first input byte of the character being decoded
+    char_start: usize,
+    offsets: OffsetMap,
+    /// set once malformed input was decoded as U+FFFD, the body can then not be encoded as it was
+    malformed: bool,
+}
+
+impl Transcoder {
+    pub fn new(declared: Option<&'static Encoding>) -> Self {
+        Self {
+            declared: declared.unwrap_or(UTF_8),
+            state: State::Sniffing(vec![]),
+            encoding: None,
+            bom_length: 0,
+            input_offset: 0,
+            char_start: 0,
+            offsets: Default::default(),
+            malformed: false,
+        }
+    }
+
+    /// returns the utf-8 transcoding of the next `data` of the body
+    pub fn write(&mut self, data: Vec<u8>) -> Vec<u8> {
+        match &mut self.state {
+            State::Passthrough => data,
+            State::Sniffing(start) => {
+                start.extend_from_slice(&data);
+                if start.len() < MAX_BOM_LENGTH {
+                    return vec![];
+                }
+                let start = std::mem::take(start);
+                self.start(start, false)
+            }
+            State::Decoding(_) => self.decode(&data, false),
+        }
+    }
+
+    /// returns the rest of the transcoded body, at its end
+    pub fn finish(&mut self) -> Vec<u8> {
+        match &mut self.state {
+            State::Passthrough => vec![],
+            State::Sniffing(start) => {
+                let start = std::mem::take(start);
+                self.start(start, true)
+            }
+            State::Decoding(_) => self.decode(&[], true),
+        }
+    }
+
+    fn start(&mut self, start: Vec<u8>, last: bool) -> Vec<u8> {
+        // a byte order mark takes precedence over the declared charset
+        let (encoding, bom_length) = Encoding::for_bom(&start).unwrap_or((self.declared, 0));
+        if encoding == UTF_8 && bom_length == 0 {
+            self.state = State::Passthrough;
+            return start;
+        }
+        self.encoding = Some(encoding);
+        self.bom_length = bom_length;
+        self.input_offset = bom_length;
+        self.char_start = bom_length;
+        self.state = State::Decoding(encoding.new_decoder_without_bom_handling());
+        self.decode(&start[bom_length..], last)
+    }
+
+    fn decode(&mut self, data: &[u8], last: bool) -> Vec<u8> {
+        let encoding = self.encoding.unwrap_or(UTF_8);
+        let decoder = match &mut self.state {
+            State::Decoding(decoder) => decoder,
+            _ => return vec![],
+        };
+        let mut output = String::new();
+        if encoding.is_single_byte() || encoding == UTF_16LE || encoding == UTF_16BE {
+            // the encoded length of each character follows from the character
+            output.reserve(
+                decoder
+                    .max_utf8_buffer_length(data.len())
+                    .unwrap_or_default(),
+            );
+            // with the output reserved, all input is read
+            let (_, _, replaced) = decoder.decode_to_string(data, &mut output, last);
+            self.malformed |= replaced;
+            for c in output.chars() {
+                let input_length = if encoding.is_single_byte() {
+                    1
+                } else {
+                    c.len_utf16() * 2
+                };
+                self.offsets
+                    .push(self.char_start, input_length, c.len_utf8());
+                self.char_start += input_length;
+            }
+            return output.into_bytes();
+        }
+        // variable length encodings are decoded a byte at a time to find where characters start
+        let steps = data.iter().map(std::slice::from_ref);
+        let steps = steps.chain(last.then_some(&[][..]));
+        for step in steps {
+            let before = output.len();
+            output.reserve(decoder.max_utf8_buffer_length(1).unwrap_or_default());
+            let (_, _, replaced) =
+                decoder.decode_to_string(step, &mut output, last && step.is_empty());
+            self.malformed |= replaced;
+            self.input_offset += step.len();
+            if output.len() == before {
+                continue;
+            }
+            let mut input_length = self.input_offset - self.char_start;
+            for c in output[before..].chars() {
+                self.offsets
+                    .push(self.char_start, input_length, c.len_utf8());
+                // further characters of the same step, i.e. after a malformed sequence, share its position
+                input_length = 0;
+            }
+            self.char_start = self.input_offset;
+        }
+        output.into_bytes()
+    }
+
+    /// maps an offset of the transcoded body to the offset in the body it was decoded from
+    pub fn map_offset(&self, offset: usize) -> usize {
+        match self.encoding {
+            Some(_) => self.offsets.map(offset),
+            None => offset,
+        }
+    }
+
+    /// encodes a rewritten transcoded body back into the charset of the body.
+    /// fails if the body can't be encoded without changing more than the rewritten parts
+    pub fn encode(&self, body: Vec<u8>) -> Result<Vec<u8>> {
+        let encoding = match self.encoding {
+            Some(encoding) => encoding,
+            None => return Ok(body),
+        };
+        if self.malformed {
+            bail!("body is malformed {}", encoding.name());
+        }
+        let text = String::from_utf8(body)?;
+        let mut output = Vec::with_capacity(self.bom_length + text.len());
+        if self.bom_length > 0 {
+            let bom: &[u8] = match encoding {
+                x if x == UTF_16LE => b"\xFF\xFE",
+                x if x == UTF_16BE => b"\xFE\xFF",
+                _ => b"\xEF\xBB\xBF",
+            };
+            output.extend_from_slice(bom);
+        }
+        // encoding_rs only encodes utf-16 as utf-8
+        if encoding == UTF_16LE {
+            output.extend(text.encode_utf16().flat_map(u16::to_le_bytes));
+        } else if encoding == UTF_16BE {
+            output.extend(text.encode_utf16().flat_map(u16::to_be_bytes));
+        } else {
+            let (encoded, _, unmappable) = encoding.encode(&text);
+            if unmappable {
+                bail!("rewritten body has characters not in {}", encoding.name());
+            }
+            output.extend_from_slice(&encoded);
+        }
+        Ok(output)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use encoding_rs::{SHIFT_JIS, WINDOWS_1252};
+
+    use super::*;
+
+    fn transcode(transcoder: &mut Transcoder, body: &[u8], chunk_size: usize) -> String {
+        let mut output = vec![];
+        for chunk in body.chunks(chunk_size) {
+            output.extend(transcoder.write(chunk.to_vec()));
+        }
+        output.extend(transcoder.finish());
+        String::from_utf8(output).unwrap()
+    }
+
+    #[test]
+    fn test_charset_from_content_type() {
+        assert_eq!(
+            charset_from_content_type("text/html; charset=\"ISO-8859-1\""),
+            Some(WINDOWS_1252)
+        );
+        assert_eq!(
+            charset_from_content_type("application/json;Charset=utf-16"),
+            Some(UTF_16LE)
+        );
+        assert_eq!(charset_from_content_type("text/plain"), None);
+        assert_eq!(charset_from_content_type("text/plain; charset=bogus"), None);
+    }
+
+    #[test]
+    fn test_passthrough() {
+        let mut transcoder = Transcoder::new(None);
+        let body = "caf\u{e9} a@b.com";
+        assert_eq!(transcode(&mut transcoder, body.as_bytes(), 2), body);
+        assert_eq!(transcoder.map_offset(6), 6);
+        assert_eq!(
+            transcoder.encode(body.as_bytes().to_vec()).unwrap(),
+            body.as_bytes()
+        );
+    }
+
+    #[test]
+    fn test_single_byte() {
+        let mut transcoder = Transcoder::new(Some(WINDOWS_1252));
+        let body = b"caf\xe9 \x93a@b.com\x94";
+        let text = transcode(&mut transcoder, body, 4);
+        assert_eq!(text, "caf\u{e9} \u{201c}a@b.com\u{201d}");
+        let start = text.find("a@b").unwrap();
+        assert_eq!(transcoder.map_offset(start), 6);
+        assert_eq!(transcoder.map_offset(start + 7), 13);
+        assert_eq!(transcoder.map_offset(text.len()), body.len());
+
+        let masked = text.replace("a@b.com", "*******");
+        assert_eq!(
+            transcoder.encode(masked.into_bytes()).unwrap(),
+            b"caf\xe9 \x93*******\x94"
+        );
+        assert!(transcoder
+            .encode(text.replace("a@b.com", "\u{1f600}").into_bytes())
+            .is_err());
+    }
+
+    #[test]
+    fn test_utf16_bom() {
+        // the byte order mark overrides the declared charset
+        let mut transcoder = Transcoder::new(Some(WINDOWS_1252));
+        let mut body = vec![0xFE, 0xFF];
+        body.extend(
+            "\u{1f600} a@b.com"
+                .encode_utf16()
+                .flat_map(u16::to_be_bytes),
+        );
+        let text = transcode(&mut transcoder, &body, 3);
+        assert_eq!(text, "\u{1f600} a@b.com");
+        let start = text.find("a@b").unwrap();
+        assert_eq!(transcoder.map_offset(0), 2);
+        assert_eq!(transcoder.map_offset(start), 2 + 4 + 2);
+        assert_eq!(transcoder.map_offset(start + 7), body.len());
+        assert_eq!(transcoder.encode(text.into_bytes()).unwrap(), body);
+    }
+
+    #[test]
+    fn test_multi_byte() {
+        let mut transcoder = Transcoder::new(Some(SHIFT_JIS));
+        // "日本 a@b.com"
+        let body = b"\x93\xfa\x96\x7b a@b.com";
+        let text = transcode(&mut transcoder, body, 1);
+        assert_eq!(text, "\u{65e5}\u{672c} a@b.com");
+        let start = text.find("a@b").unwrap();
+        assert_eq!(transcoder.map_offset(3), 2);
+        assert_eq!(transcoder.map_offset(start), 5);
+        assert_eq!(transcoder.map_offset(text.len()), body.len());
+    }
+
+    #[test]
+    fn test_utf8_bom() {
+        let mut transcoder = Transcoder::new(None);
+        let text = transcode(&mut transcoder, b"\xEF\xBB\xBF{}", 8);
+        assert_eq!(text, "{}");
+        assert_eq!(transcoder.map_offset(1), 4);
+        assert_eq!(
+            transcoder.encode(b"{}".to_vec()).unwrap(),
+            b"\xEF\xBB\xBF{}"
+        );
+    }
+
+    #[test]
+    fn test_malformed() {
+        let mut transcoder = Transcoder::new(Some(SHIFT_JIS));
+        let body = b"\x93\xfa\xff a@b.com";
+        let text = transcode(&mut transcoder, body, 1);
+        assert_eq!(text, "\u{65e5}\u{fffd} a@b.com");
+        let masked = text.replace("a@b.com", "*******");
+        assert!(transcoder.encode(masked.into_bytes()).is_err());
+    }
+}
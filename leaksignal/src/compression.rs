@@ -0,0 +1,587 @@
+use std::{io::Write, str::FromStr};
+
+use anyhow::{anyhow, bail, Result};
+use brotli::{
+    BrotliDecompressStream, BrotliResult, BrotliState, CompressorWriter, HeapAlloc, HuffmanCode,
+};
+use flate2::{
+    write::{DeflateDecoder, GzDecoder, GzEncoder, ZlibDecoder, ZlibEncoder},
+    Compression,
+};
+use ruzstd::{
+    decoding::FrameDecoder,
+    encoding::{compress_to_vec, CompressionLevel},
+};
+
+const BROTLI_BUFFER_SIZE: usize = 4096;
+const BROTLI_QUALITY: u32 = 5;
+const BROTLI_WINDOW_BITS: u32 = 22;
+
+/// largest possible zstd frame header, a frame is only started once this much input is available
+const ZSTD_MAX_FRAME_HEADER: usize = 18;
+
+/// largest possible decoded size of a compressed zstd block
+const ZSTD_MAX_BLOCK_SIZE: usize = 128 * 1024;
+
+/// A content-coding of an http body
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum ContentEncoding {
+    Gzip,
+    Deflate,
+    Brotli,
+    Zstd,
+}
+
+impl FromStr for ContentEncoding {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        Ok(match &*s.to_ascii_lowercase() {
+            "gzip" | "x-gzip" => ContentEncoding::Gzip,
+            "deflate" => ContentEncoding::Deflate,
+            "br" => ContentEncoding::Brotli,
+            "zstd" => ContentEncoding::Zstd,
+            _ => bail!("unsupported content-encoding '{s}'"),
+        })
+    }
+}
+
+/// parses a `content-encoding` header value into its codings, in the order they were applied.
+/// `identity` is ignored, any unsupported coding is an error
+pub fn parse_content_encodings(value: &str) -> Result<Vec<ContentEncoding>> {
+    value
+        .split(',')
+        .map(str::trim)
+        .filter(|coding| !coding.is_empty() && !coding.eq_ignore_ascii_case("identity"))
+        .map(str::parse)
+        .collect()
+}
+
+/// re-applies `encodings` to a decoded body, in order
+pub fn encode(encodings: &[ContentEncoding], mut body: Vec<u8>) -> Result<Vec<u8>> {
+    for encoding in encodings {
+        body = match encoding {
+            ContentEncoding::Gzip => {
+                let mut encoder = GzEncoder::new(vec![], Compression::default());
+                encoder.write_all(&body)?;
+                encoder.finish()?
+            }
+            ContentEncoding::Deflate => {
+                let mut encoder = ZlibEncoder::new(vec![], Compression::default());
+                encoder.write_all(&body)?;
+                encoder.finish()?
+            }
+            ContentEncoding::Brotli => {
+                let mut encoder = CompressorWriter::new(
+                    vec![],
+                    BROTLI_BUFFER_SIZE,
+                    BROTLI_QUALITY,
+                    BROTLI_WINDOW_BITS,
+                );
+                encoder.write_all(&body)?;
+                encoder.into_inner()
+            }
+            ContentEncoding::Zstd => compress_to_vec(&body[..], CompressionLevel::Fastest),
+        };
+    }
+    Ok(body)
+}
+
+/// writes `data` to a flate2 decoder until it decoded more than `max_output` bytes.
+/// a single write decodes at most one internal buffer of output, so the output can only overshoot by that much.
+/// returns the decoded output, and whether decoding stopped before all of `data` was decoded
+fn write_capped<W: Write>(
+    decoder: &mut W,
+    get_mut: fn(&mut W) -> &mut Vec<u8>,
+    mut data: &[u8],
+    max_output: usize,
+) -> Result<(Vec<u8>, bool)> {
+    let mut out = vec![];
+    while !data.is_empty() {
+        if out.len() > max_output {
+            return Ok((out, true));
+        }
+        let written = decoder.write(data)?;
+        if written == 0 {
+            bail!("trailing data after compressed stream");
+        }
+        data = &data[written..];
+        out.append(get_mut(decoder));
+    }
+    // the decoder holds back the output of the last write until it is flushed
+    decoder.flush()?;
+    out.append(get_mut(decoder));
+    Ok((out, false))
+}
+
+/// `deflate` is zlib wrapped per the spec, but some servers send raw deflate streams
+enum DeflateState {
+    /// not enough input yet to tell zlib from raw deflate
+    Pending(Vec<u8>),
+    Zlib(ZlibDecoder<Vec<u8>>),
+    Raw(DeflateDecoder<Vec<u8>>),
+}
+
+impl DeflateState {
+    /// picks the decoder from the first bytes of the stream
+    fn start(header: &[u8]) -> Self {
+        let is_zlib = header.len() >= 2
+            && header[0] & 0x0f == 8
+            && (u16::from(header[0]) << 8 | u16::from(header[1])) % 31 == 0;
+        if is_zlib {
+            DeflateState::Zlib(ZlibDecoder::new(vec![]))
+        } else {
+            DeflateState::Raw(DeflateDecoder::new(vec![]))
+        }
+    }
+
+    fn write(&mut self, data: &[u8], max_output: usize) -> Result<(Vec<u8>, bool)> {
+        match self {
+            DeflateState::Pending(header) => {
+                header.extend_from_slice(data);
+                if header.len() < 2 {
+                    return Ok((vec![], false));
+                }
+                let header = std::mem::take(header);
+                *self = DeflateState::start(&header);
+                self.write(&header, max_output)
+            }
+            DeflateState::Zlib(decoder) => {
+                write_capped(decoder, ZlibDecoder::get_mut, data, max_output)
+            }
+            DeflateState::Raw(decoder) => {
+                write_capped(decoder, DeflateDecoder::get_mut, data, max_output)
+            }
+        }
+    }
+
+    fn finish(&mut self) -> Result<Vec<u8>> {
+        let mut out = vec![];
+        match self {
+            DeflateState::Pending(header) if header.is_empty() => return Ok(out),
+            DeflateState::Pending(header) => {
+                let header = std::mem::take(header);
+                *self = DeflateState::start(&header);
+                out = self.write(&header, usize::MAX)?.0;
+            }
+            _ => (),
+        }
+        match self {
+            DeflateState::Pending(_) => unreachable!(),
+            DeflateState::Zlib(decoder) => {
+                decoder.try_finish()?;
+                out.append(decoder.get_mut());
+            }
+            DeflateState::Raw(decoder) => {
+                decoder.try_finish()?;
+                out.append(decoder.get_mut());
+            }
+        }
+        Ok(out)
+    }
+}
+
+type BrotliDecoderState = BrotliState<HeapAlloc<u8>, HeapAlloc<u32>, HeapAlloc<HuffmanCode>>;
+
+/// streams brotli through a fixed output buffer, so decoding can stop at any output size
+struct BrotliDecoder {
+    state: BrotliDecoderState,
+    finished: bool,
+}
+
+impl BrotliDecoder {
+    fn new() -> Self {
+        Self {
+            state: BrotliState::new(
+                HeapAlloc::default(),
+                HeapAlloc::default(),
+                HeapAlloc::default(),
+            ),
+            finished: false,
+        }
+    }
+
+    fn write(&mut self, data: &[u8], max_output: usize) -> Result<(Vec<u8>, bool)> {
+        let mut out = vec![];
+        let mut buffer = [0u8; BROTLI_BUFFER_SIZE];
+        let mut available_in = data.len();
+        let mut input_offset = 0;
+        let mut total_out = 0;
+        loop {
+            if out.len() > max_output {
+                return Ok((out, true));
+            }
+            let mut available_out = buffer.len();
+            let mut output_offset = 0;
+            let result = BrotliDecompressStream(
+                &mut available_in,
+                &mut input_offset,
+                data,
+                &mut available_out,
+                &mut output_offset,
+                &mut buffer,
+                &mut total_out,
+                &mut self.state,
+            );
+            out.extend_from_slice(&buffer[..output_offset]);
+            match result {
+                BrotliResult::NeedsMoreOutput => (),
+                BrotliResult::NeedsMoreInput => break,
+                BrotliResult::ResultSuccess => {
+                    self.finished = true;
+                    break;
+                }
+                BrotliResult::ResultFailure => bail!("invalid brotli stream"),
+            }
+        }
+        Ok((out, false))
+    }
+
+    fn finish(&mut self) -> Result<Vec<u8>> {
+        let (out, _) = self.write(&[], usize::MAX)?;
+        if !self.finished {
+            bail!("truncated brotli stream");
+        }
+        Ok(out)
+    }
+}
+
+/// streams zstd frames, which the decoder can only read block by block
+struct ZstdState {
+    decoder: FrameDecoder,
+    /// input not yet consumed by the decoder
+    pending: Vec<u8>,
+    in_frame: bool,
+    /// set once the last block of the frame was decoded, only its checksum can follow
+    last_block: bool,
+    /// upper bound of the bytes decoded in the current frame that the decoder still holds back as its window
+    uncollected: usize,
+}
+
+impl ZstdState {
+    fn new() -> Self {
+        Self {
+            decoder: FrameDecoder::new(),
+            pending: vec![],
+            in_frame: false,
+            last_block: false,
+            uncollected: 0,
+        }
+    }
+
+    /// returns the size of the next block in `pending` and an upper bound of its decoded size
+    fn next_block(&self) -> Option<(usize, usize, bool)> {
+        if self.pending.len() < 3 {
+            return None;
+        }
+        let header = u32::from_le_bytes([self.pending[0], self.pending[1], self.pending[2], 0]);
+        let size = (header >> 3) as usize;
+        let last = header & 1 == 1;
+        Some(match (header >> 1) & 3 {
+            // rle, a single byte repeated `size` times
+            1 => (3 + 1, size, last),
+            2 => (3 + size, ZSTD_MAX_BLOCK_SIZE, last),
+            _ => (3 + size, size, last),
+        })
+    }
+
+    fn write(
+        &mut self,
+        data: &[u8],
+        finishing: bool,
+        max_output: usize,
+    ) -> Result<(Vec<u8>, bool)> {
+        self.pending.extend_from_slice(data);
+        let mut out = vec![];
+        loop {
+            if !self.in_frame {
+                if self.pending.is_empty()
+                    || (!finishing && self.pending.len() < ZSTD_MAX_FRAME_HEADER)
+                {
+                    break;
+                }
+                if self.pending.len() >= 8 {
+                    let magic = u32::from_le_bytes(self.pending[..4].try_into().unwrap());
+                    if magic & 0xFFFF_FFF0 == 0x184D_2A50 {
+                        // skippable frame
+                        let length =
+                            u32::from_le_bytes(self.pending[4..8].try_into().unwrap()) as usize;
+                        if self.pending.len() < 8 + length {
+                            break;
+                        }
+                        self.pending.drain(..8 + length);
+                        continue;
+                    }
+                }
+                let mut source = &self.pending[..];
+                self.decoder
+                    .init(&mut source)
+                    .map_err(|e| anyhow!("invalid zstd frame header: {e}"))?;
+                let consumed = self.pending.len() - source.len();
+                self.pending.drain(..consumed);
+                self.in_frame = true;
+                self.last_block = false;
+                self.uncollected = 0;
+            }
+            if out.len() + self.uncollected > max_output {
+                return Ok((out, true));
+            }
+            let source = if self.last_block {
+                // a checksum is 4 bytes and is only consumed when entirely available
+                if self.pending.len() < 4 && (!finishing || self.pending.is_empty()) {
+                    break;
+                }
+                &self.pending[..]
+            } else {
+                // the decoder decodes every block it is given at once, so blocks are passed one at a time
+                let (size, decoded, last) = match self.next_block() {
+                    Some(block) if block.0 <= self.pending.len() => block,
+                    _ => break,
+                };
+                self.uncollected += decoded;
+                self.last_block = last;
+                &self.pending[..size]
+            };
+            let (read, _) = self
+                .decoder
+                .decode_from_to(source, &mut [])
+                .map_err(|e| anyhow!("invalid zstd block: {e}"))?;
+            self.pending.drain(..read.min(self.pending.len()));
+            if let Some(decoded) = self.decoder.collect() {
+                self.uncollected = self.uncollected.saturating_sub(decoded.len());
+                out.extend_from_slice(&decoded);
+            }
+            if self.decoder.is_finished() {
+                self.in_frame = false;
+            } else if read == 0 {
+                break;
+            }
+        }
+        Ok((out, false))
+    }
+
+    fn finish(&mut self) -> Result<Vec<u8>> {
+        let (out, _) = self.write(&[], true, usize::MAX)?;
+        if self.in_frame || !self.pending.is_empty() {
+            bail!("truncated zstd stream");
+        }
+        Ok(out)
+    }
+}
+
+/// Streaming decoder of one content-coding
+enum Decoder {
+    Gzip(GzDecoder<Vec<u8>>),
+    Deflate(DeflateState),
+    Brotli(Box<BrotliDecoder>),
+    Zstd(Box<ZstdState>),
+}
+
+impl Decoder {
+    fn new(encoding: ContentEncoding) -> Self {
+        match encoding {
+            ContentEncoding::Gzip => Decoder::Gzip(GzDecoder::new(vec![])),
+            ContentEncoding::Deflate => Decoder::Deflate(DeflateState::Pending(vec![])),
+            ContentEncoding::Brotli => Decoder::Brotli(Box::new(BrotliDecoder::new())),
+            ContentEncoding::Zstd => Decoder::Zstd(Box::new(ZstdState::new())),
+        }
+    }
+
+    /// decodes `data` until more than `max_output` bytes were decoded.
+    /// returns the decoded output, and whether decoding stopped before all of `data` was decoded
+    fn write(&mut self, data: &[u8], max_output: usize) -> Result<(Vec<u8>, bool)> {
+        match self {
+            Decoder::Gzip(decoder) => write_capped(decoder, GzDecoder::get_mut, data, max_output),
+            Decoder::Deflate(state) => state.write(data, max_output),
+            Decoder::Brotli(decoder) => decoder.write(data, max_output),
+            Decoder::Zstd(state) => state.write(data, false, max_output),
+        }
+    }
+
+    fn finish(&mut self) -> Result<Vec<u8>> {
+        match self {
+            Decoder::Gzip(decoder) => {
+                decoder.try_finish()?;
+                Ok(std::mem::take(decoder.get_mut()))
+            }
+            Decoder::Deflate(state) => state.finish(),
+            Decoder::Brotli(decoder) => decoder.finish(),
+            Decoder::Zstd(state) => state.finish(),
+        }
+    }
+}
+
+/// Streaming decoder undoing every content-coding of a body, the last applied first
+#[derive(Default)]
+pub struct Decompressor {
+    decoders: Vec<Decoder>,
+    /// set once decoding stopped at the output limit, nothing more is decoded after
+    truncated: bool,
+}
+
+impl Decompressor {
+    pub fn new(encodings: &[ContentEncoding]) -> Self {
+        Self {
+            decoders: encodings.iter().rev().copied().map(Decoder::new).collect(),
+            truncated: false,
+        }
+    }
+
+    /// true once decoding stopped at `max_output`, the rest of the body is not decoded
+    pub fn is_truncated(&self) -> bool {
+        self.truncated
+    }
+
+    /// decodes the next chunk of the body, returning whatever decoded output is available.
+    /// every coding stops decoding once it decoded more than `max_output` bytes, the rest of the body is dropped
+    pub fn write(&mut self, data: &[u8], max_output: usize) -> Result<Vec<u8>> {
+        if self.truncated {
+            return Ok(vec![]);
+        }
+        let mut data = data.to_vec();
+        for decoder in &mut self.decoders {
+            if data.is_empty() {
+                break;
+            }
+            let (out, truncated) = decoder.write(&data, max_output)?;
+            self.truncated |= truncated;
+            data = out;
+        }
+        Ok(data)
+    }
+
+    /// ends the body, returning the remaining decoded output
+    pub fn finish(&mut self, max_output: usize) -> Result<Vec<u8>> {
+        let mut data = vec![];
+        for decoder in &mut self.decoders {
+            let (mut out, truncated) = decoder.write(&data, max_output)?;
+            self.truncated |= truncated;
+            // a stream cut at the limit is incomplete, only whatever it decoded so far is passed on
+            if !self.truncated {
+                out.extend(decoder.finish()?);
+            }
+            data = out;
+        }
+        self.decoders.clear();
+        Ok(data)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn decode_chunked(encodings: &[ContentEncoding], body: &[u8], chunk_size: usize) -> Vec<u8> {
+        let mut decompressor = Decompressor::new(encodings);
+        let mut out = vec![];
+        for chunk in body.chunks(chunk_size) {
+            out.extend(decompressor.write(chunk, usize::MAX).unwrap());
+        }
+        out.extend(decompressor.finish(usize::MAX).unwrap());
+        out
+    }
+
+    fn sample_body() -> Vec<u8> {
+        (0..2000)
+            .map(|i| format!("{{\"id\":{i},\"email\":\"user{i}@example.com\"}}\n"))
+            .collect::<String>()
+            .into_bytes()
+    }
+
+    #[test]
+    fn test_parse_content_encodings() {
+        assert_eq!(
+            parse_content_encodings("gzip, br").unwrap(),
+            vec![ContentEncoding::Gzip, ContentEncoding::Brotli]
+        );
+        assert_eq!(
+            parse_content_encodings("identity").unwrap(),
+            Vec::<ContentEncoding>::new()
+        );
+        assert_eq!(
+            parse_content_encodings("ZSTD,deflate").unwrap(),
+            vec![ContentEncoding::Zstd, ContentEncoding::Deflate]
+        );
+        assert!(parse_content_encodings("gzip, compress").is_err());
+    }
+
+    #[test]
+    fn test_round_trip() {
+        let body = sample_body();
+        for encoding in [
+            ContentEncoding::Gzip,
+            ContentEncoding::Deflate,
+            ContentEncoding::Brotli,
+            ContentEncoding::Zstd,
+        ] {
+            let encoded = encode(&[encoding], body.clone()).unwrap();
+            assert_ne!(encoded, body);
+            for chunk_size in [1, 7, 4096, encoded.len()] {
+                assert_eq!(
+                    decode_chunked(&[encoding], &encoded, chunk_size),
+                    body,
+                    "{encoding:?} in chunks of {chunk_size}"
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn test_stacked_encodings() {
+        let body = sample_body();
+        let encodings = [
+            ContentEncoding::Gzip,
+            ContentEncoding::Brotli,
+            ContentEncoding::Zstd,
+        ];
+        let encoded = encode(&encodings, body.clone()).unwrap();
+        assert_eq!(decode_chunked(&encodings, &encoded, 13), body);
+    }
+
+    #[test]
+    fn test_raw_deflate() {
+        let body = sample_body();
+        let mut encoder = flate2::write::DeflateEncoder::new(vec![], Compression::default());
+        encoder.write_all(&body).unwrap();
+        let encoded = encoder.finish().unwrap();
+        assert_eq!(
+            decode_chunked(&[ContentEncoding::Deflate], &encoded, 5),
+            body
+        );
+    }
+
+    #[test]
+    fn test_truncated_body() {
+        let encoded = encode(&[ContentEncoding::Zstd], sample_body()).unwrap();
+        let mut decompressor = Decompressor::new(&[ContentEncoding::Zstd]);
+        decompressor
+            .write(&encoded[..encoded.len() / 2], usize::MAX)
+            .unwrap();
+        assert!(decompressor.finish(usize::MAX).is_err());
+    }
+
+    #[test]
+    fn test_bounded_output() {
+        for encodings in [
+            &[ContentEncoding::Gzip][..],
+            &[ContentEncoding::Deflate],
+            &[ContentEncoding::Brotli],
+            &[ContentEncoding::Zstd],
+            &[ContentEncoding::Gzip, ContentEncoding::Gzip],
+            &[ContentEncoding::Zstd, ContentEncoding::Brotli],
+            &[
+                ContentEncoding::Gzip,
+                ContentEncoding::Brotli,
+                ContentEncoding::Zstd,
+            ],
+        ] {
+            let bomb = encode(encodings, vec![0; 16 * 1024 * 1024]).unwrap();
+            let mut decompressor = Decompressor::new(encodings);
+            let mut out = decompressor.write(&bomb, 4096).unwrap();
+            assert!(decompressor.is_truncated(), "{encodings:?}");
+            assert!(out.len() < 256 * 1024, "{encodings:?}: {}", out.len());
+            out.extend(decompressor.finish(4096).unwrap());
+            assert!(out.len() < 256 * 1024, "{encodings:?}: {}", out.len());
+        }
+    }
+}
@@ -0,0 +1,419 @@
+//! Decoders undoing common obfuscations of sensitive data, configured per category by `ConfiguredPolicyAction::decode`.
+//! Decoded text keeps the source span of each of its bytes, so matches within it map back onto the original body.
+
+use crate::policy::Decoder;
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+struct ByteSource {
+    start: usize,
+    end: usize,
+    /// false if the byte was copied as is from the source
+    decoded: bool,
+}
+
+/// Text decoded from a span of a source
+#[derive(Debug)]
+pub struct Decoded {
+    pub text: String,
+    /// one per byte of `text`
+    sources: Vec<ByteSource>,
+}
+
+impl Decoded {
+    /// maps `start`..`end` of `text` back to the span of the source it was decoded from
+    pub fn source_span(&self, start: usize, end: usize) -> (usize, usize) {
+        let source_start = match self.sources.get(start) {
+            Some(source) => source.start,
+            None => self.sources.last().map(|x| x.end).unwrap_or_default(),
+        };
+        let source_end = match end.checked_sub(1).and_then(|x| self.sources.get(x)) {
+            Some(source) if end > start => source.end,
+            _ => source_start,
+        };
+        (source_start, source_end)
+    }
+
+    /// returns true if any byte of `start`..`end` of `text` was decoded, rather than copied from the source
+    pub fn is_decoded(&self, start: usize, end: usize) -> bool {
+        self.sources
+            .get(start..end)
+            .map(|sources| sources.iter().any(|x| x.decoded))
+            .unwrap_or_default()
+    }
+}
+
+#[derive(Default)]
+struct DecodedBuilder {
+    bytes: Vec<u8>,
+    sources: Vec<ByteSource>,
+    any_decoded: bool,
+}
+
+impl DecodedBuilder {
+    fn copy(&mut self, source: &[u8], index: usize) {
+        self.bytes.push(source[index]);
+        self.sources.push(ByteSource {
+            start: index,
+            end: index + 1,
+            decoded: false,
+        });
+    }
+
+    fn push(&mut self, bytes: &[u8], start: usize, end: usize) {
+        self.bytes.extend_from_slice(bytes);
+        self.sources.resize(
+            self.sources.len() + bytes.len(),
+            ByteSource {
+                start,
+                end,
+                decoded: true,
+            },
+        );
+        self.any_decoded = true;
+    }
+
+    fn push_char(&mut self, c: char, start: usize, end: usize) {
+        self.push(c.encode_utf8(&mut [0u8; 4]).as_bytes(), start, end);
+    }
+
+    /// `None` if nothing was decoded, or if the decoded bytes aren't utf-8
+    fn finish(self) -> Option<Decoded> {
+        if !self.any_decoded {
+            return None;
+        }
+        Some(Decoded {
+            text: String::from_utf8(self.bytes).ok()?,
+            sources: self.sources,
+        })
+    }
+}
+
+/// returns the decoded texts of all spans of `source` holding `decoder`'s encoding
+pub fn decode(decoder: Decoder, source: &str) -> Vec<Decoded> {
+    let source = source.as_bytes();
+    match decoder {
+        Decoder::Url => decode_url(source).into_iter().collect(),
+        Decoder::HtmlEntities => decode_html_entities(source).into_iter().collect(),
+        Decoder::Base64 => decode_base64(source),
+        Decoder::JsonEscapes => decode_json_escapes(source).into_iter().collect(),
+    }
+}
+
+pub fn hex_value(c: u8) -> Option<u8> {
+    match c {
+        b'0'..=b'9' => Some(c - b'0'),
+        b'a'..=b'f' => Some(c - b'a' + 10),
+        b'A'..=b'F' => Some(c - b'A' + 10),
+        _ => None,
+    }
+}
+
+fn parse_hex(digits: &[u8]) -> Option<u32> {
+    if digits.is_empty() {
+        return None;
+    }
+    digits.iter().try_fold(0u32, |value, digit| {
+        Some(value.checked_mul(16)? + hex_value(*digit)? as u32)
+    })
+}
+
+fn decode_url(source: &[u8]) -> Option<Decoded> {
+    let mut out = DecodedBuilder::default();
+    let mut i = 0usize;
+    while i < source.len() {
+        match source[i] {
+            b'%' => {
+                let high = source.get(i + 1).copied().and_then(hex_value);
+                let low = source.get(i + 2).copied().and_then(hex_value);
+                match (high, low) {
+                    (Some(high), Some(low)) => {
+                        out.push(&[high << 4 | low], i, i + 3);
+                        i += 3;
+                        continue;
+                    }
+                    _ => out.copy(source, i),
+                }
+            }
+            b'+' => out.push(b" ", i, i + 1),
+            _ => out.copy(source, i),
+        }
+        i += 1;
+    }
+    out.finish()
+}
+
+fn named_entity(name: &[u8]) -> Option<char> {
+    Some(match name {
+        b"amp" => '&',
+        b"lt" => '<',
+        b"gt" => '>',
+        b"quot" => '"',
+        b"apos" => '\'',
+        b"nbsp" => '\u{a0}',
+        _ => return None,
+    })
+}
+
+/// parses the character reference at the start of `source`, returning it and its length
+pub fn parse_entity(source: &[u8]) -> Option<(char, usize)> {
+    let end = source.iter().take(12).position(|x| *x == b';')?;
+    let body = &source[1..end];
+    let c = match body {
+        [b'#', b'x' | b'X', digits @ ..] => char::from_u32(parse_hex(digits)?)?,
+        [b'#', digits @ ..] => {
+            if digits.is_empty() || !digits.iter().all(u8::is_ascii_digit) {
+                return None;
+            }
+            char::from_u32(std::str::from_utf8(digits).ok()?.parse().ok()?)?
+        }
+        name => named_entity(name)?,
+    };
+    Some((c, end + 1))
+}
+
+fn decode_html_entities(source: &[u8]) -> Option<Decoded> {
+    let mut out = DecodedBuilder::default();
+    let mut i = 0usize;
+    while i < source.len() {
+        if source[i] == b'&' {
+            if let Some((c, length)) = parse_entity(&source[i..]) {
+                out.push_char(c, i, i + length);
+                i += length;
+                continue;
+            }
+        }
+        out.copy(source, i);
+        i += 1;
+    }
+    out.finish()
+}
+
+fn parse_unicode_escape(source: &[u8], index: usize) -> Option<u32> {
+    match source.get(index..index + 6)? {
+        [b'\\', b'u', digits @ ..] => parse_hex(digits),
+        _ => None,
+    }
+}
+
+fn decode_json_escapes(source: &[u8]) -> Option<Decoded> {
+    let mut out = DecodedBuilder::default();
+    let mut i = 0usize;
+    while i < source.len() {
+        if source[i] == b'\\' {
+            let simple = match source.get(i + 1) {
+                Some(b'"') => Some('"'),
+                Some(b'\\') => Some('\\'),
+                Some(b'/') => Some('/'),
+                Some(b'b') => Some('\u{8}'),
+                Some(b'f') => Some('\u{c}'),
+                Some(b'n') => Some('\n'),
+                Some(b'r') => Some('\r'),
+                Some(b't') => Some('\t'),
+                _ => None,
+            };
+            if let Some(c) = simple {
+                out.push_char(c, i, i + 2);
+                i += 2;
+                continue;
+            }
+            if let Some(unit) = parse_unicode_escape(source, i) {
+                let low =
+                    parse_unicode_escape(source, i + 6).filter(|x| (0xDC00..0xE000).contains(x));
+                let (c, length) = match (unit, low) {
+                    (0xD800..=0xDBFF, Some(low)) => (
+                        char::from_u32(0x10000 + ((unit - 0xD800) << 10) + (low - 0xDC00)),
+                        12,
+                    ),
+                    _ => (char::from_u32(unit), 6),
+                };
+                out.push_char(c.unwrap_or(char::REPLACEMENT_CHARACTER), i, i + length);
+                i += length;
+                continue;
+            }
+        }
+        out.copy(source, i);
+        i += 1;
+    }
+    out.finish()
+}
+
+/// shortest run of base64 characters considered for decoding, 9 decoded bytes
+const MIN_BASE64_LENGTH: usize = 12;
+
+fn base64_value(c: u8) -> Option<u8> {
+    match c {
+        b'A'..=b'Z' => Some(c - b'A'),
+        b'a'..=b'z' => Some(c - b'a' + 26),
+        b'0'..=b'9' => Some(c - b'0' + 52),
+        b'+' | b'-' => Some(62),
+        b'/' | b'_' => Some(63),
+        _ => None,
+    }
+}
+
+/// decodes a group of 2 to 4 base64 characters into its 1 to 3 bytes
+fn decode_base64_group(group: &[u8]) -> Option<Vec<u8>> {
+    let mut value = 0u32;
+    for c in group {
+        value = value << 6 | base64_value(*c)? as u32;
+    }
+    Some(match group.len() {
+        4 => value.to_be_bytes()[1..].to_vec(),
+        3 => (value >> 2).to_be_bytes()[2..].to_vec(),
+        2 => vec![(value >> 4) as u8],
+        _ => return None,
+    })
+}
+
+/// decodes a whole standard or url-safe base64 document, ignoring padding and whitespace
+pub fn decode_base64_bytes(source: &str) -> Option<Vec<u8>> {
+    let digits = source
+        .bytes()
+        .filter(|c| *c != b'=' && !c.is_ascii_whitespace())
+        .collect::<Vec<_>>();
+    let mut out = Vec::with_capacity(digits.len() / 4 * 3);
+    for group in digits.chunks(4) {
+        out.extend(decode_base64_group(group)?);
+    }
+    Some(out)
+}
+
+/// decodes a run of standard or url-safe base64 characters, without padding
+fn decode_base64_run(source: &[u8], start: usize, end: usize) -> Option<Decoded> {
+    let mut out = DecodedBuilder::default();
+    for group_start in (start..end).step_by(4) {
+        let group_end = (group_start + 4).min(end);
+        let bytes = decode_base64_group(&source[group_start..group_end])?;
+        // padding of a partial group belongs to its span
+        let mut span_end = group_end;
+        if group_end == end {
+            while source.get(span_end) == Some(&b'=') {
+                span_end += 1;
+            }
+        }
+        out.push(&bytes, group_start, span_end);
+    }
+    let decoded = out.finish()?;
+    // random words of base64 characters rarely decode to printable text
+    if decoded
+        .text
+        .chars()
+        .any(|c| c.is_control() && !matches!(c, '\n' | '\r' | '\t'))
+    {
+        return None;
+    }
+    Some(decoded)
+}
+
+fn decode_base64(source: &[u8]) -> Vec<Decoded> {
+    let mut out = vec![];
+    let mut i = 0usize;
+    while i < source.len() {
+        if base64_value(source[i]).is_none() {
+            i += 1;
+            continue;
+        }
+        let start = i;
+        while i < source.len() && base64_value(source[i]).is_some() {
+            i += 1;
+        }
+        if i - start >= MIN_BASE64_LENGTH {
+            out.extend(decode_base64_run(source, start, i));
+        }
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// decodes all of `source`, returning the decoded texts and the source of each of their words
+    fn decoded_words(decoder: Decoder, source: &str) -> Vec<(String, Vec<&str>)> {
+        decode(decoder, source)
+            .into_iter()
+            .map(|decoded| {
+                let mut words = vec![];
+                let mut start = 0usize;
+                for word in decoded.text.split(' ') {
+                    let (source_start, source_end) = decoded.source_span(start, start + word.len());
+                    words.push(&source[source_start..source_end]);
+                    start += word.len() + 1;
+                }
+                (decoded.text, words)
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_url() {
+        assert_eq!(
+            decoded_words(Decoder::Url, "ssn=%31%32%33-45-6789+x%2"),
+            vec![(
+                "ssn=123-45-6789 x%2".to_string(),
+                vec!["ssn=%31%32%33-45-6789", "x%2"]
+            )]
+        );
+        assert!(decode(Decoder::Url, "no encoding").is_empty());
+    }
+
+    #[test]
+    fn test_html_entities() {
+        let decoded = decode(
+            Decoder::HtmlEntities,
+            "&#49;&#x32;3 &amp; &unknown; &#xffffff;",
+        );
+        assert_eq!(decoded.len(), 1);
+        assert_eq!(decoded[0].text, "123 & &unknown; &#xffffff;");
+        assert_eq!(decoded[0].source_span(0, 3), (0, 12));
+        assert!(decoded[0].is_decoded(0, 3));
+        assert!(!decoded[0].is_decoded(2, 3));
+    }
+
+    #[test]
+    fn test_json_escapes() {
+        assert_eq!(
+            decoded_words(Decoder::JsonEscapes, r#"122 \"😀\" \q"#),
+            vec![(
+                "122 \"\u{1f600}\" \\q".to_string(),
+                vec![r#"122"#, r#"\"😀\""#, r#"\q"#]
+            )]
+        );
+    }
+
+    #[test]
+    fn test_base64() {
+        // "ssn 123-45-6789" and "ssn 123-45-678"
+        assert_eq!(
+            decoded_words(
+                Decoder::Base64,
+                "a c3NuIDEyMy00NS02Nzg5 c3NuIDEyMy00NS02Nzg= authentication_token"
+            ),
+            vec![
+                (
+                    "ssn 123-45-6789".to_string(),
+                    vec!["c3Nu", "IDEyMy00NS02Nzg5"]
+                ),
+                (
+                    "ssn 123-45-678".to_string(),
+                    vec!["c3Nu", "IDEyMy00NS02Nzg="]
+                ),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_base64_bytes() {
+        assert_eq!(decode_base64_bytes("").unwrap(), b"");
+        assert_eq!(decode_base64_bytes("YQ==").unwrap(), b"a");
+        assert_eq!(decode_base64_bytes("YWI=\n").unwrap(), b"ab");
+        assert_eq!(
+            decode_base64_bytes("/wAQgA==").unwrap(),
+            b"\xff\x00\x10\x80"
+        );
+        assert_eq!(decode_base64_bytes("YWI/").unwrap(), b"ab?");
+        assert_eq!(decode_base64_bytes("YWI_").unwrap(), b"ab?");
+        assert_eq!(decode_base64_bytes("YW*"), None);
+        assert_eq!(decode_base64_bytes("YWJjZ"), None);
+    }
+}
@@ -1,40 +1,61 @@
-use std::collections::{BTreeMap, HashSet};
+use std::{
+    collections::{BTreeMap, HashSet},
+    sync::Arc,
+};
 
+use aho_corasick::AhoCorasick;
 use fancy_regex::Regex;
-use leakpolicy::{CorrelateInterest, DataReportStyle, MatchGroup};
+use indexmap::IndexMap;
+use leakpolicy::{AlertConfig, CorrelateInterest, DataReportStyle, MatchGroup, PathConfiguration};
 use log::{debug, error, info, warn};
+use regex::RegexSet;
 use smallvec::SmallVec;
 
 use crate::proto::{Action, Match};
 use crate::{
-    parsers::ParseResponse,
-    policy::{evaluate_report_style, Category, Policy, PolicyAction},
+    alert::AlertTracker,
+    decode,
+    direct::DirectMatcher,
+    parsers::{ParseResponse, Replacement},
+    policy::{evaluate_report_style, Category, Decoder, Policy, PolicyAction},
 };
 
-#[derive(Clone, PartialEq, Eq)]
-pub struct CategoryMatch<'a> {
-    pub start: usize,
-    pub length: usize,
-    pub value: &'a str,
-}
-
 #[derive(Clone, PartialEq, Eq, Debug)]
 pub struct CategoryPreparedMatch<'a> {
     pub metadata: &'a MatcherMetadata,
     pub start: usize,
     pub length: usize,
+    /// set if matched in decoded text, `start` and `length` are then the encoded span of the source
+    pub decoder: Option<Decoder>,
+    pub decoded_value: Option<String>,
 }
 
-pub struct MatchRegex<'a> {
+impl<'a> CategoryPreparedMatch<'a> {
+    /// the matched text, decoded if matched in decoded text
+    pub fn value<'b>(&'b self, source: &'b str) -> &'b str {
+        match &self.decoded_value {
+            Some(value) => value,
+            None => &source[self.start..self.start + self.length],
+        }
+    }
+}
+
+pub struct MatchRegex {
     metadata: MatcherMetadata,
-    regex: &'a Regex,
+    regex: Regex,
     regex_strip: usize,
-    ignore: SmallVec<[&'a HashSet<String>; 2]>,
+    ignore: Arc<HashSet<String>>,
 }
 
-pub struct MatchRaw<'a> {
+pub struct MatchRaw {
     metadata: MatcherMetadata,
-    raw: &'a str,
+    raw: String,
+}
+
+pub struct MatchDirect {
+    metadata: MatcherMetadata,
+    matcher: DirectMatcher,
+    ignore: Arc<HashSet<String>>,
 }
 
 #[derive(Clone, PartialEq, Eq, Debug)]
@@ -43,7 +64,28 @@ pub struct MatcherMetadata {
     pub category_name: String,
     pub action: PolicyAction,
     pub local_report_style: DataReportStyle,
+    pub alert: AlertConfig,
     pub correlation: Option<CorrelationState>,
+    /// if set, matches are re-evaluated by the rematcher at this index rather than emitted directly
+    pub rematch_index: Option<usize>,
+    /// the category is also matched against text decoded by these
+    pub decoders: Vec<Decoder>,
+}
+
+impl MatcherMetadata {
+    /// metadata of the matches of `category_name`, as configured for a path by `config`
+    pub fn new(category_name: &str, config: &PathConfiguration) -> Self {
+        MatcherMetadata {
+            policy_path: config.matcher_path.clone(),
+            category_name: category_name.to_string(),
+            action: config.category_config.action.clone().unwrap_or_default(),
+            local_report_style: config.report_style,
+            alert: config.category_config.alert.clone(),
+            correlation: None,
+            rematch_index: None,
+            decoders: config.category_config.decode.iter().copied().collect(),
+        }
+    }
 }
 
 #[derive(Clone, Copy, PartialEq, Eq, Debug)]
@@ -54,18 +96,25 @@ pub struct CorrelationState {
     pub interest: CorrelateInterest,
 }
 
+/// Owns everything it needs from the policy, so it can be prepared once and reused across responses
 #[derive(Default)]
-pub struct MatcherState<'a> {
+pub struct MatcherState {
     correlation_index: usize,
-    regexes: Vec<MatchRegex<'a>>,
-    raws: Vec<MatchRaw<'a>>,
+    regexes: Vec<MatchRegex>,
+    raws: Vec<MatchRaw>,
+    directs: Vec<MatchDirect>,
+    rematchers: Vec<MatcherState>,
+    /// evaluated against the text decoded from the source by each decoder
+    decoded: Vec<(Decoder, MatcherState)>,
+    /// built by `compile` once all matchers are prepared
+    compiled: Option<CompiledMatchers>,
 }
 
-fn prepare_match_group<'a>(
-    match_group: &'a MatchGroup,
-    state: &mut MatcherState<'a>,
+pub fn prepare_match_group(
+    match_group: &MatchGroup,
+    state: &mut MatcherState,
     metadata: &MatcherMetadata,
-    extra_ignore: &'a HashSet<String>,
+    extra_ignore: &HashSet<String>,
 ) {
     let MatchGroup {
         raw,
@@ -74,33 +123,59 @@ fn prepare_match_group<'a>(
         direct,
         ignore,
     } = match_group;
+    let ignore = Arc::new(extra_ignore.union(ignore).cloned().collect::<HashSet<_>>());
 
     for raw in raw {
         state.raws.push(MatchRaw {
             metadata: metadata.clone(),
-            raw: &**raw,
+            raw: raw.clone(),
         });
     }
 
     for regex in regexes {
         state.regexes.push(MatchRegex {
             metadata: metadata.clone(),
-            regex: &regex.0,
+            regex: regex.0.clone(),
             regex_strip: *regex_strip,
-            ignore: smallvec::smallvec![extra_ignore, ignore],
+            ignore: ignore.clone(),
         });
     }
-    for _ in direct {
-        todo!("direct matching implementation")
+    for direct in direct {
+        let matcher = match direct.parse::<DirectMatcher>() {
+            Ok(x) => x,
+            Err(e) => {
+                warn!("{e}, skipped for '{}'", metadata.category_name);
+                continue;
+            }
+        };
+        state.directs.push(MatchDirect {
+            metadata: metadata.clone(),
+            matcher,
+            ignore: ignore.clone(),
+        });
     }
 }
 
-pub fn prepare_matches<'a>(
-    policy: &'a Policy,
-    category_name: &'a str,
-    state: &mut MatcherState<'a>,
+/// matches any non-empty source in full
+pub fn prepare_whole_value(
+    state: &mut MatcherState,
+    metadata: &MatcherMetadata,
+    extra_ignore: &HashSet<String>,
+) {
+    state.regexes.push(MatchRegex {
+        metadata: metadata.clone(),
+        regex: Regex::new("(?s).+").expect("invalid whole value regex"),
+        regex_strip: 0,
+        ignore: Arc::new(extra_ignore.clone()),
+    });
+}
+
+pub fn prepare_matches(
+    policy: &Policy,
+    category_name: &str,
+    state: &mut MatcherState,
     metadata: &MatcherMetadata,
-    extra_ignore: &'a HashSet<String>,
+    extra_ignore: &HashSet<String>,
 ) {
     let category = match policy.categories.get(category_name) {
         Some(x) => x,
@@ -110,6 +185,48 @@ pub fn prepare_matches<'a>(
         }
     };
 
+    prepare_decoded(state, metadata, |state, metadata| {
+        prepare_category(
+            policy,
+            category,
+            category_name,
+            state,
+            metadata,
+            extra_ignore,
+        )
+    });
+}
+
+/// runs `prepare` on `state`, and on the state of the text decoded by each of `metadata.decoders`
+pub fn prepare_decoded(
+    state: &mut MatcherState,
+    metadata: &MatcherMetadata,
+    mut prepare: impl FnMut(&mut MatcherState, &MatcherMetadata),
+) {
+    prepare(state, metadata);
+    if metadata.decoders.is_empty() {
+        return;
+    }
+    let mut decoded_metadata = metadata.clone();
+    decoded_metadata.decoders.clear();
+    for decoder in &metadata.decoders {
+        // correlation indices stay unique across decoded states, as their matches are correlated together
+        let correlation_index = state.correlation_index;
+        let decoded = state.decoded_mut(*decoder);
+        decoded.correlation_index = decoded.correlation_index.max(correlation_index);
+        prepare(decoded, &decoded_metadata);
+        state.correlation_index = decoded.correlation_index;
+    }
+}
+
+fn prepare_category(
+    policy: &Policy,
+    category: &Category,
+    category_name: &str,
+    state: &mut MatcherState,
+    metadata: &MatcherMetadata,
+    extra_ignore: &HashSet<String>,
+) {
     match category {
         Category::Matchers { match_group } => {
             prepare_match_group(match_group, state, metadata, extra_ignore);
@@ -152,77 +269,305 @@ pub fn prepare_matches<'a>(
             });
             prepare_match_group(group2, state, &metadata, extra_ignore);
         }
-        Category::Rematch { .. } => {
-            error!("rematch in prepared evaluation not implemented");
+        Category::Rematch { target, rematcher } => {
+            let target = match target.match_group(policy) {
+                Some(x) => x,
+                None => {
+                    warn!("rematch match group for '{category_name}' is missing a target");
+                    return;
+                }
+            };
+            let rematcher = match rematcher.match_group(policy) {
+                Some(x) => x,
+                None => {
+                    warn!("rematch match group for '{category_name}' is missing a rematcher");
+                    return;
+                }
+            };
+            let mut rematch_state = MatcherState::default();
+            prepare_match_group(rematcher, &mut rematch_state, metadata, extra_ignore);
+            let mut metadata = metadata.clone();
+            metadata.rematch_index = Some(state.rematchers.len());
+            state.rematchers.push(rematch_state);
+            prepare_match_group(target, state, &metadata, extra_ignore);
         }
+        // Keyed categories are prepared by the json and csv parsers, see `parsers::json::prepare_match_state`
+        Category::Keyed { .. } => (),
+        // only images have tags, see `parsers::image::prepare_match_state`
+        Category::ImageMetadata { .. } => (),
     }
 }
 
-impl<'a> MatcherState<'a> {
-    pub fn evaluate(&self, source: &str) -> Vec<CategoryPreparedMatch> {
-        let mut matches = vec![];
-        for raw in &self.raws {
-            let metadata = &raw.metadata;
-            let raw = raw.raw;
-            if source.len() < raw.len() {
+/// Single pass automatons over all raws and plain regexes of a `MatcherState`
+struct CompiledMatchers {
+    /// over the unique, non-empty raws
+    raws: Option<AhoCorasick>,
+    /// for each pattern in `raws`, the indices of `MatcherState::raws` it belongs to
+    raw_targets: Vec<SmallVec<[usize; 1]>>,
+    /// prefilter over all regexes in `plain_regexes`
+    regex_set: Option<RegexSet>,
+    /// for each of `MatcherState::regexes`, the `regex` crate equivalent and its index in `regex_set`.
+    /// `None` if the regex needs `fancy_regex` features (i.e. lookaround).
+    plain_regexes: Vec<Option<(usize, regex::Regex)>>,
+}
+
+impl CompiledMatchers {
+    fn new(state: &MatcherState) -> Self {
+        let mut unique_raws: IndexMap<&str, SmallVec<[usize; 1]>> = IndexMap::new();
+        for (i, raw) in state.raws.iter().enumerate() {
+            if raw.raw.is_empty() {
                 continue;
             }
-            let mut source_iter = source.char_indices().peekable();
-            while let Some((i, _)) = source_iter.next() {
-                if i > source.len() - raw.len() {
-                    break;
+            unique_raws.entry(&*raw.raw).or_default().push(i);
+        }
+        let raws = if unique_raws.is_empty() {
+            None
+        } else {
+            Some(AhoCorasick::new(unique_raws.keys()))
+        };
+
+        let mut set_patterns = vec![];
+        let plain_regexes = state
+            .regexes
+            .iter()
+            .map(|regex| {
+                let plain = regex::Regex::new(regex.regex.as_str()).ok()?;
+                set_patterns.push(regex.regex.as_str());
+                Some((set_patterns.len() - 1, plain))
+            })
+            .collect();
+        let regex_set = match RegexSet::new(&set_patterns) {
+            Ok(x) => Some(x),
+            Err(e) => {
+                warn!("failed to build regex set, regexes will be evaluated individually: {e}");
+                None
+            }
+        };
+
+        Self {
+            raws,
+            raw_targets: unique_raws.into_values().collect(),
+            regex_set,
+            plain_regexes,
+        }
+    }
+}
+
+impl MatcherState {
+    fn decoded_mut(&mut self, decoder: Decoder) -> &mut MatcherState {
+        let index = match self.decoded.iter().position(|(x, _)| *x == decoder) {
+            Some(index) => index,
+            None => {
+                self.decoded.push((decoder, MatcherState::default()));
+                self.decoded.len() - 1
+            }
+        };
+        &mut self.decoded[index].1
+    }
+
+    /// builds the automatons of this state and its rematch and decoded states, once all matchers are prepared
+    pub fn compile(&mut self) {
+        for rematcher in &mut self.rematchers {
+            rematcher.compile();
+        }
+        for (_, decoded) in &mut self.decoded {
+            decoded.compile();
+        }
+        self.compiled = Some(CompiledMatchers::new(self));
+    }
+
+    pub fn evaluate(&self, source: &str) -> Vec<CategoryPreparedMatch> {
+        let uncompiled;
+        let compiled = match &self.compiled {
+            Some(compiled) => compiled,
+            None => {
+                debug_assert!(false, "matcher state evaluated before it was compiled");
+                uncompiled = CompiledMatchers::new(self);
+                &uncompiled
+            }
+        };
+        let mut matches = vec![];
+
+        if let Some(automaton) = &compiled.raws {
+            // each raw is matched without overlapping itself, but may overlap other raws
+            let mut raw_ends = vec![0usize; compiled.raw_targets.len()];
+            for matching in automaton.find_overlapping_iter(source) {
+                let pattern = matching.pattern();
+                if matching.start() < raw_ends[pattern] {
+                    continue;
                 }
-                if source.as_bytes()[i + raw.len() - 1] == raw.as_bytes()[raw.len() - 1]
-                    && source[i..].starts_with(raw)
-                {
+                raw_ends[pattern] = matching.end();
+                for raw in &compiled.raw_targets[pattern] {
                     matches.push(CategoryPreparedMatch {
-                        start: i,
-                        length: raw.len(),
-                        metadata,
+                        start: matching.start(),
+                        length: matching.len(),
+                        metadata: &self.raws[*raw].metadata,
+                        decoder: None,
+                        decoded_value: None,
                     });
-                    let target = i + raw.len();
-                    while let Some((i, _)) = source_iter.peek() {
-                        if *i < target {
-                            source_iter.next();
+                }
+            }
+        }
+
+        let set_matches = compiled.regex_set.as_ref().map(|set| set.matches(source));
+        for (regex, plain) in self.regexes.iter().zip(&compiled.plain_regexes) {
+            let mut push_match = |start: usize, end: usize| {
+                if regex.ignore.contains(&source[start..end]) {
+                    return;
+                }
+                let start = start + regex.regex_strip;
+                let length = end.saturating_sub(start + regex.regex_strip);
+                matches.push(CategoryPreparedMatch {
+                    metadata: &regex.metadata,
+                    start,
+                    length,
+                    decoder: None,
+                    decoded_value: None,
+                });
+            };
+            match plain {
+                Some((set_index, plain)) => {
+                    if let Some(set_matches) = &set_matches {
+                        if !set_matches.matched(*set_index) {
                             continue;
                         }
-                        break;
                     }
+                    for matching in plain.find_iter(source) {
+                        push_match(matching.start(), matching.end());
+                    }
+                }
+                None => {
+                    for matching in regex.regex.find_iter(source) {
+                        let matching = matching.unwrap();
+                        push_match(matching.start(), matching.end());
+                    }
+                }
+            }
+        }
+
+        for direct in &self.directs {
+            for (start, end) in direct.matcher.find_all(source) {
+                if direct.ignore.contains(&source[start..end]) {
+                    continue;
                 }
+                matches.push(CategoryPreparedMatch {
+                    metadata: &direct.metadata,
+                    start,
+                    length: end - start,
+                    decoder: None,
+                    decoded_value: None,
+                });
             }
         }
 
-        {
-            for regex in &self.regexes {
-                for matching in regex.regex.find_iter(source) {
-                    let matching = matching.unwrap();
-                    if regex.ignore.iter().any(|x| x.contains(matching.as_str())) {
+        if !self.rematchers.is_empty() {
+            matches = self.rematch(source, matches);
+        }
+
+        for (decoder, state) in &self.decoded {
+            for decoded in decode::decode(*decoder, source) {
+                for mut matching in state.evaluate(&decoded.text) {
+                    let end = matching.start + matching.length;
+                    // matches of text that wasn't encoded are already found in the source
+                    if !decoded.is_decoded(matching.start, end) {
                         continue;
                     }
-                    let start = matching.start() + regex.regex_strip;
-                    let length = matching.end().saturating_sub(start + regex.regex_strip);
-                    matches.push(CategoryPreparedMatch {
-                        metadata: &regex.metadata,
-                        start,
-                        length,
-                    });
+                    let (source_start, source_end) = decoded.source_span(matching.start, end);
+                    matching.decoded_value = Some(decoded.text[matching.start..end].to_string());
+                    matching.decoder = Some(*decoder);
+                    matching.start = source_start;
+                    matching.length = source_end - source_start;
+                    matches.push(matching);
                 }
             }
         }
 
         matches
     }
+
+    /// replaces each rematch target hit with the hits of its rematcher, evaluated within the target span
+    fn rematch<'b>(
+        &'b self,
+        source: &str,
+        matches: Vec<CategoryPreparedMatch<'b>>,
+    ) -> Vec<CategoryPreparedMatch<'b>> {
+        let mut output = Vec::with_capacity(matches.len());
+        for matching in matches {
+            let rematcher = match matching.metadata.rematch_index {
+                Some(index) => &self.rematchers[index],
+                None => {
+                    output.push(matching);
+                    continue;
+                }
+            };
+            let target = match source.get(matching.start..matching.start + matching.length) {
+                Some(x) => x,
+                None => {
+                    debug!("skipping rematch target on non-char boundary");
+                    continue;
+                }
+            };
+            for mut rematched in rematcher.evaluate(target) {
+                rematched.start += matching.start;
+                if !output.contains(&rematched) {
+                    output.push(rematched);
+                }
+            }
+        }
+        output
+    }
+}
+
+fn proto_decoder(decoder: Decoder) -> crate::proto::Decoder {
+    match decoder {
+        Decoder::Url => crate::proto::Decoder::Url,
+        Decoder::HtmlEntities => crate::proto::Decoder::HtmlEntities,
+        Decoder::Base64 => crate::proto::Decoder::Base64,
+        Decoder::JsonEscapes => crate::proto::Decoder::JsonEscapes,
+    }
+}
+
+fn action_taken(action: &PolicyAction) -> Action {
+    match action {
+        PolicyAction::Ignore => Action::None,
+        PolicyAction::Alert => Action::Alert,
+        PolicyAction::Mask { .. } => Action::Mask,
+        PolicyAction::Block => Action::Block,
+    }
+}
+
+/// alerts and blocks only escalate once the category's alert thresholds are met
+fn escalated_action(metadata: &MatcherMetadata, value: &str, alerts: &mut AlertTracker) -> Action {
+    match &metadata.action {
+        PolicyAction::Alert | PolicyAction::Block if !alerts.record(metadata, value) => {
+            Action::None
+        }
+        action => action_taken(action),
+    }
 }
 
-impl<'a> MatcherState<'a> {
+/// returns the text that replaces `value` when masked
+pub fn mask_value(mask_replacement: &str, value: &str) -> String {
+    if mask_replacement.is_empty() {
+        "*".repeat(value.chars().count())
+    } else {
+        mask_replacement.to_string()
+    }
+}
+
+impl MatcherState {
+    /// `masks` receives the spans of `Mask` matches, offset like the emitted `Match`es
     pub fn do_matching(
         &self,
         offset: usize,
         minimum_end_index: usize,
         body: &str,
         matches: &mut Vec<Match>,
+        masks: &mut Vec<Replacement>,
+        alerts: &mut AlertTracker,
     ) -> ParseResponse {
         let local_matches = self.evaluate(body);
+        let mut is_block = false;
 
         let mut correlated_matches_first: BTreeMap<usize, SmallVec<[CategoryPreparedMatch; 2]>> =
             BTreeMap::new();
@@ -245,10 +590,8 @@ impl<'a> MatcherState<'a> {
                 .push(matching);
                 continue;
             }
-            let matched_value = evaluate_report_style(
-                matching.metadata.local_report_style,
-                &body[matching.start..matching.start + matching.length],
-            );
+            let value = matching.value(body);
+            let matched_value = evaluate_report_style(matching.metadata.local_report_style, value);
             info!(
                 "matched {} @ {}-{} -> {:?}: '{}'",
                 matching.metadata.category_name,
@@ -257,18 +600,30 @@ impl<'a> MatcherState<'a> {
                 matching.metadata.action,
                 matched_value.as_deref().unwrap_or_default()
             );
+            if let PolicyAction::Mask { mask_replacement } = &matching.metadata.action {
+                masks.push(Replacement {
+                    start: matching.start + offset,
+                    length: matching.length,
+                    value: mask_value(
+                        mask_replacement,
+                        &body[matching.start..matching.start + matching.length],
+                    )
+                    .into_bytes(),
+                });
+            }
+            let action = escalated_action(matching.metadata, value, alerts);
             matches.push(Match {
                 category_name: matching.metadata.category_name.to_string(),
                 global_start_position: Some(matching.start as u64 + offset as u64),
                 global_length: Some(matching.length as u64),
                 matcher_path: matching.metadata.policy_path.clone(),
                 matched_value,
-                action_taken: match matching.metadata.action {
-                    PolicyAction::Ignore => Action::None,
-                    PolicyAction::Alert => Action::Alert,
-                    PolicyAction::Block => Action::Block,
-                } as i32,
+                action_taken: action as i32,
+                decoder: matching.decoder.map(|x| proto_decoder(x) as i32),
+                // tagged by the body scan
+                ..Default::default()
             });
+            is_block |= action == Action::Block;
         }
         for (index, mut group1) in correlated_matches_first.into_iter() {
             if let Some(mut group2) = correlated_matches_second.remove(&index) {
@@ -343,18 +698,35 @@ impl<'a> MatcherState<'a> {
                             group1_item.metadata.action,
                             matched_value.as_deref().unwrap_or_default()
                         );
+                        if let PolicyAction::Mask { mask_replacement } =
+                            &group1_item.metadata.action
+                        {
+                            masks.push(Replacement {
+                                start: emit_start + offset,
+                                length: emit_end - emit_start,
+                                value: mask_value(mask_replacement, &body[emit_start..emit_end])
+                                    .into_bytes(),
+                            });
+                        }
+                        let action = escalated_action(
+                            group1_item.metadata,
+                            &body[emit_start..emit_end],
+                            alerts,
+                        );
                         matches.push(Match {
                             category_name: group1_item.metadata.category_name.to_string(),
                             global_start_position: Some(emit_start as u64 + offset as u64),
                             global_length: Some((emit_end - emit_start) as u64),
                             matcher_path: group1_item.metadata.policy_path.clone(),
                             matched_value,
-                            action_taken: match group1_item.metadata.action {
-                                PolicyAction::Ignore => Action::None,
-                                PolicyAction::Alert => Action::Alert,
-                                PolicyAction::Block => Action::Block,
-                            } as i32,
+                            action_taken: action as i32,
+                            decoder: group1_item
+                                .decoder
+                                .or(group2_item.decoder)
+                                .map(|x| proto_decoder(x) as i32),
+                            ..Default::default()
                         });
+                        is_block |= action == Action::Block;
                         group2_index += 1;
                     }
                     break;
@@ -362,10 +734,288 @@ impl<'a> MatcherState<'a> {
             }
         }
 
-        // if is_block {
-        //     return ParseResponse::Block;
-        // }
+        if is_block {
+            return ParseResponse::Block;
+        }
 
         ParseResponse::Continue
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use leakpolicy::parse_policy;
+
+    use super::*;
+
+    fn test_metadata(category_name: &str) -> MatcherMetadata {
+        MatcherMetadata {
+            policy_path: "**".to_string(),
+            category_name: category_name.to_string(),
+            action: PolicyAction::Alert,
+            local_report_style: DataReportStyle::Raw,
+            alert: AlertConfig::default(),
+            correlation: None,
+            rematch_index: None,
+            decoders: vec![],
+        }
+    }
+
+    #[test]
+    fn test_rematch() {
+        let policy = parse_policy(
+            r#"
+categories:
+  credit_card:
+    Rematch:
+      target:
+        regex_strip: 1
+        regexes:
+          - "[^0-9]\\d{4}-\\d{4}-\\d{4}-\\d{4}[^0-9]"
+      rematcher:
+        regexes:
+          - "4\\d{3}-"
+        ignore:
+          - "4000-"
+endpoints: []
+"#,
+        )
+        .unwrap();
+        let ignore = HashSet::new();
+        let mut state = MatcherState::default();
+        prepare_matches(
+            &policy,
+            "credit_card",
+            &mut state,
+            &test_metadata("credit_card"),
+            &ignore,
+        );
+
+        let source = " 4111-1111-1111-1111 5111-1111-1111-1111 4000-1111-1111-1111 ";
+        state.compile();
+        let matches = state.evaluate(source);
+        assert_eq!(matches.len(), 1);
+        assert_eq!(&source[matches[0].start..][..matches[0].length], "4111-");
+        assert_eq!(matches[0].metadata.rematch_index, None);
+    }
+
+    #[test]
+    fn test_mask() {
+        let policy = parse_policy(
+            r#"
+categories:
+  ssn:
+    Matchers:
+      regexes:
+        - "\\d{3}-\\d{2}-\\d{4}"
+endpoints: []
+"#,
+        )
+        .unwrap();
+        let ignore = HashSet::new();
+        let mut state = MatcherState::default();
+        let mut metadata = test_metadata("ssn");
+        metadata.action = PolicyAction::Mask {
+            mask_replacement: String::new(),
+        };
+        prepare_matches(&policy, "ssn", &mut state, &metadata, &ignore);
+
+        let mut matches = vec![];
+        let mut masks = vec![];
+        state.compile();
+        state.do_matching(
+            10,
+            0,
+            "ssn: 123-45-6789",
+            &mut matches,
+            &mut masks,
+            &mut AlertTracker::default(),
+        );
+        assert_eq!(matches.len(), 1);
+        assert_eq!(matches[0].action_taken, Action::Mask as i32);
+        assert_eq!(
+            masks,
+            vec![Replacement {
+                start: 15,
+                length: 11,
+                value: b"***********".to_vec(),
+            }]
+        );
+    }
+
+    #[test]
+    fn test_direct() {
+        let policy = parse_policy(
+            r#"
+categories:
+  pii:
+    Matchers:
+      direct:
+        - ssn
+        - credit_card
+      ignore:
+        - "4111111111111111"
+endpoints: []
+"#,
+        )
+        .unwrap();
+        let ignore = HashSet::new();
+        let mut state = MatcherState::default();
+        prepare_matches(&policy, "pii", &mut state, &test_metadata("pii"), &ignore);
+
+        let source = "123-45-6789 4111111111111111 5500000000000004";
+        state.compile();
+        let matches = state.evaluate(source);
+        let matched = matches
+            .iter()
+            .map(|x| &source[x.start..x.start + x.length])
+            .collect::<Vec<_>>();
+        assert_eq!(matched, vec!["123-45-6789", "5500000000000004"]);
+    }
+
+    #[test]
+    fn test_alert_per_request() {
+        let policy = parse_policy(
+            r#"
+categories:
+  ssn:
+    Matchers:
+      regexes:
+        - "\\d{3}-\\d{2}-\\d{4}"
+endpoints: []
+"#,
+        )
+        .unwrap();
+        let ignore = HashSet::new();
+        let mut state = MatcherState::default();
+        let mut metadata = test_metadata("ssn");
+        metadata.action = PolicyAction::Block;
+        metadata.alert.per_request = Some(2);
+        prepare_matches(&policy, "ssn", &mut state, &metadata, &ignore);
+
+        let mut matches = vec![];
+        let mut alerts = AlertTracker::default();
+        state.compile();
+        let response = state.do_matching(
+            0,
+            0,
+            "123-45-6789 123-45-6789 987-65-4321 111-22-3333",
+            &mut matches,
+            &mut vec![],
+            &mut alerts,
+        );
+        assert!(matches!(response, ParseResponse::Block));
+        let actions = matches.iter().map(|x| x.action_taken).collect::<Vec<_>>();
+        assert_eq!(
+            actions,
+            vec![
+                Action::None as i32,
+                Action::None as i32,
+                Action::Block as i32,
+                Action::Block as i32
+            ]
+        );
+    }
+
+    #[test]
+    fn test_decoded() {
+        let policy = parse_policy(
+            r#"
+categories:
+  ssn:
+    Matchers:
+      regexes:
+        - "\\d{3}-\\d{2}-\\d{4}"
+endpoints: []
+"#,
+        )
+        .unwrap();
+        let ignore = HashSet::new();
+        let mut state = MatcherState::default();
+        let mut metadata = test_metadata("ssn");
+        metadata.decoders = vec![Decoder::Url, Decoder::HtmlEntities];
+        prepare_matches(&policy, "ssn", &mut state, &metadata, &ignore);
+
+        let mut matches = vec![];
+        state.compile();
+        state.do_matching(
+            0,
+            0,
+            "111-22-3333 %31%32%33-45-6789 &#57;87-65-4321 %20",
+            &mut matches,
+            &mut vec![],
+            &mut AlertTracker::default(),
+        );
+        let matched = matches
+            .iter()
+            .map(|x| {
+                (
+                    x.global_start_position.unwrap(),
+                    x.global_length.unwrap(),
+                    x.matched_value.as_deref().unwrap(),
+                    x.decoder,
+                )
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(
+            matched,
+            vec![
+                (0, 11, "111-22-3333", None),
+                (
+                    12,
+                    17,
+                    "123-45-6789",
+                    Some(crate::proto::Decoder::Url as i32)
+                ),
+                (
+                    30,
+                    15,
+                    "987-65-4321",
+                    Some(crate::proto::Decoder::HtmlEntities as i32)
+                ),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_evaluate_engines() {
+        let policy = parse_policy(
+            r#"
+categories:
+  raws:
+    Matchers:
+      raw:
+        - aa
+        - aab
+  regexes:
+    Matchers:
+      regexes:
+        - "\\d{3}"
+        - "\\d+(?=px)"
+        - "zzz"
+endpoints: []
+"#,
+        )
+        .unwrap();
+        let ignore = HashSet::new();
+        let mut state = MatcherState::default();
+        prepare_matches(&policy, "raws", &mut state, &test_metadata("raws"), &ignore);
+        prepare_matches(
+            &policy,
+            "regexes",
+            &mut state,
+            &test_metadata("regexes"),
+            &ignore,
+        );
+
+        let source = "aaab 1234 56px";
+        state.compile();
+        let mut matched = state
+            .evaluate(source)
+            .iter()
+            .map(|x| (x.start, &source[x.start..x.start + x.length]))
+            .collect::<Vec<_>>();
+        matched.sort();
+        assert_eq!(matched, vec![(0, "aa"), (1, "aab"), (5, "123"), (10, "56")]);
+    }
+}
@@ -1,108 +1,324 @@
 use std::{
+    cell::Cell,
     collections::HashMap,
-    io::Write,
     pin::Pin,
-    str::FromStr,
+    rc::Rc,
     sync::Arc,
     task::Poll,
     time::{Duration, SystemTime},
 };
 
 use anyhow::{bail, Result};
-use flate2::write::GzDecoder;
+use encoding_rs::Encoding;
 use futures::{task::waker, Future, FutureExt};
 use leakpolicy::RegexWrapper;
-use log::{error, warn};
+use log::{debug, error, warn};
 use prost::Message;
 use proxy_wasm::{
     hostcalls,
     traits::{Context, HttpContext},
-    types::{Action, MetricType},
+    types::{Action, Bytes, MetricType},
 };
 use rand::{thread_rng, Rng};
 
 use crate::{
-    config::{upstream, UpstreamConfigHandle, LEAKSIGNAL_SERVICE_NAME},
+    alert::AlertTracker,
+    charset::{charset_from_content_type, Transcoder},
+    compression::{self, parse_content_encodings, ContentEncoding, Decompressor},
+    config::{upstream, LEAKSIGNAL_SERVICE_NAME},
     metric::Metric,
-    parsers::{html::parse_html, json::parse_json, ParseResponse},
+    parsers::{parse_body, replace_matches, ContentType, ParseResponse},
     pipe::{pipe, DummyWaker, PipeReader, PipeWriter},
-    policy::{policy, PathPolicy, TokenExtractionConfig, TokenExtractionSite},
-    proto::{Header, MatchDataRequest},
+    policy::{
+        policy, BodyLimits, CompiledEndpoint, Direction, PolicyAction, TokenExtractionConfig,
+        TokenExtractionSite,
+    },
+    proto::{Header, Match, MatchDataRequest},
     GIT_COMMIT,
 };
 
 const MATCH_PUSH_TIMEOUT: Duration = Duration::from_secs(5);
 
-#[derive(Clone, Copy, PartialEq, Debug)]
-enum ContentType {
-    Html,
-    Json,
-    Jpeg,
-    Unknown,
+impl ContentType {
+    //todo: we might need to cover multiple content-type headers here
+    /// bodies without a content-type are sniffed
+    fn from_header(value: Option<Bytes>) -> Self {
+        let value = match value {
+            Some(value) => String::from_utf8_lossy(&value).into_owned(),
+            None => return ContentType::Sniff,
+        };
+        match policy() {
+            Some(policy) => ContentType::from_aliased_value(&value, &policy.content_type_aliases),
+            None => ContentType::from_value(&value),
+        }
+    }
 }
 
-impl Default for ContentType {
-    fn default() -> Self {
-        ContentType::Unknown
-    }
+/// returns the encoding of the `charset` parameter of the `content-type` header
+fn charset_from_header(value: Option<&Bytes>) -> Option<&'static Encoding> {
+    charset_from_content_type(&String::from_utf8_lossy(value?))
 }
 
-impl FromStr for ContentType {
-    type Err = anyhow::Error;
+/// parses the `content-encoding` header, an error names an unsupported coding
+fn content_encodings_from_header(value: Option<Bytes>) -> Result<Vec<ContentEncoding>> {
+    match value {
+        Some(value) => parse_content_encodings(&String::from_utf8_lossy(&value)),
+        None => Ok(vec![]),
+    }
+}
 
-    fn from_str(s: &str) -> Result<Self> {
-        Ok(match s {
-            "text/html" => ContentType::Html,
-            "image/jpg" | "image/jpeg" => ContentType::Jpeg,
-            "application/json" => ContentType::Json,
-            _ => ContentType::Unknown,
-        })
+/// parses a `grpc-encoding` header value, `identity` or no header is uncompressed
+fn grpc_encoding_from_header(value: Option<Bytes>) -> Result<Option<ContentEncoding>> {
+    match value {
+        Some(value) => {
+            let value = String::from_utf8_lossy(&value);
+            let value = value.trim();
+            if value.is_empty() || value.eq_ignore_ascii_case("identity") {
+                Ok(None)
+            } else {
+                value.parse().map(Some)
+            }
+        }
+        None => Ok(None),
     }
 }
 
-impl Default for ContentEncoding {
-    fn default() -> Self {
-        ContentEncoding::None
+fn direction_name(direction: Direction) -> &'static str {
+    match direction {
+        Direction::Request => "request",
+        Direction::Response => "response",
     }
 }
 
-#[derive(Clone, Copy, PartialEq, Debug)]
-enum ContentEncoding {
-    Gzip,
-    None,
-    Unknown,
+/// State of scanning the body of one direction
+struct BodyScan {
+    direction: Direction,
+    content_type: ContentType,
+    /// content-codings of the body, in the order they were applied
+    content_encoding: Vec<ContentEncoding>,
+    decompressor: Decompressor,
+    /// transcodes text bodies to utf-8, by their charset or byte order mark
+    transcoder: Option<Transcoder>,
+    /// if true, the body is held back until end of stream so it can be rewritten
+    buffer_body: bool,
+    /// if true, the body is held back until scanning completes so it can be blocked
+    hold: bool,
+    /// maximum body size to hold back for `hold`
+    hold_limit: Option<usize>,
+    /// set when a block was decided after the body was released, remaining body is dropped
+    blocked: bool,
+    /// set once scanning was considered, a body is scanned at most once
+    started: bool,
+    /// size of the body already buffered by envoy and read by us
+    buffered_size: usize,
+    /// size of the body read by us over all chunks, before decoding its content-codings
+    compressed_size: usize,
+    limits: BodyLimits,
+    /// size of the decoded body passed to the scan
+    scanned_size: usize,
+    /// set once a body limit was hit, shared with the scan so it keeps the results of the scanned part
+    truncated: Rc<Cell<bool>>,
+    writer: Option<PipeWriter>,
+    read_task: Option<Pin<Box<dyn Future<Output = Option<ScanOutput>>>>>,
 }
 
-impl FromStr for ContentEncoding {
-    type Err = anyhow::Error;
+impl BodyScan {
+    fn new(direction: Direction) -> Self {
+        Self {
+            direction,
+            content_type: Default::default(),
+            content_encoding: Default::default(),
+            decompressor: Default::default(),
+            transcoder: None,
+            buffer_body: false,
+            hold: false,
+            hold_limit: None,
+            blocked: false,
+            started: false,
+            buffered_size: 0,
+            compressed_size: 0,
+            limits: Default::default(),
+            scanned_size: 0,
+            truncated: Default::default(),
+            writer: None,
+            read_task: None,
+        }
+    }
+
+    fn configure(
+        &mut self,
+        endpoint: &CompiledEndpoint,
+        content_type: ContentType,
+        charset: Option<&'static Encoding>,
+        content_encoding: Result<Vec<ContentEncoding>>,
+        grpc_encoding: Result<Option<ContentEncoding>>,
+    ) {
+        let mut content_type = match content_encoding {
+            Ok(content_encoding) => {
+                self.decompressor = Decompressor::new(&content_encoding);
+                self.content_encoding = content_encoding;
+                content_type
+            }
+            Err(e) => {
+                // an encoded body can't be scanned as plaintext, skip it
+                if content_type.is_scanned() {
+                    debug!("not scanning {} body: {e}", direction_name(self.direction));
+                    let policy_path = &endpoint.path_policy.policy_path;
+                    Metric::lookup_or_define(
+                        format!("ls.{policy_path}.unsupported_encoding.count"),
+                        MetricType::Counter,
+                    )
+                    .increment(1);
+                }
+                ContentType::Unknown
+            }
+        };
+        if let ContentType::Grpc { encoding, .. } = &mut content_type {
+            match grpc_encoding {
+                Ok(grpc_encoding) => *encoding = grpc_encoding,
+                Err(e) => {
+                    // uncompressed messages of the stream are still scanned
+                    debug!(
+                        "not scanning compressed {} messages: {e}",
+                        direction_name(self.direction)
+                    );
+                    let policy_path = &endpoint.path_policy.policy_path;
+                    Metric::lookup_or_define(
+                        format!("ls.{policy_path}.unsupported_encoding.count"),
+                        MetricType::Counter,
+                    )
+                    .increment(1);
+                }
+            }
+        }
+        if content_type.is_scanned() {
+            let direction = self.direction;
+            let mut actions = endpoint
+                .path_policy
+                .configuration
+                .values()
+                .filter(|config| config.scans(direction))
+                .filter_map(|config| config.category_config.action.as_ref());
+            self.buffer_body = actions
+                .clone()
+                .any(|x| matches!(x, PolicyAction::Mask { .. }));
+            self.hold = actions.any(|x| matches!(x, PolicyAction::Block));
+            self.hold_limit = policy().and_then(|policy| policy.block.max_hold_bytes);
+            self.limits = endpoint.path_policy.limits.clone();
+            if content_type.is_text() {
+                self.transcoder = Some(Transcoder::new(charset));
+            }
+        }
+        self.content_type = content_type;
+    }
+
+    /// number of decoded bytes that can still be scanned within the body limits
+    fn remaining_scan(&self) -> usize {
+        let mut limit = self.limits.max_scanned_bytes.unwrap_or(usize::MAX);
+        if !self.content_encoding.is_empty() {
+            if let Some(max_decompressed_bytes) = self.limits.max_decompressed_bytes {
+                limit = limit.min(max_decompressed_bytes);
+            }
+            if let Some(ratio) = self.limits.max_compression_ratio {
+                limit = limit.min((self.compressed_size as f64 * ratio) as usize);
+            }
+        }
+        limit.saturating_sub(self.scanned_size)
+    }
+
+    /// cuts decoded body data at the body limits, marking the body as truncated if anything was cut
+    fn limit(&mut self, mut body: Vec<u8>) -> Vec<u8> {
+        let remaining = self.remaining_scan();
+        if body.len() > remaining {
+            body.truncate(remaining);
+            self.truncated.set(true);
+        }
+        if self.decompressor.is_truncated() {
+            self.truncated.set(true);
+        }
+        self.scanned_size += body.len();
+        body
+    }
+
+    /// ends the scan of a body once it was cut at the body limits, as it can no longer be rewritten.
+    /// returns true if the scan was stopped by this call
+    fn stop_truncated(&mut self) -> bool {
+        if !self.truncated.get() || self.writer.is_none() {
+            return false;
+        }
+        self.writer.take();
+        self.buffer_body = false;
+        self.decompressor = Default::default();
+        true
+    }
+
+    /// decodes the next chunk of the body as received, cut at the body limits and transcoded
+    fn decode(&mut self, body: Vec<u8>) -> Result<Vec<u8>> {
+        let body = if self.content_encoding.is_empty() {
+            body
+        } else {
+            self.compressed_size += body.len();
+            let max_output = self.remaining_scan();
+            self.decompressor.write(&body, max_output)?
+        };
+        let body = self.limit(body);
+        Ok(self.transcode(body))
+    }
+
+    /// passes decoded body data through the transcoder, if any
+    fn transcode(&mut self, body: Vec<u8>) -> Vec<u8> {
+        match &mut self.transcoder {
+            Some(transcoder) => transcoder.write(body),
+            None => body,
+        }
+    }
+
+    /// encodes a rewritten body in the charset and content-codings of the original body
+    fn encode(&self, body: Vec<u8>) -> Result<Vec<u8>> {
+        let body = match &self.transcoder {
+            Some(transcoder) => transcoder.encode(body)?,
+            None => body,
+        };
+        compression::encode(&self.content_encoding, body)
+    }
 
-    fn from_str(s: &str) -> Result<Self> {
-        Ok(match s {
-            "gzip" => ContentEncoding::Gzip,
-            _ => ContentEncoding::Unknown,
-        })
+    /// maps the positions of matches in the transcoded body to the body as received
+    fn map_offsets(&self, output: &mut ScanOutput) {
+        let transcoder = match &self.transcoder {
+            Some(transcoder) => transcoder,
+            None => return,
+        };
+        for matching in &mut output.matches {
+            if let Some(start) = matching.global_start_position {
+                let end = start + matching.global_length.unwrap_or_default();
+                let start = transcoder.map_offset(start as usize) as u64;
+                matching.global_start_position = Some(start);
+                if matching.global_length.is_some() {
+                    matching.global_length =
+                        Some(transcoder.map_offset(end as usize) as u64 - start);
+                }
+            }
+        }
+        output.body_size = transcoder.map_offset(output.body_size as usize) as u64;
     }
 }
 
 pub struct HttpResponseContext {
     //todo: remove when on_http_response_headers fixed (see internal comment)
     has_response_started: bool,
+    /// taken once the exchange is reported
     data: Option<ResponseData>,
-    content_encoding: ContentEncoding,
-    decompressor: GzDecoder<Vec<u8>>,
-    response_writer: Option<PipeWriter>,
-    response_read_task: Option<Pin<Box<dyn Future<Output = Option<ResponseOutputData>>>>>,
+    request: BodyScan,
+    response: BodyScan,
 }
 
 impl Default for HttpResponseContext {
     fn default() -> Self {
         Self {
             has_response_started: false,
-            content_encoding: Default::default(),
             data: Some(Default::default()),
-            response_writer: None,
-            response_read_task: None,
-            decompressor: GzDecoder::new(vec![]),
+            request: BodyScan::new(Direction::Request),
+            response: BodyScan::new(Direction::Response),
         }
     }
 }
@@ -110,22 +326,99 @@ impl Default for HttpResponseContext {
 #[derive(Default)]
 struct ResponseData {
     request_start: u64,
+    request_body_start: u64,
+    request_body_end: u64,
+    request_body_size: u64,
+    request_body_truncated: bool,
     response_start: u64,
     response_body_start: u64,
     request_headers: Vec<Header>,
     response_headers: Vec<Header>,
-    content_type: ContentType,
-    content_encoding: ContentEncoding,
     path: String,
     token: Option<String>,
-    policy: Option<PathPolicy>,
+    endpoint: Option<Arc<CompiledEndpoint>>,
+    ip: String,
+    /// matches within the request body, reported along with the response
+    request_matches: Vec<Match>,
+}
+
+impl ResponseData {
+    /// the match data uploaded for this exchange, `response` is none if the request was blocked.
+    /// the upstream credentials are left to the caller
+    fn into_match_data(
+        mut self,
+        response: Option<&mut ScanOutput>,
+        policy_id: &str,
+        policy_path: &str,
+    ) -> MatchDataRequest {
+        let mut matches = std::mem::take(&mut self.request_matches);
+        let (body_size, body, response_body_end, body_truncated) = match response {
+            Some(response) => {
+                matches.append(&mut response.matches);
+                (
+                    response.body_size,
+                    response.body.take(),
+                    response.parse_end,
+                    response.truncated,
+                )
+            }
+            None => (0, None, 0, false),
+        };
+
+        let highest_action_taken = matches
+            .iter()
+            .map(|x| x.action_taken)
+            .max()
+            .unwrap_or(crate::proto::Action::None as i32);
+
+        MatchDataRequest {
+            policy_id: policy_id.to_string(),
+            highest_action_taken,
+            time_request_start: self.request_start,
+            time_response_start: self.response_start,
+            time_response_body_start: self.response_body_start,
+            time_response_body_end: response_body_end,
+            time_response_parse_end: response_body_end,
+            request_headers: self.request_headers,
+            response_headers: self.response_headers,
+            matches,
+            body_size,
+            body,
+            policy_path: policy_path.to_string(),
+            commit: GIT_COMMIT.to_string(),
+            token: self.token.unwrap_or_default(),
+            ip: self.ip,
+            request_body_size: self.request_body_size,
+            time_request_body_start: self.request_body_start,
+            time_request_body_end: self.request_body_end,
+            body_truncated,
+            request_body_truncated: self.request_body_truncated,
+            ..Default::default()
+        }
+    }
+}
+
+/// Everything a body scan needs, owned by its task
+struct ScanInput {
+    direction: Direction,
+    content_type: ContentType,
+    endpoint: Arc<CompiledEndpoint>,
     ip: String,
+    token: Option<String>,
+    truncated: Rc<Cell<bool>>,
 }
 
-struct ResponseOutputData {
+struct ScanOutput {
     response: ParseResponse,
-    packet: MatchDataRequest,
-    upstream: Option<UpstreamConfigHandle>,
+    /// decompressed body with masked matches replaced, if any were masked
+    masked_body: Option<Vec<u8>>,
+    matches: Vec<Match>,
+    body_size: u64,
+    /// sampled response body, never collected for requests
+    body: Option<Vec<u8>>,
+    parse_end: u64,
+    /// set if a body limit was hit, only the start of the body was scanned
+    truncated: bool,
 }
 
 fn timestamp() -> u64 {
@@ -136,61 +429,81 @@ fn timestamp() -> u64 {
         .as_nanos() as u64
 }
 
-async fn response_body_task(
-    mut data: ResponseData,
-    mut reader: PipeReader,
-) -> Option<ResponseOutputData> {
+async fn body_task(input: ScanInput, mut reader: PipeReader) -> Option<ScanOutput> {
     let policy = match policy() {
         Some(policy) => policy,
         None => {
-            warn!("processing response, but no policy loaded");
+            warn!(
+                "processing {}, but no policy loaded",
+                direction_name(input.direction)
+            );
             return None;
         }
     };
-    // todo: cache this?
-    let path_policy = data.policy.expect("missing policy");
+    let matchers = input.endpoint.matchers(input.direction);
 
     let mut matches = vec![];
+    let mut masks = vec![];
+    let mut alerts = AlertTracker::new(input.ip, input.token);
 
-    let response = match data.content_type {
-        ContentType::Html => {
-            match parse_html(
-                &*policy,
-                &mut reader,
-                &path_policy.configuration,
-                &mut matches,
-            )
-            .await
-            {
-                Ok(x) => x,
-                Err(e) => {
-                    error!("failed to read html: {:?}", e);
-                    return None;
-                }
+    let response = match parse_body(
+        &input.content_type,
+        matchers,
+        &mut reader,
+        &mut matches,
+        &mut masks,
+        &mut alerts,
+    )
+    .await
+    {
+        Ok(x) => x,
+        Err(e) if input.truncated.get() => {
+            // parsers may fail where the body was cut, keep what was scanned before that
+            debug!(
+                "stopped reading truncated {:?} body: {:?}",
+                input.content_type, e
+            );
+            let blocked = matches
+                .iter()
+                .any(|x| x.action_taken == crate::proto::Action::Block as i32);
+            if blocked {
+                ParseResponse::Block
+            } else {
+                ParseResponse::Continue
             }
         }
-        ContentType::Json => {
-            match parse_json(
-                &*policy,
-                &mut reader,
-                &path_policy.configuration,
-                &mut matches,
-            )
-            .await
-            {
-                Ok(x) => x,
-                Err(e) => {
-                    error!("failed to read json: {:?}", e);
-                    return None;
-                }
+        Err(e) => {
+            error!("failed to read {:?} body: {:?}", input.content_type, e);
+            return None;
+        }
+    };
+
+    let direction = match input.direction {
+        Direction::Request => crate::proto::Direction::Request,
+        Direction::Response => crate::proto::Direction::Response,
+    };
+    for matching in &mut matches {
+        matching.set_direction(direction);
+    }
+
+    let masked_body = if masks.is_empty() {
+        None
+    } else {
+        masks.sort_by_key(|x| x.start);
+        match reader.fetch_full_content() {
+            Some(body) => Some(replace_matches(&body[..], &masks[..]).into_owned()),
+            None => {
+                error!(
+                    "{} body was not retained for masking",
+                    direction_name(input.direction)
+                );
+                None
             }
         }
-        ContentType::Jpeg => unimplemented!(), // parse_jpeg(&*body, &configuration),
-        ContentType::Unknown => unreachable!(),
     };
 
     let body_size = reader.total_read() as u64;
-    let body = if policy.body_collection_rate <= 0.0 {
+    let body = if input.direction != Direction::Response || policy.body_collection_rate <= 0.0 {
         None
     } else {
         let chance: f64 = thread_rng().gen();
@@ -201,51 +514,14 @@ async fn response_body_task(
         }
     };
 
-    let response_body_end = timestamp();
-
-    let upstream = upstream();
-
-    let mut match_counts: HashMap<&str, i64> = HashMap::new();
-    for matching in &matches {
-        *match_counts.entry(&*matching.category_name).or_default() += 1;
-    }
-    let policy_path = path_policy.policy_path;
-    for (category_name, count) in match_counts {
-        let metric = Metric::lookup_or_define(
-            format!("ls.{policy_path}.{category_name}.count"),
-            MetricType::Counter,
-        );
-        metric.increment(count);
-    }
-
-    let packet = MatchDataRequest {
-        api_key: upstream.as_ref().map(|x| x.api_key.clone()).flatten(),
-        deployment_name: upstream
-            .as_ref()
-            .map(|x| x.deployment_name.clone())
-            .unwrap_or_default(),
-        policy_id: policy.policy_id().to_string(),
-        highest_action_taken: crate::proto::Action::None as i32,
-        time_request_start: data.request_start,
-        time_response_start: data.response_start,
-        time_response_body_start: data.response_body_start,
-        time_response_body_end: response_body_end,
-        time_response_parse_end: response_body_end,
-        request_headers: std::mem::take(&mut data.request_headers),
-        response_headers: std::mem::take(&mut data.response_headers),
+    Some(ScanOutput {
+        response,
+        masked_body,
         matches,
         body_size,
         body,
-        policy_path,
-        commit: GIT_COMMIT.to_string(),
-        token: data.token.unwrap_or_default(),
-        ip: data.ip,
-    };
-
-    Some(ResponseOutputData {
-        response,
-        packet,
-        upstream,
+        parse_end: timestamp(),
+        truncated: input.truncated.get(),
     })
 }
 
@@ -258,25 +534,335 @@ impl Context for HttpResponseContext {
 }
 
 impl HttpResponseContext {
-    fn process_data(&mut self, body_size: usize) -> Result<Vec<u8>> {
-        let body = match self.get_http_response_body(0, body_size) {
+    fn scan(&mut self, direction: Direction) -> &mut BodyScan {
+        match direction {
+            Direction::Request => &mut self.request,
+            Direction::Response => &mut self.response,
+        }
+    }
+
+    fn get_body(&self, direction: Direction, start: usize, size: usize) -> Option<Bytes> {
+        match direction {
+            Direction::Request => self.get_http_request_body(start, size),
+            Direction::Response => self.get_http_response_body(start, size),
+        }
+    }
+
+    fn set_body(&self, direction: Direction, start: usize, size: usize, value: &[u8]) {
+        match direction {
+            Direction::Request => self.set_http_request_body(start, size, value),
+            Direction::Response => self.set_http_response_body(start, size, value),
+        }
+    }
+
+    fn process_data(&mut self, direction: Direction, body_size: usize) -> Result<Vec<u8>> {
+        let buffered_size = self.scan(direction).buffered_size;
+        let body = match self.get_body(direction, buffered_size, body_size - buffered_size) {
             Some(x) => x,
             None => {
-                bail!("missing body for response");
+                bail!("missing body for {}", direction_name(direction));
+            }
+        };
+        let scan = self.scan(direction);
+        scan.buffered_size = body_size;
+        scan.decode(body)
+    }
+
+    /// starts scanning the body of `direction`, if its content type is supported
+    fn start_scan(&mut self, direction: Direction) {
+        let mut content_type = self.scan(direction).content_type.clone();
+        if !content_type.is_scanned() {
+            return;
+        }
+        let data = match &mut self.data {
+            Some(data) => data,
+            None => return,
+        };
+        if let ContentType::Grpc { method, .. } = &mut content_type {
+            *method = Some(data.path.clone());
+        }
+        let endpoint = match &data.endpoint {
+            Some(endpoint) => endpoint.clone(),
+            None => return,
+        };
+        match direction {
+            Direction::Request => data.request_body_start = timestamp(),
+            Direction::Response => data.response_body_start = timestamp(),
+        }
+        let input = ScanInput {
+            direction,
+            content_type,
+            endpoint,
+            ip: data.ip.clone(),
+            token: data.token.clone(),
+            truncated: self.scan(direction).truncated.clone(),
+        };
+        let scan = self.scan(direction);
+        let (reader, writer) = pipe(if scan.buffer_body { usize::MAX } else { 0 });
+        scan.writer = Some(writer);
+        scan.read_task = Some(Box::pin(body_task(input, reader)));
+    }
+
+    fn handle_body(
+        &mut self,
+        direction: Direction,
+        body_size: usize,
+        end_of_stream: bool,
+    ) -> Action {
+        if self.scan(direction).blocked {
+            self.set_body(direction, 0, body_size, &[]);
+            return Action::Continue;
+        }
+        if !self.scan(direction).started {
+            self.scan(direction).started = true;
+            self.start_scan(direction);
+        }
+        // cleared when we fail to do anything (i.e. no policy).
+        // when there is more chunks of that original body, continue skipping here.
+        if self.scan(direction).read_task.is_none() {
+            return Action::Continue;
+        }
+
+        if self.scan(direction).truncated.get() {
+            // the rest of a truncated body passes through unscanned
+            self.scan(direction).buffered_size = body_size;
+        } else if body_size > self.scan(direction).buffered_size {
+            let body = match self.process_data(direction, body_size) {
+                Err(e) => {
+                    error!("failed to read body: {:?}", e);
+                    return Action::Continue;
+                }
+                Ok(x) => x,
+            };
+            if !body.is_empty() {
+                self.scan(direction)
+                    .writer
+                    .as_mut()
+                    .expect("receives data after end_of_stream")
+                    .append(body);
+            }
+        }
+        let scan = self.scan(direction);
+        if end_of_stream && !scan.truncated.get() {
+            let mut body = vec![];
+            if !scan.content_encoding.is_empty() {
+                let max_output = scan.remaining_scan();
+                body = match scan.decompressor.finish(max_output) {
+                    Err(e) => {
+                        error!("failed to read body: {:?}", e);
+                        return Action::Continue;
+                    }
+                    Ok(x) => x,
+                };
+                body = scan.limit(body);
+            }
+            if let Some(transcoder) = &mut scan.transcoder {
+                body = transcoder.write(body);
+                body.extend(transcoder.finish());
+            }
+            if !body.is_empty() {
+                scan.writer
+                    .as_mut()
+                    .expect("receives data after end_of_stream")
+                    .append(body);
+            }
+        }
+        if scan.stop_truncated() {
+            let policy_path = self
+                .data
+                .as_ref()
+                .and_then(|data| data.endpoint.as_ref())
+                .map(|endpoint| endpoint.path_policy.policy_path.clone())
+                .unwrap_or_default();
+            warn!(
+                "{} for '{policy_path}' exceeded body limits, scanning stopped",
+                direction_name(direction)
+            );
+            Metric::lookup_or_define(
+                format!("ls.{policy_path}.truncated_body.count"),
+                MetricType::Counter,
+            )
+            .increment(1);
+        }
+        let scan = self.scan(direction);
+        if end_of_stream {
+            scan.writer.take();
+        } else if scan.buffer_body {
+            // masking needs the entire body
+            return Action::Pause;
+        } else if body_size == 0 {
+            return self.pending_action(direction);
+        }
+
+        let waker = waker(Arc::new(DummyWaker));
+        let mut context = std::task::Context::from_waker(&waker);
+        match scan.read_task.as_mut().unwrap().poll_unpin(&mut context) {
+            Poll::Ready(None) => {
+                scan.read_task.take();
+                Action::Continue
+            }
+            Poll::Ready(Some(output)) => {
+                scan.read_task.take();
+                self.finish_scan(direction, output, body_size)
+            }
+            Poll::Pending => self.pending_action(direction),
+        }
+    }
+
+    fn finish_scan(
+        &mut self,
+        direction: Direction,
+        mut output: ScanOutput,
+        body_size: usize,
+    ) -> Action {
+        let policy_path = self
+            .data
+            .as_ref()
+            .and_then(|data| data.endpoint.as_ref())
+            .map(|endpoint| endpoint.path_policy.policy_path.clone())
+            .unwrap_or_default();
+        let is_block = matches!(output.response, ParseResponse::Block);
+        self.scan(direction).map_offsets(&mut output);
+        match direction {
+            Direction::Request => {
+                if let Some(data) = &mut self.data {
+                    data.request_body_end = output.parse_end;
+                    data.request_body_size = output.body_size;
+                    data.request_body_truncated = output.truncated;
+                    data.request_matches = std::mem::take(&mut output.matches);
+                }
+                // a blocked request never gets a response to be reported with
+                if is_block {
+                    self.report(None);
+                }
+            }
+            Direction::Response => self.report(Some(&mut output)),
+        }
+
+        let scan = self.scan(direction);
+        match output.response {
+            ParseResponse::Block if scan.hold => {
+                warn!("blocking request for '{policy_path}'");
+                self.send_block_response();
+                Action::Pause
+            }
+            ParseResponse::Block => {
+                warn!(
+                    "blocking request for '{policy_path}' after {} started, truncating",
+                    direction_name(direction)
+                );
+                scan.blocked = true;
+                self.set_body(direction, 0, body_size, &[]);
+                Action::Continue
+            }
+            ParseResponse::Continue => {
+                if output.truncated && output.masked_body.is_some() {
+                    warn!(
+                        "not masking truncated {} for '{policy_path}'",
+                        direction_name(direction)
+                    );
+                } else if let Some(masked_body) = output.masked_body {
+                    match scan.encode(masked_body) {
+                        Ok(masked_body) => {
+                            self.set_body(direction, 0, body_size, &masked_body[..]);
+                        }
+                        Err(e) => {
+                            error!("failed to encode masked body: {:?}", e);
+                        }
+                    }
+                }
+                Action::Continue
             }
+        }
+    }
+
+    /// counts and uploads the matches of both directions, consuming the data of this exchange
+    fn report(&mut self, response: Option<&mut ScanOutput>) {
+        let mut data = match self.data.take() {
+            Some(data) => data,
+            None => return,
         };
-        match self.content_encoding {
-            ContentEncoding::Gzip => {
-                self.decompressor.write_all(&body[..])?;
-                Ok(self.decompressor.get_mut().drain(..).collect::<Vec<_>>())
+        let policy = match policy() {
+            Some(policy) => policy,
+            None => {
+                warn!("reporting matches, but no policy loaded");
+                return;
             }
-            ContentEncoding::None | ContentEncoding::Unknown => Ok(body),
+        };
+        let endpoint = match data.endpoint.take() {
+            Some(endpoint) => endpoint,
+            None => return,
+        };
+
+        let policy_path = endpoint.path_policy.policy_path.clone();
+        let mut packet = data.into_match_data(response, policy.policy_id(), &policy_path);
+
+        let mut match_counts: HashMap<&str, i64> = HashMap::new();
+        for matching in &packet.matches {
+            *match_counts.entry(&*matching.category_name).or_default() += 1;
+        }
+        for (category_name, count) in match_counts {
+            let metric = Metric::lookup_or_define(
+                format!("ls.{policy_path}.{category_name}.count"),
+                MetricType::Counter,
+            );
+            metric.increment(count);
+        }
+
+        let upstream = match upstream() {
+            Some(upstream) => upstream,
+            None => return,
+        };
+        packet.api_key = upstream.api_key.clone();
+        packet.deployment_name = upstream.deployment_name.clone();
+
+        let emitted_packet = packet.encode_to_vec();
+
+        if let Err(e) = self.dispatch_grpc_call(
+            unsafe { std::str::from_utf8_unchecked(&upstream.service_definition[..]) },
+            LEAKSIGNAL_SERVICE_NAME,
+            "MatchData",
+            vec![],
+            Some(&emitted_packet[..]),
+            MATCH_PUSH_TIMEOUT,
+        ) {
+            error!("failed to upstream match information: {:?}", e);
         }
     }
 
     fn data(&mut self) -> &mut ResponseData {
         self.data.as_mut().unwrap()
     }
+
+    fn send_block_response(&self) {
+        let block = policy()
+            .map(|policy| policy.block.clone())
+            .unwrap_or_default();
+        let mut headers = vec![];
+        if let Some(content_type) = &block.content_type {
+            headers.push(("content-type", &**content_type));
+        }
+        self.send_http_response(block.status_code, headers, Some(block.body.as_bytes()));
+    }
+
+    /// action for a body chunk while scanning is still in progress
+    fn pending_action(&mut self, direction: Direction) -> Action {
+        let scan = self.scan(direction);
+        if !scan.hold {
+            return Action::Continue;
+        }
+        match scan.hold_limit {
+            Some(limit) if scan.buffered_size > limit => {
+                warn!(
+                    "{} exceeded block max_hold_bytes, releasing it before scanning completed",
+                    direction_name(direction)
+                );
+                scan.hold = false;
+                Action::Continue
+            }
+            _ => Action::Pause,
+        }
+    }
 }
 
 fn extract_token_regex(value: &str, regex: Option<&RegexWrapper>) -> Option<String> {
@@ -307,7 +893,8 @@ impl HttpContext for HttpResponseContext {
             }
         };
 
-        if self.data().request_start == 0 {
+        let is_first = self.data().request_start == 0;
+        if is_first {
             let mut ip = String::from_utf8_lossy(
                 &self
                     .get_property(vec!["source", "address"])
@@ -332,8 +919,8 @@ impl HttpContext for HttpResponseContext {
             data.request_start = timestamp();
             data.path = path;
             let full_path = format!("{}{}", hostname, data.path);
-            data.policy = Some(policy.get_path_config(&*full_path));
-        } else if self.data().policy.is_none() {
+            data.endpoint = Some(policy.compiled().endpoint(&*full_path));
+        } else if self.data().endpoint.is_none() {
             return Action::Continue;
         }
 
@@ -345,8 +932,8 @@ impl HttpContext for HttpResponseContext {
 
             let data = self.data();
 
-            if let Some(policy) = &data.policy {
-                match policy.token_extractor.as_deref() {
+            if let Some(endpoint) = &data.endpoint {
+                match endpoint.path_policy.token_extractor.as_deref() {
                     Some(TokenExtractionConfig {
                         location: TokenExtractionSite::Request,
                         header,
@@ -382,17 +969,54 @@ impl HttpContext for HttpResponseContext {
 
             data.request_headers.push(Header { name, value });
         }
+
+        let endpoint = self.data().endpoint.clone().unwrap();
+        // requests without a body have nothing to scan or sniff
+        if !is_first || end_of_stream || !endpoint.path_policy.scans(Direction::Request) {
+            return Action::Continue;
+        }
+        let content_type_header = self.get_http_request_header_bytes("content-type");
+        let charset = charset_from_header(content_type_header.as_ref());
+        let content_type = ContentType::from_header(content_type_header);
+        let content_encoding =
+            content_encodings_from_header(self.get_http_request_header_bytes("content-encoding"));
+        let grpc_encoding =
+            grpc_encoding_from_header(self.get_http_request_header_bytes("grpc-encoding"));
+        self.request.configure(
+            &endpoint,
+            content_type,
+            charset,
+            content_encoding,
+            grpc_encoding,
+        );
+        if self.request.buffer_body {
+            self.set_http_request_header("content-length", None);
+        }
+        if self.request.hold && !end_of_stream {
+            return Action::Pause;
+        }
         Action::Continue
     }
 
+    fn on_http_request_body(&mut self, body_size: usize, end_of_stream: bool) -> Action {
+        let action = self.handle_body(Direction::Request, body_size, end_of_stream);
+        if matches!(action, Action::Continue) {
+            // envoy releases any body it buffered for us
+            self.request.buffered_size = 0;
+        }
+        action
+    }
+
     fn on_http_response_headers(&mut self, _: usize, end_of_stream: bool) -> Action {
         if !end_of_stream {
             //TODO: this doesn't work for some reason
             // return Action::Pause;
         }
 
-        if self.data().policy.is_none() {
-            return Action::Continue;
+        // data is already consumed if this is a response sent by us after blocking
+        match &self.data {
+            Some(data) if data.endpoint.is_some() => (),
+            _ => return Action::Continue,
         }
 
         if !self.has_response_started {
@@ -405,8 +1029,8 @@ impl HttpContext for HttpResponseContext {
                 let value = String::from_utf8_lossy(&value).into_owned();
 
                 let data = self.data();
-                if let Some(policy) = &data.policy {
-                    match policy.token_extractor.as_deref() {
+                if let Some(endpoint) = &data.endpoint {
+                    match endpoint.path_policy.token_extractor.as_deref() {
                         Some(TokenExtractionConfig {
                             location: TokenExtractionSite::Response,
                             header,
@@ -430,136 +1054,169 @@ impl HttpContext for HttpResponseContext {
             warn!("processing response headers, but no policy loaded");
         }
 
-        //todo: we might need to cover multiple content-type headers here
-        let content_type = self.get_http_response_header_bytes("content-type");
-        self.data.as_mut().unwrap().content_type =
-            match content_type.map(|x| String::from_utf8_lossy(&x).into_owned()) {
-                Some(value) => {
-                    if let Some((init, _)) = value.split_once(';') {
-                        init.trim()
-                            .parse()
-                            .expect("content-type parse failed (impossible)")
-                    } else {
-                        value
-                            .trim()
-                            .parse()
-                            .expect("content-type parse failed (impossible)")
-                    }
-                }
-                None => ContentType::Unknown,
-            };
-        let content_encoding = self.get_http_response_header_bytes("content-encoding");
-        self.data.as_mut().unwrap().content_encoding =
-            match content_encoding.map(|x| String::from_utf8_lossy(&x).into_owned()) {
-                Some(value) => value
-                    .trim()
-                    .parse()
-                    .expect("content-type parse failed (impossible)"),
-                None => ContentEncoding::None,
-            };
-        self.content_encoding = self.data.as_ref().unwrap().content_encoding;
+        let content_type_header = self.get_http_response_header_bytes("content-type");
+        let charset = charset_from_header(content_type_header.as_ref());
+        let content_type = ContentType::from_header(content_type_header);
+        let content_encoding =
+            content_encodings_from_header(self.get_http_response_header_bytes("content-encoding"));
+        let endpoint = self.data().endpoint.clone().unwrap();
+        let grpc_encoding =
+            grpc_encoding_from_header(self.get_http_response_header_bytes("grpc-encoding"));
+        self.response.configure(
+            &endpoint,
+            content_type,
+            charset,
+            content_encoding,
+            grpc_encoding,
+        );
+        if self.response.hold && !end_of_stream {
+            return Action::Pause;
+        }
         Action::Continue
     }
 
     fn on_http_response_body(&mut self, body_size: usize, end_of_stream: bool) -> Action {
-        if let Some(data) = &mut self.data {
-            if data.policy.is_none() {
-                return Action::Continue;
-            }
-
-            if data.content_type == ContentType::Unknown || data.content_type == ContentType::Jpeg {
-                return Action::Continue;
-            }
-            data.response_body_start = timestamp();
-            let (reader, writer) = pipe(0);
-            self.response_writer = Some(writer);
-            self.response_read_task = Some(Box::pin(response_body_task(
-                self.data.take().unwrap(),
-                reader,
-            )));
+        let action = self.handle_body(Direction::Response, body_size, end_of_stream);
+        if matches!(action, Action::Continue) {
+            // envoy releases any body it buffered for us
+            self.response.buffered_size = 0;
         }
-        // cleared when we fail to do anything (i.e. no policy).
-        // when there is more chunks of that original response, continue skipping here.
-        if self.response_read_task.is_none() {
-            return Action::Continue;
+        action
+    }
+
+    fn on_log(&mut self) {
+        // request matches are otherwise reported with a scanned response
+        if matches!(&self.data, Some(data) if !data.request_matches.is_empty()) {
+            self.report(None);
         }
+    }
+}
 
-        if body_size > 0 {
-            let body = match self.process_data(body_size) {
-                Err(e) => {
-                    error!("failed to read body: {:?}", e);
-                    return Action::Continue;
-                }
-                Ok(x) => x,
-            };
-            if !body.is_empty() {
-                self.response_writer
-                    .as_mut()
-                    .expect("receives data after end_of_stream")
-                    .append(body);
-            }
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn gzip_scan(limits: BodyLimits) -> BodyScan {
+        let mut scan = BodyScan::new(Direction::Response);
+        scan.content_encoding = vec![ContentEncoding::Gzip];
+        scan.decompressor = Decompressor::new(&scan.content_encoding);
+        scan.limits = limits;
+        scan
+    }
+
+    fn decode_chunked(scan: &mut BodyScan, encoded: &[u8], chunk_size: usize) -> Vec<u8> {
+        let mut out = vec![];
+        for chunk in encoded.chunks(chunk_size) {
+            out.extend(scan.decode(chunk.to_vec()).unwrap());
         }
-        if end_of_stream {
-            if matches!(self.content_encoding, ContentEncoding::Gzip) {
-                let body = match std::mem::replace(&mut self.decompressor, GzDecoder::new(vec![]))
-                    .finish()
-                {
-                    Err(e) => {
-                        error!("failed to read body: {:?}", e);
-                        return Action::Continue;
-                    }
-                    Ok(x) => x,
-                };
-                if !body.is_empty() {
-                    self.response_writer
-                        .as_mut()
-                        .expect("receives data after end_of_stream")
-                        .append(body);
-                }
-            }
-            self.response_writer.take().unwrap();
-        } else if body_size == 0 {
-            return Action::Continue;
+        out
+    }
+
+    fn scan_output(truncated: bool) -> ScanOutput {
+        ScanOutput {
+            response: ParseResponse::Continue,
+            masked_body: None,
+            matches: vec![],
+            body_size: 0,
+            body: None,
+            parse_end: 0,
+            truncated,
         }
+    }
 
-        let waker = waker(Arc::new(DummyWaker));
-        let mut context = std::task::Context::from_waker(&waker);
-        match self
-            .response_read_task
-            .as_mut()
-            .unwrap()
-            .poll_unpin(&mut context)
-        {
-            Poll::Ready(None) => {
-                self.response_read_task.take();
-                Action::Continue
-            }
-            Poll::Ready(Some(data)) => {
-                self.response_read_task.take();
-                if let Some(upstream) = data.upstream {
-                    let emitted_packet = data.packet.encode_to_vec();
-
-                    if let Err(e) = self.dispatch_grpc_call(
-                        unsafe { std::str::from_utf8_unchecked(&upstream.service_definition[..]) },
-                        LEAKSIGNAL_SERVICE_NAME,
-                        "MatchData",
-                        vec![],
-                        Some(&emitted_packet[..]),
-                        MATCH_PUSH_TIMEOUT,
-                    ) {
-                        error!("failed to upstream match information: {:?}", e);
-                    }
-                }
-                match data.response {
-                    ParseResponse::Block => {
-                        warn!("blocking request for '{}'", data.packet.policy_path);
-                        self.set_http_response_body(0, body_size, &[]);
-                        Action::Continue
-                    }
-                    ParseResponse::Continue => Action::Continue,
-                }
-            }
-            Poll::Pending => Action::Continue,
+    #[test]
+    fn test_max_scanned_bytes() {
+        let mut scan = BodyScan::new(Direction::Request);
+        scan.limits = BodyLimits {
+            max_scanned_bytes: Some(10),
+            ..Default::default()
+        };
+        scan.buffer_body = true;
+        scan.writer = Some(pipe(0).1);
+        assert_eq!(scan.decode(b"0123456".to_vec()).unwrap(), b"0123456");
+        assert!(!scan.truncated.get());
+        assert!(!scan.stop_truncated());
+
+        assert_eq!(scan.decode(b"789abc".to_vec()).unwrap(), b"789");
+        assert!(scan.truncated.get());
+        // the truncated_body metric is counted once, when the scan is stopped
+        assert!(scan.stop_truncated());
+        assert!(scan.writer.is_none());
+        assert!(!scan.buffer_body);
+        assert!(!scan.stop_truncated());
+    }
+
+    #[test]
+    fn test_max_decompressed_bytes() {
+        let body = b"0123456789".repeat(1600);
+        let encoded = compression::encode(&[ContentEncoding::Gzip], body.clone()).unwrap();
+        let mut scan = gzip_scan(BodyLimits {
+            max_decompressed_bytes: Some(1000),
+            max_scanned_bytes: Some(2000),
+            ..Default::default()
+        });
+        scan.writer = Some(pipe(0).1);
+        let out = decode_chunked(&mut scan, &encoded, 64);
+        assert_eq!(out, &body[..1000]);
+        assert!(scan.truncated.get());
+        assert!(scan.stop_truncated());
+    }
+
+    #[test]
+    fn test_truncated_flags() {
+        let mut context = HttpResponseContext::default();
+        assert!(matches!(
+            context.finish_scan(Direction::Request, scan_output(true), 0),
+            Action::Continue
+        ));
+        let data = context.data.take().unwrap();
+        assert!(data.request_body_truncated);
+        let packet = data.into_match_data(Some(&mut scan_output(false)), "policy", "path");
+        assert!(packet.request_body_truncated);
+        assert!(!packet.body_truncated);
+
+        let packet =
+            ResponseData::default().into_match_data(Some(&mut scan_output(true)), "policy", "path");
+        assert!(!packet.request_body_truncated);
+        assert!(packet.body_truncated);
+
+        // a blocked request has no response body
+        let packet = ResponseData {
+            request_body_truncated: true,
+            ..Default::default()
         }
+        .into_match_data(None, "policy", "path");
+        assert!(packet.request_body_truncated);
+        assert!(!packet.body_truncated);
+    }
+
+    #[test]
+    fn test_compression_ratio_over_chunks() {
+        let body = (0..2000)
+            .map(|i| format!("{{\"id\":{i},\"email\":\"user{i}@example.com\"}}\n"))
+            .collect::<String>()
+            .into_bytes();
+        let encoded = compression::encode(&[ContentEncoding::Gzip], body.clone()).unwrap();
+        let mut scan = gzip_scan(BodyLimits {
+            max_compression_ratio: Some(20.0),
+            ..Default::default()
+        });
+        let mut out = decode_chunked(&mut scan, &encoded, 512);
+        out.extend(scan.decompressor.finish(usize::MAX).unwrap());
+        assert!(!scan.truncated.get());
+        assert_eq!(out, body);
+    }
+
+    #[test]
+    fn test_compression_ratio_bomb() {
+        let encoded =
+            compression::encode(&[ContentEncoding::Gzip], vec![0; 16 * 1024 * 1024]).unwrap();
+        let mut scan = gzip_scan(BodyLimits {
+            max_compression_ratio: Some(20.0),
+            ..Default::default()
+        });
+        let out = decode_chunked(&mut scan, &encoded, 512);
+        assert!(scan.truncated.get());
+        assert!(out.len() <= encoded.len() * 20);
     }
 }
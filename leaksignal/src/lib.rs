@@ -11,7 +11,12 @@ use proxy_wasm::{
 };
 use root::EnvoyRootContext;
 
+mod alert;
+mod charset;
+mod compression;
 mod config;
+mod decode;
+mod direct;
 mod evaluator;
 mod http_response;
 mod low_entropy_hash;
@@ -26,6 +31,20 @@ static ALLOC: GlobalDlmalloc = GlobalDlmalloc;
 
 mod proto {
     include!(concat!(env!("OUT_DIR"), "/leaksignal.rs"));
+
+    #[cfg(test)]
+    impl r#match::Location {
+        pub fn value(&self) -> &str {
+            match self {
+                Self::JsonPointer(x)
+                | Self::HtmlAttribute(x)
+                | Self::CsvColumn(x)
+                | Self::FormField(x)
+                | Self::ProtobufField(x)
+                | Self::ImageMetadataTag(x) => x,
+            }
+        }
+    }
 }
 
 lazy_static::lazy_static! {
@@ -0,0 +1,331 @@
+use anyhow::Result;
+use fancy_regex::Regex;
+use log::warn;
+use std::{cell::RefCell, sync::Arc};
+
+use indexmap::IndexMap;
+use leakpolicy::{Direction, MatchContext, PathConfiguration};
+
+mod parse;
+
+use crate::{
+    alert::AlertTracker,
+    evaluator::{self, MatcherMetadata, MatcherState},
+    pipe::PipeReader,
+    policy::{Category, ContentType, Policy},
+    proto::{r#match::Location, Match},
+};
+
+use super::{replace_matches, ParseResponse, Replacement};
+
+/// Matcher state of a `Keyed` category, only used for values of a column with a matching header
+pub struct KeyedColumn {
+    keys: Vec<Regex>,
+    values: MatcherState,
+}
+
+impl KeyedColumn {
+    fn matches_header(&self, header: &str) -> bool {
+        self.keys
+            .iter()
+            .any(|regex| regex.is_match(header).unwrap_or_default())
+    }
+}
+
+/// Matcher states of csv header cells (`keys`) and of the cells below them (`values`)
+#[derive(Default)]
+pub struct CsvMatchers {
+    pub keys: MatcherState,
+    pub values: MatcherState,
+    pub keyed: Vec<KeyedColumn>,
+}
+
+pub fn prepare_match_state(
+    policy: &Policy,
+    configuration: &IndexMap<Arc<String>, PathConfiguration>,
+    direction: Direction,
+) -> CsvMatchers {
+    let mut matchers = CsvMatchers::default();
+
+    for (category_name, action) in configuration {
+        if !action.scans_content(direction, &ContentType::Csv) {
+            continue;
+        }
+
+        let do_key = action.matches_context(MatchContext::Keys, &MatchContext::STRUCTURED);
+        let do_value = action.matches_context(MatchContext::Values, &MatchContext::STRUCTURED);
+
+        let metadata = MatcherMetadata::new(category_name, action);
+
+        if let Some(Category::Keyed { keys, values }) = policy.categories.get(&**category_name) {
+            if !do_value {
+                continue;
+            }
+            let values = match values {
+                Some(values) => match values.match_group(policy) {
+                    Some(x) => Some(x),
+                    None => {
+                        warn!("keyed match group for '{category_name}' is missing its values");
+                        continue;
+                    }
+                },
+                None => None,
+            };
+            let mut keyed = KeyedColumn {
+                keys: keys.iter().map(|x| x.0.clone()).collect(),
+                values: MatcherState::default(),
+            };
+            evaluator::prepare_decoded(
+                &mut keyed.values,
+                &metadata,
+                |state, metadata| match values {
+                    Some(values) => evaluator::prepare_match_group(
+                        values,
+                        state,
+                        metadata,
+                        &action.category_config.ignore,
+                    ),
+                    None => evaluator::prepare_whole_value(
+                        state,
+                        metadata,
+                        &action.category_config.ignore,
+                    ),
+                },
+            );
+            matchers.keyed.push(keyed);
+            continue;
+        }
+
+        if do_key {
+            evaluator::prepare_matches(
+                policy,
+                category_name,
+                &mut matchers.keys,
+                &metadata,
+                &action.category_config.ignore,
+            );
+        }
+        if do_value {
+            evaluator::prepare_matches(
+                policy,
+                category_name,
+                &mut matchers.values,
+                &metadata,
+                &action.category_config.ignore,
+            );
+        }
+    }
+    matchers.keys.compile();
+    matchers.values.compile();
+    for keyed in &mut matchers.keyed {
+        keyed.values.compile();
+    }
+
+    matchers
+}
+
+/// quotes `value` as a csv field, if needed
+fn escape_csv(value: &str) -> String {
+    if value.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", value.replace('"', "\"\""))
+    } else {
+        value.to_string()
+    }
+}
+
+/// masks the decoded csv cell at `start`..`end` in the body, re-encoding it as a csv field
+fn mask_csv_field(
+    value: &str,
+    start: usize,
+    end: usize,
+    masks: Vec<Replacement>,
+    output: &mut Vec<Replacement>,
+) {
+    if masks.is_empty() {
+        return;
+    }
+    let mut local_masks = masks
+        .into_iter()
+        .map(|mask| Replacement {
+            start: mask.start - start,
+            ..mask
+        })
+        .collect::<Vec<_>>();
+    local_masks.sort_by_key(|x| x.start);
+    let masked = replace_matches(value.as_bytes(), &local_masks);
+    output.push(Replacement {
+        start,
+        length: end - start,
+        value: escape_csv(&String::from_utf8_lossy(&masked)).into_bytes(),
+    });
+}
+
+fn set_csv_column(matches: &mut [Match], header: Option<&str>) {
+    if let Some(header) = header {
+        for matching in matches {
+            matching.location = Some(Location::CsvColumn(header.to_string()));
+        }
+    }
+}
+
+/// the first record is read as the header, naming the column of each value
+pub async fn parse_csv(
+    matchers: &CsvMatchers,
+    body: &mut PipeReader,
+    matches: &mut Vec<Match>,
+    masks: &mut Vec<Replacement>,
+    alerts: &mut AlertTracker,
+) -> Result<ParseResponse> {
+    // shared by the header and value callbacks
+    let alerts = RefCell::new(alerts);
+
+    let mut header_matches = vec![];
+    let mut header_masks = vec![];
+
+    let response = parse::parse_csv(
+        body,
+        |header, start, end| {
+            let mut local_masks = vec![];
+            let first_match = header_matches.len();
+            let response = matchers.keys.do_matching(
+                start,
+                0,
+                &header,
+                &mut header_matches,
+                &mut local_masks,
+                &mut alerts.borrow_mut(),
+            );
+            set_csv_column(&mut header_matches[first_match..], Some(&header));
+            mask_csv_field(&header, start, end, local_masks, &mut header_masks);
+            match response {
+                ParseResponse::Continue => None,
+                ParseResponse::Block => Some(ParseResponse::Block),
+            }
+        },
+        |value, header, start, end| {
+            let mut local_masks = vec![];
+            let first_match = matches.len();
+            let mut alerts = alerts.borrow_mut();
+            let mut response = ParseResponse::Continue;
+            let keyed = matchers
+                .keyed
+                .iter()
+                .filter(|keyed| {
+                    header
+                        .map(|header| keyed.matches_header(header))
+                        .unwrap_or_default()
+                })
+                .map(|keyed| &keyed.values);
+            for values in std::iter::once(&matchers.values).chain(keyed) {
+                let value_response =
+                    values.do_matching(start, 0, &value, matches, &mut local_masks, &mut alerts);
+                if matches!(value_response, ParseResponse::Block) {
+                    response = ParseResponse::Block;
+                }
+            }
+            set_csv_column(&mut matches[first_match..], header);
+            mask_csv_field(&value, start, end, local_masks, masks);
+            match response {
+                ParseResponse::Continue => None,
+                ParseResponse::Block => Some(ParseResponse::Block),
+            }
+        },
+    )
+    .await?;
+
+    matches.extend(header_matches);
+    masks.extend(header_masks);
+    masks.sort_by_key(|x| x.start);
+
+    Ok(response.unwrap_or(ParseResponse::Continue))
+}
+
+#[cfg(test)]
+mod tests {
+    use futures::{pin_mut, task::waker, Future};
+    use std::task::Poll;
+
+    use crate::pipe::{pipe, DummyWaker};
+
+    use super::*;
+
+    #[test]
+    fn test_csv_export() {
+        let policy: Policy = serde_yaml::from_str(
+            r#"
+categories:
+  email:
+    Matchers:
+      regexes:
+        - "[a-z]+@[a-z]+\\.com"
+  ssn_column:
+    Keyed:
+      keys: "(?i)^ssn$"
+  ssn_header:
+    Matchers:
+      raw:
+        - "SSN"
+endpoints:
+  - matches: "**"
+    config:
+      email:
+        action: mask
+        mask_replacement: "<redacted, see policy>"
+      ssn_column:
+        action: mask
+      ssn_header:
+        contexts: keys
+"#,
+        )
+        .unwrap();
+        let path_policy = policy.get_path_config("example.com/");
+        let matchers =
+            prepare_match_state(&policy, &path_policy.configuration, Direction::Response);
+
+        let body = "name,SSN,contact\nAlice,123456789,\"a@b.com, c@d.com\"\nBob,987654321,none\n";
+        let (mut reader, mut writer) = pipe(0);
+        assert!(writer.append(body.as_bytes()));
+        drop(writer);
+        let mut matches = vec![];
+        let mut masks = vec![];
+        let mut alerts = AlertTracker::default();
+        let waker = waker(Arc::new(DummyWaker));
+        let mut context = std::task::Context::from_waker(&waker);
+        {
+            let future = parse_csv(
+                &matchers,
+                &mut reader,
+                &mut matches,
+                &mut masks,
+                &mut alerts,
+            );
+            pin_mut!(future);
+            assert!(matches!(
+                future.poll(&mut context),
+                Poll::Ready(Ok(ParseResponse::Continue))
+            ));
+        }
+
+        assert_eq!(
+            matches
+                .iter()
+                .map(|x| (
+                    &*x.category_name,
+                    x.matched_value.as_deref(),
+                    x.location.as_ref().map(Location::value)
+                ))
+                .collect::<Vec<_>>(),
+            vec![
+                ("ssn_column", Some("123456789"), Some("SSN")),
+                ("email", Some("a@b.com"), Some("contact")),
+                ("email", Some("c@d.com"), Some("contact")),
+                ("ssn_column", Some("987654321"), Some("SSN")),
+                ("ssn_header", Some("SSN"), Some("SSN")),
+            ]
+        );
+        assert_eq!(
+            &*replace_matches(body.as_bytes(), &masks),
+            &b"name,SSN,contact\nAlice,*********,\"<redacted, see policy>, <redacted, see policy>\"\nBob,*********,none\n"[..]
+        );
+    }
+}
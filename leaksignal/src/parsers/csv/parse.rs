@@ -0,0 +1,174 @@
+use crate::pipe::{maybe_read_u8, PipeReader};
+use anyhow::Result;
+
+/// The field being read
+#[derive(Default)]
+struct Field {
+    value: Vec<u8>,
+    /// body offset of the field, including any opening quote
+    start: usize,
+    quoted: bool,
+    in_quotes: bool,
+    /// body offset after the closing quote, for quoted fields
+    end: Option<usize>,
+}
+
+/// cells of the first record are sent to `header_out`, non-empty cells of later records to `value_out` with the header of their column.
+/// spans of quoted cells include their quotes.
+pub async fn parse_csv<T>(
+    input: &mut PipeReader,
+    mut header_out: impl FnMut(String, usize, usize) -> Option<T>,
+    mut value_out: impl FnMut(String, Option<&str>, usize, usize) -> Option<T>,
+) -> Result<Option<T>> {
+    // none until the header record has been read
+    let mut headers: Option<Vec<String>> = None;
+    let mut header_record = vec![];
+    let mut column = 0usize;
+    let mut field = Field::default();
+    loop {
+        let position = input.total_read();
+        let (byte, eof) = match maybe_read_u8(input).await? {
+            Some(byte) => (byte, false),
+            None if column == 0 && field.value.is_empty() && !field.quoted => break,
+            // ends the last record
+            None => (b'\n', true),
+        };
+        if field.in_quotes {
+            if eof {
+                field.in_quotes = false;
+                field.end = Some(position);
+            } else {
+                if byte == b'"' {
+                    field.in_quotes = false;
+                    field.end = Some(position + 1);
+                } else {
+                    field.value.push(byte);
+                }
+                continue;
+            }
+        }
+        match byte {
+            b',' | b'\n' => {
+                let end = match field.end {
+                    Some(end) => end,
+                    None if field.value.last() == Some(&b'\r') => {
+                        field.value.pop();
+                        position - 1
+                    }
+                    None => position,
+                };
+                let is_blank_line =
+                    byte == b'\n' && column == 0 && field.value.is_empty() && !field.quoted;
+                let value = String::from_utf8_lossy(&field.value).into_owned();
+                let start = field.start;
+                field = Field {
+                    start: position + 1,
+                    ..Default::default()
+                };
+                if !is_blank_line {
+                    match &headers {
+                        None => {
+                            header_record.push(value.clone());
+                            if let Some(out) = header_out(value, start, end) {
+                                return Ok(Some(out));
+                            }
+                        }
+                        Some(headers) if !value.is_empty() => {
+                            let header = headers.get(column).map(|x| &**x);
+                            if let Some(out) = value_out(value, header, start, end) {
+                                return Ok(Some(out));
+                            }
+                        }
+                        Some(_) => (),
+                    }
+                    column += 1;
+                }
+                if byte == b'\n' {
+                    if headers.is_none() && !header_record.is_empty() {
+                        headers = Some(std::mem::take(&mut header_record));
+                    }
+                    column = 0;
+                }
+                if eof {
+                    break;
+                }
+            }
+            b'"' if field.value.is_empty() && !field.quoted => {
+                field.quoted = true;
+                field.in_quotes = true;
+            }
+            // an escaped quote
+            b'"' if field.quoted => {
+                field.value.push(b'"');
+                field.in_quotes = true;
+                field.end = None;
+            }
+            // after the closing quote, i.e. the `\r` of `\r\n`
+            _ if field.end.is_some() => (),
+            _ => field.value.push(byte),
+        }
+    }
+    Ok(None)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{sync::Arc, task::Poll};
+
+    use futures::{pin_mut, task::waker, Future};
+
+    use crate::pipe::{pipe, DummyWaker};
+
+    use super::*;
+
+    #[test]
+    fn test_csv_parse() {
+        let body = "name,email,\"note\"\r\nAlice,a@b.com,\"x, \"\"y\"\"\"\r\n\r\nBob,,z,extra\n\"multi\nline\",c@d.com";
+        let (mut reader, mut writer) = pipe(0);
+        assert!(writer.append(body.as_bytes()));
+        drop(writer);
+        let mut headers = vec![];
+        let mut values = vec![];
+        let waker = waker(Arc::new(DummyWaker));
+        let mut context = std::task::Context::from_waker(&waker);
+
+        {
+            let future = parse_csv::<()>(
+                &mut reader,
+                |header, start, end| {
+                    headers.push((header, &body[start..end]));
+                    None
+                },
+                |value, header, start, end| {
+                    values.push((value, header.map(|x| x.to_string()), &body[start..end]));
+                    None
+                },
+            );
+            pin_mut!(future);
+            assert!(matches!(future.poll(&mut context), Poll::Ready(Ok(None))));
+        }
+
+        assert_eq!(
+            headers,
+            vec![
+                ("name".to_string(), "name"),
+                ("email".to_string(), "email"),
+                ("note".to_string(), "\"note\""),
+            ]
+        );
+        let header = |x: &str| Some(x.to_string());
+        assert_eq!(
+            values,
+            vec![
+                ("Alice".to_string(), header("name"), "Alice"),
+                ("a@b.com".to_string(), header("email"), "a@b.com"),
+                ("x, \"y\"".to_string(), header("note"), "\"x, \"\"y\"\"\""),
+                ("Bob".to_string(), header("name"), "Bob"),
+                ("z".to_string(), header("note"), "z"),
+                ("extra".to_string(), None, "extra"),
+                ("multi\nline".to_string(), header("name"), "\"multi\nline\""),
+                ("c@d.com".to_string(), header("email"), "c@d.com"),
+            ]
+        );
+    }
+}
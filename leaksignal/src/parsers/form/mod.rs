@@ -0,0 +1,371 @@
+use anyhow::Result;
+use std::sync::Arc;
+
+use indexmap::IndexMap;
+use leakpolicy::{Direction, MatchContext, PathConfiguration};
+
+mod parse;
+
+use crate::{
+    alert::AlertTracker,
+    evaluator::{self, MatcherMetadata, MatcherState},
+    pipe::{pipe, PipeReader},
+    policy::{BodyMatchers, Category, ContentType, Policy},
+    proto::{r#match::Location, Match},
+};
+
+use super::{parse_body, replace_matches, ContentType as BodyType, ParseResponse, Replacement};
+
+/// Matcher states of form field names (`keys`) and contents (`values`)
+#[derive(Default)]
+pub struct FormMatchers {
+    pub keys: MatcherState,
+    pub values: MatcherState,
+}
+
+pub fn prepare_match_state(
+    policy: &Policy,
+    configuration: &IndexMap<Arc<String>, PathConfiguration>,
+    direction: Direction,
+) -> FormMatchers {
+    let mut matchers = FormMatchers::default();
+
+    for (category_name, action) in configuration {
+        if !action.scans_content(direction, &ContentType::Form) {
+            continue;
+        }
+
+        // Keyed categories only match json values and csv cells, see `Policy::validate`
+        if let Some(Category::Keyed { .. }) = policy.categories.get(&**category_name) {
+            continue;
+        }
+
+        let metadata = MatcherMetadata::new(category_name, action);
+
+        if action.matches_context(MatchContext::Keys, &MatchContext::STRUCTURED) {
+            evaluator::prepare_matches(
+                policy,
+                category_name,
+                &mut matchers.keys,
+                &metadata,
+                &action.category_config.ignore,
+            );
+        }
+        if action.matches_context(MatchContext::Values, &MatchContext::STRUCTURED) {
+            evaluator::prepare_matches(
+                policy,
+                category_name,
+                &mut matchers.values,
+                &metadata,
+                &action.category_config.ignore,
+            );
+        }
+    }
+    matchers.keys.compile();
+    matchers.values.compile();
+
+    matchers
+}
+
+/// percent-encodes `value` as an urlencoded name or value
+fn encode_form(value: &str) -> String {
+    let mut out = String::with_capacity(value.len());
+    for byte in value.bytes() {
+        match byte {
+            b'a'..=b'z' | b'A'..=b'Z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'*' => {
+                out.push(byte as char)
+            }
+            b' ' => out.push('+'),
+            byte => out.push_str(&format!("%{byte:02X}")),
+        }
+    }
+    out
+}
+
+/// masks the decoded urlencoded name or value at `start`..`end` in the body, re-encoding it
+fn mask_form_value(
+    value: &str,
+    start: usize,
+    end: usize,
+    masks: Vec<Replacement>,
+    output: &mut Vec<Replacement>,
+) {
+    if masks.is_empty() {
+        return;
+    }
+    let mut local_masks = masks
+        .into_iter()
+        .map(|mask| Replacement {
+            start: mask.start - start,
+            ..mask
+        })
+        .collect::<Vec<_>>();
+    local_masks.sort_by_key(|x| x.start);
+    let masked = replace_matches(value.as_bytes(), &local_masks);
+    output.push(Replacement {
+        start,
+        length: end - start,
+        value: encode_form(&String::from_utf8_lossy(&masked)).into_bytes(),
+    });
+}
+
+/// sets the field of `matches` that have no location yet, matches in parts scanned as another content type keep theirs
+fn set_form_field(matches: &mut [Match], name: Option<&str>) {
+    if let Some(name) = name {
+        for matching in matches.iter_mut().filter(|x| x.location.is_none()) {
+            matching.location = Some(Location::FormField(name.to_string()));
+        }
+    }
+}
+
+/// scans an `application/x-www-form-urlencoded` body
+pub async fn parse_form(
+    matchers: &FormMatchers,
+    body: &mut PipeReader,
+    matches: &mut Vec<Match>,
+    masks: &mut Vec<Replacement>,
+    alerts: &mut AlertTracker,
+) -> Result<ParseResponse> {
+    let response = parse::parse_urlencoded(body, |field| {
+        let first_match = matches.len();
+        let mut local_masks = vec![];
+        let mut response = matchers.keys.do_matching(
+            field.name_start,
+            0,
+            &field.name,
+            matches,
+            &mut local_masks,
+            alerts,
+        );
+        mask_form_value(
+            &field.name,
+            field.name_start,
+            field.name_end,
+            local_masks,
+            masks,
+        );
+        if let Some((value, start, end)) = field.value.filter(|(value, _, _)| !value.is_empty()) {
+            let mut local_masks = vec![];
+            let value_response =
+                matchers
+                    .values
+                    .do_matching(start, 0, &value, matches, &mut local_masks, alerts);
+            if matches!(value_response, ParseResponse::Block) {
+                response = ParseResponse::Block;
+            }
+            mask_form_value(&value, start, end, local_masks, masks);
+        }
+        set_form_field(&mut matches[first_match..], Some(&field.name));
+        match response {
+            ParseResponse::Continue => None,
+            ParseResponse::Block => Some(ParseResponse::Block),
+        }
+    })
+    .await?;
+
+    masks.sort_by_key(|x| x.start);
+
+    Ok(response.unwrap_or(ParseResponse::Continue))
+}
+
+/// scans a multipart body, parts with a content-type are scanned by the parser of that type.
+/// part names are matched as keys, but never masked.
+pub async fn parse_multipart(
+    matchers: &BodyMatchers,
+    boundary: &str,
+    body: &mut PipeReader,
+    matches: &mut Vec<Match>,
+    masks: &mut Vec<Replacement>,
+    alerts: &mut AlertTracker,
+) -> Result<ParseResponse> {
+    let mut multipart = parse::MultipartReader::new(boundary);
+    while let Some(part) = multipart.next_part(body).await? {
+        let first_match = matches.len();
+        let mut response = ParseResponse::Continue;
+        if let Some((name, start, _)) = &part.name {
+            response =
+                matchers
+                    .form
+                    .keys
+                    .do_matching(*start, 0, name, matches, &mut vec![], alerts);
+        }
+        let part_response = match part.content_type.as_deref().map(BodyType::from_value) {
+            // a plain form field
+            None => matchers.form.values.do_matching(
+                part.start,
+                0,
+                &String::from_utf8_lossy(&part.content),
+                matches,
+                masks,
+                alerts,
+            ),
+            Some(content_type) if content_type.is_scanned() => {
+                let (mut reader, mut writer) = pipe(0);
+                writer.append(part.content);
+                drop(writer);
+                let mut part_matches = vec![];
+                let mut part_masks = vec![];
+                let response = parse_body(
+                    &content_type,
+                    matchers,
+                    &mut reader,
+                    &mut part_matches,
+                    &mut part_masks,
+                    alerts,
+                )
+                .await?;
+                for matching in &mut part_matches {
+                    if let Some(position) = &mut matching.global_start_position {
+                        *position += part.start as u64;
+                    }
+                }
+                matches.extend(part_matches);
+                masks.extend(part_masks.into_iter().map(|mask| Replacement {
+                    start: mask.start + part.start,
+                    ..mask
+                }));
+                response
+            }
+            Some(_) => ParseResponse::Continue,
+        };
+        set_form_field(
+            &mut matches[first_match..],
+            part.name.as_ref().map(|(name, _, _)| &**name),
+        );
+        if matches!(response, ParseResponse::Block) || matches!(part_response, ParseResponse::Block)
+        {
+            return Ok(ParseResponse::Block);
+        }
+    }
+
+    masks.sort_by_key(|x| x.start);
+
+    Ok(ParseResponse::Continue)
+}
+
+#[cfg(test)]
+mod tests {
+    use futures::{pin_mut, task::waker, Future};
+    use std::task::Poll;
+
+    use crate::{
+        pipe::{pipe, DummyWaker},
+        policy::BodyMatchers,
+    };
+
+    use super::*;
+
+    const POLICY: &str = r#"
+categories:
+  email:
+    Matchers:
+      regexes:
+        - "[a-z]+@[a-z]+\\.com"
+  password_field:
+    Matchers:
+      raw:
+        - "password"
+endpoints:
+  - matches: "**"
+    directions: request
+    config:
+      email:
+        action: mask
+        mask_replacement: "<redacted email>"
+      password_field:
+        contexts: keys
+"#;
+
+    fn run_body(content_type: BodyType, body: &str) -> (Vec<Match>, Vec<u8>) {
+        let policy: Policy = serde_yaml::from_str(POLICY).unwrap();
+        let path_policy = policy.get_path_config("example.com/");
+        let matchers = BodyMatchers::new(
+            &policy,
+            &Default::default(),
+            &path_policy,
+            Direction::Request,
+        );
+
+        let (mut reader, mut writer) = pipe(0);
+        assert!(writer.append(body.as_bytes()));
+        drop(writer);
+        let mut matches = vec![];
+        let mut masks = vec![];
+        let mut alerts = AlertTracker::default();
+        let waker = waker(Arc::new(DummyWaker));
+        let mut context = std::task::Context::from_waker(&waker);
+        {
+            let future = parse_body(
+                &content_type,
+                &matchers,
+                &mut reader,
+                &mut matches,
+                &mut masks,
+                &mut alerts,
+            );
+            pin_mut!(future);
+            assert!(matches!(
+                future.poll(&mut context),
+                Poll::Ready(Ok(ParseResponse::Continue))
+            ));
+        }
+        masks.sort_by_key(|x| x.start);
+        let masked = replace_matches(body.as_bytes(), &masks).into_owned();
+        (matches, masked)
+    }
+
+    fn summary(matches: &[Match]) -> Vec<(&str, Option<&str>, Option<&str>)> {
+        matches
+            .iter()
+            .map(|x| {
+                (
+                    &*x.category_name,
+                    x.matched_value.as_deref(),
+                    x.location.as_ref().map(Location::value),
+                )
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_urlencoded_form() {
+        let body = "user=alice&contact=a%40b.com+or+c@d.com&password=hunter2";
+        let (matches, masked) = run_body(BodyType::Form, body);
+        assert_eq!(
+            summary(&matches),
+            vec![
+                ("email", Some("a@b.com"), Some("contact")),
+                ("email", Some("c@d.com"), Some("contact")),
+                ("password_field", Some("password"), Some("password")),
+            ]
+        );
+        assert_eq!(
+            &*masked,
+            &b"user=alice&contact=%3Credacted+email%3E+or+%3Credacted+email%3E&password=hunter2"[..]
+        );
+    }
+
+    #[test]
+    fn test_multipart_form() {
+        let body = "--b\r\nContent-Disposition: form-data; name=\"contact\"\r\n\r\na@b.com\r\n--b\r\nContent-Disposition: form-data; name=\"password\"\r\n\r\nhunter2\r\n--b\r\nContent-Disposition: form-data; name=\"profile\"; filename=\"p.json\"\r\nContent-Type: application/json\r\n\r\n{\"email\": \"c@d.com\"}\r\n--b\r\nContent-Disposition: form-data; name=\"avatar\"; filename=\"a.bin\"\r\nContent-Type: application/octet-stream\r\n\r\n\u{0}\u{1}x@y.com\r\n--b--\r\n";
+        let (matches, masked) = run_body(
+            BodyType::Multipart {
+                boundary: "b".to_string(),
+            },
+            body,
+        );
+        assert_eq!(
+            summary(&matches),
+            vec![
+                ("email", Some("a@b.com"), Some("contact")),
+                ("password_field", Some("password"), Some("password")),
+                ("email", Some("c@d.com"), Some("/email")),
+            ]
+        );
+        assert_eq!(
+            String::from_utf8(masked).unwrap(),
+            body.replace("a@b.com", "<redacted email>")
+                .replace("c@d.com", "<redacted email>")
+        );
+    }
+}
@@ -0,0 +1,341 @@
+use crate::{
+    decode::hex_value,
+    pipe::{maybe_read_u8, PipeReader},
+};
+use anyhow::Result;
+
+/// A decoded field of an urlencoded body, with the body spans of its name and value
+pub struct FormField {
+    pub name: String,
+    pub name_start: usize,
+    pub name_end: usize,
+    /// none if the field has no `=`
+    pub value: Option<(String, usize, usize)>,
+}
+
+/// undoes percent-encoding, and `+` for spaces
+fn decode_form(raw: &[u8]) -> String {
+    let mut out = Vec::with_capacity(raw.len());
+    let mut i = 0usize;
+    while i < raw.len() {
+        match raw[i] {
+            b'%' => {
+                let high = raw.get(i + 1).copied().and_then(hex_value);
+                let low = raw.get(i + 2).copied().and_then(hex_value);
+                match (high, low) {
+                    (Some(high), Some(low)) => {
+                        out.push(high << 4 | low);
+                        i += 3;
+                        continue;
+                    }
+                    _ => out.push(b'%'),
+                }
+            }
+            b'+' => out.push(b' '),
+            byte => out.push(byte),
+        }
+        i += 1;
+    }
+    String::from_utf8_lossy(&out).into_owned()
+}
+
+/// sends every non-empty `&` separated field of an urlencoded body to `field_out`
+pub async fn parse_urlencoded<T>(
+    input: &mut PipeReader,
+    mut field_out: impl FnMut(FormField) -> Option<T>,
+) -> Result<Option<T>> {
+    let mut raw = vec![];
+    let mut start = input.total_read();
+    // set once the `=` of the current field was read
+    let mut name: Option<(String, usize, usize)> = None;
+    loop {
+        let position = input.total_read();
+        let byte = maybe_read_u8(input).await?;
+        match byte {
+            Some(b'=') if name.is_none() => {
+                name = Some((decode_form(&raw), start, position));
+                raw.clear();
+                start = position + 1;
+            }
+            Some(b'&') | Some(b'\n') | None => {
+                let field = match name.take() {
+                    Some((name, name_start, name_end)) => Some(FormField {
+                        name,
+                        name_start,
+                        name_end,
+                        value: Some((decode_form(&raw), start, position)),
+                    }),
+                    None if !raw.is_empty() => Some(FormField {
+                        name: decode_form(&raw),
+                        name_start: start,
+                        name_end: position,
+                        value: None,
+                    }),
+                    None => None,
+                };
+                if let Some(out) = field.and_then(&mut field_out) {
+                    return Ok(Some(out));
+                }
+                raw.clear();
+                start = position + 1;
+                if byte.is_none() {
+                    break;
+                }
+            }
+            Some(b'\r') => (),
+            Some(byte) => raw.push(byte),
+        }
+    }
+    Ok(None)
+}
+
+/// A part of a multipart body
+pub struct Part {
+    /// the `name` of the part's content-disposition, with its body span
+    pub name: Option<(String, usize, usize)>,
+    pub content_type: Option<String>,
+    /// body offset of `content`
+    pub start: usize,
+    pub content: Vec<u8>,
+}
+
+/// reads up to and excluding the next `\n`, dropping a trailing `\r`. `None` at end of body
+async fn read_line(input: &mut PipeReader) -> Result<Option<Vec<u8>>> {
+    let mut line = vec![];
+    loop {
+        match maybe_read_u8(input).await? {
+            Some(b'\n') => break,
+            Some(byte) => line.push(byte),
+            None if line.is_empty() => return Ok(None),
+            None => break,
+        }
+    }
+    if line.last() == Some(&b'\r') {
+        line.pop();
+    }
+    Ok(Some(line))
+}
+
+/// returns the `name` parameter of a content-disposition header value, with its span in `value`
+fn disposition_name(value: &str) -> Option<(String, usize, usize)> {
+    let mut offset = 0usize;
+    for parameter in value.split(';') {
+        let parameter_start = offset;
+        offset += parameter.len() + 1;
+        let (key, raw) = match parameter.split_once('=') {
+            Some(x) => x,
+            None => continue,
+        };
+        if !key.trim().eq_ignore_ascii_case("name") {
+            continue;
+        }
+        let trimmed = raw.trim_start();
+        let start = parameter_start + key.len() + 1 + raw.len() - trimmed.len();
+        let trimmed = trimmed.trim_end();
+        if trimmed.len() >= 2 && trimmed.starts_with('"') && trimmed.ends_with('"') {
+            let name = &trimmed[1..trimmed.len() - 1];
+            return Some((name.to_string(), start + 1, start + 1 + name.len()));
+        }
+        return Some((trimmed.to_string(), start, start + trimmed.len()));
+    }
+    None
+}
+
+/// Reads the parts of a multipart body one at a time, the preamble and epilogue are skipped
+pub struct MultipartReader {
+    delimiter: Vec<u8>,
+    started: bool,
+    done: bool,
+}
+
+impl MultipartReader {
+    pub fn new(boundary: &str) -> Self {
+        Self {
+            delimiter: format!("\r\n--{boundary}").into_bytes(),
+            started: false,
+            done: false,
+        }
+    }
+
+    /// reads past the first delimiter
+    async fn skip_preamble(&mut self, input: &mut PipeReader) -> Result<()> {
+        // the first delimiter may start the body, without a preceding line break
+        let mut buffer = b"\r\n".to_vec();
+        loop {
+            match maybe_read_u8(input).await? {
+                Some(byte) => buffer.push(byte),
+                None => {
+                    self.done = true;
+                    return Ok(());
+                }
+            }
+            if buffer.ends_with(&self.delimiter) {
+                return Ok(());
+            }
+            if buffer.len() > self.delimiter.len() * 2 {
+                buffer.drain(..buffer.len() - self.delimiter.len());
+            }
+        }
+    }
+
+    /// returns the next part, `None` after the closing delimiter.
+    /// a body truncated within a part returns the part as read so far.
+    pub async fn next_part(&mut self, input: &mut PipeReader) -> Result<Option<Part>> {
+        if !self.started {
+            self.started = true;
+            self.skip_preamble(input).await?;
+        }
+        if self.done {
+            return Ok(None);
+        }
+        // the rest of the delimiter line is `--` for the closing delimiter, or transport padding
+        match read_line(input).await? {
+            Some(line) if !line.starts_with(b"--") => (),
+            _ => {
+                self.done = true;
+                return Ok(None);
+            }
+        }
+        let mut part = Part {
+            name: None,
+            content_type: None,
+            start: 0,
+            content: vec![],
+        };
+        loop {
+            let line_start = input.total_read();
+            let line = match read_line(input).await? {
+                Some(line) if !line.is_empty() => String::from_utf8_lossy(&line).into_owned(),
+                _ => break,
+            };
+            let (header, value) = match line.split_once(':') {
+                Some(x) => x,
+                None => continue,
+            };
+            let value_start = line_start + header.len() + 1;
+            if header.trim().eq_ignore_ascii_case("content-disposition") {
+                part.name = disposition_name(value)
+                    .map(|(name, start, end)| (name, value_start + start, value_start + end));
+            } else if header.trim().eq_ignore_ascii_case("content-type") {
+                part.content_type = Some(value.trim().to_string());
+            }
+        }
+
+        part.start = input.total_read();
+        loop {
+            match maybe_read_u8(input).await? {
+                Some(byte) => part.content.push(byte),
+                None => {
+                    self.done = true;
+                    break;
+                }
+            }
+            if part.content.ends_with(&self.delimiter) {
+                part.content
+                    .truncate(part.content.len() - self.delimiter.len());
+                break;
+            }
+        }
+        Ok(Some(part))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{sync::Arc, task::Poll};
+
+    use futures::{pin_mut, task::waker, Future};
+
+    use crate::pipe::{pipe, DummyWaker};
+
+    use super::*;
+
+    #[test]
+    fn test_urlencoded_parse() {
+        let body = "name=J%C3%B6rg+Smith&empty=&flag&email=a%40b.com";
+        let (mut reader, mut writer) = pipe(0);
+        assert!(writer.append(body.as_bytes()));
+        drop(writer);
+        let mut fields = vec![];
+        let waker = waker(Arc::new(DummyWaker));
+        let mut context = std::task::Context::from_waker(&waker);
+
+        {
+            let future = parse_urlencoded::<()>(&mut reader, |field| {
+                fields.push((
+                    field.name,
+                    &body[field.name_start..field.name_end],
+                    field
+                        .value
+                        .map(|(value, start, end)| (value, &body[start..end])),
+                ));
+                None
+            });
+            pin_mut!(future);
+            assert!(matches!(future.poll(&mut context), Poll::Ready(Ok(None))));
+        }
+
+        assert_eq!(
+            fields,
+            vec![
+                (
+                    "name".to_string(),
+                    "name",
+                    Some(("Jörg Smith".to_string(), "J%C3%B6rg+Smith"))
+                ),
+                ("empty".to_string(), "empty", Some((String::new(), ""))),
+                ("flag".to_string(), "flag", None),
+                (
+                    "email".to_string(),
+                    "email",
+                    Some(("a@b.com".to_string(), "a%40b.com"))
+                ),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_multipart_parse() {
+        let body = "preamble\r\n--XyZ\r\nContent-Disposition: form-data; name=\"bio\"\r\n\r\nhello\r\nthere\r\n--XyZ\r\nContent-Disposition: form-data; name=\"file\"; filename=\"a.json\"\r\nContent-Type: application/json\r\n\r\n{\"a\":1}\r\n--XyZ--\r\nepilogue";
+        let (mut reader, mut writer) = pipe(0);
+        assert!(writer.append(body.as_bytes()));
+        drop(writer);
+        let mut parts = vec![];
+        let waker = waker(Arc::new(DummyWaker));
+        let mut context = std::task::Context::from_waker(&waker);
+
+        {
+            let future = async {
+                let mut multipart = MultipartReader::new("XyZ");
+                while let Some(part) = multipart.next_part(&mut reader).await? {
+                    let (name, start, end) = part.name.unwrap();
+                    assert_eq!(&body[start..end], name);
+                    assert_eq!(
+                        &body.as_bytes()[part.start..part.start + part.content.len()],
+                        &part.content[..]
+                    );
+                    parts.push((
+                        name,
+                        part.content_type,
+                        String::from_utf8(part.content).unwrap(),
+                    ));
+                }
+                Result::<()>::Ok(())
+            };
+            pin_mut!(future);
+            assert!(matches!(future.poll(&mut context), Poll::Ready(Ok(()))));
+        }
+
+        assert_eq!(
+            parts,
+            vec![
+                ("bio".to_string(), None, "hello\r\nthere".to_string()),
+                (
+                    "file".to_string(),
+                    Some("application/json".to_string()),
+                    "{\"a\":1}".to_string()
+                ),
+            ]
+        );
+    }
+}
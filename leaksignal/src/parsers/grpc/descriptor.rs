@@ -0,0 +1,181 @@
+use std::{borrow::Cow, collections::HashMap};
+
+use log::warn;
+use prost::Message;
+use prost_types::{DescriptorProto, FileDescriptorProto, FileDescriptorSet};
+
+use crate::{
+    decode::decode_base64_bytes,
+    policy::{DescriptorSet, Direction, Policy},
+};
+
+/// replaces `DescriptorSet::Reference`s of `policy` with their resolved sets from `descriptor_sets`,
+/// so the policy can be sent to worker threads on its own
+pub fn resolve_references(policy: &mut Policy, descriptor_sets: &HashMap<String, Vec<u8>>) {
+    for descriptor_set in &mut policy.grpc_descriptor_sets {
+        if let DescriptorSet::Reference(name) = descriptor_set {
+            match descriptor_sets.get(&*name) {
+                Some(data) => *descriptor_set = DescriptorSet::Resolved(data.clone()),
+                None => warn!("policy references missing grpc descriptor set '{name}'"),
+            }
+        }
+    }
+}
+
+/// Messages and methods of the `grpc_descriptor_sets` of a policy
+#[derive(Default)]
+pub struct Descriptors {
+    /// input and output message types of methods, by `/package.Service/Method`
+    methods: HashMap<String, (String, String)>,
+    /// by fully qualified name, i.e. `.package.Message`
+    messages: HashMap<String, DescriptorProto>,
+}
+
+impl Descriptors {
+    pub fn new(policy: &Policy) -> Self {
+        let mut descriptors = Descriptors::default();
+        for descriptor_set in &policy.grpc_descriptor_sets {
+            let data = match descriptor_set {
+                DescriptorSet::Inline(data) => match decode_base64_bytes(data) {
+                    Some(data) => Cow::Owned(data),
+                    None => {
+                        warn!("invalid base64 in inline grpc descriptor set, skipped");
+                        continue;
+                    }
+                },
+                DescriptorSet::Resolved(data) => Cow::Borrowed(data),
+                DescriptorSet::Reference(name) => {
+                    warn!("unresolved grpc descriptor set reference '{name}', skipped");
+                    continue;
+                }
+            };
+            let descriptor_set = match FileDescriptorSet::decode(&data[..]) {
+                Ok(x) => x,
+                Err(_) => {
+                    warn!("invalid grpc descriptor set, skipped");
+                    continue;
+                }
+            };
+            for file in &descriptor_set.file {
+                descriptors.add_file(file);
+            }
+        }
+        descriptors
+    }
+
+    fn add_file(&mut self, file: &FileDescriptorProto) {
+        let prefix = match file.package() {
+            "" => String::new(),
+            package => format!("{package}."),
+        };
+        for service in &file.service {
+            for method in &service.method {
+                self.methods.insert(
+                    format!("/{prefix}{}/{}", service.name(), method.name()),
+                    (
+                        method.input_type().to_string(),
+                        method.output_type().to_string(),
+                    ),
+                );
+            }
+        }
+        for message in &file.message_type {
+            self.add_message(&format!(".{prefix}"), message);
+        }
+    }
+
+    fn add_message(&mut self, prefix: &str, message: &DescriptorProto) {
+        let name = format!("{prefix}{}", message.name());
+        for nested in &message.nested_type {
+            self.add_message(&format!("{name}."), nested);
+        }
+        self.messages.insert(name, message.clone());
+    }
+
+    /// the message type of `method`'s requests or responses
+    pub fn method_message(&self, method: &str, direction: Direction) -> Option<&DescriptorProto> {
+        // the query string of a grpc path is not part of the method
+        let method = method.split('?').next().unwrap_or_default();
+        let (input, output) = self.methods.get(method)?;
+        self.message(match direction {
+            Direction::Request => input,
+            Direction::Response => output,
+        })
+    }
+
+    /// `name` is fully qualified, i.e. `.package.Message`
+    pub fn message(&self, name: &str) -> Option<&DescriptorProto> {
+        self.messages.get(name)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use prost_types::{MethodDescriptorProto, ServiceDescriptorProto};
+
+    use super::*;
+
+    #[test]
+    fn test_resolve_references() {
+        let descriptor_set = FileDescriptorSet {
+            file: vec![FileDescriptorProto {
+                package: Some("shop".to_string()),
+                message_type: vec![DescriptorProto {
+                    name: Some("Order".to_string()),
+                    nested_type: vec![DescriptorProto {
+                        name: Some("Item".to_string()),
+                        ..Default::default()
+                    }],
+                    ..Default::default()
+                }],
+                service: vec![ServiceDescriptorProto {
+                    name: Some("Orders".to_string()),
+                    method: vec![MethodDescriptorProto {
+                        name: Some("Get".to_string()),
+                        input_type: Some(".shop.Order.Item".to_string()),
+                        output_type: Some(".shop.Order".to_string()),
+                        ..Default::default()
+                    }],
+                    ..Default::default()
+                }],
+                ..Default::default()
+            }],
+        };
+        let mut policy: Policy = serde_yaml::from_str(
+            r#"
+categories: {}
+endpoints: []
+grpc_descriptor_sets:
+  - reference: shop
+  - reference: missing
+"#,
+        )
+        .unwrap();
+        resolve_references(
+            &mut policy,
+            &[("shop".to_string(), descriptor_set.encode_to_vec())]
+                .into_iter()
+                .collect(),
+        );
+        assert!(matches!(
+            &policy.grpc_descriptor_sets[..],
+            [DescriptorSet::Resolved(_), DescriptorSet::Reference(_)]
+        ));
+
+        let descriptors = Descriptors::new(&policy);
+        let message = |method, direction| {
+            descriptors
+                .method_message(method, direction)
+                .map(|x| x.name())
+        };
+        assert_eq!(
+            message("/shop.Orders/Get", Direction::Response),
+            Some("Order")
+        );
+        assert_eq!(
+            message("/shop.Orders/Get?x=1", Direction::Request),
+            Some("Item")
+        );
+        assert_eq!(message("/shop.Orders/List", Direction::Response), None);
+    }
+}
@@ -0,0 +1,800 @@
+use anyhow::Result;
+use log::{error, warn};
+use prost_types::{field_descriptor_proto::Type, DescriptorProto, FieldDescriptorProto};
+use std::sync::Arc;
+
+use indexmap::IndexMap;
+use leakpolicy::{Direction, MatchContext, PathConfiguration};
+
+pub mod descriptor;
+mod parse;
+
+use crate::{
+    alert::AlertTracker,
+    compression::{self, ContentEncoding, Decompressor},
+    evaluator::{self, MatcherMetadata, MatcherState},
+    pipe::PipeReader,
+    policy::{Category, ContentType, Policy},
+    proto::{r#match::Location, Match},
+};
+
+use self::{
+    descriptor::Descriptors,
+    parse::{encode_varint, parse_fields, read_varint, WireField, WireValue},
+};
+
+use super::{replace_matches, ParseResponse, Replacement};
+
+/// nested messages deeper than this are not scanned
+const MAX_MESSAGE_DEPTH: usize = 64;
+
+/// compressed messages that decompress to more than this are not scanned, the grpc default maximum message size
+const MAX_DECOMPRESSED_MESSAGE_SIZE: usize = 4 * 1024 * 1024;
+
+/// Matcher states of protobuf field names (`keys`) and string and number fields (`values`)
+#[derive(Default)]
+pub struct GrpcMatchers {
+    pub keys: MatcherState,
+    pub values: MatcherState,
+    pub descriptors: Arc<Descriptors>,
+    /// selects the input or output message type of a method
+    pub direction: Option<Direction>,
+}
+
+pub fn prepare_match_state(
+    policy: &Policy,
+    descriptors: &Arc<Descriptors>,
+    configuration: &IndexMap<Arc<String>, PathConfiguration>,
+    direction: Direction,
+) -> GrpcMatchers {
+    let mut matchers = GrpcMatchers {
+        descriptors: descriptors.clone(),
+        direction: Some(direction),
+        ..Default::default()
+    };
+
+    for (category_name, action) in configuration {
+        if !action.scans_content(direction, &ContentType::Grpc) {
+            continue;
+        }
+
+        // Keyed categories only match json values and csv cells, see `Policy::validate`
+        if let Some(Category::Keyed { .. }) = policy.categories.get(&**category_name) {
+            continue;
+        }
+
+        let metadata = MatcherMetadata::new(category_name, action);
+
+        if action.matches_context(MatchContext::Keys, &MatchContext::STRUCTURED) {
+            evaluator::prepare_matches(
+                policy,
+                category_name,
+                &mut matchers.keys,
+                &metadata,
+                &action.category_config.ignore,
+            );
+        }
+        if action.matches_context(MatchContext::Values, &MatchContext::STRUCTURED) {
+            evaluator::prepare_matches(
+                policy,
+                category_name,
+                &mut matchers.values,
+                &metadata,
+                &action.category_config.ignore,
+            );
+        }
+    }
+    matchers.keys.compile();
+    matchers.values.compile();
+
+    matchers
+}
+
+/// returns the text of a scalar field, `None` for fields that aren't numbers
+fn number_text(field_type: Type, value: &WireValue) -> Option<String> {
+    Some(match (field_type, value) {
+        (Type::Int64, WireValue::Varint(x)) => (*x as i64).to_string(),
+        (Type::Int32, WireValue::Varint(x)) => (*x as i64 as i32).to_string(),
+        (Type::Uint64, WireValue::Varint(x)) => x.to_string(),
+        (Type::Uint32, WireValue::Varint(x)) => (*x as u32).to_string(),
+        (Type::Sint64, WireValue::Varint(x)) => ((*x >> 1) as i64 ^ -((*x & 1) as i64)).to_string(),
+        (Type::Sint32, WireValue::Varint(x)) => {
+            ((*x as u32 >> 1) as i32 ^ -((*x & 1) as i32)).to_string()
+        }
+        (Type::Fixed64, WireValue::Fixed64(x)) => x.to_string(),
+        (Type::Sfixed64, WireValue::Fixed64(x)) => (*x as i64).to_string(),
+        (Type::Double, WireValue::Fixed64(x)) => f64::from_bits(*x).to_string(),
+        (Type::Fixed32, WireValue::Fixed32(x)) => x.to_string(),
+        (Type::Sfixed32, WireValue::Fixed32(x)) => (*x as i32).to_string(),
+        (Type::Float, WireValue::Fixed32(x)) => f32::from_bits(*x).to_string(),
+        _ => return None,
+    })
+}
+
+/// splits a packed repeated field of `field_type` into its values
+fn unpack(field_type: Type, data: &[u8]) -> Vec<WireValue<'_>> {
+    let mut values = vec![];
+    let mut index = 0usize;
+    while index < data.len() {
+        let value = match field_type {
+            Type::Fixed64 | Type::Sfixed64 | Type::Double => match data.get(index..index + 8) {
+                Some(bytes) => {
+                    index += 8;
+                    WireValue::Fixed64(u64::from_le_bytes(bytes.try_into().unwrap()))
+                }
+                None => break,
+            },
+            Type::Fixed32 | Type::Sfixed32 | Type::Float => match data.get(index..index + 4) {
+                Some(bytes) => {
+                    index += 4;
+                    WireValue::Fixed32(u32::from_le_bytes(bytes.try_into().unwrap()))
+                }
+                None => break,
+            },
+            _ => match read_varint(data, &mut index) {
+                Some(x) => WireValue::Varint(x),
+                None => break,
+            },
+        };
+        values.push(value);
+    }
+    values
+}
+
+/// returns true if undescribed length-delimited `data` reads as text rather than as a nested message
+fn is_text(data: &[u8]) -> bool {
+    match std::str::from_utf8(data) {
+        Ok(text) => !text
+            .chars()
+            .any(|c| c.is_control() && !matches!(c, '\t' | '\r' | '\n')),
+        Err(_) => false,
+    }
+}
+
+fn field_path(parent: &str, field: &str) -> String {
+    if parent.is_empty() {
+        field.to_string()
+    } else {
+        format!("{parent}.{field}")
+    }
+}
+
+fn set_protobuf_field(matches: &mut [Match], path: &str) {
+    for matching in matches {
+        matching.location = Some(Location::ProtobufField(path.to_string()));
+    }
+}
+
+/// State of scanning the messages of one grpc body
+struct MessageScan<'a> {
+    matchers: &'a GrpcMatchers,
+    matches: &'a mut Vec<Match>,
+    masks: &'a mut Vec<Replacement>,
+    alerts: &'a mut AlertTracker,
+    blocked: bool,
+}
+
+impl<'a> MessageScan<'a> {
+    fn respond(&mut self, response: ParseResponse) {
+        if matches!(response, ParseResponse::Block) {
+            self.blocked = true;
+        }
+    }
+
+    /// field names aren't part of the body, so their matches are reported at the field's tag and never masked
+    fn scan_key(&mut self, name: &str, path: &str, tag_start: usize) {
+        let first_match = self.matches.len();
+        let response = self.matchers.keys.do_matching(
+            tag_start,
+            0,
+            name,
+            self.matches,
+            &mut vec![],
+            self.alerts,
+        );
+        set_protobuf_field(&mut self.matches[first_match..], path);
+        self.respond(response);
+    }
+
+    /// numbers are reported, but never masked
+    fn scan_number(&mut self, value: &str, path: &str, start: usize) {
+        let first_match = self.matches.len();
+        let response = self.matchers.values.do_matching(
+            start,
+            0,
+            value,
+            self.matches,
+            &mut vec![],
+            self.alerts,
+        );
+        set_protobuf_field(&mut self.matches[first_match..], path);
+        self.respond(response);
+    }
+
+    /// returns the masked string, if any of it was masked
+    fn scan_string(&mut self, value: &str, path: &str, start: usize) -> Option<Vec<u8>> {
+        let first_match = self.matches.len();
+        let mut local_masks = vec![];
+        let response = self.matchers.values.do_matching(
+            start,
+            0,
+            value,
+            self.matches,
+            &mut local_masks,
+            self.alerts,
+        );
+        set_protobuf_field(&mut self.matches[first_match..], path);
+        self.respond(response);
+        if local_masks.is_empty() {
+            return None;
+        }
+        let mut local_masks = local_masks
+            .into_iter()
+            .map(|mask| Replacement {
+                start: mask.start - start,
+                ..mask
+            })
+            .collect::<Vec<_>>();
+        local_masks.sort_by_key(|x| x.start);
+        Some(replace_matches(value.as_bytes(), &local_masks).into_owned())
+    }
+
+    /// scans the message at body offset `offset`, returning how many bytes masking added to it
+    fn scan_message(
+        &mut self,
+        data: &[u8],
+        offset: usize,
+        message: Option<&DescriptorProto>,
+        path: &str,
+        depth: usize,
+    ) -> isize {
+        if depth > MAX_MESSAGE_DEPTH {
+            return 0;
+        }
+        let fields = match parse_fields(data) {
+            Some(x) => x,
+            None => return 0,
+        };
+        let mut added = 0isize;
+        for field in fields {
+            if self.blocked {
+                break;
+            }
+            let descriptor = message.and_then(|message| {
+                message
+                    .field
+                    .iter()
+                    .find(|x| x.number() as u64 == field.number)
+            });
+            added += match descriptor {
+                Some(descriptor) => self.scan_described(&field, descriptor, offset, path, depth),
+                None => self.scan_raw(&field, offset, path, depth),
+            };
+        }
+        added
+    }
+
+    /// scans the compressed message at body offset `start`, returning it compressed again if any of it was masked.
+    /// matches within the message are reported at the whole compressed message
+    fn scan_compressed(
+        &mut self,
+        data: &[u8],
+        start: usize,
+        encoding: ContentEncoding,
+        message: Option<&DescriptorProto>,
+    ) -> Option<Vec<u8>> {
+        let mut decompressor = Decompressor::new(&[encoding]);
+        let decoded = decompressor
+            .write(data, MAX_DECOMPRESSED_MESSAGE_SIZE)
+            .and_then(|mut decoded| {
+                decoded.extend(
+                    decompressor
+                        .finish(MAX_DECOMPRESSED_MESSAGE_SIZE.saturating_sub(decoded.len()))?,
+                );
+                Ok(decoded)
+            });
+        let decoded = match decoded {
+            Ok(_) if decompressor.is_truncated() => {
+                warn!(
+                    "grpc message @ {start} decompresses to over {MAX_DECOMPRESSED_MESSAGE_SIZE} bytes, not scanned"
+                );
+                return None;
+            }
+            Ok(x) => x,
+            Err(e) => {
+                warn!("failed to decompress grpc message @ {start}: {e:?}");
+                return None;
+            }
+        };
+
+        let first_match = self.matches.len();
+        let body_masks = std::mem::take(self.masks);
+        self.scan_message(&decoded, 0, message, "", 0);
+        let mut local_masks = std::mem::replace(self.masks, body_masks);
+        for matching in &mut self.matches[first_match..] {
+            matching.global_start_position = Some(start as u64);
+            matching.global_length = Some(data.len() as u64);
+        }
+        if local_masks.is_empty() {
+            return None;
+        }
+        local_masks.sort_by_key(|x| x.start);
+        let masked = replace_matches(&decoded, &local_masks).into_owned();
+        match compression::encode(&[encoding], masked) {
+            Ok(x) => Some(x),
+            Err(e) => {
+                error!("failed to compress masked grpc message @ {start}: {e:?}");
+                None
+            }
+        }
+    }
+
+    /// replaces the contents of a length-delimited field with `value`, returning how many bytes were added
+    fn replace_contents(
+        &mut self,
+        length_start: usize,
+        start: usize,
+        length: usize,
+        value: Vec<u8>,
+    ) -> isize {
+        let new_length = value.len();
+        self.masks.push(Replacement {
+            start,
+            length,
+            value,
+        });
+        self.resize(length_start, start, length, new_length)
+    }
+
+    /// rewrites the varint length at `length_start`..`start` of a field resized to `new_length`,
+    /// returning how many bytes were added to the field, including its length
+    fn resize(
+        &mut self,
+        length_start: usize,
+        start: usize,
+        length: usize,
+        new_length: usize,
+    ) -> isize {
+        if new_length == length {
+            return 0;
+        }
+        let encoded = encode_varint(new_length as u64);
+        let added = new_length as isize - length as isize + encoded.len() as isize
+            - (start - length_start) as isize;
+        self.masks.push(Replacement {
+            start: length_start,
+            length: start - length_start,
+            value: encoded,
+        });
+        added
+    }
+
+    fn scan_described(
+        &mut self,
+        field: &WireField,
+        descriptor: &FieldDescriptorProto,
+        offset: usize,
+        parent: &str,
+        depth: usize,
+    ) -> isize {
+        let path = field_path(parent, descriptor.name());
+        self.scan_key(descriptor.name(), &path, offset + field.tag_start);
+        let field_type = descriptor.r#type();
+        let (length_start, start, data) = match &field.value {
+            WireValue::LengthDelimited {
+                length_start,
+                start,
+                data,
+            } => (offset + length_start, offset + start, *data),
+            value => {
+                if let Some(text) = number_text(field_type, value) {
+                    self.scan_number(&text, &path, offset + field.tag_start);
+                }
+                return 0;
+            }
+        };
+        match field_type {
+            Type::String => {
+                let value = match std::str::from_utf8(data) {
+                    Ok(x) => x,
+                    Err(_) => return 0,
+                };
+                match self.scan_string(value, &path, start) {
+                    Some(masked) => self.replace_contents(length_start, start, data.len(), masked),
+                    None => 0,
+                }
+            }
+            Type::Message => {
+                let message = self.matchers.descriptors.message(descriptor.type_name());
+                let added = self.scan_message(data, start, message, &path, depth + 1);
+                self.resize(
+                    length_start,
+                    start,
+                    data.len(),
+                    (data.len() as isize + added) as usize,
+                )
+            }
+            Type::Bytes | Type::Group | Type::Bool | Type::Enum => 0,
+            // packed repeated numbers
+            field_type => {
+                for value in unpack(field_type, data) {
+                    if let Some(text) = number_text(field_type, &value) {
+                        self.scan_number(&text, &path, start);
+                    }
+                }
+                0
+            }
+        }
+    }
+
+    /// undescribed fields are named by number, and only their strings are scanned
+    fn scan_raw(&mut self, field: &WireField, offset: usize, parent: &str, depth: usize) -> isize {
+        let (length_start, start, data) = match &field.value {
+            WireValue::LengthDelimited {
+                length_start,
+                start,
+                data,
+            } => (offset + length_start, offset + start, *data),
+            _ => return 0,
+        };
+        let path = field_path(parent, &field.number.to_string());
+        if is_text(data) {
+            let value = std::str::from_utf8(data).unwrap();
+            return match self.scan_string(value, &path, start) {
+                Some(masked) => self.replace_contents(length_start, start, data.len(), masked),
+                None => 0,
+            };
+        }
+        let added = self.scan_message(data, start, None, &path, depth + 1);
+        self.resize(
+            length_start,
+            start,
+            data.len(),
+            (data.len() as isize + added) as usize,
+        )
+    }
+}
+
+/// scans the length-prefixed messages of a grpc body. messages are decoded by the message type of `method`
+/// if a descriptor set describes it, otherwise their string fields are scanned without field names.
+/// compressed messages are decompressed with the stream's `encoding`.
+pub async fn parse_grpc(
+    matchers: &GrpcMatchers,
+    method: Option<&str>,
+    encoding: Option<ContentEncoding>,
+    body: &mut PipeReader,
+    matches: &mut Vec<Match>,
+    masks: &mut Vec<Replacement>,
+    alerts: &mut AlertTracker,
+) -> Result<ParseResponse> {
+    let message = method
+        .zip(matchers.direction)
+        .and_then(|(method, direction)| matchers.descriptors.method_message(method, direction));
+    let mut scan = MessageScan {
+        matchers,
+        matches,
+        masks,
+        alerts,
+        blocked: false,
+    };
+    let mut warned = false;
+
+    while let Some(frame) = parse::read_frame(body).await? {
+        if !frame.compressed {
+            let added = scan.scan_message(&frame.message, frame.start, message, "", 0);
+            if added != 0 {
+                let length = (frame.message.len() as isize + added) as u32;
+                scan.masks.push(Replacement {
+                    start: frame.length_start,
+                    length: 4,
+                    value: length.to_be_bytes().to_vec(),
+                });
+            }
+        } else if let Some(encoding) = encoding {
+            if let Some(masked) =
+                scan.scan_compressed(&frame.message, frame.start, encoding, message)
+            {
+                scan.masks.push(Replacement {
+                    start: frame.length_start,
+                    length: 4,
+                    value: (masked.len() as u32).to_be_bytes().to_vec(),
+                });
+                scan.masks.push(Replacement {
+                    start: frame.start,
+                    length: frame.message.len(),
+                    value: masked,
+                });
+            }
+        } else if !warned {
+            warn!("compressed grpc messages without a grpc-encoding are not scanned");
+            warned = true;
+        }
+        if scan.blocked {
+            return Ok(ParseResponse::Block);
+        }
+    }
+
+    masks.sort_by_key(|x| x.start);
+
+    Ok(ParseResponse::Continue)
+}
+
+#[cfg(test)]
+mod tests {
+    use futures::{pin_mut, task::waker, Future};
+    use prost::Message;
+    use prost_types::{
+        field_descriptor_proto::Label, FileDescriptorProto, FileDescriptorSet,
+        MethodDescriptorProto, ServiceDescriptorProto,
+    };
+    use std::task::Poll;
+
+    use crate::pipe::{pipe, DummyWaker};
+
+    use super::*;
+
+    fn field(
+        name: &str,
+        number: i32,
+        field_type: Type,
+        type_name: Option<&str>,
+    ) -> FieldDescriptorProto {
+        FieldDescriptorProto {
+            name: Some(name.to_string()),
+            number: Some(number),
+            label: Some(Label::Optional as i32),
+            r#type: Some(field_type as i32),
+            type_name: type_name.map(str::to_string),
+            ..Default::default()
+        }
+    }
+
+    fn descriptor_set() -> Vec<u8> {
+        FileDescriptorSet {
+            file: vec![FileDescriptorProto {
+                package: Some("crm".to_string()),
+                message_type: vec![
+                    DescriptorProto {
+                        name: Some("Customer".to_string()),
+                        field: vec![
+                            field("name", 1, Type::String, None),
+                            field("email", 2, Type::String, None),
+                            field("ssn", 3, Type::Int64, None),
+                            field("address", 4, Type::Message, Some(".crm.Address")),
+                        ],
+                        ..Default::default()
+                    },
+                    DescriptorProto {
+                        name: Some("Address".to_string()),
+                        field: vec![field("contact", 1, Type::String, None)],
+                        ..Default::default()
+                    },
+                ],
+                service: vec![ServiceDescriptorProto {
+                    name: Some("Crm".to_string()),
+                    method: vec![MethodDescriptorProto {
+                        name: Some("Get".to_string()),
+                        input_type: Some(".crm.Address".to_string()),
+                        output_type: Some(".crm.Customer".to_string()),
+                        ..Default::default()
+                    }],
+                    ..Default::default()
+                }],
+                ..Default::default()
+            }],
+        }
+        .encode_to_vec()
+    }
+
+    fn length_delimited(number: u64, data: &[u8]) -> Vec<u8> {
+        let mut out = encode_varint(number << 3 | 2);
+        out.extend(encode_varint(data.len() as u64));
+        out.extend_from_slice(data);
+        out
+    }
+
+    fn customer(email: &str, contact: &str) -> Vec<u8> {
+        let mut out = length_delimited(1, b"Alice");
+        out.extend(length_delimited(2, email.as_bytes()));
+        out.extend(encode_varint(3 << 3));
+        out.extend(encode_varint(123456789));
+        out.extend(length_delimited(
+            4,
+            &length_delimited(1, contact.as_bytes()),
+        ));
+        out
+    }
+
+    fn frame(message: &[u8]) -> Vec<u8> {
+        let mut out = vec![0u8];
+        out.extend((message.len() as u32).to_be_bytes());
+        out.extend_from_slice(message);
+        out
+    }
+
+    fn compressed_frame(message: &[u8]) -> Vec<u8> {
+        let mut out =
+            frame(&compression::encode(&[ContentEncoding::Gzip], message.to_vec()).unwrap());
+        out[0] = 1;
+        out
+    }
+
+    fn run_grpc(
+        method: Option<&str>,
+        encoding: Option<ContentEncoding>,
+        body: &[u8],
+    ) -> (Vec<Match>, Vec<u8>) {
+        let mut policy: Policy = serde_yaml::from_str(
+            r#"
+categories:
+  email:
+    Matchers:
+      regexes:
+        - "[a-z]+@[a-z]+\\.com"
+  ssn_number:
+    Matchers:
+      regexes:
+        - "^\\d{9}$"
+  ssn_field:
+    Matchers:
+      raw:
+        - "ssn"
+endpoints:
+  - matches: "**"
+    config:
+      email:
+        action: mask
+        mask_replacement: "<redacted email>"
+      ssn_number: {}
+      ssn_field:
+        contexts: keys
+grpc_descriptor_sets:
+  - reference: crm
+"#,
+        )
+        .unwrap();
+        descriptor::resolve_references(
+            &mut policy,
+            &[("crm".to_string(), descriptor_set())]
+                .into_iter()
+                .collect(),
+        );
+        let descriptors = Arc::new(Descriptors::new(&policy));
+        let path_policy = policy.get_path_config("example.com/");
+        let matchers = prepare_match_state(
+            &policy,
+            &descriptors,
+            &path_policy.configuration,
+            Direction::Response,
+        );
+
+        let (mut reader, mut writer) = pipe(0);
+        assert!(writer.append(body));
+        drop(writer);
+        let mut matches = vec![];
+        let mut masks = vec![];
+        let mut alerts = AlertTracker::default();
+        let waker = waker(Arc::new(DummyWaker));
+        let mut context = std::task::Context::from_waker(&waker);
+        {
+            let future = parse_grpc(
+                &matchers,
+                method,
+                encoding,
+                &mut reader,
+                &mut matches,
+                &mut masks,
+                &mut alerts,
+            );
+            pin_mut!(future);
+            assert!(matches!(
+                future.poll(&mut context),
+                Poll::Ready(Ok(ParseResponse::Continue))
+            ));
+        }
+        let masked = replace_matches(body, &masks).into_owned();
+        (matches, masked)
+    }
+
+    fn summary(matches: &[Match]) -> Vec<(&str, Option<&str>, Option<&str>)> {
+        matches
+            .iter()
+            .map(|x| {
+                (
+                    &*x.category_name,
+                    x.matched_value.as_deref(),
+                    x.location.as_ref().map(Location::value),
+                )
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_described_messages() {
+        let mut body = frame(&customer("a@b.com", "call x@y.com"));
+        body.extend(frame(&customer("none", "none")));
+        let (matches, masked) = run_grpc(Some("/crm.Crm/Get"), None, &body);
+        assert_eq!(
+            summary(&matches),
+            vec![
+                ("email", Some("a@b.com"), Some("email")),
+                ("ssn_field", Some("ssn"), Some("ssn")),
+                ("ssn_number", Some("123456789"), Some("ssn")),
+                ("email", Some("x@y.com"), Some("address.contact")),
+                ("ssn_field", Some("ssn"), Some("ssn")),
+                ("ssn_number", Some("123456789"), Some("ssn")),
+            ]
+        );
+        let mut expected = frame(&customer("<redacted email>", "call <redacted email>"));
+        expected.extend(frame(&customer("none", "none")));
+        assert_eq!(masked, expected);
+    }
+
+    #[test]
+    fn test_undescribed_messages() {
+        let body = frame(&customer("a@b.com", "call x@y.com"));
+        let (matches, masked) = run_grpc(Some("/crm.Crm/Unknown"), None, &body);
+        assert_eq!(
+            summary(&matches),
+            vec![
+                ("email", Some("a@b.com"), Some("2")),
+                ("email", Some("x@y.com"), Some("4.1")),
+            ]
+        );
+        assert_eq!(
+            masked,
+            frame(&customer("<redacted email>", "call <redacted email>"))
+        );
+    }
+
+    #[test]
+    fn test_compressed_messages() {
+        let mut body = frame(&customer("a@b.com", "none"));
+        let compressed_start = body.len() + 5;
+        body.extend(compressed_frame(&customer("c@d.com", "call x@y.com")));
+        let compressed_length = body.len() - compressed_start;
+        let (matches, masked) = run_grpc(Some("/crm.Crm/Get"), Some(ContentEncoding::Gzip), &body);
+        assert_eq!(
+            summary(&matches[3..]),
+            vec![
+                ("email", Some("c@d.com"), Some("email")),
+                ("ssn_field", Some("ssn"), Some("ssn")),
+                ("ssn_number", Some("123456789"), Some("ssn")),
+                ("email", Some("x@y.com"), Some("address.contact")),
+            ]
+        );
+        assert!(matches[3..].iter().all(|x| {
+            x.global_start_position == Some(compressed_start as u64)
+                && x.global_length == Some(compressed_length as u64)
+        }));
+
+        let mut reader = &masked[..];
+        let mut messages = vec![];
+        while !reader.is_empty() {
+            let length = u32::from_be_bytes(reader[1..5].try_into().unwrap()) as usize;
+            let message = reader[5..5 + length].to_vec();
+            messages.push(if reader[0] == 1 {
+                let mut decompressor = Decompressor::new(&[ContentEncoding::Gzip]);
+                decompressor.write(&message, usize::MAX).unwrap()
+            } else {
+                message
+            });
+            reader = &reader[5 + length..];
+        }
+        assert_eq!(
+            messages,
+            vec![
+                customer("<redacted email>", "none"),
+                customer("<redacted email>", "call <redacted email>"),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_compressed_messages_without_encoding() {
+        let body = compressed_frame(&customer("a@b.com", "call x@y.com"));
+        let (matches, masked) = run_grpc(Some("/crm.Crm/Get"), None, &body);
+        assert!(matches.is_empty());
+        assert_eq!(masked, body);
+    }
+}
@@ -0,0 +1,224 @@
+use futures::AsyncReadExt;
+
+use crate::pipe::PipeReader;
+use anyhow::Result;
+use std::io::ErrorKind;
+
+/// A length-prefixed message of a grpc body
+pub struct Frame {
+    /// set if the message is compressed with the stream's `grpc-encoding`
+    pub compressed: bool,
+    /// body offset of the 4 byte message length
+    pub length_start: usize,
+    /// body offset of `message`
+    pub start: usize,
+    pub message: Vec<u8>,
+}
+
+/// reads the next frame of a grpc body, `None` at the end of the body.
+/// a body truncated within a frame returns the frame as read so far.
+pub async fn read_frame(input: &mut PipeReader) -> Result<Option<Frame>> {
+    let mut header = [0u8; 5];
+    let header_start = input.total_read();
+    match input.read_exact(&mut header[..]).await {
+        Ok(()) => (),
+        Err(e) if e.kind() == ErrorKind::UnexpectedEof => return Ok(None),
+        Err(e) => return Err(e.into()),
+    }
+    let length = u32::from_be_bytes(header[1..].try_into().unwrap()) as usize;
+    let start = input.total_read();
+    let mut message = vec![];
+    (&mut *input)
+        .take(length as u64)
+        .read_to_end(&mut message)
+        .await?;
+    Ok(Some(Frame {
+        compressed: header[0] & 1 != 0,
+        length_start: header_start + 1,
+        start,
+        message,
+    }))
+}
+
+/// returns the varint at `*index` of `data`, advancing `index` past it
+pub fn read_varint(data: &[u8], index: &mut usize) -> Option<u64> {
+    let mut value = 0u64;
+    for shift in (0..64).step_by(7) {
+        let byte = *data.get(*index)?;
+        *index += 1;
+        value |= ((byte & 0x7f) as u64) << shift;
+        if byte & 0x80 == 0 {
+            return Some(value);
+        }
+    }
+    None
+}
+
+pub fn encode_varint(mut value: u64) -> Vec<u8> {
+    let mut out = vec![];
+    loop {
+        if value < 0x80 {
+            out.push(value as u8);
+            return out;
+        }
+        out.push(value as u8 | 0x80);
+        value >>= 7;
+    }
+}
+
+/// Wire encoded value of a protobuf field
+pub enum WireValue<'a> {
+    Varint(u64),
+    Fixed64(u64),
+    Fixed32(u32),
+    LengthDelimited {
+        /// offset of the varint length, within the message
+        length_start: usize,
+        /// offset of `data`, within the message
+        start: usize,
+        data: &'a [u8],
+    },
+}
+
+/// A field of a protobuf message, as read without a descriptor
+pub struct WireField<'a> {
+    pub number: u64,
+    /// offset of the field's tag, within the message
+    pub tag_start: usize,
+    pub value: WireValue<'a>,
+}
+
+/// splits an encoded protobuf message into its fields, `None` if it isn't a valid message.
+/// groups are not supported.
+pub fn parse_fields(data: &[u8]) -> Option<Vec<WireField<'_>>> {
+    let mut fields = vec![];
+    let mut index = 0usize;
+    while index < data.len() {
+        let tag_start = index;
+        let tag = read_varint(data, &mut index)?;
+        let number = tag >> 3;
+        if number == 0 {
+            return None;
+        }
+        let value = match tag & 7 {
+            0 => WireValue::Varint(read_varint(data, &mut index)?),
+            1 => {
+                let bytes = data.get(index..index + 8)?;
+                index += 8;
+                WireValue::Fixed64(u64::from_le_bytes(bytes.try_into().unwrap()))
+            }
+            2 => {
+                let length_start = index;
+                let length = read_varint(data, &mut index)? as usize;
+                let start = index;
+                let data = data.get(start..start.checked_add(length)?)?;
+                index += length;
+                WireValue::LengthDelimited {
+                    length_start,
+                    start,
+                    data,
+                }
+            }
+            5 => {
+                let bytes = data.get(index..index + 4)?;
+                index += 4;
+                WireValue::Fixed32(u32::from_le_bytes(bytes.try_into().unwrap()))
+            }
+            _ => return None,
+        };
+        fields.push(WireField {
+            number,
+            tag_start,
+            value,
+        });
+    }
+    Some(fields)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{sync::Arc, task::Poll};
+
+    use futures::{pin_mut, task::waker, Future};
+
+    use crate::pipe::{pipe, DummyWaker};
+
+    use super::*;
+
+    #[test]
+    fn test_varint() {
+        for value in [0, 1, 127, 128, 300, u32::MAX as u64, u64::MAX] {
+            let encoded = encode_varint(value);
+            let mut index = 0;
+            assert_eq!(read_varint(&encoded, &mut index), Some(value));
+            assert_eq!(index, encoded.len());
+        }
+        assert_eq!(encode_varint(300), vec![0xac, 0x02]);
+        assert_eq!(read_varint(&[0x80], &mut 0), None);
+    }
+
+    #[test]
+    fn test_parse_fields() {
+        // 1: 150, 2: "hi", 3: fixed32 1, 4: fixed64 2
+        let data =
+            b"\x08\x96\x01\x12\x02hi\x1d\x01\x00\x00\x00\x21\x02\x00\x00\x00\x00\x00\x00\x00";
+        let fields = parse_fields(data).unwrap();
+        assert_eq!(
+            fields
+                .iter()
+                .map(|x| (x.number, x.tag_start))
+                .collect::<Vec<_>>(),
+            vec![(1, 0), (2, 3), (3, 7), (4, 12)]
+        );
+        assert!(matches!(fields[0].value, WireValue::Varint(150)));
+        assert!(matches!(
+            fields[1].value,
+            WireValue::LengthDelimited {
+                length_start: 4,
+                start: 5,
+                data: b"hi"
+            }
+        ));
+        assert!(matches!(fields[2].value, WireValue::Fixed32(1)));
+        assert!(matches!(fields[3].value, WireValue::Fixed64(2)));
+
+        // truncated length-delimited field, and a group
+        assert!(parse_fields(b"\x12\x05hi").is_none());
+        assert!(parse_fields(b"\x0b").is_none());
+    }
+
+    #[test]
+    fn test_read_frame() {
+        let body = b"\x00\x00\x00\x00\x02\x08\x01\x01\x00\x00\x00\x05abc";
+        let (mut reader, mut writer) = pipe(0);
+        assert!(writer.append(&body[..]));
+        drop(writer);
+        let mut frames = vec![];
+        let waker = waker(Arc::new(DummyWaker));
+        let mut context = std::task::Context::from_waker(&waker);
+
+        {
+            let future = async {
+                while let Some(frame) = read_frame(&mut reader).await? {
+                    frames.push((
+                        frame.compressed,
+                        frame.length_start,
+                        frame.start,
+                        frame.message,
+                    ));
+                }
+                Result::<()>::Ok(())
+            };
+            pin_mut!(future);
+            assert!(matches!(future.poll(&mut context), Poll::Ready(Ok(()))));
+        }
+
+        assert_eq!(
+            frames,
+            vec![
+                (false, 1, 5, b"\x08\x01".to_vec()),
+                (true, 8, 12, b"abc".to_vec()),
+            ]
+        );
+    }
+}
@@ -0,0 +1,362 @@
+use std::{io, sync::Arc};
+
+use futures::AsyncReadExt;
+use indexmap::IndexMap;
+use leakpolicy::{Direction, MatchContext, PathConfiguration};
+use log::debug;
+
+use crate::{
+    alert::AlertTracker,
+    evaluator::{self, MatcherMetadata, MatcherState},
+    pipe::PipeReader,
+    policy::{ContentType, Policy},
+    proto::{r#match::Location, Match},
+};
+
+mod tokenize;
+
+use tokenize::{Segment, Token, Tokenizer};
+
+use super::{
+    json::{self, parse_json_script, JsonMatchers},
+    ParseResponse, Replacement,
+};
+
+/// Matcher states of html text by its `MatchContext`
+#[derive(Default)]
+pub struct HtmlMatchers {
+    text: MatcherState,
+    attributes: MatcherState,
+    comments: MatcherState,
+    scripts: MatcherState,
+    /// matchers of json scripts, filtered on the `Html` content type
+    json: JsonMatchers,
+}
+
+impl HtmlMatchers {
+    fn get(&self, context: MatchContext) -> Option<&MatcherState> {
+        match context {
+            MatchContext::Text => Some(&self.text),
+            MatchContext::Attributes => Some(&self.attributes),
+            MatchContext::Comments => Some(&self.comments),
+            MatchContext::Scripts => Some(&self.scripts),
+            MatchContext::Keys | MatchContext::Values => None,
+        }
+    }
+
+    fn get_mut(&mut self, context: MatchContext) -> Option<&mut MatcherState> {
+        match context {
+            MatchContext::Text => Some(&mut self.text),
+            MatchContext::Attributes => Some(&mut self.attributes),
+            MatchContext::Comments => Some(&mut self.comments),
+            MatchContext::Scripts => Some(&mut self.scripts),
+            MatchContext::Keys | MatchContext::Values => None,
+        }
+    }
+}
+
+pub fn prepare_match_state(
+    policy: &Policy,
+    configuration: &IndexMap<Arc<String>, PathConfiguration>,
+    direction: Direction,
+) -> HtmlMatchers {
+    let mut matchers = HtmlMatchers {
+        json: json::prepare_match_state(policy, configuration, direction, &ContentType::Html),
+        ..Default::default()
+    };
+
+    for (category_name, action) in configuration {
+        if !action.scans_content(direction, &ContentType::Html) {
+            continue;
+        }
+
+        let metadata = MatcherMetadata::new(category_name, action);
+
+        for context in MatchContext::HTML {
+            if !action.matches_context(context, &MatchContext::HTML) {
+                continue;
+            }
+            if let Some(match_state) = matchers.get_mut(context) {
+                evaluator::prepare_matches(
+                    policy,
+                    &**category_name,
+                    match_state,
+                    &metadata,
+                    &action.category_config.ignore,
+                );
+            }
+        }
+    }
+    matchers.text.compile();
+    matchers.attributes.compile();
+    matchers.comments.compile();
+    matchers.scripts.compile();
+
+    matchers
+}
+
+const CHUNK_SIZE: usize = 1024 * 64;
+
+fn match_segment(
+    matchers: &HtmlMatchers,
+    segment: Segment,
+    matches: &mut Vec<Match>,
+    masks: &mut Vec<Replacement>,
+    alerts: &mut AlertTracker,
+) -> ParseResponse {
+    let match_state = match matchers.get(segment.context) {
+        Some(x) => x,
+        None => return ParseResponse::Continue,
+    };
+    let first_match = matches.len();
+    //TODO: make a better way to trim truncated utf8 characters to avoid allocation
+    let response = match_state.do_matching(
+        segment.start,
+        segment.minimum_end,
+        &String::from_utf8_lossy(&segment.text),
+        matches,
+        masks,
+        alerts,
+    );
+    if let Some(attribute) = &segment.attribute {
+        for matching in &mut matches[first_match..] {
+            matching.location = Some(Location::HtmlAttribute(attribute.clone()));
+        }
+    }
+    response
+}
+
+//todo: remove unwraps for regex failure
+/// text nodes, attribute values, comments and scripts are matched by the categories of their `MatchContext`
+/// json `<script>` blocks are also scanned by `json`, if `Policy::embedded_json_depth` allows
+pub async fn parse_html(
+    matchers: &HtmlMatchers,
+    body: &mut PipeReader,
+    matches: &mut Vec<Match>,
+    masks: &mut Vec<Replacement>,
+    alerts: &mut AlertTracker,
+) -> io::Result<ParseResponse> {
+    let mut tokenizer = Tokenizer::default();
+    let mut tokens = vec![];
+    // body spans of scanned json scripts
+    let mut script_spans = vec![];
+    let mut json_matches = vec![];
+    let mut json_masks = vec![];
+
+    let mut chunk = vec![0u8; CHUNK_SIZE];
+    loop {
+        let offset = body.total_read();
+        let chunk_len = body.read(&mut chunk[..]).await?;
+        if chunk_len == 0 {
+            tokenizer.finish(&mut tokens);
+        } else {
+            tokenizer.read(offset, &chunk[..chunk_len], &mut tokens);
+        }
+
+        for token in tokens.drain(..) {
+            let response = match token {
+                Token::Segment(segment) => match_segment(matchers, segment, matches, masks, alerts),
+                Token::JsonScript(script) if matchers.json.embedded_depth > 0 => {
+                    match parse_json_script(
+                        &matchers.json,
+                        script.start,
+                        &script.text,
+                        &mut json_matches,
+                        &mut json_masks,
+                        alerts,
+                    )
+                    .await
+                    {
+                        Ok(response) => {
+                            script_spans.push((script.start, script.start + script.text.len()));
+                            response
+                        }
+                        Err(e) => {
+                            debug!("skipping malformed json script: {e:?}");
+                            ParseResponse::Continue
+                        }
+                    }
+                }
+                Token::JsonScript(_) => ParseResponse::Continue,
+            };
+            if let ParseResponse::Block = response {
+                return Ok(ParseResponse::Block);
+            }
+        }
+        if chunk_len == 0 {
+            break;
+        }
+    }
+
+    // json scripts were also matched in the `scripts` context, keep their json matches over those
+    let in_script = |start: Option<u64>| {
+        start
+            .map(|start| {
+                script_spans.iter().any(|(script_start, script_end)| {
+                    (*script_start..*script_end).contains(&(start as usize))
+                })
+            })
+            .unwrap_or_default()
+    };
+    matches.retain(|matching| {
+        !in_script(matching.global_start_position)
+            || !json_matches.iter().any(|x| {
+                x.category_name == matching.category_name && in_script(x.global_start_position)
+            })
+    });
+    masks.retain(|mask| {
+        !json_masks
+            .iter()
+            .any(|x| mask.start < x.start + x.length && x.start < mask.start + mask.length)
+    });
+    matches.extend(json_matches);
+    masks.extend(json_masks);
+    Ok(ParseResponse::Continue)
+}
+
+#[cfg(test)]
+mod tests {
+    use futures::{pin_mut, task::waker, Future};
+    use std::task::Poll;
+
+    use crate::{
+        parsers::replace_matches,
+        pipe::{pipe, DummyWaker},
+    };
+
+    use super::*;
+
+    /// returns the matches and masked body
+    fn run_html(policy: &str, body: &str) -> (Vec<Match>, String) {
+        let policy: Policy = serde_yaml::from_str(policy).unwrap();
+        let path_policy = policy.get_path_config("example.com/");
+        let html = prepare_match_state(&policy, &path_policy.configuration, Direction::Response);
+
+        let (mut reader, mut writer) = pipe(0);
+        assert!(writer.append(body.as_bytes()));
+        drop(writer);
+        let mut matches = vec![];
+        let mut masks = vec![];
+        let mut alerts = AlertTracker::default();
+        let waker = waker(Arc::new(DummyWaker));
+        let mut context = std::task::Context::from_waker(&waker);
+        {
+            let future = parse_html(&html, &mut reader, &mut matches, &mut masks, &mut alerts);
+            pin_mut!(future);
+            assert!(matches!(
+                future.poll(&mut context),
+                Poll::Ready(Ok(ParseResponse::Continue))
+            ));
+        }
+        masks.sort_by_key(|x| x.start);
+        let masked = replace_matches(body.as_bytes(), &masks);
+        (matches, String::from_utf8(masked.into_owned()).unwrap())
+    }
+
+    #[test]
+    fn test_contexts() {
+        let (matches, _) = run_html(
+            r#"
+categories:
+  email:
+    Matchers:
+      regexes:
+        - "[a-z]+@[a-z]+\\.com"
+  digits:
+    Matchers:
+      regexes:
+        - "\\d{4}"
+endpoints:
+  - matches: "**"
+    config:
+      email:
+        contexts: [text, attributes]
+      digits:
+        contexts: comments
+"#,
+            r#"<style>.a@b.com { width: 1234px }</style><a href="mailto:a@b.com">c@d.com</a>
+<!-- id 5678 --><p>e@f.com 9012</p><script>var x = "g@h.com";</script>"#,
+        );
+
+        assert_eq!(
+            matches
+                .iter()
+                .map(|x| (
+                    &*x.category_name,
+                    x.matched_value.as_deref(),
+                    x.location.as_ref().map(Location::value)
+                ))
+                .collect::<Vec<_>>(),
+            vec![
+                ("email", Some("a@b.com"), Some("href")),
+                ("email", Some("c@d.com"), None),
+                ("digits", Some("5678"), None),
+                ("email", Some("e@f.com"), None),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_parse_json_script() {
+        let (matches, masked) = run_html(
+            r#"
+categories:
+  ssn:
+    Matchers:
+      regexes:
+        - "\\d{3}-\\d{2}-\\d{4}"
+endpoints:
+  - matches: "**"
+    config:
+      ssn:
+        action: mask
+"#,
+            r#"<p>123-45-6789</p><script type="application/json">{"user": {"ssn": "123-45-6789"}}</script>"#,
+        );
+
+        assert_eq!(
+            matches
+                .iter()
+                .map(|x| x.location.as_ref().map(Location::value))
+                .collect::<Vec<_>>(),
+            vec![None, Some("/user/ssn")]
+        );
+        assert_eq!(
+            masked,
+            r#"<p>***********</p><script type="application/json">{"user": {"ssn": "***********"}}</script>"#
+        );
+    }
+
+    #[test]
+    fn test_json_script_content_types() {
+        let (matches, _) = run_html(
+            r#"
+categories:
+  email:
+    Matchers:
+      regexes:
+        - "[a-z]+@[a-z]+\\.com"
+  ssn:
+    Matchers:
+      regexes:
+        - "\\d{3}-\\d{2}-\\d{4}"
+endpoints:
+  - matches: "**"
+    config:
+      email:
+        content_types: json
+      ssn:
+        content_types: html
+"#,
+            r#"<script type="application/json">{"email": "a@b.com", "ssn": "123-45-6789"}</script>"#,
+        );
+
+        assert_eq!(
+            matches
+                .iter()
+                .map(|x| (&*x.category_name, x.location.as_ref().map(Location::value)))
+                .collect::<Vec<_>>(),
+            vec![("ssn", Some("/ssn"))]
+        );
+    }
+}
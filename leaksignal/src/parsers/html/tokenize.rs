@@ -0,0 +1,453 @@
+use crate::policy::MatchContext;
+
+/// longest span of a node matched at once, longer nodes are matched in overlapping segments
+const SEGMENT_SIZE: usize = 1024 * 64;
+const SEGMENT_OVERLAP: usize = 512;
+/// longer tag and attribute names are truncated
+const MAX_NAME_SIZE: usize = 256;
+/// longer json scripts are not scanned as json
+const MAX_SCRIPT_SIZE: usize = 1024 * 1024 * 4;
+
+/// A span of the body holding html text of a single context
+#[derive(Debug, PartialEq, Eq)]
+pub struct Segment {
+    pub context: MatchContext,
+    /// name of the attribute, for attribute values
+    pub attribute: Option<String>,
+    /// body offset of `text`
+    pub start: usize,
+    /// matches ending before this body offset were found in the previous segment of the node
+    pub minimum_end: usize,
+    pub text: Vec<u8>,
+}
+
+/// The contents of a `<script>` block with a json `type`, such as `application/json` or `application/ld+json`
+#[derive(Debug, PartialEq, Eq)]
+pub struct JsonScript {
+    /// body offset of `text`
+    pub start: usize,
+    pub text: Vec<u8>,
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum Token {
+    Segment(Segment),
+    JsonScript(JsonScript),
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Default)]
+enum State {
+    #[default]
+    Text,
+    /// after `<`
+    TagOpen,
+    /// after `<!`, with the number of `-` read
+    MarkupDeclaration(u8),
+    Comment,
+    /// doctypes, cdata and processing instructions, skipped until `>`
+    BogusComment,
+    TagName,
+    /// skipped until `>`
+    EndTag,
+    BeforeAttributeName,
+    AttributeName,
+    AfterAttributeName,
+    BeforeAttributeValue,
+    /// with its quote, if quoted
+    AttributeValue(Option<u8>),
+    /// contents of `<script>` or `<style>`, until its end tag
+    RawText,
+}
+
+struct Node {
+    /// `None` for text that isn't matched, such as `<style>` contents
+    context: Option<MatchContext>,
+    attribute: Option<String>,
+    start: usize,
+    minimum_end: usize,
+    text: Vec<u8>,
+}
+
+fn push_name(name: &mut Vec<u8>, byte: u8) {
+    if name.len() < MAX_NAME_SIZE {
+        name.push(byte.to_ascii_lowercase());
+    }
+}
+
+fn is_json_type(script_type: &str) -> bool {
+    script_type
+        .split(';')
+        .next()
+        .unwrap_or_default()
+        .trim()
+        .ends_with("json")
+}
+
+/// Streaming html tokenizer, splitting a body into text nodes, attribute values, comments and scripts
+#[derive(Default)]
+pub struct Tokenizer {
+    state: State,
+    node: Option<Node>,
+    tag_name: Vec<u8>,
+    attribute_name: Vec<u8>,
+    /// `type` attribute of the current `<script>` tag
+    script_type: Option<String>,
+    /// end tag of the current raw text element, `</script` or `</style`
+    raw_text_end: &'static [u8],
+    json_script: Option<JsonScript>,
+}
+
+impl Tokenizer {
+    /// reads `data` found at `offset` in the body
+    pub fn read(&mut self, offset: usize, data: &[u8], out: &mut Vec<Token>) {
+        for (i, byte) in data.iter().enumerate() {
+            self.step(offset + i, *byte, out);
+        }
+    }
+
+    /// ends the node being read at the end of the body
+    pub fn finish(&mut self, out: &mut Vec<Token>) {
+        self.end_node(0, out);
+    }
+
+    fn start_node(
+        &mut self,
+        context: Option<MatchContext>,
+        attribute: Option<String>,
+        start: usize,
+    ) {
+        self.node = Some(Node {
+            context,
+            attribute,
+            start,
+            minimum_end: start,
+            text: vec![],
+        });
+    }
+
+    fn push(&mut self, byte: u8, out: &mut Vec<Token>) {
+        let node = match &mut self.node {
+            Some(node) => node,
+            None => return,
+        };
+        node.text.push(byte);
+        if node.text.len() < SEGMENT_SIZE {
+            return;
+        }
+        if let Some(context) = node.context {
+            out.push(Token::Segment(Segment {
+                context,
+                attribute: node.attribute.clone(),
+                start: node.start,
+                minimum_end: node.minimum_end,
+                text: node.text.clone(),
+            }));
+        }
+        let consumed = node.text.len() - SEGMENT_OVERLAP;
+        node.minimum_end = node.start + node.text.len();
+        node.start += consumed;
+        node.text.drain(..consumed);
+    }
+
+    /// ends the current node, dropping the `trim` trailing bytes of its text
+    fn end_node(&mut self, trim: usize, out: &mut Vec<Token>) {
+        let node = match self.node.take() {
+            Some(node) => node,
+            None => return,
+        };
+        let mut text = node.text;
+        text.truncate(text.len().saturating_sub(trim));
+        let context = match node.context {
+            Some(context) => context,
+            None => return,
+        };
+        if text.iter().all(u8::is_ascii_whitespace) {
+            return;
+        }
+        out.push(Token::Segment(Segment {
+            context,
+            attribute: node.attribute,
+            start: node.start,
+            minimum_end: node.minimum_end,
+            text,
+        }));
+    }
+
+    fn start_attribute(&mut self, start: usize) {
+        let name = String::from_utf8_lossy(&self.attribute_name).into_owned();
+        self.start_node(Some(MatchContext::Attributes), Some(name), start);
+    }
+
+    fn end_attribute(&mut self, out: &mut Vec<Token>) {
+        if self.tag_name == b"script" && self.attribute_name == b"type" {
+            if let Some(node) = &self.node {
+                let script_type = String::from_utf8_lossy(&node.text);
+                self.script_type = Some(script_type.trim().to_ascii_lowercase());
+            }
+        }
+        self.end_node(0, out);
+    }
+
+    /// `offset` is the body offset of the closing `>`
+    fn end_start_tag(&mut self, offset: usize) {
+        let start = offset + 1;
+        match &*self.tag_name {
+            b"script" => {
+                self.raw_text_end = b"</script";
+                self.start_node(Some(MatchContext::Scripts), None, start);
+                if self.script_type.as_deref().map(is_json_type) == Some(true) {
+                    self.json_script = Some(JsonScript {
+                        start,
+                        text: vec![],
+                    });
+                }
+                self.state = State::RawText;
+            }
+            b"style" => {
+                self.raw_text_end = b"</style";
+                self.start_node(None, None, start);
+                self.state = State::RawText;
+            }
+            _ => self.state = State::Text,
+        }
+    }
+
+    fn step(&mut self, offset: usize, byte: u8, out: &mut Vec<Token>) {
+        match self.state {
+            State::Text => {
+                if self.node.is_none() {
+                    self.start_node(Some(MatchContext::Text), None, offset);
+                }
+                // the `<` is dropped from the text if it starts a tag
+                self.push(byte, out);
+                if byte == b'<' {
+                    self.state = State::TagOpen;
+                }
+            }
+            State::TagOpen => {
+                match byte {
+                    b'!' => self.state = State::MarkupDeclaration(0),
+                    b'/' => self.state = State::EndTag,
+                    b'?' => self.state = State::BogusComment,
+                    c if c.is_ascii_alphabetic() => {
+                        self.tag_name = vec![c.to_ascii_lowercase()];
+                        self.script_type = None;
+                        self.state = State::TagName;
+                    }
+                    _ => {
+                        self.state = State::Text;
+                        self.step(offset, byte, out);
+                        return;
+                    }
+                }
+                self.end_node(1, out);
+            }
+            State::MarkupDeclaration(dashes) => match byte {
+                b'-' if dashes == 1 => {
+                    self.start_node(Some(MatchContext::Comments), None, offset + 1);
+                    self.state = State::Comment;
+                }
+                b'-' => self.state = State::MarkupDeclaration(1),
+                b'>' => self.state = State::Text,
+                _ => self.state = State::BogusComment,
+            },
+            State::Comment => {
+                self.push(byte, out);
+                let ended = byte == b'>'
+                    && self
+                        .node
+                        .as_ref()
+                        .map(|node| node.text.ends_with(b"-->"))
+                        .unwrap_or_default();
+                if ended {
+                    self.end_node(3, out);
+                    self.state = State::Text;
+                }
+            }
+            State::BogusComment | State::EndTag => {
+                if byte == b'>' {
+                    self.state = State::Text;
+                }
+            }
+            State::TagName => match byte {
+                b'>' => self.end_start_tag(offset),
+                b'/' => self.state = State::BeforeAttributeName,
+                c if c.is_ascii_whitespace() => self.state = State::BeforeAttributeName,
+                c => push_name(&mut self.tag_name, c),
+            },
+            State::BeforeAttributeName => match byte {
+                b'>' => self.end_start_tag(offset),
+                c if c.is_ascii_whitespace() || c == b'/' => (),
+                c => {
+                    self.attribute_name = vec![c.to_ascii_lowercase()];
+                    self.state = State::AttributeName;
+                }
+            },
+            State::AttributeName => match byte {
+                b'>' => self.end_start_tag(offset),
+                b'=' => self.state = State::BeforeAttributeValue,
+                b'/' => self.state = State::BeforeAttributeName,
+                c if c.is_ascii_whitespace() => self.state = State::AfterAttributeName,
+                c => push_name(&mut self.attribute_name, c),
+            },
+            State::AfterAttributeName => match byte {
+                b'>' => self.end_start_tag(offset),
+                b'=' => self.state = State::BeforeAttributeValue,
+                b'/' => self.state = State::BeforeAttributeName,
+                c if c.is_ascii_whitespace() => (),
+                c => {
+                    self.attribute_name = vec![c.to_ascii_lowercase()];
+                    self.state = State::AttributeName;
+                }
+            },
+            State::BeforeAttributeValue => match byte {
+                b'>' => self.end_start_tag(offset),
+                c if c.is_ascii_whitespace() => (),
+                quote @ (b'"' | b'\'') => {
+                    self.start_attribute(offset + 1);
+                    self.state = State::AttributeValue(Some(quote));
+                }
+                c => {
+                    self.start_attribute(offset);
+                    self.push(c, out);
+                    self.state = State::AttributeValue(None);
+                }
+            },
+            State::AttributeValue(Some(quote)) => {
+                if byte == quote {
+                    self.end_attribute(out);
+                    self.state = State::BeforeAttributeName;
+                } else {
+                    self.push(byte, out);
+                }
+            }
+            State::AttributeValue(None) => match byte {
+                b'>' => {
+                    self.end_attribute(out);
+                    self.end_start_tag(offset);
+                }
+                c if c.is_ascii_whitespace() => {
+                    self.end_attribute(out);
+                    self.state = State::BeforeAttributeName;
+                }
+                c => self.push(c, out),
+            },
+            State::RawText => {
+                self.push(byte, out);
+                if let Some(script) = &mut self.json_script {
+                    script.text.push(byte);
+                    if script.text.len() > MAX_SCRIPT_SIZE {
+                        self.json_script = None;
+                    }
+                }
+                let end = self.raw_text_end;
+                let ended = self
+                    .node
+                    .as_ref()
+                    .map(|node| {
+                        node.text.len() >= end.len()
+                            && node.text[node.text.len() - end.len()..].eq_ignore_ascii_case(end)
+                    })
+                    .unwrap_or_default();
+                if ended {
+                    self.end_node(end.len(), out);
+                    if let Some(mut script) = self.json_script.take() {
+                        script.text.truncate(script.text.len() - end.len());
+                        out.push(Token::JsonScript(script));
+                    }
+                    self.state = State::EndTag;
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// tokenizes `body` read in chunks of `chunk_size`, returning the context, attribute and body text of each segment
+    fn tokenize(body: &str, chunk_size: usize) -> Vec<(MatchContext, Option<String>, &str)> {
+        let mut tokenizer = Tokenizer::default();
+        let mut tokens = vec![];
+        for (i, chunk) in body.as_bytes().chunks(chunk_size).enumerate() {
+            tokenizer.read(i * chunk_size, chunk, &mut tokens);
+        }
+        tokenizer.finish(&mut tokens);
+        tokens
+            .into_iter()
+            .map(|token| match token {
+                Token::Segment(segment) => {
+                    let text = &body[segment.start..segment.start + segment.text.len()];
+                    assert_eq!(text.as_bytes(), &segment.text[..]);
+                    (segment.context, segment.attribute, text)
+                }
+                Token::JsonScript(script) => {
+                    let text = &body[script.start..script.start + script.text.len()];
+                    assert_eq!(text.as_bytes(), &script.text[..]);
+                    (MatchContext::Values, None, text)
+                }
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_tokenize() {
+        let body = r#"<!DOCTYPE html><html><head><style>p { color: red }</style></head>
+<body class=main data-x = 'a b'><!-- note --><p title="x &amp; y">Hello <b>world</b> 1 < 2</p>
+<SCRIPT type='application/ld+json' async>{"a": 1}</Script ><script>var a = "</p>";</script></body>"#;
+        let expected = vec![
+            (MatchContext::Attributes, Some("class".to_string()), "main"),
+            (MatchContext::Attributes, Some("data-x".to_string()), "a b"),
+            (MatchContext::Comments, None, " note "),
+            (
+                MatchContext::Attributes,
+                Some("title".to_string()),
+                "x &amp; y",
+            ),
+            (MatchContext::Text, None, "Hello "),
+            (MatchContext::Text, None, "world"),
+            (MatchContext::Text, None, " 1 < 2"),
+            (
+                MatchContext::Attributes,
+                Some("type".to_string()),
+                "application/ld+json",
+            ),
+            (MatchContext::Scripts, None, r#"{"a": 1}"#),
+            (MatchContext::Values, None, r#"{"a": 1}"#),
+            (MatchContext::Scripts, None, r#"var a = "</p>";"#),
+        ];
+        for chunk_size in [1, 7, body.len()] {
+            assert_eq!(tokenize(body, chunk_size), expected, "{chunk_size}");
+        }
+    }
+
+    #[test]
+    fn test_long_text() {
+        let body = format!("<p>{}</p>", "a".repeat(SEGMENT_SIZE * 2));
+        let mut tokenizer = Tokenizer::default();
+        let mut tokens = vec![];
+        tokenizer.read(0, body.as_bytes(), &mut tokens);
+        let segments = tokens
+            .into_iter()
+            .map(|token| match token {
+                Token::Segment(segment) => (segment.start, segment.minimum_end, segment.text.len()),
+                Token::JsonScript(_) => panic!("unexpected json script"),
+            })
+            .collect::<Vec<_>>();
+        let second_start = 3 + SEGMENT_SIZE - SEGMENT_OVERLAP;
+        let third_start = second_start + SEGMENT_SIZE - SEGMENT_OVERLAP;
+        assert_eq!(
+            segments,
+            vec![
+                (3, 3, SEGMENT_SIZE),
+                (second_start, 3 + SEGMENT_SIZE, SEGMENT_SIZE),
+                (
+                    third_start,
+                    second_start + SEGMENT_SIZE,
+                    SEGMENT_SIZE * 2 + 3 - third_start
+                ),
+            ]
+        );
+    }
+}
@@ -0,0 +1,403 @@
+use std::collections::HashSet;
+
+use anyhow::{bail, Result};
+use exif::{Context, Tag};
+
+use crate::parsers::Replacement;
+
+/// bounds the IFDs read from a single block, guarding against cycles
+const MAX_IFDS: usize = 16;
+/// longer numeric values (i.e. tables) aren't scanned
+const MAX_NUMERIC_COUNT: usize = 16;
+
+const EXIF_IFD_POINTER: u16 = 0x8769;
+const GPS_IFD_POINTER: u16 = 0x8825;
+const INTEROP_IFD_POINTER: u16 = 0xA005;
+const USER_COMMENT: u16 = 0x9286;
+const GPS_LATITUDE: u16 = 0x0002;
+const GPS_LONGITUDE: u16 = 0x0004;
+const GPS_DEST_LATITUDE: u16 = 0x0014;
+const GPS_DEST_LONGITUDE: u16 = 0x0016;
+
+const TYPE_BYTE: u16 = 1;
+const TYPE_ASCII: u16 = 2;
+const TYPE_SHORT: u16 = 3;
+const TYPE_LONG: u16 = 4;
+const TYPE_RATIONAL: u16 = 5;
+const TYPE_UNDEFINED: u16 = 7;
+const TYPE_SSHORT: u16 = 8;
+const TYPE_SLONG: u16 = 9;
+const TYPE_SRATIONAL: u16 = 10;
+
+/// An entry of an IFD with a scannable value
+pub struct ExifField {
+    /// name of the tag, i.e. `GPSLatitude`
+    pub tag: String,
+    pub value: String,
+    /// offset of the value within the TIFF data
+    pub start: usize,
+    pub length: usize,
+    /// offset of the IFD holding the entry
+    ifd: usize,
+    /// index of the entry within the IFD
+    index: usize,
+    /// set if the value is stored outside of the entry
+    external: bool,
+}
+
+struct Tiff<'a> {
+    data: &'a [u8],
+    little_endian: bool,
+}
+
+impl<'a> Tiff<'a> {
+    fn u16(&self, offset: usize) -> Option<u16> {
+        let bytes = self.data.get(offset..offset + 2)?.try_into().ok()?;
+        Some(if self.little_endian {
+            u16::from_le_bytes(bytes)
+        } else {
+            u16::from_be_bytes(bytes)
+        })
+    }
+
+    fn u32(&self, offset: usize) -> Option<u32> {
+        let bytes = self.data.get(offset..offset + 4)?.try_into().ok()?;
+        Some(if self.little_endian {
+            u32::from_le_bytes(bytes)
+        } else {
+            u32::from_be_bytes(bytes)
+        })
+    }
+
+    fn write_u16(&self, value: u16) -> [u8; 2] {
+        if self.little_endian {
+            value.to_le_bytes()
+        } else {
+            value.to_be_bytes()
+        }
+    }
+
+    /// returns the text of a value, `None` for binary or long values
+    fn render(&self, context: Context, tag: u16, value_type: u16, data: &[u8]) -> Option<String> {
+        let numbers = |size: usize| {
+            (data.len() / size <= MAX_NUMERIC_COUNT).then(|| (0..data.len()).step_by(size))
+        };
+        Some(match value_type {
+            TYPE_ASCII => text(data),
+            TYPE_UNDEFINED if context == Context::Exif && tag == USER_COMMENT => {
+                user_comment(data)?
+            }
+            TYPE_BYTE | TYPE_UNDEFINED if data.iter().all(|x| x.is_ascii() && *x != 0) => {
+                text(data)
+            }
+            TYPE_SHORT => join(numbers(2)?.map(|x| self.u16_at(data, x).to_string())),
+            TYPE_SSHORT => join(numbers(2)?.map(|x| (self.u16_at(data, x) as i16).to_string())),
+            TYPE_LONG => join(numbers(4)?.map(|x| self.u32_at(data, x).to_string())),
+            TYPE_SLONG => join(numbers(4)?.map(|x| (self.u32_at(data, x) as i32).to_string())),
+            TYPE_RATIONAL | TYPE_SRATIONAL => {
+                let values = numbers(8)?
+                    .map(|x| {
+                        let (numerator, denominator) =
+                            (self.u32_at(data, x), self.u32_at(data, x + 4));
+                        if value_type == TYPE_SRATIONAL {
+                            numerator as i32 as f64 / denominator as i32 as f64
+                        } else {
+                            numerator as f64 / denominator as f64
+                        }
+                    })
+                    .collect::<Vec<_>>();
+                let is_coordinate = context == Context::Gps
+                    && matches!(
+                        tag,
+                        GPS_LATITUDE | GPS_LONGITUDE | GPS_DEST_LATITUDE | GPS_DEST_LONGITUDE
+                    );
+                if is_coordinate && values.len() == 3 {
+                    // degrees, minutes and seconds as decimal degrees
+                    let degrees = values[0] + values[1] / 60.0 + values[2] / 3600.0;
+                    return degrees.is_finite().then(|| format!("{degrees:.6}"));
+                }
+                join(
+                    values
+                        .into_iter()
+                        .filter(|x| x.is_finite())
+                        .map(|x| x.to_string()),
+                )
+            }
+            _ => return None,
+        })
+    }
+
+    /// reads a number of a value, `data` is in bounds
+    fn u16_at(&self, data: &[u8], offset: usize) -> u16 {
+        let bytes = [data[offset], data[offset + 1]];
+        if self.little_endian {
+            u16::from_le_bytes(bytes)
+        } else {
+            u16::from_be_bytes(bytes)
+        }
+    }
+
+    fn u32_at(&self, data: &[u8], offset: usize) -> u32 {
+        let bytes = data[offset..offset + 4].try_into().unwrap();
+        if self.little_endian {
+            u32::from_le_bytes(bytes)
+        } else {
+            u32::from_be_bytes(bytes)
+        }
+    }
+}
+
+fn text(data: &[u8]) -> String {
+    let end = data
+        .iter()
+        .rposition(|x| *x != 0)
+        .map(|x| x + 1)
+        .unwrap_or_default();
+    String::from_utf8_lossy(&data[..end]).into_owned()
+}
+
+fn join(values: impl Iterator<Item = String>) -> String {
+    values.collect::<Vec<_>>().join(", ")
+}
+
+/// user comments start with an 8 byte character code
+fn user_comment(data: &[u8]) -> Option<String> {
+    if data.len() < 8 {
+        return None;
+    }
+    let (code, comment) = data.split_at(8);
+    match code {
+        b"UNICODE\0" => {
+            let units = comment
+                .chunks_exact(2)
+                .map(|x| u16::from_be_bytes([x[0], x[1]]))
+                .collect::<Vec<_>>();
+            Some(
+                String::from_utf16_lossy(&units)
+                    .trim_end_matches('\0')
+                    .to_string(),
+            )
+        }
+        _ => Some(text(comment).trim_end().to_string()),
+    }
+}
+
+fn type_size(value_type: u16) -> Option<usize> {
+    Some(match value_type {
+        1 | 2 | 6 | 7 => 1,
+        3 | 8 => 2,
+        4 | 9 | 11 => 4,
+        5 | 10 | 12 => 8,
+        _ => return None,
+    })
+}
+
+/// reads the entries of every IFD of TIFF structured `data` with a scannable value
+pub fn read_exif(data: &[u8]) -> Result<Vec<ExifField>> {
+    let little_endian = match data.get(..4) {
+        Some(b"II*\0") => true,
+        Some(b"MM\0*") => false,
+        _ => bail!("malformed exif, missing tiff header"),
+    };
+    let tiff = Tiff {
+        data,
+        little_endian,
+    };
+    let mut fields = vec![];
+    let mut visited = HashSet::new();
+    let mut pending = vec![(tiff.u32(4).unwrap() as usize, Context::Tiff)];
+    while let Some((ifd, context)) = pending.pop() {
+        if ifd == 0 || visited.len() >= MAX_IFDS || !visited.insert(ifd) {
+            continue;
+        }
+        let count = match tiff.u16(ifd) {
+            Some(x) => x as usize,
+            None => bail!("malformed exif, IFD out of bounds"),
+        };
+        for index in 0..count {
+            let entry = ifd + 2 + index * 12;
+            let (tag, value_type, value_count) =
+                match (tiff.u16(entry), tiff.u16(entry + 2), tiff.u32(entry + 4)) {
+                    (Some(tag), Some(value_type), Some(value_count)) => {
+                        (tag, value_type, value_count as usize)
+                    }
+                    _ => bail!("malformed exif, IFD entry out of bounds"),
+                };
+            let pointer_context = match (context, tag) {
+                (Context::Tiff, EXIF_IFD_POINTER) => Some(Context::Exif),
+                (Context::Tiff, GPS_IFD_POINTER) => Some(Context::Gps),
+                (Context::Exif, INTEROP_IFD_POINTER) => Some(Context::Interop),
+                _ => None,
+            };
+            if let Some(pointer_context) = pointer_context {
+                if let Some(pointer) = tiff.u32(entry + 8) {
+                    pending.push((pointer as usize, pointer_context));
+                }
+                continue;
+            }
+            let length = match type_size(value_type).and_then(|x| x.checked_mul(value_count)) {
+                Some(x) => x,
+                None => continue,
+            };
+            let external = length > 4;
+            let start = if external {
+                match tiff.u32(entry + 8) {
+                    Some(x) => x as usize,
+                    None => continue,
+                }
+            } else {
+                entry + 8
+            };
+            let value = match data.get(start..start.saturating_add(length)) {
+                Some(x) => x,
+                None => continue,
+            };
+            let value = match tiff.render(context, tag, value_type, value) {
+                Some(x) if !x.trim().is_empty() => x,
+                _ => continue,
+            };
+            fields.push(ExifField {
+                tag: Tag(context, tag).to_string(),
+                value,
+                start,
+                length,
+                ifd,
+                index,
+                external,
+            });
+        }
+        // the 1st IFD follows the 0th, describing the thumbnail
+        if context == Context::Tiff {
+            if let Some(next) = tiff.u32(ifd + 2 + count * 12) {
+                pending.push((next as usize, Context::Tiff));
+            }
+        }
+    }
+    Ok(fields)
+}
+
+/// removes the entries of `fields` from their IFDs, zeroing their values.
+/// returns the replacements within `data`, which keep its length
+pub fn strip_exif(data: &[u8], fields: &[&ExifField]) -> Vec<Replacement> {
+    let little_endian = data.starts_with(b"II");
+    let tiff = Tiff {
+        data,
+        little_endian,
+    };
+    let mut replacements = vec![];
+    let mut ifds = fields.iter().map(|x| x.ifd).collect::<Vec<_>>();
+    ifds.sort_unstable();
+    ifds.dedup();
+    for ifd in ifds {
+        let count = tiff.u16(ifd).unwrap_or_default() as usize;
+        let end = ifd + 2 + count * 12 + 4;
+        if end > data.len() {
+            continue;
+        }
+        let removed = fields
+            .iter()
+            .filter(|x| x.ifd == ifd)
+            .map(|x| x.index)
+            .collect::<HashSet<_>>();
+        let mut value = tiff.write_u16((count - removed.len()) as u16).to_vec();
+        for index in (0..count).filter(|x| !removed.contains(x)) {
+            let entry = ifd + 2 + index * 12;
+            value.extend_from_slice(&data[entry..entry + 12]);
+        }
+        // next IFD pointer
+        value.extend_from_slice(&data[end - 4..end]);
+        value.resize(end - ifd, 0);
+        replacements.push(Replacement {
+            start: ifd,
+            length: end - ifd,
+            value,
+        });
+    }
+    for field in fields.iter().filter(|x| x.external) {
+        replacements.push(Replacement {
+            start: field.start,
+            length: field.length,
+            value: vec![0; field.length],
+        });
+    }
+    replacements
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::parsers::replace_matches;
+
+    use super::*;
+
+    /// little endian tiff with an artist and a gps IFD holding a latitude
+    fn sample_exif() -> Vec<u8> {
+        let mut data = b"II*\0".to_vec();
+        data.extend(8u32.to_le_bytes());
+        // 0th IFD @ 8: Artist, GPS pointer
+        data.extend(2u16.to_le_bytes());
+        data.extend(0x013Bu16.to_le_bytes());
+        data.extend(TYPE_ASCII.to_le_bytes());
+        data.extend(9u32.to_le_bytes());
+        data.extend(38u32.to_le_bytes());
+        data.extend(GPS_IFD_POINTER.to_le_bytes());
+        data.extend(TYPE_LONG.to_le_bytes());
+        data.extend(1u32.to_le_bytes());
+        data.extend(48u32.to_le_bytes());
+        data.extend(0u32.to_le_bytes());
+        // artist @ 38
+        data.extend(b"Jane Doe\0\0");
+        // GPS IFD @ 48: GPSLatitudeRef, GPSLatitude
+        data.extend(2u16.to_le_bytes());
+        data.extend(1u16.to_le_bytes());
+        data.extend(TYPE_ASCII.to_le_bytes());
+        data.extend(2u32.to_le_bytes());
+        data.extend(b"N\0\0\0");
+        data.extend(GPS_LATITUDE.to_le_bytes());
+        data.extend(TYPE_RATIONAL.to_le_bytes());
+        data.extend(3u32.to_le_bytes());
+        data.extend(78u32.to_le_bytes());
+        data.extend(0u32.to_le_bytes());
+        // latitude @ 78
+        for (numerator, denominator) in [(37u32, 1u32), (46, 1), (2964, 100)] {
+            data.extend(numerator.to_le_bytes());
+            data.extend(denominator.to_le_bytes());
+        }
+        data
+    }
+
+    #[test]
+    fn test_read_exif() {
+        let data = sample_exif();
+        let fields = read_exif(&data).unwrap();
+        let fields = fields
+            .iter()
+            .map(|x| (&*x.tag, &*x.value, x.start))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            fields,
+            vec![
+                ("Artist", "Jane Doe", 38),
+                ("GPSLatitudeRef", "N", 58),
+                ("GPSLatitude", "37.774900", 78),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_strip_exif() {
+        let data = sample_exif();
+        let fields = read_exif(&data).unwrap();
+        let stripped = fields
+            .iter()
+            .filter(|x| x.tag == "GPSLatitude" || x.tag == "Artist")
+            .collect::<Vec<_>>();
+        let mut replacements = strip_exif(&data, &stripped);
+        replacements.sort_by_key(|x| x.start);
+        let output = replace_matches(&data, &replacements);
+        assert_eq!(output.len(), data.len());
+        let fields = read_exif(&output).unwrap();
+        let tags = fields.iter().map(|x| &*x.tag).collect::<Vec<_>>();
+        assert_eq!(tags, vec!["GPSLatitudeRef"]);
+        assert!(!output.windows(8).any(|x| x == b"Jane Doe"));
+    }
+}
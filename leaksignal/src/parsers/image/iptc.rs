@@ -0,0 +1,136 @@
+/// photoshop image resource holding IPTC-IIM records
+const IPTC_RESOURCE: u16 = 0x0404;
+const RESOURCE_SIGNATURE: &[u8] = b"8BIM";
+const TAG_MARKER: u8 = 0x1C;
+/// the application record, holding the descriptive datasets
+const APPLICATION_RECORD: u8 = 2;
+
+/// A dataset of the application record of IPTC-IIM
+#[derive(PartialEq, Eq, Debug)]
+pub struct IptcField {
+    /// name of the dataset, i.e. `IPTC:By-line`
+    pub tag: String,
+    pub value: String,
+    /// offset of the value within the photoshop resources
+    pub start: usize,
+    pub length: usize,
+}
+
+fn dataset_name(dataset: u8) -> Option<&'static str> {
+    Some(match dataset {
+        5 => "ObjectName",
+        25 => "Keywords",
+        40 => "SpecialInstructions",
+        80 => "By-line",
+        85 => "By-lineTitle",
+        90 => "City",
+        92 => "Sub-location",
+        95 => "Province-State",
+        100 => "Country-PrimaryLocationCode",
+        101 => "Country-PrimaryLocationName",
+        103 => "OriginalTransmissionReference",
+        105 => "Headline",
+        110 => "Credit",
+        115 => "Source",
+        116 => "CopyrightNotice",
+        118 => "Contact",
+        120 => "Caption-Abstract",
+        122 => "Writer-Editor",
+        _ => return None,
+    })
+}
+
+/// returns the offset and length of each IPTC resource in photoshop image resources
+fn iptc_resources(data: &[u8]) -> Vec<(usize, usize)> {
+    let mut resources = vec![];
+    let mut i = 0usize;
+    while data.len() >= i + 8 && &data[i..i + 4] == RESOURCE_SIGNATURE {
+        let id = u16::from_be_bytes([data[i + 4], data[i + 5]]);
+        // pascal string name, padded to an even length
+        let name_length = data[i + 6] as usize + 1;
+        i += 6 + name_length + name_length % 2;
+        let size = match data.get(i..i + 4) {
+            Some(x) => u32::from_be_bytes(x.try_into().unwrap()) as usize,
+            None => break,
+        };
+        i += 4;
+        if i + size > data.len() {
+            break;
+        }
+        if id == IPTC_RESOURCE {
+            resources.push((i, size));
+        }
+        i += size + size % 2;
+    }
+    resources
+}
+
+/// reads the application record datasets with known names from photoshop image resources
+pub fn read_iptc(data: &[u8]) -> Vec<IptcField> {
+    let mut fields = vec![];
+    for (start, size) in iptc_resources(data) {
+        let records = &data[start..start + size];
+        let mut i = 0usize;
+        while records.len() >= i + 5 && records[i] == TAG_MARKER {
+            let record = records[i + 1];
+            let dataset = records[i + 2];
+            let length = u16::from_be_bytes([records[i + 3], records[i + 4]]) as usize;
+            // extended datasets aren't used for text
+            if length & 0x8000 != 0 || i + 5 + length > records.len() {
+                break;
+            }
+            i += 5;
+            if record == APPLICATION_RECORD {
+                if let Some(name) = dataset_name(dataset) {
+                    fields.push(IptcField {
+                        tag: format!("IPTC:{name}"),
+                        value: String::from_utf8_lossy(&records[i..i + length]).into_owned(),
+                        start: start + i,
+                        length,
+                    });
+                }
+            }
+            i += length;
+        }
+    }
+    fields
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_read_iptc() {
+        let mut records = vec![];
+        for (record, dataset, value) in [
+            (1u8, 90u8, &b"\x1b%G"[..]),
+            (2, 0, b"\0\x04"),
+            (2, 80, b"Jane Doe"),
+            (2, 120, b"at home"),
+        ] {
+            records.extend([TAG_MARKER, record, dataset]);
+            records.extend((value.len() as u16).to_be_bytes());
+            records.extend(value);
+        }
+        // an unrelated resource, with a name
+        let mut data = b"8BIM\x04\x0c\x03abc\0\0\0\x01\x00\0".to_vec();
+        data.extend(b"8BIM\x04\x04\0\0");
+        data.extend((records.len() as u32).to_be_bytes());
+        let start = data.len();
+        data.extend(&records);
+
+        let fields = read_iptc(&data);
+        assert_eq!(
+            fields
+                .iter()
+                .map(|x| (&*x.tag, &*x.value, &data[x.start..x.start + x.length]))
+                .collect::<Vec<_>>(),
+            vec![
+                ("IPTC:By-line", "Jane Doe", &b"Jane Doe"[..]),
+                ("IPTC:Caption-Abstract", "at home", b"at home"),
+            ]
+        );
+        assert_eq!(fields[0].start, start + 20);
+    }
+}
@@ -0,0 +1,549 @@
+use std::{io::Read, sync::Arc};
+
+use anyhow::Result;
+use fancy_regex::Regex;
+use flate2::{read::ZlibDecoder, Crc};
+use log::{debug, warn};
+
+use indexmap::IndexMap;
+use leakpolicy::{Direction, MatchContext, PathConfiguration};
+
+mod exif;
+mod iptc;
+mod parse;
+mod xmp;
+
+pub use parse::ImageFormat;
+
+use crate::{
+    alert::AlertTracker,
+    evaluator::{self, MatcherMetadata, MatcherState},
+    pipe::PipeReader,
+    policy::{Category, ContentType, Policy},
+    proto::{r#match::Location, Match},
+};
+
+use self::{
+    exif::ExifField,
+    parse::{Block, BlockKind, ImageReader},
+};
+
+use super::{replace_matches, ParseResponse, Replacement};
+
+/// bounds the decompressed size of a png `zTXt` or `iTXt` chunk
+const MAX_PNG_TEXT: u64 = 1 << 20;
+
+/// Matcher state of an `ImageMetadata` category, only used for values of fields with a matching tag
+pub struct TagMatchers {
+    tags: Vec<Regex>,
+    values: MatcherState,
+}
+
+impl TagMatchers {
+    fn matches_tag(&self, tag: &str) -> bool {
+        self.tags
+            .iter()
+            .any(|regex| regex.is_match(tag).unwrap_or_default())
+    }
+}
+
+/// Matcher states of all image metadata values (`values`) and of the values of tagged fields (`tagged`)
+#[derive(Default)]
+pub struct ImageMatchers {
+    pub values: MatcherState,
+    pub tagged: Vec<TagMatchers>,
+}
+
+pub fn prepare_match_state(
+    policy: &Policy,
+    configuration: &IndexMap<Arc<String>, PathConfiguration>,
+    direction: Direction,
+) -> ImageMatchers {
+    let mut matchers = ImageMatchers::default();
+
+    for (category_name, action) in configuration {
+        if !action.scans_content(direction, &ContentType::Image) {
+            continue;
+        }
+
+        if !action.matches_context(MatchContext::Values, &MatchContext::STRUCTURED) {
+            continue;
+        }
+
+        let metadata = MatcherMetadata::new(category_name, action);
+
+        match policy.categories.get(&**category_name) {
+            Some(Category::ImageMetadata { tags, values }) => {
+                let values = match values {
+                    Some(values) => match values.match_group(policy) {
+                        Some(x) => Some(x),
+                        None => {
+                            warn!("image metadata match group for '{category_name}' is missing its values");
+                            continue;
+                        }
+                    },
+                    None => None,
+                };
+                let mut tagged = TagMatchers {
+                    tags: tags.iter().map(|x| x.0.clone()).collect(),
+                    values: MatcherState::default(),
+                };
+                evaluator::prepare_decoded(&mut tagged.values, &metadata, |state, metadata| {
+                    match values {
+                        Some(values) => evaluator::prepare_match_group(
+                            values,
+                            state,
+                            metadata,
+                            &action.category_config.ignore,
+                        ),
+                        None => evaluator::prepare_whole_value(
+                            state,
+                            metadata,
+                            &action.category_config.ignore,
+                        ),
+                    }
+                });
+                matchers.tagged.push(tagged);
+            }
+            // Keyed categories only match json values and csv cells, see `Policy::validate`
+            Some(Category::Keyed { .. }) => (),
+            _ => evaluator::prepare_matches(
+                policy,
+                category_name,
+                &mut matchers.values,
+                &metadata,
+                &action.category_config.ignore,
+            ),
+        }
+    }
+    matchers.values.compile();
+    for tagged in &mut matchers.tagged {
+        tagged.values.compile();
+    }
+
+    matchers
+}
+
+/// A metadata field of a block, `start` and `length` are of the encoded value within the block data
+struct Field {
+    tag: String,
+    value: String,
+    start: usize,
+    length: usize,
+}
+
+fn latin1(data: &[u8]) -> String {
+    data.iter().map(|x| *x as char).collect()
+}
+
+fn inflate(data: &[u8]) -> Option<Vec<u8>> {
+    let mut out = vec![];
+    ZlibDecoder::new(data)
+        .take(MAX_PNG_TEXT)
+        .read_to_end(&mut out)
+        .ok()?;
+    Some(out)
+}
+
+/// reads a `tEXt`, `zTXt` or `iTXt` chunk, named by its keyword
+fn read_png_text(chunk_type: &[u8; 4], data: &[u8]) -> Option<Field> {
+    let keyword_end = data.iter().position(|x| *x == 0)?;
+    let tag = latin1(&data[..keyword_end]);
+    let mut start = keyword_end + 1;
+    let value = match chunk_type {
+        b"tEXt" => latin1(&data[start..]),
+        b"zTXt" => {
+            // compression method
+            start += 1;
+            latin1(&inflate(data.get(start..)?)?)
+        }
+        b"iTXt" => {
+            let compressed = *data.get(start)? != 0;
+            start += 2;
+            // language tag and translated keyword
+            for _ in 0..2 {
+                start += data.get(start..)?.iter().position(|x| *x == 0)? + 1;
+            }
+            if compressed {
+                String::from_utf8_lossy(&inflate(&data[start..])?).into_owned()
+            } else {
+                String::from_utf8_lossy(&data[start..]).into_owned()
+            }
+        }
+        _ => return None,
+    };
+    Some(Field {
+        tag,
+        value,
+        start,
+        length: data.len() - start,
+    })
+}
+
+/// reads the fields of a block, along with the EXIF entries they were read from
+fn read_fields(block: &Block) -> (Vec<Field>, Vec<ExifField>) {
+    let payload = &block.data[block.payload..];
+    match block.kind {
+        BlockKind::Exif => match exif::read_exif(payload) {
+            Ok(entries) => {
+                let fields = entries
+                    .iter()
+                    .map(|x| Field {
+                        tag: x.tag.clone(),
+                        value: x.value.clone(),
+                        start: block.payload + x.start,
+                        length: x.length,
+                    })
+                    .collect();
+                (fields, entries)
+            }
+            Err(e) => {
+                debug!("skipping malformed exif @ {}: {e:#}", block.start);
+                (vec![], vec![])
+            }
+        },
+        BlockKind::Xmp => {
+            let fields = xmp::read_xmp(payload)
+                .into_iter()
+                .map(|x| Field {
+                    tag: x.name,
+                    value: x.value,
+                    start: block.payload + x.start,
+                    length: x.end - x.start,
+                })
+                .collect();
+            (fields, vec![])
+        }
+        BlockKind::Photoshop => {
+            let fields = iptc::read_iptc(payload)
+                .into_iter()
+                .map(|x| Field {
+                    tag: x.tag,
+                    value: x.value,
+                    start: block.payload + x.start,
+                    length: x.length,
+                })
+                .collect();
+            (fields, vec![])
+        }
+        BlockKind::PngText => {
+            let fields = block
+                .png_type
+                .and_then(|chunk_type| read_png_text(&chunk_type, &block.data))
+                .into_iter()
+                .collect();
+            (fields, vec![])
+        }
+    }
+}
+
+/// strips the fields at `stripped` from the block. png text chunks are removed entirely,
+/// other blocks keep their length
+fn strip_fields(
+    block: &Block,
+    fields: &[Field],
+    exif_fields: &[ExifField],
+    stripped: &[usize],
+    masks: &mut Vec<Replacement>,
+) {
+    if block.kind == BlockKind::PngText {
+        // length, type and crc
+        masks.push(Replacement {
+            start: block.start - 8,
+            length: block.data.len() + 12,
+            value: vec![],
+        });
+        return;
+    }
+    let mut local_masks = match block.kind {
+        BlockKind::Exif => {
+            let entries = stripped
+                .iter()
+                .map(|x| &exif_fields[*x])
+                .collect::<Vec<_>>();
+            exif::strip_exif(&block.data[block.payload..], &entries)
+                .into_iter()
+                .map(|x| Replacement {
+                    start: block.payload + x.start,
+                    ..x
+                })
+                .collect::<Vec<_>>()
+        }
+        _ => stripped
+            .iter()
+            .map(|x| Replacement {
+                start: fields[*x].start,
+                length: fields[*x].length,
+                value: vec![b' '; fields[*x].length],
+            })
+            .collect(),
+    };
+    local_masks.sort_by_key(|x| x.start);
+    match block.png_type {
+        Some(chunk_type) => {
+            // the crc of the chunk covers the changed data
+            let mut value = replace_matches(&block.data, &local_masks).into_owned();
+            let mut crc = Crc::new();
+            crc.update(&chunk_type);
+            crc.update(&value);
+            value.extend(crc.sum().to_be_bytes());
+            masks.push(Replacement {
+                start: block.start,
+                length: block.data.len() + 4,
+                value,
+            });
+        }
+        None => masks.extend(local_masks.into_iter().map(|x| Replacement {
+            start: block.start + x.start,
+            ..x
+        })),
+    }
+}
+
+fn scan_block(
+    matchers: &ImageMatchers,
+    block: &Block,
+    matches: &mut Vec<Match>,
+    masks: &mut Vec<Replacement>,
+    alerts: &mut AlertTracker,
+) -> ParseResponse {
+    let (fields, exif_fields) = read_fields(block);
+    let mut stripped = vec![];
+    let mut response = ParseResponse::Continue;
+    for (index, field) in fields.iter().enumerate() {
+        let mut local_masks = vec![];
+        let first_match = matches.len();
+        let tagged = matchers
+            .tagged
+            .iter()
+            .filter(|tagged| tagged.matches_tag(&field.tag))
+            .map(|tagged| &tagged.values);
+        for values in std::iter::once(&matchers.values).chain(tagged) {
+            let value_response = values.do_matching(
+                block.start + field.start,
+                0,
+                &field.value,
+                matches,
+                &mut local_masks,
+                alerts,
+            );
+            if matches!(value_response, ParseResponse::Block) {
+                response = ParseResponse::Block;
+            }
+        }
+        for matching in &mut matches[first_match..] {
+            matching.location = Some(Location::ImageMetadataTag(field.tag.clone()));
+        }
+        if matches!(response, ParseResponse::Block) {
+            return response;
+        }
+        // masks of decoded values can't be applied in place, the field is stripped instead
+        if !local_masks.is_empty() {
+            stripped.push(index);
+        }
+    }
+    if !stripped.is_empty() {
+        strip_fields(block, &fields, &exif_fields, &stripped, masks);
+    }
+    response
+}
+
+/// scans the EXIF, XMP, IPTC and png text metadata of an image, the image data is passed through
+pub async fn parse_image(
+    matchers: &ImageMatchers,
+    format: ImageFormat,
+    body: &mut PipeReader,
+    matches: &mut Vec<Match>,
+    masks: &mut Vec<Replacement>,
+    alerts: &mut AlertTracker,
+) -> Result<ParseResponse> {
+    let mut image = ImageReader::new(format);
+    while let Some(block) = image.next_block(body).await? {
+        if let ParseResponse::Block = scan_block(matchers, &block, matches, masks, alerts) {
+            return Ok(ParseResponse::Block);
+        }
+    }
+    parse::skip_to_end(body).await?;
+    masks.sort_by_key(|x| x.start);
+
+    Ok(ParseResponse::Continue)
+}
+
+#[cfg(test)]
+mod tests {
+    use futures::{pin_mut, task::waker, Future};
+    use std::task::Poll;
+
+    use crate::pipe::{pipe, DummyWaker};
+
+    use super::*;
+
+    fn scan_image(
+        policy: &str,
+        format: ImageFormat,
+        body: &[u8],
+    ) -> (Vec<(String, String, String)>, Vec<u8>) {
+        let policy: Policy = serde_yaml::from_str(policy).unwrap();
+        let path_policy = policy.get_path_config("example.com/");
+        let matchers =
+            prepare_match_state(&policy, &path_policy.configuration, Direction::Response);
+
+        let (mut reader, mut writer) = pipe(0);
+        assert!(writer.append(body));
+        drop(writer);
+        let mut matches = vec![];
+        let mut masks = vec![];
+        let mut alerts = AlertTracker::default();
+        let waker = waker(Arc::new(DummyWaker));
+        let mut context = std::task::Context::from_waker(&waker);
+        {
+            let future = parse_image(
+                &matchers,
+                format,
+                &mut reader,
+                &mut matches,
+                &mut masks,
+                &mut alerts,
+            );
+            pin_mut!(future);
+            assert!(matches!(
+                future.poll(&mut context),
+                Poll::Ready(Ok(ParseResponse::Continue))
+            ));
+        }
+        assert_eq!(reader.total_read(), body.len());
+        let matches = matches
+            .into_iter()
+            .map(|x| {
+                (
+                    x.category_name,
+                    x.matched_value.unwrap_or_default(),
+                    x.location
+                        .as_ref()
+                        .map(Location::value)
+                        .unwrap_or_default()
+                        .to_string(),
+                )
+            })
+            .collect();
+        (matches, replace_matches(body, &masks).into_owned())
+    }
+
+    const POLICY: &str = r#"
+categories:
+  gps:
+    ImageMetadata:
+      tags: "^GPS(Latitude|Longitude)$"
+  author:
+    ImageMetadata:
+      tags: "^(Artist|dc:creator|IPTC:By-line)$"
+  email:
+    Matchers:
+      regexes:
+        - "[a-z]+@[a-z]+\\.com"
+endpoints:
+  - matches: "**"
+    config:
+      gps:
+        action: mask
+      author: {}
+      email:
+        action: mask
+"#;
+
+    fn ifd_entry(tag: u16, value_type: u16, count: u32, value: [u8; 4]) -> Vec<u8> {
+        let mut entry = tag.to_be_bytes().to_vec();
+        entry.extend(value_type.to_be_bytes());
+        entry.extend(count.to_be_bytes());
+        entry.extend(value);
+        entry
+    }
+
+    /// big endian tiff with an artist and a gps IFD holding a longitude
+    fn sample_exif() -> Vec<u8> {
+        let mut data = b"MM\0*".to_vec();
+        data.extend(8u32.to_be_bytes());
+        // 0th IFD @ 8
+        data.extend(2u16.to_be_bytes());
+        data.extend(ifd_entry(0x013B, 2, 4, *b"Ann\0"));
+        data.extend(ifd_entry(0x8825, 4, 1, 38u32.to_be_bytes()));
+        data.extend(0u32.to_be_bytes());
+        // GPS IFD @ 38, longitude @ 56
+        data.extend(1u16.to_be_bytes());
+        data.extend(ifd_entry(0x0004, 5, 3, 56u32.to_be_bytes()));
+        data.extend(0u32.to_be_bytes());
+        for (numerator, denominator) in [(122u32, 1u32), (25, 1), (12, 1)] {
+            data.extend(numerator.to_be_bytes());
+            data.extend(denominator.to_be_bytes());
+        }
+        data
+    }
+
+    #[test]
+    fn test_jpeg_metadata() {
+        let exif = sample_exif();
+        let mut body = vec![0xFF, 0xD8, 0xFF, 0xE1];
+        body.extend((exif.len() as u16 + 8).to_be_bytes());
+        body.extend(b"Exif\0\0");
+        body.extend(&exif);
+        let xmp = br#"<x:xmpmeta><rdf:RDF><rdf:Description dc:creator="ann@example.com"/></rdf:RDF></x:xmpmeta>"#;
+        body.extend([0xFF, 0xE1]);
+        body.extend((xmp.len() as u16 + 31).to_be_bytes());
+        body.extend(b"http://ns.adobe.com/xap/1.0/\0");
+        body.extend(xmp);
+        body.extend([0xFF, 0xDA, 0, 2, 1, 2, 3, 0xFF, 0xD9]);
+
+        let (matches, output) = scan_image(POLICY, ImageFormat::Jpeg, &body);
+        let matches = matches
+            .iter()
+            .map(|(category, value, tag)| (&**category, &**value, &**tag))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            matches,
+            vec![
+                ("author", "Ann", "Artist"),
+                ("gps", "122.420000", "GPSLongitude"),
+                ("email", "ann@example.com", "dc:creator"),
+                ("author", "ann@example.com", "dc:creator"),
+            ]
+        );
+        assert_eq!(output.len(), body.len());
+        // the artist is only alerted on, the longitude is stripped
+        assert!(output.windows(3).any(|x| x == b"Ann"));
+        assert!(!output.windows(4).any(|x| x == 122u32.to_be_bytes()));
+        assert!(!output.windows(15).any(|x| x == b"ann@example.com"));
+        assert!(output.ends_with(&[0xFF, 0xDA, 0, 2, 1, 2, 3, 0xFF, 0xD9]));
+    }
+
+    #[test]
+    fn test_png_metadata() {
+        let mut body = b"\x89PNG\r\n\x1a\n".to_vec();
+        let mut chunk = |chunk_type: &[u8], data: &[u8]| {
+            body.extend((data.len() as u32).to_be_bytes());
+            body.extend(chunk_type);
+            body.extend(data);
+            let mut crc = Crc::new();
+            crc.update(chunk_type);
+            crc.update(data);
+            body.extend(crc.sum().to_be_bytes());
+        };
+        chunk(b"IHDR", &[0; 13]);
+        chunk(b"tEXt", b"Comment\0mail ann@example.com");
+        chunk(b"tEXt", b"Software\0paint");
+        chunk(b"IEND", &[]);
+
+        let (matches, output) = scan_image(POLICY, ImageFormat::Png, &body);
+        assert_eq!(
+            matches,
+            vec![(
+                "email".to_string(),
+                "ann@example.com".to_string(),
+                "Comment".to_string()
+            )]
+        );
+        let mut expected = b"\x89PNG\r\n\x1a\n".to_vec();
+        expected.extend(&body[8..33]);
+        expected.extend(&body[body.len() - 38..]);
+        assert_eq!(output, expected);
+    }
+}
@@ -0,0 +1,407 @@
+use futures::{io::sink, AsyncReadExt};
+
+use crate::pipe::PipeReader;
+use anyhow::{bail, Result};
+use std::io::ErrorKind;
+
+const PNG_SIGNATURE: &[u8] = b"\x89PNG\r\n\x1a\n";
+const EXIF_HEADER: &[u8] = b"Exif\0\0";
+const XMP_HEADER: &[u8] = b"http://ns.adobe.com/xap/1.0/\0";
+const PHOTOSHOP_HEADER: &[u8] = b"Photoshop 3.0\0";
+const PNG_XMP_KEYWORD: &[u8] = b"XML:com.adobe.xmp";
+/// metadata chunks larger than this are skipped instead of scanned
+const MAX_METADATA_SIZE: usize = 1024 * 1024 * 4;
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum ImageFormat {
+    Jpeg,
+    Png,
+    Webp,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum BlockKind {
+    /// TIFF structured EXIF data
+    Exif,
+    /// XMP packet
+    Xmp,
+    /// photoshop image resources, holding IPTC-IIM records
+    Photoshop,
+    /// png `tEXt`, `zTXt` or `iTXt` chunk
+    PngText,
+}
+
+/// A jpeg segment, png chunk or webp chunk holding metadata
+pub struct Block {
+    pub kind: BlockKind,
+    /// body offset of `data`
+    pub start: usize,
+    /// entire data of the segment or chunk
+    pub data: Vec<u8>,
+    /// offset of the metadata within `data`, past any identifying header
+    pub payload: usize,
+    /// type of a png chunk, whose crc has to be updated when `data` changes
+    pub png_type: Option<[u8; 4]>,
+}
+
+impl Block {
+    fn new(kind: BlockKind, start: usize, data: Vec<u8>, payload: usize) -> Self {
+        Self {
+            kind,
+            start,
+            data,
+            payload,
+            png_type: None,
+        }
+    }
+}
+
+async fn read_array<const N: usize>(input: &mut PipeReader) -> Result<[u8; N]> {
+    let mut out = [0u8; N];
+    input.read_exact(&mut out[..]).await?;
+    Ok(out)
+}
+
+/// reads `N` bytes, `None` at the end of the body
+async fn maybe_read_array<const N: usize>(input: &mut PipeReader) -> Result<Option<[u8; N]>> {
+    let mut out = [0u8; N];
+    match input.read_exact(&mut out[..]).await {
+        Ok(()) => Ok(Some(out)),
+        Err(e) if e.kind() == ErrorKind::UnexpectedEof => Ok(None),
+        Err(e) => Err(e.into()),
+    }
+}
+
+/// reads `length` bytes, which are only allocated as they arrive
+async fn read_data(input: &mut PipeReader, length: usize) -> Result<Vec<u8>> {
+    let mut data = vec![];
+    (&mut *input)
+        .take(length as u64)
+        .read_to_end(&mut data)
+        .await?;
+    if data.len() < length {
+        bail!("image truncated");
+    }
+    Ok(data)
+}
+
+async fn skip(input: &mut PipeReader, length: usize) -> Result<()> {
+    let skipped = futures::io::copy((&mut *input).take(length as u64), &mut sink()).await?;
+    if skipped < length as u64 {
+        bail!("image truncated");
+    }
+    Ok(())
+}
+
+/// reads through the rest of the body
+pub async fn skip_to_end(input: &mut PipeReader) -> Result<()> {
+    futures::io::copy(input, &mut sink()).await?;
+    Ok(())
+}
+
+/// Walks the segments or chunks of an image, returning the ones holding metadata
+pub struct ImageReader {
+    format: ImageFormat,
+    started: bool,
+    finished: bool,
+}
+
+impl ImageReader {
+    pub fn new(format: ImageFormat) -> Self {
+        Self {
+            format,
+            started: false,
+            finished: false,
+        }
+    }
+
+    /// returns the next metadata block, `None` once no more metadata can follow
+    pub async fn next_block(&mut self, input: &mut PipeReader) -> Result<Option<Block>> {
+        if self.finished {
+            return Ok(None);
+        }
+        if !self.started {
+            self.started = true;
+            match self.format {
+                ImageFormat::Jpeg => {
+                    if read_array::<2>(input).await? != [0xFF, 0xD8] {
+                        bail!("malformed jpeg, missing start of image");
+                    }
+                }
+                ImageFormat::Png => {
+                    if read_array::<8>(input).await? != PNG_SIGNATURE {
+                        bail!("malformed png, missing signature");
+                    }
+                }
+                ImageFormat::Webp => {
+                    let header = read_array::<12>(input).await?;
+                    if &header[..4] != b"RIFF" || &header[8..] != b"WEBP" {
+                        bail!("malformed webp, missing riff header");
+                    }
+                }
+            }
+        }
+        let block = match self.format {
+            ImageFormat::Jpeg => next_jpeg_block(input).await?,
+            ImageFormat::Png => next_png_block(input).await?,
+            ImageFormat::Webp => next_webp_block(input).await?,
+        };
+        if block.is_none() {
+            self.finished = true;
+        }
+        Ok(block)
+    }
+}
+
+/// metadata segments all precede the first scan, which ends the search
+async fn next_jpeg_block(input: &mut PipeReader) -> Result<Option<Block>> {
+    loop {
+        if read_array::<1>(input).await?[0] != 0xFF {
+            bail!(
+                "malformed jpeg, expected marker @ {}",
+                input.total_read() - 1
+            );
+        }
+        let mut marker = read_array::<1>(input).await?[0];
+        while marker == 0xFF {
+            marker = read_array::<1>(input).await?[0];
+        }
+        match marker {
+            // start of scan, end of image
+            0xDA | 0xD9 => return Ok(None),
+            // markers without a length
+            0x01 | 0xD0..=0xD7 => continue,
+            _ => (),
+        }
+        let length = u16::from_be_bytes(read_array(input).await?) as usize;
+        if length < 2 {
+            bail!("malformed jpeg, segment length underflow");
+        }
+        let length = length - 2;
+        if !matches!(marker, 0xE1 | 0xED) {
+            skip(input, length).await?;
+            continue;
+        }
+        let start = input.total_read();
+        let data = read_data(input, length).await?;
+        let kind = match marker {
+            0xE1 if data.starts_with(EXIF_HEADER) => (BlockKind::Exif, EXIF_HEADER.len()),
+            0xE1 if data.starts_with(XMP_HEADER) => (BlockKind::Xmp, XMP_HEADER.len()),
+            0xED if data.starts_with(PHOTOSHOP_HEADER) => {
+                (BlockKind::Photoshop, PHOTOSHOP_HEADER.len())
+            }
+            _ => continue,
+        };
+        return Ok(Some(Block::new(kind.0, start, data, kind.1)));
+    }
+}
+
+/// returns the offset of the text of an uncompressed `iTXt` chunk holding an XMP packet
+fn png_xmp_payload(data: &[u8]) -> Option<usize> {
+    let rest = data.strip_prefix(PNG_XMP_KEYWORD)?.strip_prefix(b"\0")?;
+    // compression flag and method
+    if rest.len() < 2 || rest[0] != 0 {
+        return None;
+    }
+    let mut index = data.len() - rest.len() + 2;
+    // language tag and translated keyword
+    for _ in 0..2 {
+        index += data[index..].iter().position(|x| *x == 0)? + 1;
+    }
+    Some(index)
+}
+
+/// text chunks may follow the image data, chunks are read until the end of the image
+async fn next_png_block(input: &mut PipeReader) -> Result<Option<Block>> {
+    loop {
+        let header = match maybe_read_array::<8>(input).await? {
+            Some(x) => x,
+            None => return Ok(None),
+        };
+        let length = u32::from_be_bytes(header[..4].try_into().unwrap()) as usize;
+        let chunk_type: [u8; 4] = header[4..].try_into().unwrap();
+        let kind = match &chunk_type {
+            b"IEND" => return Ok(None),
+            b"eXIf" => BlockKind::Exif,
+            b"tEXt" | b"zTXt" | b"iTXt" => BlockKind::PngText,
+            _ => {
+                // data and crc
+                skip(input, length + 4).await?;
+                continue;
+            }
+        };
+        if length > MAX_METADATA_SIZE {
+            skip(input, length + 4).await?;
+            continue;
+        }
+        let start = input.total_read();
+        let data = read_data(input, length).await?;
+        read_array::<4>(input).await?;
+        let (kind, payload) = match png_xmp_payload(&data) {
+            Some(payload) if &chunk_type == b"iTXt" => (BlockKind::Xmp, payload),
+            _ => (kind, 0),
+        };
+        let mut block = Block::new(kind, start, data, payload);
+        block.png_type = Some(chunk_type);
+        return Ok(Some(block));
+    }
+}
+
+/// metadata chunks follow the image data, chunks are read until the end of the image
+async fn next_webp_block(input: &mut PipeReader) -> Result<Option<Block>> {
+    loop {
+        let header = match maybe_read_array::<8>(input).await? {
+            Some(x) => x,
+            None => return Ok(None),
+        };
+        let length = u32::from_le_bytes(header[4..].try_into().unwrap()) as usize;
+        let padding = length % 2;
+        let kind = match &header[..4] {
+            b"EXIF" => BlockKind::Exif,
+            b"XMP " => BlockKind::Xmp,
+            _ => {
+                skip(input, length + padding).await?;
+                continue;
+            }
+        };
+        if length > MAX_METADATA_SIZE {
+            skip(input, length + padding).await?;
+            continue;
+        }
+        let start = input.total_read();
+        let data = read_data(input, length).await?;
+        if padding != 0 {
+            // a missing final padding byte is tolerated
+            maybe_read_array::<1>(input).await?;
+        }
+        // some writers keep the jpeg header in EXIF chunks
+        let payload = if kind == BlockKind::Exif && data.starts_with(EXIF_HEADER) {
+            EXIF_HEADER.len()
+        } else {
+            0
+        };
+        return Ok(Some(Block::new(kind, start, data, payload)));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{sync::Arc, task::Poll};
+
+    use futures::{pin_mut, task::waker, Future};
+
+    use crate::pipe::{pipe, DummyWaker};
+
+    use super::*;
+
+    fn read_blocks(format: ImageFormat, body: &[u8]) -> Vec<(BlockKind, usize, Vec<u8>)> {
+        let (mut reader, mut writer) = pipe(0);
+        assert!(writer.append(body));
+        drop(writer);
+        let waker = waker(Arc::new(DummyWaker));
+        let mut context = std::task::Context::from_waker(&waker);
+        let future = async {
+            let mut blocks = vec![];
+            let mut image = ImageReader::new(format);
+            while let Some(block) = image.next_block(&mut reader).await.unwrap() {
+                blocks.push((
+                    block.kind,
+                    block.start,
+                    block.data[block.payload..].to_vec(),
+                ));
+            }
+            blocks
+        };
+        pin_mut!(future);
+        match future.poll(&mut context) {
+            Poll::Ready(blocks) => blocks,
+            Poll::Pending => panic!("image parse pending"),
+        }
+    }
+
+    #[test]
+    fn test_jpeg_blocks() {
+        let mut body = vec![0xFF, 0xD8];
+        // jfif
+        body.extend([0xFF, 0xE0, 0, 4, 1, 2]);
+        body.extend([0xFF, 0xE1, 0, 12]);
+        body.extend(b"Exif\0\0tiff");
+        body.extend([0xFF, 0xED, 0, 20]);
+        body.extend(b"Photoshop 3.0\08BIM");
+        body.extend([0xFF, 0xDA, 0, 2, 0xFF, 0xE1, 0, 3, 0]);
+        assert_eq!(
+            read_blocks(ImageFormat::Jpeg, &body),
+            vec![
+                (BlockKind::Exif, 12, b"tiff".to_vec()),
+                (BlockKind::Photoshop, 26, b"8BIM".to_vec()),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_png_blocks() {
+        let mut body = PNG_SIGNATURE.to_vec();
+        let mut chunk = |chunk_type: &[u8], data: &[u8]| {
+            body.extend((data.len() as u32).to_be_bytes());
+            body.extend(chunk_type);
+            body.extend(data);
+            body.extend([0; 4]);
+        };
+        chunk(b"IHDR", &[0; 13]);
+        chunk(b"IDAT", &[1, 2, 3]);
+        chunk(b"tEXt", b"Comment\0hello");
+        chunk(b"iTXt", b"XML:com.adobe.xmp\0\0\0\0\0<x/>");
+        chunk(b"IEND", &[]);
+        assert_eq!(
+            read_blocks(ImageFormat::Png, &body),
+            vec![
+                (BlockKind::PngText, 56, b"Comment\0hello".to_vec()),
+                (BlockKind::Xmp, 81, b"<x/>".to_vec()),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_oversized_blocks() {
+        let mut body = PNG_SIGNATURE.to_vec();
+        body.extend(((MAX_METADATA_SIZE + 1) as u32).to_be_bytes());
+        body.extend(b"tEXt");
+        body.extend(vec![b'a'; MAX_METADATA_SIZE + 1]);
+        body.extend([0; 4]);
+        body.extend(5u32.to_be_bytes());
+        body.extend(b"tEXt");
+        body.extend(b"a\0bcd");
+        body.extend([0; 4]);
+        let start = body.len() - 9;
+        assert_eq!(
+            read_blocks(ImageFormat::Png, &body),
+            vec![(BlockKind::PngText, start, b"a\0bcd".to_vec())]
+        );
+
+        let mut body = b"RIFF\0\0\0\0WEBP".to_vec();
+        body.extend(b"EXIF");
+        body.extend(((MAX_METADATA_SIZE + 1) as u32).to_le_bytes());
+        body.extend(vec![b'a'; MAX_METADATA_SIZE + 2]);
+        body.extend(b"XMP \x04\0\0\0<x/>");
+        let start = body.len() - 4;
+        assert_eq!(
+            read_blocks(ImageFormat::Webp, &body),
+            vec![(BlockKind::Xmp, start, b"<x/>".to_vec())]
+        );
+    }
+
+    #[test]
+    fn test_webp_blocks() {
+        let mut body = b"RIFF\0\0\0\0WEBP".to_vec();
+        body.extend(b"VP8 \x03\0\0\0abc\0");
+        body.extend(b"EXIF\x0a\0\0\0Exif\0\0tiff");
+        body.extend(b"XMP \x04\0\0\0<x/>");
+        assert_eq!(
+            read_blocks(ImageFormat::Webp, &body),
+            vec![
+                (BlockKind::Exif, 32, b"tiff".to_vec()),
+                (BlockKind::Xmp, 50, b"<x/>".to_vec()),
+            ]
+        );
+    }
+}
@@ -0,0 +1,209 @@
+use crate::decode::parse_entity;
+
+/// A property value of an XMP packet
+#[derive(PartialEq, Eq, Debug)]
+pub struct XmpProperty {
+    /// qualified name of the property, i.e. `dc:creator`
+    pub name: String,
+    /// decoded value
+    pub value: String,
+    /// span of the encoded value within the packet
+    pub start: usize,
+    pub end: usize,
+}
+
+/// rdf containers and descriptions don't name properties, their values belong to the enclosing property
+fn is_property(name: &str) -> bool {
+    !name.starts_with("rdf:") && !name.starts_with("x:") && name != "?xpacket"
+}
+
+/// attributes that are namespace declarations or rdf and xml syntax rather than properties
+fn is_property_attribute(name: &str) -> bool {
+    is_property(name) && !name.starts_with("xmlns") && !name.starts_with("xml:")
+}
+
+fn decode_value(source: &[u8]) -> String {
+    let mut out = String::new();
+    let mut i = 0usize;
+    while i < source.len() {
+        if source[i] == b'&' {
+            if let Some((c, length)) = parse_entity(&source[i..]) {
+                out.push(c);
+                i += length;
+                continue;
+            }
+        }
+        let end = source[i + 1..]
+            .iter()
+            .position(|x| *x == b'&')
+            .map(|x| i + 1 + x)
+            .unwrap_or(source.len());
+        out.push_str(&String::from_utf8_lossy(&source[i..end]));
+        i = end;
+    }
+    out
+}
+
+fn is_name_byte(byte: u8) -> bool {
+    !byte.is_ascii_whitespace() && !matches!(byte, b'>' | b'/' | b'=')
+}
+
+/// reads the property values of an XMP packet, text of elements and attributes of descriptions.
+/// malformed markup ends the packet
+pub fn read_xmp(data: &[u8]) -> Vec<XmpProperty> {
+    let mut properties = vec![];
+    // names of the open elements
+    let mut stack: Vec<String> = vec![];
+    let property = |stack: &[String]| stack.iter().rev().find(|x| is_property(x)).cloned();
+    let mut i = 0usize;
+    while i < data.len() {
+        if data[i] != b'<' {
+            let end = data[i..]
+                .iter()
+                .position(|x| *x == b'<')
+                .map(|x| i + x)
+                .unwrap_or(data.len());
+            let value = decode_value(&data[i..end]);
+            if !value.trim().is_empty() {
+                if let Some(name) = property(&stack) {
+                    properties.push(XmpProperty {
+                        name,
+                        value,
+                        start: i,
+                        end,
+                    });
+                }
+            }
+            i = end;
+            continue;
+        }
+        i += 1;
+        match data.get(i) {
+            Some(b'/') => {
+                stack.pop();
+                match data[i..].iter().position(|x| *x == b'>') {
+                    Some(x) => i += x + 1,
+                    None => break,
+                }
+                continue;
+            }
+            Some(b'!' | b'?') => {
+                let end: &[u8] = if data[i..].starts_with(b"!--") {
+                    b"-->"
+                } else {
+                    b">"
+                };
+                match data[i..].windows(end.len()).position(|x| x == end) {
+                    Some(x) => i += x + end.len(),
+                    None => break,
+                }
+                continue;
+            }
+            Some(_) => (),
+            None => break,
+        }
+        let name_end = data[i..]
+            .iter()
+            .position(|x| !is_name_byte(*x))
+            .map(|x| i + x)
+            .unwrap_or(data.len());
+        let name = String::from_utf8_lossy(&data[i..name_end]).into_owned();
+        i = name_end;
+        let mut self_closing = false;
+        loop {
+            while i < data.len() && data[i].is_ascii_whitespace() {
+                i += 1;
+            }
+            match data.get(i) {
+                Some(b'>') => {
+                    i += 1;
+                    break;
+                }
+                Some(b'/') => {
+                    self_closing = true;
+                    i += 1;
+                    continue;
+                }
+                Some(_) => (),
+                None => return properties,
+            }
+            let attribute_end = data[i..]
+                .iter()
+                .position(|x| !is_name_byte(*x))
+                .map(|x| i + x)
+                .unwrap_or(data.len());
+            let attribute = String::from_utf8_lossy(&data[i..attribute_end]).into_owned();
+            i = attribute_end;
+            while i < data.len() && data[i].is_ascii_whitespace() {
+                i += 1;
+            }
+            if data.get(i) != Some(&b'=') {
+                return properties;
+            }
+            i += 1;
+            while i < data.len() && data[i].is_ascii_whitespace() {
+                i += 1;
+            }
+            let quote = match data.get(i) {
+                Some(x @ (b'"' | b'\'')) => *x,
+                _ => return properties,
+            };
+            let start = i + 1;
+            let end = match data[start..].iter().position(|x| *x == quote) {
+                Some(x) => start + x,
+                None => return properties,
+            };
+            i = end + 1;
+            if !is_property_attribute(&attribute) {
+                continue;
+            }
+            let value = decode_value(&data[start..end]);
+            if !value.trim().is_empty() {
+                properties.push(XmpProperty {
+                    name: attribute,
+                    value,
+                    start,
+                    end,
+                });
+            }
+        }
+        if !self_closing {
+            stack.push(name);
+        }
+    }
+    properties
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_read_xmp() {
+        let packet = r#"<?xpacket begin="" id="W5M0MpCehiHzreSzNTczkc9d"?>
+<x:xmpmeta xmlns:x="adobe:ns:meta/">
+ <rdf:RDF xmlns:rdf="http://www.w3.org/1999/02/22-rdf-syntax-ns#">
+  <rdf:Description rdf:about="" xmlns:exif="http://ns.adobe.com/exif/1.0/" exif:GPSLatitude="37,46.494N">
+   <!-- <dc:rights>comment</dc:rights> -->
+   <dc:creator><rdf:Seq><rdf:li>Jane &amp; John</rdf:li></rdf:Seq></dc:creator>
+   <aux:SerialNumber>SN123</aux:SerialNumber>
+   <dc:subject/>
+  </rdf:Description>
+ </rdf:RDF>
+</x:xmpmeta>
+<?xpacket end="w"?>"#;
+        let properties = read_xmp(packet.as_bytes());
+        let properties = properties
+            .iter()
+            .map(|x| (&*x.name, &*x.value, &packet[x.start..x.end]))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            properties,
+            vec![
+                ("exif:GPSLatitude", "37,46.494N", "37,46.494N"),
+                ("dc:creator", "Jane & John", "Jane &amp; John"),
+                ("aux:SerialNumber", "SN123", "SN123"),
+            ]
+        );
+    }
+}
@@ -1,123 +1,849 @@
 use anyhow::Result;
-use std::sync::Arc;
+use fancy_regex::Regex;
+use futures::AsyncReadExt;
+use log::warn;
+use std::{cell::RefCell, sync::Arc};
 
 use indexmap::IndexMap;
-use leakpolicy::{MatchContext, PathConfiguration};
+use leakpolicy::{Direction, MatchContext, PathConfiguration};
 
 mod parse;
 
+use parse::JsonSpan;
+
 use crate::{
+    alert::AlertTracker,
     evaluator::{self, MatcherMetadata, MatcherState},
-    pipe::PipeReader,
-    policy::{ContentType, Policy, PolicyAction},
-    proto::Match,
+    pipe::{pipe, PipeReader},
+    policy::{Category, ContentType, JsonPathScope, JsonSegment, JsonValueType, Policy},
+    proto::{r#match::Location, Match},
 };
 
-use super::ParseResponse;
+use super::{replace_matches, ParseResponse, Replacement};
 
-/// returns (key, value) matcher states
-fn prepare_match_state<'a>(
-    policy: &'a Policy,
-    configuration: &'a IndexMap<Arc<String>, PathConfiguration>,
-) -> (MatcherState<'a>, MatcherState<'a>) {
-    let mut key_match_state = MatcherState::default();
-    let mut value_match_state = MatcherState::default();
+const VALUE_TYPES: [JsonValueType; 4] = [
+    JsonValueType::String,
+    JsonValueType::Number,
+    JsonValueType::Boolean,
+    JsonValueType::Null,
+];
 
-    for (category_name, action) in configuration {
-        if !action.category_config.content_types.is_empty() {
-            if !action
-                .category_config
-                .content_types
-                .contains(&ContentType::Json)
-            {
-                continue;
-            }
+/// Matcher states of json values by their type
+#[derive(Default)]
+pub struct ValueMatchers {
+    strings: MatcherState,
+    numbers: MatcherState,
+    booleans: MatcherState,
+    nulls: MatcherState,
+}
+
+impl ValueMatchers {
+    fn get(&self, value_type: JsonValueType) -> &MatcherState {
+        match value_type {
+            JsonValueType::String => &self.strings,
+            JsonValueType::Number => &self.numbers,
+            JsonValueType::Boolean => &self.booleans,
+            JsonValueType::Null => &self.nulls,
         }
+    }
 
-        let mut do_key = true;
-        let mut do_value = true;
-        if !action.category_config.contexts.is_empty() {
-            if !action
-                .category_config
-                .contexts
-                .contains(&MatchContext::Keys)
-            {
-                do_key = false;
-            }
-            if !action
-                .category_config
-                .contexts
-                .contains(&MatchContext::Values)
-            {
-                do_value = false;
+    fn get_mut(&mut self, value_type: JsonValueType) -> &mut MatcherState {
+        match value_type {
+            JsonValueType::String => &mut self.strings,
+            JsonValueType::Number => &mut self.numbers,
+            JsonValueType::Boolean => &mut self.booleans,
+            JsonValueType::Null => &mut self.nulls,
+        }
+    }
+
+    fn compile(&mut self) {
+        for value_type in VALUE_TYPES {
+            self.get_mut(value_type).compile();
+        }
+    }
+}
+
+/// Matcher states of a `Keyed` category, only used for values under a matching key
+pub struct KeyedMatchers {
+    keys: Vec<Regex>,
+    values: ValueMatchers,
+}
+
+impl KeyedMatchers {
+    fn matches_key(&self, key: &str) -> bool {
+        self.keys
+            .iter()
+            .any(|regex| regex.is_match(key).unwrap_or_default())
+    }
+}
+
+/// Matcher states of the categories sharing the same `paths` scope
+#[derive(Default)]
+pub struct ScopedMatchers {
+    pub scope: JsonPathScope,
+    pub keys: MatcherState,
+    pub values: ValueMatchers,
+    pub keyed: Vec<KeyedMatchers>,
+}
+
+#[derive(Default)]
+pub struct JsonMatchers {
+    pub scopes: Vec<ScopedMatchers>,
+    /// see `Policy::embedded_json_depth`
+    pub embedded_depth: usize,
+    /// longest ndjson line buffered for scanning, only the start of longer lines is scanned
+    pub max_line_size: usize,
+}
+
+impl JsonMatchers {
+    fn scope_mut(&mut self, scope: &JsonPathScope) -> &mut ScopedMatchers {
+        let index = match self.scopes.iter().position(|x| &x.scope == scope) {
+            Some(index) => index,
+            None => {
+                self.scopes.push(ScopedMatchers {
+                    scope: scope.clone(),
+                    ..Default::default()
+                });
+                self.scopes.len() - 1
             }
+        };
+        &mut self.scopes[index]
+    }
+
+    fn in_scope<'a>(&'a self, path: &'a [JsonSegment]) -> impl Iterator<Item = &'a ScopedMatchers> {
+        self.scopes.iter().filter(|x| x.scope.contains(path))
+    }
+}
+
+/// prepares the matchers of json documents, filtering categories on `content_type`:
+/// `Json` for json bodies, `Html` for json scripts of html bodies
+pub fn prepare_match_state(
+    policy: &Policy,
+    configuration: &IndexMap<Arc<String>, PathConfiguration>,
+    direction: Direction,
+    content_type: &ContentType,
+) -> JsonMatchers {
+    let mut matchers = JsonMatchers {
+        embedded_depth: policy.embedded_json_depth,
+        max_line_size: MAX_LINE_SIZE,
+        ..Default::default()
+    };
+
+    for (category_name, action) in configuration {
+        if !action.scans_content(direction, content_type) {
+            continue;
         }
+
+        let do_key = action.matches_context(MatchContext::Keys, &MatchContext::STRUCTURED);
+        let do_value = action.matches_context(MatchContext::Values, &MatchContext::STRUCTURED);
         if !do_key && !do_value {
             continue;
         }
 
-        if matches!(
-            action.category_config.action.unwrap_or_default(),
-            PolicyAction::Ignore
-        ) {
+        let metadata = MatcherMetadata::new(category_name, action);
+        let scoped = matchers.scope_mut(&action.category_config.paths);
+
+        if let Some(Category::Keyed { keys, values }) = policy.categories.get(&**category_name) {
+            if !do_value {
+                continue;
+            }
+            let values = match values {
+                Some(values) => match values.match_group(policy) {
+                    Some(x) => Some(x),
+                    None => {
+                        warn!("keyed match group for '{category_name}' is missing its values");
+                        continue;
+                    }
+                },
+                None => None,
+            };
+            let mut keyed = KeyedMatchers {
+                keys: keys.iter().map(|x| x.0.clone()).collect(),
+                values: ValueMatchers::default(),
+            };
+            for value_type in VALUE_TYPES {
+                if !action.matches_value_type(value_type) {
+                    continue;
+                }
+                let state = keyed.values.get_mut(value_type);
+                evaluator::prepare_decoded(state, &metadata, |state, metadata| match values {
+                    Some(values) => evaluator::prepare_match_group(
+                        values,
+                        state,
+                        metadata,
+                        &action.category_config.ignore,
+                    ),
+                    None => evaluator::prepare_whole_value(
+                        state,
+                        metadata,
+                        &action.category_config.ignore,
+                    ),
+                });
+            }
+            scoped.keyed.push(keyed);
             continue;
         }
 
-        let metadata = MatcherMetadata {
-            policy_path: action.matcher_path.clone(),
-            category_name: category_name.to_string(),
-            action: action.category_config.action.unwrap_or_default(),
-            local_report_style: action.report_style,
-            correlation: None,
-        };
-
         if do_key {
             evaluator::prepare_matches(
                 &policy,
                 &**category_name,
-                &mut key_match_state,
+                &mut scoped.keys,
                 &metadata,
                 &action.category_config.ignore,
             );
         }
         if do_value {
-            evaluator::prepare_matches(
-                &policy,
-                &**category_name,
-                &mut value_match_state,
-                &metadata,
-                &action.category_config.ignore,
-            );
+            for value_type in VALUE_TYPES {
+                if !action.matches_value_type(value_type) {
+                    continue;
+                }
+                evaluator::prepare_matches(
+                    &policy,
+                    &**category_name,
+                    scoped.values.get_mut(value_type),
+                    &metadata,
+                    &action.category_config.ignore,
+                );
+            }
+        }
+    }
+    for scoped in &mut matchers.scopes {
+        scoped.keys.compile();
+        scoped.values.compile();
+        for keyed in &mut scoped.keyed {
+            keyed.values.compile();
+        }
+    }
+
+    matchers
+}
+
+/// masks the decoded json value at `span` in the body. strings are re-encoded as a json string
+/// and escaped again for each json string they are embedded in, other values are replaced whole
+/// by a literal of their type so the document keeps its schema
+fn mask_json_value(
+    value: &str,
+    value_type: JsonValueType,
+    span: JsonSpan,
+    masks: Vec<Replacement>,
+    output: &mut Vec<Replacement>,
+) {
+    if masks.is_empty() {
+        return;
+    }
+    let encoded = match value_type {
+        JsonValueType::String => {
+            let mut local_masks = masks
+                .into_iter()
+                .map(|mask| Replacement {
+                    start: mask.start - span.start,
+                    ..mask
+                })
+                .collect::<Vec<_>>();
+            local_masks.sort_by_key(|x| x.start);
+            let masked = replace_matches(value.as_bytes(), &local_masks);
+            let mut encoded = serde_json::to_string(&*String::from_utf8_lossy(&masked))
+                .expect("failed to serialize json string");
+            for _ in 0..span.embedded {
+                let escaped =
+                    serde_json::to_string(&encoded).expect("failed to serialize json string");
+                encoded = escaped[1..escaped.len() - 1].to_string();
+            }
+            encoded
         }
+        JsonValueType::Number => "0".to_string(),
+        JsonValueType::Boolean => "false".to_string(),
+        JsonValueType::Null => "null".to_string(),
+    };
+    output.push(Replacement {
+        start: span.start,
+        length: span.end - span.start,
+        value: encoded.into_bytes(),
+    });
+}
+
+fn offset_span(span: JsonSpan, offset: usize) -> JsonSpan {
+    JsonSpan {
+        start: span.start + offset,
+        end: span.end + offset,
+        ..span
     }
+}
 
-    (key_match_state, value_match_state)
+/// the key of the closest object containing the value at `path`
+fn nearest_key(path: &[JsonSegment]) -> Option<&str> {
+    path.iter().rev().find_map(|segment| match segment {
+        JsonSegment::Key(key) => Some(&**key),
+        JsonSegment::Index(_) => None,
+    })
+}
+
+fn set_json_pointer(matches: &mut [Match], path: &[JsonSegment]) {
+    if matches.is_empty() {
+        return;
+    }
+    let pointer = parse::json_pointer(path);
+    for matching in matches {
+        matching.location = Some(Location::JsonPointer(pointer.clone()));
+    }
 }
 
 pub async fn parse_json(
-    policy: &Policy,
+    matchers: &JsonMatchers,
+    body: &mut PipeReader,
+    matches: &mut Vec<Match>,
+    masks: &mut Vec<Replacement>,
+    alerts: &mut AlertTracker,
+) -> Result<ParseResponse> {
+    scan_json(
+        matchers,
+        body,
+        0,
+        matchers.embedded_depth,
+        matches,
+        masks,
+        alerts,
+    )
+    .await
+}
+
+/// scans the json document of an html `<script>` block, found at `offset` in the body
+pub async fn parse_json_script(
+    matchers: &JsonMatchers,
+    offset: usize,
+    script: &[u8],
+    matches: &mut Vec<Match>,
+    masks: &mut Vec<Replacement>,
+    alerts: &mut AlertTracker,
+) -> Result<ParseResponse> {
+    let (mut reader, mut writer) = pipe(0);
+    writer.append(script);
+    drop(writer);
+    scan_json(
+        matchers,
+        &mut reader,
+        offset,
+        matchers.embedded_depth.saturating_sub(1),
+        matches,
+        masks,
+        alerts,
+    )
+    .await
+}
+
+const CHUNK_SIZE: usize = 1024 * 64;
+/// default `JsonMatchers::max_line_size`
+const MAX_LINE_SIZE: usize = 1024 * 1024 * 4;
+
+/// scans each line of the body as a json document, skipping malformed lines
+pub async fn parse_ndjson(
+    matchers: &JsonMatchers,
     body: &mut PipeReader,
-    configuration: &IndexMap<Arc<String>, PathConfiguration>,
     matches: &mut Vec<Match>,
+    masks: &mut Vec<Replacement>,
+    alerts: &mut AlertTracker,
 ) -> Result<ParseResponse> {
-    let (key_matcher, value_matcher) = prepare_match_state(policy, configuration);
+    let mut chunk = vec![0u8; CHUNK_SIZE];
+    let mut line = vec![];
+    let mut line_start = 0usize;
+    // set while the rest of an overlong line is skipped
+    let mut skipping = false;
+    loop {
+        let offset = body.total_read();
+        let chunk_len = body.read(&mut chunk[..]).await?;
+        let mut lines = vec![];
+        for (i, byte) in chunk[..chunk_len].iter().enumerate() {
+            if *byte == b'\n' {
+                if !skipping {
+                    lines.push((line_start, std::mem::take(&mut line), false));
+                }
+                skipping = false;
+                line_start = offset + i + 1;
+            } else if !skipping {
+                line.push(*byte);
+                if line.len() >= matchers.max_line_size {
+                    warn!(
+                        "ndjson line @ {line_start} exceeds {} bytes, scanning only its start",
+                        matchers.max_line_size
+                    );
+                    lines.push((line_start, std::mem::take(&mut line), true));
+                    skipping = true;
+                }
+            }
+        }
+        if chunk_len == 0 && !skipping {
+            lines.push((line_start, std::mem::take(&mut line), false));
+        }
+
+        for (start, line, truncated) in lines {
+            if line.iter().all(u8::is_ascii_whitespace) {
+                continue;
+            }
+            let (mut reader, mut writer) = pipe(0);
+            writer.append(line);
+            drop(writer);
+            let response = scan_json(
+                matchers,
+                &mut reader,
+                start,
+                matchers.embedded_depth,
+                matches,
+                masks,
+                alerts,
+            )
+            .await;
+            match response {
+                Ok(ParseResponse::Continue) => (),
+                Ok(ParseResponse::Block) => return Ok(ParseResponse::Block),
+                // the start of an overlong line ends within the document
+                Err(_) if truncated => (),
+                Err(e) => warn!("skipping malformed ndjson line @ {start}: {e:?}"),
+            }
+        }
+        if chunk_len == 0 {
+            break;
+        }
+    }
+    Ok(ParseResponse::Continue)
+}
+
+/// scans a json document found at `offset` in the body
+async fn scan_json(
+    matchers: &JsonMatchers,
+    body: &mut PipeReader,
+    offset: usize,
+    depth: usize,
+    matches: &mut Vec<Match>,
+    masks: &mut Vec<Replacement>,
+    alerts: &mut AlertTracker,
+) -> Result<ParseResponse> {
+    // shared by the key and value callbacks
+    let alerts = RefCell::new(alerts);
 
     let mut key_matches = vec![];
+    let mut key_masks = vec![];
 
-    parse::parse_json(
+    let response = parse::parse_json(
         body,
-        |key, start, _end| match key_matcher.do_matching(start, 0, &*key, &mut key_matches) {
-            ParseResponse::Continue => None,
-            ParseResponse::Block => Some(ParseResponse::Block),
+        depth,
+        |key, path, span| {
+            let span = offset_span(span, offset);
+            let mut local_masks = vec![];
+            let first_match = key_matches.len();
+            let mut alerts = alerts.borrow_mut();
+            let mut response = ParseResponse::Continue;
+            for scoped in matchers.in_scope(path) {
+                let scoped_response = scoped.keys.do_matching(
+                    span.start,
+                    0,
+                    &*key,
+                    &mut key_matches,
+                    &mut local_masks,
+                    &mut alerts,
+                );
+                if matches!(scoped_response, ParseResponse::Block) {
+                    response = ParseResponse::Block;
+                }
+            }
+            set_json_pointer(&mut key_matches[first_match..], path);
+            mask_json_value(
+                &key,
+                JsonValueType::String,
+                span,
+                local_masks,
+                &mut key_masks,
+            );
+            match response {
+                ParseResponse::Continue => None,
+                ParseResponse::Block => Some(ParseResponse::Block),
+            }
         },
-        |value, start, _end| match value_matcher.do_matching(start, 0, &*value, matches) {
-            ParseResponse::Continue => None,
-            ParseResponse::Block => Some(ParseResponse::Block),
+        |value, value_type, path, span| {
+            let span = offset_span(span, offset);
+            let mut local_masks = vec![];
+            let first_match = matches.len();
+            let mut alerts = alerts.borrow_mut();
+            let mut response = ParseResponse::Continue;
+            let key = nearest_key(path);
+            for scoped in matchers.in_scope(path) {
+                let keyed = scoped
+                    .keyed
+                    .iter()
+                    .filter(|keyed| key.map(|key| keyed.matches_key(key)).unwrap_or_default())
+                    .map(|keyed| &keyed.values);
+                for values in std::iter::once(&scoped.values).chain(keyed) {
+                    let scoped_response = values.get(value_type).do_matching(
+                        span.start,
+                        0,
+                        &*value,
+                        matches,
+                        &mut local_masks,
+                        &mut alerts,
+                    );
+                    if matches!(scoped_response, ParseResponse::Block) {
+                        response = ParseResponse::Block;
+                    }
+                }
+            }
+            set_json_pointer(&mut matches[first_match..], path);
+            mask_json_value(&value, value_type, span, local_masks, masks);
+            match response {
+                ParseResponse::Continue => None,
+                ParseResponse::Block => Some(ParseResponse::Block),
+            }
         },
     )
     .await?;
 
     matches.extend(key_matches);
+    masks.extend(key_masks);
+    masks.sort_by_key(|x| x.start);
 
-    Ok(ParseResponse::Continue)
+    Ok(response.unwrap_or(ParseResponse::Continue))
+}
+
+#[cfg(test)]
+mod tests {
+    use futures::{pin_mut, task::waker, Future};
+    use std::task::Poll;
+
+    use crate::pipe::{pipe, DummyWaker};
+
+    use super::*;
+
+    /// returns the matches and masked body
+    fn run_json(policy: &str, body: &str) -> (Vec<Match>, String) {
+        let policy: Policy = serde_yaml::from_str(policy).unwrap();
+        let path_policy = policy.get_path_config("example.com/");
+        let matchers = prepare_match_state(
+            &policy,
+            &path_policy.configuration,
+            Direction::Response,
+            &ContentType::Json,
+        );
+
+        let (mut reader, mut writer) = pipe(0);
+        assert!(writer.append(body.as_bytes()));
+        drop(writer);
+        let mut matches = vec![];
+        let mut masks = vec![];
+        let mut alerts = AlertTracker::default();
+        let waker = waker(Arc::new(DummyWaker));
+        let mut context = std::task::Context::from_waker(&waker);
+        {
+            let future = parse_json(
+                &matchers,
+                &mut reader,
+                &mut matches,
+                &mut masks,
+                &mut alerts,
+            );
+            pin_mut!(future);
+            assert!(matches!(
+                future.poll(&mut context),
+                Poll::Ready(Ok(ParseResponse::Continue))
+            ));
+        }
+        let masked = replace_matches(body.as_bytes(), &masks);
+        (matches, String::from_utf8(masked.into_owned()).unwrap())
+    }
+
+    #[test]
+    fn test_keyed_values() {
+        let (matches, _) = run_json(
+            r#"
+categories:
+  digits:
+    Matchers:
+      regexes:
+        - "\\d{4}"
+  ssn_field:
+    Keyed:
+      keys: "(?i)^(ssn|social_security)$"
+  session_token:
+    Keyed:
+      keys: "_token$"
+      values:
+        name: digits
+endpoints:
+  - matches: "**"
+    config:
+      ssn_field:
+        value_types: [string, number]
+      session_token: {}
+"#,
+            r#"{"SSN": 123456789, "id": "1234", "a": {"auth_token": ["x1234", ""]}}"#,
+        );
+
+        assert_eq!(
+            matches
+                .iter()
+                .map(|x| (
+                    &*x.category_name,
+                    x.matched_value.as_deref(),
+                    x.location.as_ref().map(Location::value)
+                ))
+                .collect::<Vec<_>>(),
+            vec![
+                ("ssn_field", Some("123456789"), Some("/SSN")),
+                ("session_token", Some("1234"), Some("/a/auth_token/0")),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_default_value_types() {
+        let (matches, _) = run_json(
+            r#"
+categories:
+  digits:
+    Matchers:
+      regexes:
+        - "\\d{4}"
+endpoints:
+  - matches: "**"
+    config:
+      digits: {}
+"#,
+            r#"{"a": 1234, "b": "1234", "c": true}"#,
+        );
+
+        assert_eq!(
+            matches
+                .iter()
+                .map(|x| x.location.as_ref().map(Location::value))
+                .collect::<Vec<_>>(),
+            vec![Some("/b")]
+        );
+    }
+
+    #[test]
+    fn test_masked_number() {
+        let (matches, masked) = run_json(
+            r#"
+categories:
+  ssn:
+    Matchers:
+      regexes:
+        - "^\\d{9}$"
+endpoints:
+  - matches: "**"
+    config:
+      ssn:
+        action: mask
+        value_types: [string, number]
+"#,
+            r#"{"ssn": 123456789, "id": "123456789", "n": [1, 123456789]}"#,
+        );
+
+        assert_eq!(matches.len(), 3);
+        assert_eq!(masked, r#"{"ssn": 0, "id": "*********", "n": [1, 0]}"#);
+        assert!(serde_json::from_str::<serde_json::Value>(&masked).is_ok());
+    }
+
+    #[test]
+    fn test_scoped_paths() {
+        let (matches, _) = run_json(
+            r#"
+categories:
+  email:
+    Matchers:
+      regexes:
+        - "[a-z]+@[a-z]+\\.com"
+  digits:
+    Matchers:
+      regexes:
+        - "\\d{4}"
+endpoints:
+  - matches: "**"
+    config:
+      email:
+        paths:
+          include: ["$.data[*].customer"]
+      digits:
+        paths:
+          exclude: ["$.meta"]
+        value_types: [string, number]
+"#,
+            r#"{
+                "data": [{"customer": {"email": "a@b.com", "id": 1234}, "note": "c@d.com"}],
+                "meta": {"contact": "e@f.com", "id": 5678}
+            }"#,
+        );
+
+        assert_eq!(
+            matches
+                .iter()
+                .map(|x| (&*x.category_name, x.location.as_ref().map(Location::value)))
+                .collect::<Vec<_>>(),
+            vec![
+                ("email", Some("/data/0/customer/email")),
+                ("digits", Some("/data/0/customer/id")),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_embedded_json() {
+        let (matches, masked) = run_json(
+            r#"
+categories:
+  ssn:
+    Matchers:
+      regexes:
+        - "\\d{3}-\\d{2}-\\d{4}"
+endpoints:
+  - matches: "**"
+    config:
+      ssn:
+        action: mask
+"#,
+            r#"{"payload": "{\"user\": {\"ssn\": \"123-45-6789\"}}", "raw": "123-45-6789"}"#,
+        );
+
+        assert_eq!(
+            matches
+                .iter()
+                .map(|x| x.location.as_ref().map(Location::value))
+                .collect::<Vec<_>>(),
+            vec![Some("/payload/user/ssn"), Some("/raw")]
+        );
+        assert_eq!(
+            masked,
+            r#"{"payload": "{\"user\": {\"ssn\": \"***********\"}}", "raw": "***********"}"#
+        );
+        let payload: serde_json::Value = serde_json::from_str(&masked).unwrap();
+        assert!(
+            serde_json::from_str::<serde_json::Value>(payload["payload"].as_str().unwrap()).is_ok()
+        );
+    }
+
+    #[test]
+    fn test_ndjson() {
+        let policy: Policy = serde_yaml::from_str(
+            r#"
+categories:
+  ssn:
+    Matchers:
+      regexes:
+        - "\\d{3}-\\d{2}-\\d{4}"
+endpoints:
+  - matches: "**"
+    config:
+      ssn:
+        action: mask
+"#,
+        )
+        .unwrap();
+        let path_policy = policy.get_path_config("example.com/");
+        let matchers = prepare_match_state(
+            &policy,
+            &path_policy.configuration,
+            Direction::Response,
+            &ContentType::Json,
+        );
+
+        let body = "{\"ssn\": \"123-45-6789\"}\n{malformed\n\n[\"987-65-4321\"]";
+        let (mut reader, mut writer) = pipe(0);
+        assert!(writer.append(body.as_bytes()));
+        drop(writer);
+        let mut matches = vec![];
+        let mut masks = vec![];
+        let mut alerts = AlertTracker::default();
+        let waker = waker(Arc::new(DummyWaker));
+        let mut context = std::task::Context::from_waker(&waker);
+        {
+            let future = parse_ndjson(
+                &matchers,
+                &mut reader,
+                &mut matches,
+                &mut masks,
+                &mut alerts,
+            );
+            pin_mut!(future);
+            assert!(matches!(
+                future.poll(&mut context),
+                Poll::Ready(Ok(ParseResponse::Continue))
+            ));
+        }
+
+        assert_eq!(
+            matches
+                .iter()
+                .map(|x| x.location.as_ref().map(Location::value))
+                .collect::<Vec<_>>(),
+            vec![Some("/ssn"), Some("/0")]
+        );
+        masks.sort_by_key(|x| x.start);
+        assert_eq!(
+            &*replace_matches(body.as_bytes(), &masks),
+            &b"{\"ssn\": \"***********\"}\n{malformed\n\n[\"***********\"]"[..]
+        );
+    }
+
+    #[test]
+    fn test_ndjson_long_line() {
+        let policy: Policy = serde_yaml::from_str(
+            r#"
+categories:
+  ssn:
+    Matchers:
+      regexes:
+        - "\\d{3}-\\d{2}-\\d{4}"
+endpoints:
+  - matches: "**"
+    config:
+      ssn:
+        action: mask
+"#,
+        )
+        .unwrap();
+        let path_policy = policy.get_path_config("example.com/");
+        let mut matchers = prepare_match_state(
+            &policy,
+            &path_policy.configuration,
+            Direction::Response,
+            &ContentType::Json,
+        );
+        matchers.max_line_size = 24;
+
+        let body = "{\"ssn\": \"123-45-6789\", \"other\": \"111-22-3333\"}\n[\"987-65-4321\"]";
+        let (mut reader, mut writer) = pipe(0);
+        assert!(writer.append(body.as_bytes()));
+        drop(writer);
+        let mut matches = vec![];
+        let mut masks = vec![];
+        let mut alerts = AlertTracker::default();
+        let waker = waker(Arc::new(DummyWaker));
+        let mut context = std::task::Context::from_waker(&waker);
+        {
+            let future = parse_ndjson(
+                &matchers,
+                &mut reader,
+                &mut matches,
+                &mut masks,
+                &mut alerts,
+            );
+            pin_mut!(future);
+            assert!(matches!(
+                future.poll(&mut context),
+                Poll::Ready(Ok(ParseResponse::Continue))
+            ));
+        }
+
+        // the line is cut before its second value, which isn't scanned
+        assert_eq!(
+            matches
+                .iter()
+                .map(|x| x.location.as_ref().map(Location::value))
+                .collect::<Vec<_>>(),
+            vec![Some("/ssn"), Some("/0")]
+        );
+        masks.sort_by_key(|x| x.start);
+        assert_eq!(
+            &*replace_matches(body.as_bytes(), &masks),
+            &b"{\"ssn\": \"***********\", \"other\": \"111-22-3333\"}\n[\"***********\"]"[..]
+        );
+    }
 }
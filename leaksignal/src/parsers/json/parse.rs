@@ -1,7 +1,11 @@
 use futures::{AsyncRead, AsyncReadExt};
 
-use crate::pipe::PipeReader;
+use crate::{
+    pipe::{maybe_read_u8, pipe, PipeReader},
+    policy::{JsonSegment, JsonValueType},
+};
 use anyhow::{bail, Context, Result};
+use serde::de::IgnoredAny;
 use std::{
     char::REPLACEMENT_CHARACTER,
     io::{ErrorKind, Result as IoResult},
@@ -13,16 +17,6 @@ async fn read_u8(reader: &mut (impl AsyncRead + Unpin)) -> IoResult<u8> {
     Ok(out[0])
 }
 
-async fn maybe_read_u8(reader: &mut (impl AsyncRead + Unpin)) -> IoResult<Option<u8>> {
-    let mut out = [0u8; 1];
-    match reader.read_exact(&mut out[..]).await {
-        Ok(()) => (),
-        Err(e) if e.kind() == ErrorKind::UnexpectedEof => return Ok(None),
-        Err(e) => return Err(e),
-    }
-    Ok(Some(out[0]))
-}
-
 async fn read_u8_non_whitespace(reader: &mut (impl AsyncRead + Unpin)) -> IoResult<u8> {
     loop {
         let value = read_u8(reader).await?;
@@ -109,17 +103,31 @@ async fn read_char_with(input: &mut PipeReader, start: u8) -> IoResult<char> {
     Ok(std::char::from_u32(codepoint).unwrap_or(std::char::REPLACEMENT_CHARACTER))
 }
 
+/// extends the source offsets of `sources` to cover the bytes of `out` added since
+fn record_sources(out: &str, sources: &mut Option<&mut Vec<usize>>, source: usize) {
+    if let Some(sources) = sources {
+        sources.resize(out.len(), source);
+    }
+}
+
 // assumes the leading " is already consumed
-async fn read_json_string(input: &mut PipeReader) -> IoResult<String> {
+// if given, `sources` receives the body offset of the escape or character each byte of the string was read from
+async fn read_json_string(
+    input: &mut PipeReader,
+    mut sources: Option<&mut Vec<usize>>,
+) -> IoResult<String> {
     let mut out = String::new();
     let mut current_unicode_sequence: Vec<u16> = vec![];
+    let mut sequence_start = 0usize;
     loop {
+        let char_start = input.total_read();
         let char1 = read_u8(input).await?;
         if char1 != b'\\' && !current_unicode_sequence.is_empty() {
             out.extend(
                 char::decode_utf16(current_unicode_sequence.drain(..))
                     .map(|r| r.unwrap_or(REPLACEMENT_CHARACTER)),
             );
+            record_sources(&out, &mut sources, sequence_start);
         }
         match char1 {
             b'\\' => {
@@ -129,6 +137,7 @@ async fn read_json_string(input: &mut PipeReader) -> IoResult<String> {
                         char::decode_utf16(current_unicode_sequence.drain(..))
                             .map(|r| r.unwrap_or(REPLACEMENT_CHARACTER)),
                     );
+                    record_sources(&out, &mut sources, sequence_start);
                 }
                 match char2 {
                     b'"' => {
@@ -155,14 +164,19 @@ async fn read_json_string(input: &mut PipeReader) -> IoResult<String> {
                     b't' => {
                         out.push('\t');
                     }
-                    b'u' => match read_hex4(input).await? {
-                        Ok(hex) => current_unicode_sequence.push(hex),
-                        Err(_bytes) => {
-                            let mut out = [0u16; 1];
-                            REPLACEMENT_CHARACTER.encode_utf16(&mut out[..]);
-                            current_unicode_sequence.push(out[0]);
+                    b'u' => {
+                        if current_unicode_sequence.is_empty() {
+                            sequence_start = char_start;
                         }
-                    },
+                        match read_hex4(input).await? {
+                            Ok(hex) => current_unicode_sequence.push(hex),
+                            Err(_bytes) => {
+                                let mut out = [0u16; 1];
+                                REPLACEMENT_CHARACTER.encode_utf16(&mut out[..]);
+                                current_unicode_sequence.push(out[0]);
+                            }
+                        }
+                    }
                     c => out.push(read_char_with(input, c).await?),
                 }
             }
@@ -173,12 +187,14 @@ async fn read_json_string(input: &mut PipeReader) -> IoResult<String> {
                 out.push(read_char_with(input, c).await?);
             }
         }
+        record_sources(&out, &mut sources, char_start);
     }
     if !current_unicode_sequence.is_empty() {
         out.extend(
             char::decode_utf16(current_unicode_sequence.drain(..))
                 .map(|r| r.unwrap_or(REPLACEMENT_CHARACTER)),
         );
+        record_sources(&out, &mut sources, sequence_start);
     }
     Ok(out)
 }
@@ -190,11 +206,15 @@ fn decode_digit(c: u8) -> Option<u8> {
     })
 }
 
-// returns first byte after number, if any (not eof)
-async fn read_json_number(first_byte: u8, input: &mut PipeReader) -> Result<Option<u8>> {
+// returns the number literal, and the first byte after it, if any (not eof)
+async fn read_json_number(first_byte: u8, input: &mut PipeReader) -> Result<(String, Option<u8>)> {
+    let mut out = String::new();
+    out.push(first_byte as char);
     let first_number = match first_byte {
         b'-' => {
-            decode_digit(read_u8(input).await?).context("missing digit after negative symbol")?
+            let digit = read_u8(input).await?;
+            out.push(digit as char);
+            decode_digit(digit).context("missing digit after negative symbol")?
         }
         b'0'..=b'9' => first_byte - b'0',
         _ => unreachable!(),
@@ -202,71 +222,159 @@ async fn read_json_number(first_byte: u8, input: &mut PipeReader) -> Result<Opti
 
     let mut post_digit = if first_number == 0 {
         match maybe_read_u8(input).await? {
-            None => return Ok(None),
+            None => return Ok((out, None)),
             Some(c) => c,
         }
     } else {
         loop {
             match maybe_read_u8(input).await? {
-                Some(c) if c.is_ascii_digit() => (),
+                Some(c) if c.is_ascii_digit() => out.push(c as char),
                 Some(c) => break c,
-                None => return Ok(None),
+                None => return Ok((out, None)),
             };
         }
     };
     if post_digit == b'.' {
+        out.push('.');
         post_digit = loop {
             match maybe_read_u8(input).await? {
-                Some(c) if c.is_ascii_digit() => (),
+                Some(c) if c.is_ascii_digit() => out.push(c as char),
                 Some(c) => break c,
-                None => return Ok(None),
+                None => return Ok((out, None)),
             };
         };
     }
     if post_digit == b'e' || post_digit == b'E' {
+        out.push(post_digit as char);
         match maybe_read_u8(input).await? {
-            Some(c) if c.is_ascii_digit() || c == b'+' || c == b'-' => (),
-            c => return Ok(c),
+            Some(c) if c.is_ascii_digit() || c == b'+' || c == b'-' => out.push(c as char),
+            c => return Ok((out, c)),
         }
         post_digit = loop {
             match maybe_read_u8(input).await? {
-                Some(c) if c.is_ascii_digit() => (),
+                Some(c) if c.is_ascii_digit() => out.push(c as char),
                 Some(c) => break c,
-                None => return Ok(None),
+                None => return Ok((out, None)),
             };
         };
     }
-    Ok(Some(post_digit))
+    Ok((out, Some(post_digit)))
 }
 
+/// formats the segments of a path as a JSON Pointer (RFC 6901), i.e. `/users/3/ssn`
+pub fn json_pointer(path: &[JsonSegment]) -> String {
+    let mut out = String::new();
+    for segment in path {
+        out.push('/');
+        match segment {
+            JsonSegment::Key(key) => out.push_str(&key.replace('~', "~0").replace('/', "~1")),
+            JsonSegment::Index(index) => out.push_str(&index.to_string()),
+        }
+    }
+    out
+}
+
+/// Location of a json key or value in the body
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct JsonSpan {
+    pub start: usize,
+    pub end: usize,
+    /// number of json strings the key or value is embedded in, each escaping its text once more
+    pub embedded: usize,
+}
+
+type KeyOut<'a, T> = dyn FnMut(String, &[JsonSegment], JsonSpan) -> Option<T> + 'a;
+type ValueOut<'a, T> = dyn FnMut(String, JsonValueType, &[JsonSegment], JsonSpan) -> Option<T> + 'a;
+
 enum JsonResult<T> {
     Eof,
     NextByte(u8),
     EarlyReturn(T),
 }
 
+/// returns true if the string `value` holds a json object or array
+fn is_embedded_json(value: &str) -> bool {
+    matches!(value.trim_start().as_bytes().first(), Some(b'{' | b'['))
+        && serde_json::from_str::<IgnoredAny>(value).is_ok()
+}
+
+/// parses the json document held by a json string, mapping its spans through the `sources` of the string
+#[async_recursion::async_recursion(?Send)]
+async fn parse_embedded_json<T>(
+    value: &str,
+    sources: &[usize],
+    string_end: usize,
+    path: &mut Vec<JsonSegment>,
+    depth: usize,
+    key_out: &mut KeyOut<'_, T>,
+    value_out: &mut ValueOut<'_, T>,
+) -> Result<Option<T>> {
+    let map_span = |span: JsonSpan| JsonSpan {
+        start: sources.get(span.start).copied().unwrap_or(string_end),
+        end: sources.get(span.end).copied().unwrap_or(string_end),
+        embedded: span.embedded + 1,
+    };
+    let mut key_out = |key, path: &[JsonSegment], span| key_out(key, path, map_span(span));
+    let mut value_out = |value, value_type, path: &[JsonSegment], span| {
+        value_out(value, value_type, path, map_span(span))
+    };
+
+    let (mut reader, mut writer) = pipe(0);
+    writer.append(value.as_bytes());
+    drop(writer);
+    let first_byte = read_u8_non_whitespace(&mut reader).await?;
+    match parse_json_internal(
+        first_byte,
+        &mut reader,
+        path,
+        depth,
+        &mut key_out,
+        &mut value_out,
+    )
+    .await?
+    {
+        JsonResult::NextByte(_) | JsonResult::Eof => Ok(None),
+        JsonResult::EarlyReturn(t) => Ok(Some(t)),
+    }
+}
+
 /// returns trailing byte after read, if any
+/// json documents embedded in strings are parsed up to `depth` levels deep
 #[async_recursion::async_recursion(?Send)]
 async fn parse_json_internal<T>(
     first_byte: u8,
     input: &mut PipeReader,
-    key_out: &mut (impl FnMut(String, usize, usize) -> Option<T> + Send + Sync),
-    value_out: &mut (impl FnMut(String, usize, usize) -> Option<T> + Send + Sync),
+    path: &mut Vec<JsonSegment>,
+    depth: usize,
+    key_out: &mut KeyOut<'_, T>,
+    value_out: &mut ValueOut<'_, T>,
 ) -> Result<JsonResult<T>> {
     let start = input.total_read().saturating_sub(1);
+    let span = |end| JsonSpan {
+        start,
+        end,
+        embedded: 0,
+    };
     match first_byte {
         // object
         b'{' => {
             let mut first_key = true;
             loop {
-                let start_key = input.total_read();
-                match read_u8_non_whitespace(input).await? {
+                let first_char = read_u8_non_whitespace(input).await?;
+                let start_key = input.total_read().saturating_sub(1);
+                match first_char {
                     b'}' if first_key => {
                         break;
                     },
                     b'"' => {
-                        let key = read_json_string(input).await?;
-                        if let Some(out) = key_out(key, start_key, input.total_read()) {
+                        let key = read_json_string(input, None).await?;
+                        path.push(JsonSegment::Key(key.clone()));
+                        let key_span = JsonSpan {
+                            start: start_key,
+                            end: input.total_read(),
+                            embedded: 0,
+                        };
+                        if let Some(out) = key_out(key, path, key_span) {
                             return Ok(JsonResult::EarlyReturn(out));
                         }
                     },
@@ -280,10 +388,13 @@ async fn parse_json_internal<T>(
                 let comma_byte = parse_json_internal(
                     read_u8_non_whitespace(input).await?,
                     input,
+                    path,
+                    depth,
                     key_out,
                     value_out,
                 )
                 .await?;
+                path.pop();
                 let comma_index = input.total_read().saturating_sub(1);
                 match comma_byte {
                     JsonResult::Eof => return Err(std::io::Error::new(ErrorKind::UnexpectedEof, "unexpected eof").into()),
@@ -297,13 +408,18 @@ async fn parse_json_internal<T>(
         // array
         b'[' => {
             let mut first_value = true;
+            let mut index = 0usize;
             loop {
                 let first_char = read_u8_non_whitespace(input).await?;
                 if first_value && first_char == b']' {
                     break;
                 }
                 first_value = false;
-                let comma_byte = parse_json_internal(first_char, input, key_out, value_out).await?;
+                path.push(JsonSegment::Index(index));
+                let comma_byte =
+                    parse_json_internal(first_char, input, path, depth, key_out, value_out).await?;
+                path.pop();
+                index += 1;
                 let comma_index = input.total_read().saturating_sub(1);
                 match comma_byte {
                     JsonResult::Eof => return Err(std::io::Error::new(ErrorKind::UnexpectedEof, "unexpected eof").into()),
@@ -316,14 +432,38 @@ async fn parse_json_internal<T>(
         }
         // string
         b'"' => {
-            let value = read_json_string(input).await?;
-            if let Some(out) = value_out(value, start, input.total_read()) {
+            let mut sources = vec![];
+            let value = read_json_string(input, Some(&mut sources)).await?;
+            if depth > 0 && is_embedded_json(&value) {
+                // the closing quote ends the embedded document
+                let string_end = input.total_read() - 1;
+                if let Some(out) = parse_embedded_json(
+                    &value,
+                    &sources,
+                    string_end,
+                    path,
+                    depth - 1,
+                    key_out,
+                    value_out,
+                )
+                .await?
+                {
+                    return Ok(JsonResult::EarlyReturn(out));
+                }
+            } else if let Some(out) =
+                value_out(value, JsonValueType::String, path, span(input.total_read()))
+            {
                 return Ok(JsonResult::EarlyReturn(out));
             }
         }
         // number
         b'-' | b'0' | b'1' | b'2' | b'3' | b'4' | b'5' | b'6' | b'7' | b'8' | b'9' => {
-            return match read_json_number(first_byte, input).await? {
+            let (number, next_byte) = read_json_number(first_byte, input).await?;
+            let end = start + number.len();
+            if let Some(out) = value_out(number, JsonValueType::Number, path, span(end)) {
+                return Ok(JsonResult::EarlyReturn(out));
+            }
+            return match next_byte {
                 Some(c) => Ok(JsonResult::NextByte(
                     reread_u8_non_whitespace(c, input).await?,
                 )),
@@ -337,6 +477,14 @@ async fn parse_json_internal<T>(
             if &word != b"true" {
                 bail!("malformed json, unknown keyword: {word:?}")
             }
+            if let Some(out) = value_out(
+                "true".to_string(),
+                JsonValueType::Boolean,
+                path,
+                span(start + 4),
+            ) {
+                return Ok(JsonResult::EarlyReturn(out));
+            }
         }
         b'f' => {
             let mut word = [0u8; 5];
@@ -345,6 +493,14 @@ async fn parse_json_internal<T>(
             if &word != b"false" {
                 bail!("malformed json, unknown keyword: {word:?}")
             }
+            if let Some(out) = value_out(
+                "false".to_string(),
+                JsonValueType::Boolean,
+                path,
+                span(start + 5),
+            ) {
+                return Ok(JsonResult::EarlyReturn(out));
+            }
         }
         b'n' => {
             let mut word = [0u8; 4];
@@ -353,6 +509,14 @@ async fn parse_json_internal<T>(
             if &word != b"null" {
                 bail!("malformed json, unknown keyword: {word:?}")
             }
+            if let Some(out) = value_out(
+                "null".to_string(),
+                JsonValueType::Null,
+                path,
+                span(start + 4),
+            ) {
+                return Ok(JsonResult::EarlyReturn(out));
+            }
         }
         // unexpected
         c => {
@@ -365,14 +529,18 @@ async fn parse_json_internal<T>(
         .unwrap_or(JsonResult::Eof))
 }
 
+/// json documents embedded in strings are parsed up to `depth` levels deep, see `Policy::embedded_json_depth`
 pub async fn parse_json<T>(
     input: &mut PipeReader,
-    mut key_out: impl FnMut(String, usize, usize) -> Option<T> + Send + Sync,
-    mut value_out: impl FnMut(String, usize, usize) -> Option<T> + Send + Sync,
+    depth: usize,
+    mut key_out: impl FnMut(String, &[JsonSegment], JsonSpan) -> Option<T>,
+    mut value_out: impl FnMut(String, JsonValueType, &[JsonSegment], JsonSpan) -> Option<T>,
 ) -> Result<Option<T>> {
     match parse_json_internal(
         read_u8_non_whitespace(input).await?,
         input,
+        &mut vec![],
+        depth,
         &mut key_out,
         &mut value_out,
     )
@@ -412,11 +580,12 @@ mod tests {
             {
                 let future = parse_json::<()>(
                     &mut reader,
+                    0,
                     |key, _, _| {
                         keys.push(key);
                         None
                     },
-                    |value, _, _| {
+                    |value, _, _, _| {
                         values.push(value);
                         None
                     },
@@ -435,4 +604,161 @@ mod tests {
             println!("values: {:?}\n", values);
         }
     }
+
+    #[test]
+    fn test_json_literals() {
+        let body = r#"{"ssn": 123456789, "a": [-1.5e3, true, false, null, 0], "b": "x"}"#;
+        let (mut reader, mut writer) = pipe(0);
+        assert!(writer.append(body.as_bytes()));
+        drop(writer);
+        let mut values = vec![];
+        let waker = waker(Arc::new(DummyWaker));
+        let mut context = std::task::Context::from_waker(&waker);
+
+        {
+            let future = parse_json::<()>(
+                &mut reader,
+                0,
+                |_, _, _| None,
+                |value, value_type, _, span| {
+                    values.push((value, value_type, span.start, span.end));
+                    None
+                },
+            );
+            pin_mut!(future);
+            assert!(matches!(future.poll(&mut context), Poll::Ready(Ok(None))));
+        }
+
+        for (value, value_type, start, end) in &values {
+            if *value_type != JsonValueType::String {
+                assert_eq!(&body[*start..*end], value);
+            }
+        }
+        assert_eq!(
+            values
+                .into_iter()
+                .map(|(value, value_type, _, _)| (value, value_type))
+                .collect::<Vec<_>>(),
+            vec![
+                ("123456789".to_string(), JsonValueType::Number),
+                ("-1.5e3".to_string(), JsonValueType::Number),
+                ("true".to_string(), JsonValueType::Boolean),
+                ("false".to_string(), JsonValueType::Boolean),
+                ("null".to_string(), JsonValueType::Null),
+                ("0".to_string(), JsonValueType::Number),
+                ("x".to_string(), JsonValueType::String),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_json_pointer() {
+        let body = r#"{"users": [{"a/b": 1}, {"ssn": "x", "e~": [true]}], "support": {}}"#;
+        let (mut reader, mut writer) = pipe(0);
+        assert!(writer.append(body.as_bytes()));
+        drop(writer);
+        let mut keys = vec![];
+        let mut values = vec![];
+        let waker = waker(Arc::new(DummyWaker));
+        let mut context = std::task::Context::from_waker(&waker);
+
+        {
+            let future = parse_json::<()>(
+                &mut reader,
+                0,
+                |_, path, _| {
+                    keys.push(json_pointer(path));
+                    None
+                },
+                |_, _, path, _| {
+                    values.push(json_pointer(path));
+                    None
+                },
+            );
+            pin_mut!(future);
+            assert!(matches!(future.poll(&mut context), Poll::Ready(Ok(None))));
+        }
+
+        assert_eq!(
+            keys,
+            vec![
+                "/users",
+                "/users/0/a~1b",
+                "/users/1/ssn",
+                "/users/1/e~0",
+                "/support"
+            ]
+        );
+        assert_eq!(
+            values,
+            vec!["/users/0/a~1b", "/users/1/ssn", "/users/1/e~0/0"]
+        );
+    }
+
+    #[test]
+    fn test_embedded_json() {
+        let body = r#"{"payload": "{\"ssn\": \"123\", \"inner\": \"[\\\"x\\\"]\"}", "n": "{1}"}"#;
+        let parse = |depth| {
+            let (mut reader, mut writer) = pipe(0);
+            assert!(writer.append(body.as_bytes()));
+            drop(writer);
+            let mut keys = vec![];
+            let mut values = vec![];
+            let waker = waker(Arc::new(DummyWaker));
+            let mut context = std::task::Context::from_waker(&waker);
+            {
+                let future = parse_json::<()>(
+                    &mut reader,
+                    depth,
+                    |key, path, span: JsonSpan| {
+                        keys.push((key, json_pointer(path), span.embedded));
+                        None
+                    },
+                    |value, _, path, span: JsonSpan| {
+                        values.push((value, json_pointer(path), span));
+                        None
+                    },
+                );
+                pin_mut!(future);
+                assert!(matches!(future.poll(&mut context), Poll::Ready(Ok(None))));
+            }
+            (keys, values)
+        };
+
+        let (keys, values) = parse(2);
+        assert_eq!(
+            keys,
+            vec![
+                ("payload".to_string(), "/payload".to_string(), 0),
+                ("ssn".to_string(), "/payload/ssn".to_string(), 1),
+                ("inner".to_string(), "/payload/inner".to_string(), 1),
+                ("n".to_string(), "/n".to_string(), 0),
+            ]
+        );
+        let spans = values
+            .iter()
+            .map(|(value, path, span)| {
+                (
+                    value.as_str(),
+                    path.as_str(),
+                    &body[span.start..span.end],
+                    span.embedded,
+                )
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(
+            spans,
+            vec![
+                ("123", "/payload/ssn", r#"\"123\""#, 1),
+                ("x", "/payload/inner/0", r#"\\\"x\\\""#, 2),
+                ("{1}", "/n", r#""{1}""#, 0),
+            ]
+        );
+
+        let (_, values) = parse(1);
+        assert_eq!(values[1].0, r#"["x"]"#);
+        assert_eq!(values[1].1, "/payload/inner");
+        let (_, values) = parse(0);
+        assert_eq!(values[0].1, "/payload");
+    }
 }
@@ -1,40 +1,456 @@
-use std::borrow::Cow;
+use std::{borrow::Cow, str::FromStr};
 
-use log::warn;
+use anyhow::Result;
+use futures::{future::LocalBoxFuture, FutureExt};
+use indexmap::IndexMap;
+use log::{debug, warn};
 
-use crate::evaluator::CategoryMatch;
+use crate::{
+    alert::AlertTracker, compression::ContentEncoding, pipe::PipeReader, policy::BodyMatchers,
+    proto::Match,
+};
 
+pub mod csv;
+pub mod form;
+pub mod grpc;
 pub mod html;
-// pub mod jpeg;
+pub mod image;
 pub mod json;
+pub mod text;
+pub mod xml;
+
+/// number of leading body bytes used to sniff its content type
+const SNIFF_LENGTH: usize = 512;
 
-#[allow(dead_code)]
 pub enum ParseResponse {
     Continue,
     Block,
 }
 
-#[allow(dead_code)]
-fn replace_matches<'a>(
-    body: &'a str,
-    matches: &[CategoryMatch],
-    expected_length: usize,
-    replace_with: &str,
-) -> Cow<'a, str> {
-    if matches.is_empty() {
+/// Format of a body, as named by its content-type
+#[derive(Clone, PartialEq, Debug)]
+pub enum ContentType {
+    Html,
+    Json,
+    Xml,
+    Text,
+    Csv,
+    Ndjson,
+    Form,
+    Multipart {
+        boundary: String,
+    },
+    /// `method` is the request path, naming the message types of descriptor sets.
+    /// `encoding` is the `grpc-encoding` of compressed messages
+    Grpc {
+        method: Option<String>,
+        encoding: Option<ContentEncoding>,
+    },
+    Jpeg,
+    Png,
+    Webp,
+    /// missing or generic content-type, sniffed from the start of the body
+    Sniff,
+    Unknown,
+}
+
+impl Default for ContentType {
+    fn default() -> Self {
+        ContentType::Unknown
+    }
+}
+
+impl FromStr for ContentType {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        Ok(match s {
+            "text/html" => ContentType::Html,
+            "image/jpg" | "image/jpeg" => ContentType::Jpeg,
+            "image/png" => ContentType::Png,
+            "image/webp" => ContentType::Webp,
+            "application/json" | "text/json" => ContentType::Json,
+            "application/xml" | "text/xml" | "application/soap+xml" => ContentType::Xml,
+            "text/plain" => ContentType::Text,
+            "text/csv" => ContentType::Csv,
+            "application/x-ndjson" | "application/ndjson" => ContentType::Ndjson,
+            "application/x-www-form-urlencoded" => ContentType::Form,
+            "application/grpc" | "application/grpc+proto" => ContentType::Grpc {
+                method: None,
+                encoding: None,
+            },
+            "" | "application/octet-stream" => ContentType::Sniff,
+            // structured syntax suffixes, i.e. `application/problem+json`
+            _ if s.ends_with("+json") => ContentType::Json,
+            _ if s.ends_with("+xml") => ContentType::Xml,
+            _ => ContentType::Unknown,
+        })
+    }
+}
+
+impl ContentType {
+    /// parses a content-type header value, multipart bodies need their `boundary` parameter
+    pub fn from_value(value: &str) -> Self {
+        let mut parameters = value.split(';');
+        let mime = parameters
+            .next()
+            .unwrap_or_default()
+            .trim()
+            .to_ascii_lowercase();
+        if mime.starts_with("multipart/") {
+            return parameters
+                .filter_map(|x| x.split_once('='))
+                .find(|(name, _)| name.trim().eq_ignore_ascii_case("boundary"))
+                .map(|(_, boundary)| ContentType::Multipart {
+                    boundary: boundary.trim().trim_matches('"').to_string(),
+                })
+                .unwrap_or_default();
+        }
+        mime.parse()
+            .expect("content-type parse failed (impossible)")
+    }
+
+    /// parses a content-type header value, bodies with a media type of `aliases` are parsed as its alias
+    pub fn from_aliased_value(value: &str, aliases: &IndexMap<String, String>) -> Self {
+        let (mime, parameters) = value.split_once(';').unwrap_or((value, ""));
+        let alias = aliases
+            .iter()
+            .find(|(name, _)| name.eq_ignore_ascii_case(mime.trim()));
+        match alias {
+            Some((_, alias)) => ContentType::from_value(&format!("{alias};{parameters}")),
+            None => ContentType::from_value(value),
+        }
+    }
+
+    /// guesses the content type of a body from its first bytes
+    pub fn sniff(start: &[u8]) -> Self {
+        if start.starts_with(&[0xFF, 0xD8, 0xFF]) {
+            return ContentType::Jpeg;
+        }
+        if start.starts_with(b"\x89PNG\r\n\x1a\n") {
+            return ContentType::Png;
+        }
+        if start.len() >= 12 && &start[..4] == b"RIFF" && &start[8..12] == b"WEBP" {
+            return ContentType::Webp;
+        }
+        let start = start.strip_prefix(b"\xEF\xBB\xBF").unwrap_or(start);
+        let text = match std::str::from_utf8(start) {
+            Ok(text) => text,
+            // a character cut at the end of the sniffed bytes
+            Err(e) if e.error_len().is_none() => {
+                std::str::from_utf8(&start[..e.valid_up_to()]).unwrap()
+            }
+            Err(_) => return ContentType::Unknown,
+        };
+        if text.is_empty() || text.contains(|c: char| c.is_control() && !c.is_whitespace()) {
+            return ContentType::Unknown;
+        }
+        let trimmed = text.trim_start();
+        let mut chars = trimmed.chars();
+        let first = chars.next();
+        let second = chars.find(|c| !c.is_whitespace());
+        match (first, second) {
+            (Some('{'), None | Some('"' | '}')) => return ContentType::Json,
+            (Some('['), None | Some('{' | '[' | '"' | ']' | '-' | '0'..='9' | 't' | 'f' | 'n')) => {
+                return ContentType::Json
+            }
+            (Some('<'), _) => {
+                let lower = trimmed.to_ascii_lowercase();
+                let is_html = ["<!doctype html", "<html", "<head", "<body"]
+                    .iter()
+                    .any(|x| lower.starts_with(x));
+                return if is_html {
+                    ContentType::Html
+                } else {
+                    ContentType::Xml
+                };
+            }
+            _ => (),
+        }
+        if let Some((boundary, headers)) = text
+            .strip_prefix("--")
+            .and_then(|rest| rest.split_once("\r\n"))
+        {
+            if !boundary.is_empty()
+                && !boundary.contains(char::is_whitespace)
+                && headers.to_ascii_lowercase().starts_with("content-")
+            {
+                return ContentType::Multipart {
+                    boundary: boundary.to_string(),
+                };
+            }
+        }
+        ContentType::Text
+    }
+
+    /// returns true if bodies of this type are text, transcoded to utf-8 from their charset.
+    /// sniffed bodies have no charset, but may start with a byte order mark
+    pub fn is_text(&self) -> bool {
+        matches!(
+            self,
+            ContentType::Html
+                | ContentType::Json
+                | ContentType::Xml
+                | ContentType::Text
+                | ContentType::Csv
+                | ContentType::Ndjson
+                | ContentType::Form
+                | ContentType::Sniff
+        )
+    }
+
+    /// returns true if bodies of this type can be scanned
+    pub fn is_scanned(&self) -> bool {
+        !matches!(self, ContentType::Unknown)
+    }
+}
+
+/// scans `body` with the parser of `content_type`, boxed since multipart parts are scanned recursively
+pub fn parse_body<'a>(
+    content_type: &'a ContentType,
+    matchers: &'a BodyMatchers,
+    body: &'a mut PipeReader,
+    matches: &'a mut Vec<Match>,
+    masks: &'a mut Vec<Replacement>,
+    alerts: &'a mut AlertTracker,
+) -> LocalBoxFuture<'a, Result<ParseResponse>> {
+    async move {
+        Ok(match content_type {
+            ContentType::Html => {
+                html::parse_html(&matchers.html, body, matches, masks, alerts).await?
+            }
+            ContentType::Json => {
+                json::parse_json(&matchers.json, body, matches, masks, alerts).await?
+            }
+            ContentType::Xml => xml::parse_xml(&matchers.xml, body, matches, masks, alerts).await?,
+            ContentType::Text => {
+                text::parse_text(&matchers.text, body, matches, masks, alerts).await?
+            }
+            ContentType::Csv => csv::parse_csv(&matchers.csv, body, matches, masks, alerts).await?,
+            ContentType::Ndjson => {
+                json::parse_ndjson(&matchers.json, body, matches, masks, alerts).await?
+            }
+            ContentType::Form => {
+                form::parse_form(&matchers.form, body, matches, masks, alerts).await?
+            }
+            ContentType::Multipart { boundary } => {
+                form::parse_multipart(matchers, boundary, body, matches, masks, alerts).await?
+            }
+            ContentType::Grpc { method, encoding } => {
+                grpc::parse_grpc(
+                    &matchers.grpc,
+                    method.as_deref(),
+                    *encoding,
+                    body,
+                    matches,
+                    masks,
+                    alerts,
+                )
+                .await?
+            }
+            ContentType::Jpeg => {
+                image::parse_image(
+                    &matchers.image,
+                    image::ImageFormat::Jpeg,
+                    body,
+                    matches,
+                    masks,
+                    alerts,
+                )
+                .await?
+            }
+            ContentType::Png => {
+                image::parse_image(
+                    &matchers.image,
+                    image::ImageFormat::Png,
+                    body,
+                    matches,
+                    masks,
+                    alerts,
+                )
+                .await?
+            }
+            ContentType::Webp => {
+                image::parse_image(
+                    &matchers.image,
+                    image::ImageFormat::Webp,
+                    body,
+                    matches,
+                    masks,
+                    alerts,
+                )
+                .await?
+            }
+            ContentType::Sniff => {
+                let content_type = ContentType::sniff(&body.peek(SNIFF_LENGTH).await);
+                debug!("sniffed body as {content_type:?}");
+                if content_type.is_scanned() {
+                    parse_body(&content_type, matchers, body, matches, masks, alerts).await?
+                } else {
+                    ParseResponse::Continue
+                }
+            }
+            ContentType::Unknown => ParseResponse::Continue,
+        })
+    }
+    .boxed_local()
+}
+
+/// A span of the decompressed response body to be rewritten
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct Replacement {
+    pub start: usize,
+    pub length: usize,
+    pub value: Vec<u8>,
+}
+
+/// `replacements` must be sorted by `start`
+pub fn replace_matches<'a>(body: &'a [u8], replacements: &[Replacement]) -> Cow<'a, [u8]> {
+    if replacements.is_empty() {
         return Cow::Borrowed(body);
     }
-    let mut output = String::with_capacity(expected_length);
+    let mut output = Vec::with_capacity(body.len());
     let mut body_index = 0usize;
-    for policy_match in matches {
-        if body_index > policy_match.start {
+    for replacement in replacements {
+        let end = replacement.start + replacement.length;
+        if end > body.len() {
+            warn!("replacement out of body bounds, skipped");
+            continue;
+        }
+        if body_index > replacement.start {
             warn!("overlapping matches detected!");
-            body_index = policy_match.start;
+            if body_index >= end {
+                continue;
+            }
+        } else {
+            output.extend_from_slice(&body[body_index..replacement.start]);
         }
-        output.push_str(&body[body_index..policy_match.start]);
-        output.push_str(replace_with);
-        body_index = policy_match.start + policy_match.length;
+        output.extend_from_slice(&replacement.value);
+        body_index = end;
     }
-    output.push_str(&body[body_index..]);
+    output.extend_from_slice(&body[body_index..]);
     Cow::Owned(output)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_content_type() {
+        assert_eq!(
+            ContentType::from_value("application/json; charset=utf-8"),
+            ContentType::Json
+        );
+        assert_eq!(
+            ContentType::from_value("multipart/form-data; boundary=\"abc 123\""),
+            ContentType::Multipart {
+                boundary: "abc 123".to_string()
+            }
+        );
+        assert_eq!(
+            ContentType::from_value("multipart/form-data"),
+            ContentType::Unknown
+        );
+        assert_eq!(
+            ContentType::from_value("Application/Problem+JSON"),
+            ContentType::Json
+        );
+        assert_eq!(
+            ContentType::from_value("application/atom+xml"),
+            ContentType::Xml
+        );
+        assert_eq!(
+            ContentType::from_value("application/octet-stream"),
+            ContentType::Sniff
+        );
+
+        let mut aliases = IndexMap::new();
+        aliases.insert(
+            "application/x-amz-json-1.1".to_string(),
+            "application/json".to_string(),
+        );
+        aliases.insert(
+            "text/x-multipart".to_string(),
+            "multipart/mixed".to_string(),
+        );
+        assert_eq!(
+            ContentType::from_aliased_value("application/X-Amz-Json-1.1", &aliases),
+            ContentType::Json
+        );
+        assert_eq!(
+            ContentType::from_aliased_value("text/x-multipart; boundary=b", &aliases),
+            ContentType::Multipart {
+                boundary: "b".to_string()
+            }
+        );
+        assert_eq!(
+            ContentType::from_aliased_value("text/csv", &aliases),
+            ContentType::Csv
+        );
+    }
+
+    #[test]
+    fn test_sniff() {
+        let sniffed = [
+            &b"\xFF\xD8\xFF\xE0\0\x10JFIF"[..],
+            b"\x89PNG\r\n\x1a\n\0\0\0\x0dIHDR",
+            b"RIFF\x24\0\0\0WEBPVP8 ",
+            b"\xEF\xBB\xBF  {\"a\": 1}",
+            b"[1, 2",
+            b"[INFO] started",
+            b"\n<!DOCTYPE html><html>",
+            b"<?xml version=\"1.0\"?><a/>",
+            b"--xyz\r\nContent-Disposition: form-data; name=\"a\"\r\n\r\n",
+            b"plain \xC3",
+            b"\0\x01\x02",
+            b"",
+        ]
+        .iter()
+        .map(|x| ContentType::sniff(x))
+        .collect::<Vec<_>>();
+        assert_eq!(
+            sniffed,
+            vec![
+                ContentType::Jpeg,
+                ContentType::Png,
+                ContentType::Webp,
+                ContentType::Json,
+                ContentType::Json,
+                ContentType::Text,
+                ContentType::Html,
+                ContentType::Xml,
+                ContentType::Multipart {
+                    boundary: "xyz".to_string()
+                },
+                ContentType::Text,
+                ContentType::Unknown,
+                ContentType::Unknown,
+            ]
+        );
+    }
+
+    #[test]
+    fn test_replace_matches() {
+        let replacement = |start, length, value: &str| Replacement {
+            start,
+            length,
+            value: value.as_bytes().to_vec(),
+        };
+        assert_eq!(&*replace_matches(b"hello world", &[]), b"hello world");
+        assert_eq!(
+            &*replace_matches(
+                b"hello world",
+                &[replacement(0, 1, "J"), replacement(6, 5, "*****")]
+            ),
+            b"Jello *****"
+        );
+        assert_eq!(
+            &*replace_matches(
+                b"hello world",
+                &[replacement(0, 5, "HELLO"), replacement(2, 2, "__")]
+            ),
+            b"HELLO world"
+        );
+    }
+}
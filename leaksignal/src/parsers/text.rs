@@ -0,0 +1,158 @@
+use std::{io, sync::Arc};
+
+use futures::AsyncReadExt;
+use indexmap::IndexMap;
+use leakpolicy::{Direction, PathConfiguration};
+
+use crate::{
+    alert::AlertTracker,
+    evaluator::{self, MatcherMetadata, MatcherState},
+    pipe::PipeReader,
+    policy::{ContentType, Policy},
+    proto::Match,
+};
+
+use super::{ParseResponse, Replacement};
+
+pub fn prepare_match_state(
+    policy: &Policy,
+    configuration: &IndexMap<Arc<String>, PathConfiguration>,
+    direction: Direction,
+) -> MatcherState {
+    let mut match_state = MatcherState::default();
+
+    for (category_name, action) in configuration {
+        if !action.scans_content(direction, &ContentType::Text) {
+            continue;
+        }
+
+        let metadata = MatcherMetadata::new(category_name, action);
+
+        evaluator::prepare_matches(
+            policy,
+            category_name,
+            &mut match_state,
+            &metadata,
+            &action.category_config.ignore,
+        );
+    }
+    match_state.compile();
+
+    match_state
+}
+
+const CHUNK_SIZE: usize = 1024 * 64;
+const CHUNK_OVERLAP: usize = 512;
+
+/// scans the body as raw text, in overlapping chunks
+pub async fn parse_text(
+    match_state: &MatcherState,
+    body: &mut PipeReader,
+    matches: &mut Vec<Match>,
+    masks: &mut Vec<Replacement>,
+    alerts: &mut AlertTracker,
+) -> io::Result<ParseResponse> {
+    let mut chunk = vec![0u8; CHUNK_SIZE];
+    let mut overlap_index = 0usize;
+    let mut chunk_len = 0usize;
+
+    loop {
+        let minimum_end_index = body.total_read();
+        let index = minimum_end_index - overlap_index;
+        chunk.copy_within(chunk_len - overlap_index..chunk_len, 0);
+        chunk_len = body.read(&mut chunk[overlap_index..]).await?;
+        if chunk_len == 0 {
+            break;
+        }
+        chunk_len += overlap_index;
+        let data = &chunk[..chunk_len];
+
+        overlap_index = CHUNK_OVERLAP.min(chunk_len);
+        //TODO: make a better way to trim truncated utf8 characters to avoid allocation
+        match match_state.do_matching(
+            index,
+            minimum_end_index,
+            &String::from_utf8_lossy(data),
+            matches,
+            masks,
+            alerts,
+        ) {
+            ParseResponse::Continue => (),
+            ParseResponse::Block => return Ok(ParseResponse::Block),
+        }
+    }
+    Ok(ParseResponse::Continue)
+}
+
+#[cfg(test)]
+mod tests {
+    use futures::{pin_mut, task::waker, Future};
+    use std::task::Poll;
+
+    use crate::pipe::{pipe, DummyWaker};
+
+    use super::*;
+
+    #[test]
+    fn test_chunk_overlap() {
+        let policy: Policy = serde_yaml::from_str(
+            r#"
+categories:
+  ssn:
+    Matchers:
+      regexes:
+        - "\\d{3}-\\d{2}-\\d{4}"
+endpoints:
+  - matches: "**"
+    config:
+      ssn: {}
+"#,
+        )
+        .unwrap();
+        let path_policy = policy.get_path_config("example.com/");
+        let match_state =
+            prepare_match_state(&policy, &path_policy.configuration, Direction::Response);
+
+        // the second ssn spans the first two chunks
+        let mut body = " ".repeat(CHUNK_SIZE - 17);
+        body.insert_str(0, "123-45-6789 ");
+        body.push_str("987-65-4321 555-55-5555");
+        let (mut reader, mut writer) = pipe(0);
+        assert!(writer.append(body.as_bytes()));
+        drop(writer);
+        let mut matches = vec![];
+        let mut masks = vec![];
+        let mut alerts = AlertTracker::default();
+        let waker = waker(Arc::new(DummyWaker));
+        let mut context = std::task::Context::from_waker(&waker);
+        {
+            let future = parse_text(
+                &match_state,
+                &mut reader,
+                &mut matches,
+                &mut masks,
+                &mut alerts,
+            );
+            pin_mut!(future);
+            assert!(matches!(
+                future.poll(&mut context),
+                Poll::Ready(Ok(ParseResponse::Continue))
+            ));
+        }
+
+        assert_eq!(
+            matches
+                .iter()
+                .map(|x| (
+                    x.matched_value.as_deref().unwrap(),
+                    x.global_start_position.unwrap() as usize
+                ))
+                .collect::<Vec<_>>(),
+            vec![
+                ("123-45-6789", 0),
+                ("987-65-4321", CHUNK_SIZE - 5),
+                ("555-55-5555", CHUNK_SIZE + 7),
+            ]
+        );
+    }
+}
@@ -0,0 +1,244 @@
+use anyhow::Result;
+use std::{cell::RefCell, sync::Arc};
+
+use indexmap::IndexMap;
+use leakpolicy::{Direction, MatchContext, PathConfiguration};
+
+mod parse;
+
+use crate::{
+    alert::AlertTracker,
+    evaluator::{self, MatcherMetadata, MatcherState},
+    pipe::PipeReader,
+    policy::{Category, ContentType, Policy},
+    proto::Match,
+};
+
+use super::{replace_matches, ParseResponse, Replacement};
+
+/// Matcher states of xml element names (`keys`) and of text and attribute values (`values`)
+#[derive(Default)]
+pub struct XmlMatchers {
+    pub keys: MatcherState,
+    pub values: MatcherState,
+}
+
+pub fn prepare_match_state(
+    policy: &Policy,
+    configuration: &IndexMap<Arc<String>, PathConfiguration>,
+    direction: Direction,
+) -> XmlMatchers {
+    let mut matchers = XmlMatchers::default();
+
+    for (category_name, action) in configuration {
+        if !action.scans_content(direction, &ContentType::Xml) {
+            continue;
+        }
+
+        // Keyed categories only match json values and csv cells, see `Policy::validate`
+        if let Some(Category::Keyed { .. }) = policy.categories.get(&**category_name) {
+            continue;
+        }
+
+        let metadata = MatcherMetadata::new(category_name, action);
+
+        if action.matches_context(MatchContext::Keys, &MatchContext::STRUCTURED) {
+            evaluator::prepare_matches(
+                policy,
+                category_name,
+                &mut matchers.keys,
+                &metadata,
+                &action.category_config.ignore,
+            );
+        }
+        if action.matches_context(MatchContext::Values, &MatchContext::STRUCTURED) {
+            evaluator::prepare_matches(
+                policy,
+                category_name,
+                &mut matchers.values,
+                &metadata,
+                &action.category_config.ignore,
+            );
+        }
+    }
+    matchers.keys.compile();
+    matchers.values.compile();
+
+    matchers
+}
+
+fn escape_xml(value: &str) -> String {
+    let mut out = String::with_capacity(value.len());
+    for c in value.chars() {
+        match c {
+            '&' => out.push_str("&amp;"),
+            '<' => out.push_str("&lt;"),
+            '>' => out.push_str("&gt;"),
+            '"' => out.push_str("&quot;"),
+            '\'' => out.push_str("&apos;"),
+            c => out.push(c),
+        }
+    }
+    out
+}
+
+/// masks the decoded xml text or attribute value at `start`..`end` in the body, re-encoding it as escaped text
+fn mask_xml_value(
+    value: &str,
+    start: usize,
+    end: usize,
+    masks: Vec<Replacement>,
+    output: &mut Vec<Replacement>,
+) {
+    if masks.is_empty() {
+        return;
+    }
+    let mut local_masks = masks
+        .into_iter()
+        .map(|mask| Replacement {
+            start: mask.start - start,
+            ..mask
+        })
+        .collect::<Vec<_>>();
+    local_masks.sort_by_key(|x| x.start);
+    let masked = replace_matches(value.as_bytes(), &local_masks);
+    output.push(Replacement {
+        start,
+        length: end - start,
+        value: escape_xml(&String::from_utf8_lossy(&masked)).into_bytes(),
+    });
+}
+
+pub async fn parse_xml(
+    matchers: &XmlMatchers,
+    body: &mut PipeReader,
+    matches: &mut Vec<Match>,
+    masks: &mut Vec<Replacement>,
+    alerts: &mut AlertTracker,
+) -> Result<ParseResponse> {
+    // shared by the key and value callbacks
+    let alerts = RefCell::new(alerts);
+
+    let mut key_matches = vec![];
+
+    let response = parse::parse_xml(
+        body,
+        |key, start, _| {
+            // masking element names would leave their end tags unmatched, only values are masked
+            let mut key_masks = vec![];
+            let response = matchers.keys.do_matching(
+                start,
+                0,
+                &key,
+                &mut key_matches,
+                &mut key_masks,
+                &mut alerts.borrow_mut(),
+            );
+            match response {
+                ParseResponse::Continue => None,
+                ParseResponse::Block => Some(ParseResponse::Block),
+            }
+        },
+        |value, start, end| {
+            let mut local_masks = vec![];
+            let response = matchers.values.do_matching(
+                start,
+                0,
+                &value,
+                matches,
+                &mut local_masks,
+                &mut alerts.borrow_mut(),
+            );
+            mask_xml_value(&value, start, end, local_masks, masks);
+            match response {
+                ParseResponse::Continue => None,
+                ParseResponse::Block => Some(ParseResponse::Block),
+            }
+        },
+    )
+    .await?;
+
+    matches.extend(key_matches);
+    masks.sort_by_key(|x| x.start);
+
+    Ok(response.unwrap_or(ParseResponse::Continue))
+}
+
+#[cfg(test)]
+mod tests {
+    use futures::{pin_mut, task::waker, Future};
+    use std::task::Poll;
+
+    use crate::pipe::{pipe, DummyWaker};
+
+    use super::*;
+
+    #[test]
+    fn test_soap_response() {
+        let policy: Policy = serde_yaml::from_str(
+            r#"
+categories:
+  ssn:
+    Matchers:
+      regexes:
+        - "\\d{3}-\\d{2}-\\d{4}"
+  ssn_element:
+    Matchers:
+      raw:
+        - "SocialSecurityNumber"
+endpoints:
+  - matches: "**"
+    config:
+      ssn:
+        action: mask
+        mask_replacement: "<redacted>"
+      ssn_element:
+        contexts: keys
+"#,
+        )
+        .unwrap();
+        let path_policy = policy.get_path_config("example.com/");
+        let matchers =
+            prepare_match_state(&policy, &path_policy.configuration, Direction::Response);
+
+        let body = r#"<soap:Envelope><soap:Body><SocialSecurityNumber>123-45-6789</SocialSecurityNumber><Holder id="987-65-4321"/></soap:Body></soap:Envelope>"#;
+        let (mut reader, mut writer) = pipe(0);
+        assert!(writer.append(body.as_bytes()));
+        drop(writer);
+        let mut matches = vec![];
+        let mut masks = vec![];
+        let mut alerts = AlertTracker::default();
+        let waker = waker(Arc::new(DummyWaker));
+        let mut context = std::task::Context::from_waker(&waker);
+        {
+            let future = parse_xml(
+                &matchers,
+                &mut reader,
+                &mut matches,
+                &mut masks,
+                &mut alerts,
+            );
+            pin_mut!(future);
+            assert!(matches!(
+                future.poll(&mut context),
+                Poll::Ready(Ok(ParseResponse::Continue))
+            ));
+        }
+
+        assert_eq!(
+            matches
+                .iter()
+                .map(|x| (&*x.category_name, x.matched_value.as_deref()))
+                .collect::<Vec<_>>(),
+            vec![
+                ("ssn", Some("123-45-6789")),
+                ("ssn", Some("987-65-4321")),
+                ("ssn_element", Some("SocialSecurityNumber")),
+            ]
+        );
+        assert_eq!(
+            &*replace_matches(body.as_bytes(), &masks),
+            br#"<soap:Envelope><soap:Body><SocialSecurityNumber>&lt;redacted&gt;</SocialSecurityNumber><Holder id="&lt;redacted&gt;"/></soap:Body></soap:Envelope>"#
+        );
+    }
+}
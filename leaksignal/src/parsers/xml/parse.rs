@@ -0,0 +1,343 @@
+use futures::AsyncReadExt;
+
+use crate::{decode::parse_entity, pipe::PipeReader};
+use anyhow::{bail, Result};
+use std::io::{ErrorKind, Result as IoResult};
+
+/// longest character reference decoded, i.e. `&#x10FFFF;`
+const MAX_ENTITY_SIZE: usize = 12;
+
+/// Reads bytes from the body, with a single byte of lookahead
+struct Input<'a> {
+    reader: &'a mut PipeReader,
+    peeked: Option<u8>,
+}
+
+impl<'a> Input<'a> {
+    /// body offset of the next byte
+    fn position(&self) -> usize {
+        self.reader.total_read() - self.peeked.is_some() as usize
+    }
+
+    async fn next(&mut self) -> IoResult<Option<u8>> {
+        if let Some(byte) = self.peeked.take() {
+            return Ok(Some(byte));
+        }
+        let mut out = [0u8; 1];
+        match self.reader.read_exact(&mut out[..]).await {
+            Ok(()) => Ok(Some(out[0])),
+            Err(e) if e.kind() == ErrorKind::UnexpectedEof => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
+
+    async fn read(&mut self) -> IoResult<u8> {
+        self.next()
+            .await?
+            .ok_or_else(|| std::io::Error::new(ErrorKind::UnexpectedEof, "unexpected eof"))
+    }
+
+    fn unread(&mut self, byte: u8) {
+        self.peeked = Some(byte);
+    }
+
+    async fn read_non_whitespace(&mut self) -> IoResult<u8> {
+        loop {
+            let byte = self.read().await?;
+            if !byte.is_ascii_whitespace() {
+                break Ok(byte);
+            }
+        }
+    }
+
+    /// skips input until `end` has been read
+    async fn skip_until(&mut self, end: &[u8]) -> IoResult<()> {
+        let mut window = Vec::with_capacity(end.len());
+        loop {
+            if window.len() == end.len() {
+                window.remove(0);
+            }
+            window.push(self.read().await?);
+            if window == end {
+                break Ok(());
+            }
+        }
+    }
+}
+
+/// reads the character reference following a `&`, returning its decoded bytes, or its raw bytes if it isn't one
+async fn read_entity(input: &mut Input<'_>) -> IoResult<Vec<u8>> {
+    let mut entity = vec![b'&'];
+    while entity.len() < MAX_ENTITY_SIZE {
+        let byte = match input.next().await? {
+            Some(x) => x,
+            None => break,
+        };
+        if byte == b';' {
+            entity.push(byte);
+            break;
+        }
+        if !byte.is_ascii_alphanumeric() && byte != b'#' {
+            input.unread(byte);
+            break;
+        }
+        entity.push(byte);
+    }
+    Ok(match parse_entity(&entity) {
+        Some((c, length)) if length == entity.len() => c.to_string().into_bytes(),
+        _ => entity,
+    })
+}
+
+/// reads a name up to whitespace, `/`, `>` or `=`, leaving the delimiter unread
+async fn read_name(first_byte: u8, input: &mut Input<'_>) -> IoResult<String> {
+    let mut name = vec![first_byte];
+    loop {
+        let byte = input.read().await?;
+        if byte.is_ascii_whitespace() || matches!(byte, b'/' | b'>' | b'=') {
+            input.unread(byte);
+            break;
+        }
+        name.push(byte);
+    }
+    Ok(String::from_utf8_lossy(&name).into_owned())
+}
+
+/// skips a `<!DOCTYPE ...>` or other declaration, including its internal subset
+async fn skip_declaration(input: &mut Input<'_>) -> IoResult<()> {
+    let mut depth = 0usize;
+    loop {
+        match input.read().await? {
+            b'[' => depth += 1,
+            b']' => depth = depth.saturating_sub(1),
+            b'>' if depth == 0 => break Ok(()),
+            _ => (),
+        }
+    }
+}
+
+/// reads the contents of a `<![CDATA[` section into `text`, up to and excluding its `]]>`
+async fn read_cdata(input: &mut Input<'_>, text: &mut Vec<u8>) -> IoResult<()> {
+    let start = text.len();
+    loop {
+        text.push(input.read().await?);
+        if text.len() - start >= 3 && text.ends_with(b"]]>") {
+            text.truncate(text.len() - 3);
+            break Ok(());
+        }
+    }
+}
+
+/// takes the text read so far, if it isn't only whitespace, with its span in the body
+fn take_text(
+    text: &mut Vec<u8>,
+    text_start: &mut Option<usize>,
+    end: usize,
+) -> Option<(String, usize, usize)> {
+    let start = text_start.take()?;
+    let value = String::from_utf8_lossy(text).into_owned();
+    text.clear();
+    if value.trim().is_empty() {
+        return None;
+    }
+    Some((value, start, end))
+}
+
+/// element names are sent to `key_out`, text and attribute values (decoded) to `value_out`, with their span in the body.
+/// text spans include any cdata markup, attribute value spans exclude the quotes.
+pub async fn parse_xml<T>(
+    input: &mut PipeReader,
+    mut key_out: impl FnMut(String, usize, usize) -> Option<T>,
+    mut value_out: impl FnMut(String, usize, usize) -> Option<T>,
+) -> Result<Option<T>> {
+    let mut input = Input {
+        reader: input,
+        peeked: None,
+    };
+    let mut text = vec![];
+    let mut text_start = None;
+    loop {
+        let position = input.position();
+        let byte = match input.next().await? {
+            Some(x) => x,
+            None => break,
+        };
+        if byte != b'<' {
+            text_start.get_or_insert(position);
+            if byte == b'&' {
+                text.extend(read_entity(&mut input).await?);
+            } else {
+                text.push(byte);
+            }
+            continue;
+        }
+
+        let kind = input.read().await?;
+        let mut marker = None;
+        if kind == b'!' {
+            let byte = input.read().await?;
+            if byte == b'[' {
+                let mut cdata = [0u8; 6];
+                for byte in &mut cdata {
+                    *byte = input.read().await?;
+                }
+                if &cdata != b"CDATA[" {
+                    bail!("malformed xml, unknown declaration @ {position}");
+                }
+                text_start.get_or_insert(position);
+                read_cdata(&mut input, &mut text).await?;
+                continue;
+            }
+            marker = Some(byte);
+        }
+        if let Some((value, start, end)) = take_text(&mut text, &mut text_start, position) {
+            if let Some(out) = value_out(value, start, end) {
+                return Ok(Some(out));
+            }
+        }
+
+        match (kind, marker) {
+            (b'!', Some(b'-')) => {
+                if input.read().await? != b'-' {
+                    bail!("malformed xml, unknown declaration @ {position}");
+                }
+                input.skip_until(b"-->").await?;
+            }
+            (b'!', _) => skip_declaration(&mut input).await?,
+            (b'?', _) => input.skip_until(b"?>").await?,
+            (b'/', _) => input.skip_until(b">").await?,
+            (first_byte, _) => {
+                if first_byte.is_ascii_whitespace() || matches!(first_byte, b'>' | b'=') {
+                    bail!("malformed xml, expected element name @ {}", position + 1);
+                }
+                let name = read_name(first_byte, &mut input).await?;
+                if let Some(out) = key_out(name, position + 1, input.position()) {
+                    return Ok(Some(out));
+                }
+                loop {
+                    match input.read_non_whitespace().await? {
+                        b'>' => break,
+                        b'/' => (),
+                        byte => {
+                            let name = read_name(byte, &mut input).await?;
+                            let equals = input.read_non_whitespace().await?;
+                            if equals != b'=' {
+                                bail!("malformed xml, expected '=' after attribute {name:?}, got {equals:?}");
+                            }
+                            let quote = input.read_non_whitespace().await?;
+                            if quote != b'"' && quote != b'\'' {
+                                bail!("malformed xml, expected quoted value for attribute {name:?}, got {quote:?}");
+                            }
+                            let start = input.position();
+                            let mut value = vec![];
+                            loop {
+                                match input.read().await? {
+                                    byte if byte == quote => break,
+                                    b'&' => value.extend(read_entity(&mut input).await?),
+                                    byte => value.push(byte),
+                                }
+                            }
+                            let value = String::from_utf8_lossy(&value).into_owned();
+                            if let Some(out) = value_out(value, start, input.position() - 1) {
+                                return Ok(Some(out));
+                            }
+                        }
+                    }
+                }
+            }
+        }
+    }
+    let end = input.position();
+    if let Some((value, start, end)) = take_text(&mut text, &mut text_start, end) {
+        if let Some(out) = value_out(value, start, end) {
+            return Ok(Some(out));
+        }
+    }
+    Ok(None)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{sync::Arc, task::Poll};
+
+    use futures::{pin_mut, task::waker, Future};
+
+    use crate::pipe::{pipe, DummyWaker};
+
+    use super::*;
+
+    #[test]
+    fn test_xml_parse() {
+        let body = r#"<?xml version="1.0"?>
+<!DOCTYPE note [<!ENTITY x "y">]>
+<soap:Envelope xmlns:soap="http://www.w3.org/2003/05/soap-envelope">
+  <soap:Body>
+    <!-- <ssn>comment</ssn> -->
+    <ssn type = 'us'>123&#45;45-6789</ssn>
+    <note>a &amp; <![CDATA[<b>]]> c &unknown;</note>
+    <empty/>
+  </soap:Body>
+</soap:Envelope>"#;
+        let (mut reader, mut writer) = pipe(0);
+        assert!(writer.append(body.as_bytes()));
+        drop(writer);
+        let mut keys = vec![];
+        let mut values = vec![];
+        let waker = waker(Arc::new(DummyWaker));
+        let mut context = std::task::Context::from_waker(&waker);
+
+        {
+            let future = parse_xml::<()>(
+                &mut reader,
+                |key, start, end| {
+                    assert_eq!(&body[start..end], key);
+                    keys.push(key);
+                    None
+                },
+                |value, start, end| {
+                    values.push((value, &body[start..end]));
+                    None
+                },
+            );
+            pin_mut!(future);
+            assert!(matches!(future.poll(&mut context), Poll::Ready(Ok(None))));
+        }
+
+        assert_eq!(
+            keys,
+            vec!["soap:Envelope", "soap:Body", "ssn", "note", "empty"]
+        );
+        assert_eq!(
+            values,
+            vec![
+                (
+                    "http://www.w3.org/2003/05/soap-envelope".to_string(),
+                    "http://www.w3.org/2003/05/soap-envelope"
+                ),
+                ("us".to_string(), "us"),
+                ("123-45-6789".to_string(), "123&#45;45-6789"),
+                (
+                    "a & <b> c &unknown;".to_string(),
+                    "a &amp; <![CDATA[<b>]]> c &unknown;"
+                ),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_malformed_xml() {
+        for body in ["<a b=c>", "<a b>", "< a>", "<![X[", "<a"] {
+            let (mut reader, mut writer) = pipe(0);
+            assert!(writer.append(body.as_bytes()));
+            drop(writer);
+            let waker = waker(Arc::new(DummyWaker));
+            let mut context = std::task::Context::from_waker(&waker);
+            let future = parse_xml::<()>(&mut reader, |_, _, _| None, |_, _, _| None);
+            pin_mut!(future);
+            assert!(
+                matches!(future.poll(&mut context), Poll::Ready(Err(_))),
+                "{body}"
+            );
+        }
+    }
+}
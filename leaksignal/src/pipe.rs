@@ -7,7 +7,7 @@ use std::{
     task::{Context, Poll},
 };
 
-use futures::{task::ArcWake, AsyncRead, AsyncWrite};
+use futures::{task::ArcWake, AsyncRead, AsyncReadExt, AsyncWrite, Future};
 
 struct PipeInner {
     max_persistance: usize,
@@ -49,6 +49,17 @@ pub fn pipe(max_persistance: usize) -> (PipeReader, PipeWriter) {
     )
 }
 
+/// reads the next byte of `reader`, `None` at its end
+pub async fn maybe_read_u8(reader: &mut (impl AsyncRead + Unpin)) -> io::Result<Option<u8>> {
+    let mut out = [0u8; 1];
+    match reader.read_exact(&mut out[..]).await {
+        Ok(()) => (),
+        Err(e) if e.kind() == ErrorKind::UnexpectedEof => return Ok(None),
+        Err(e) => return Err(e),
+    }
+    Ok(Some(out[0]))
+}
+
 impl PipeWriter {
     /// Returns true if successfully appended
     pub fn append(&mut self, data: impl Into<Vec<u8>>) -> bool {
@@ -110,6 +121,31 @@ impl PipeReader {
     pub fn total_read(&self) -> usize {
         self.total_read
     }
+
+    /// Waits until `length` unread bytes are available or the pipe is closed, returning up to `length` of them without
+    /// consuming them
+    pub fn peek(&self, length: usize) -> impl Future<Output = Vec<u8>> + '_ {
+        futures::future::poll_fn(move |_| self.poll_peek(length))
+    }
+
+    fn poll_peek(&self, length: usize) -> Poll<Vec<u8>> {
+        let data = unsafe { self.inner.data.get().as_ref().unwrap() };
+        let mut out = Vec::with_capacity(length);
+        let mut subindex = self.segment_subindex;
+        for segment in data.iter().skip(self.segment_index) {
+            let end = segment.len().min(subindex + length - out.len());
+            out.extend_from_slice(&segment[subindex..end]);
+            subindex = 0;
+            if out.len() >= length {
+                return Poll::Ready(out);
+            }
+        }
+        if Rc::strong_count(&self.inner) != 2 {
+            Poll::Ready(out)
+        } else {
+            Poll::Pending
+        }
+    }
 }
 
 impl AsyncRead for PipeReader {
@@ -318,4 +354,32 @@ mod tests {
         ));
         assert!(reader.fetch_full_content().is_none())
     }
+
+    #[test]
+    fn test_pipe_peek() {
+        let (mut reader, mut writer) = pipe(0);
+        let mut scratch = [0u8; 3];
+        let waker = waker(Arc::new(DummyWaker));
+        let mut context = Context::from_waker(&waker);
+
+        writer.write_all(b"HEL").unwrap();
+        assert!(reader.peek(5).poll_unpin(&mut context).is_pending());
+        writer.write_all(b"LO WORLD").unwrap();
+        assert!(matches!(reader.peek(5).poll_unpin(&mut context), Poll::Ready(x) if x == b"HELLO"));
+
+        assert!(
+            matches!(reader.read(&mut scratch[..]).poll_unpin(&mut context), Poll::Ready(Ok(x)) if x == 3)
+        );
+        assert!(matches!(reader.peek(4).poll_unpin(&mut context), Poll::Ready(x) if x == b"LO W"));
+        drop(writer);
+        assert!(
+            matches!(reader.peek(64).poll_unpin(&mut context), Poll::Ready(x) if x == b"LO WORLD")
+        );
+        let mut rest = vec![];
+        assert!(matches!(
+            reader.read_to_end(&mut rest).poll_unpin(&mut context),
+            Poll::Ready(Ok(8))
+        ));
+        assert_eq!(rest, b"LO WORLD");
+    }
 }
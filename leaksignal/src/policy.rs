@@ -1,31 +1,190 @@
-use std::{fmt::Write, ops::Deref, sync::Arc};
+use std::{
+    collections::HashMap,
+    fmt::Write,
+    ops::Deref,
+    sync::{Arc, RwLock},
+};
 
 use arc_swap::{ArcSwap, Guard};
 use sha2::{Digest, Sha256};
 
-use crate::low_entropy_hash::LowEntropyHash;
+use crate::{
+    evaluator::MatcherState,
+    low_entropy_hash::LowEntropyHash,
+    parsers::{
+        csv, form,
+        grpc::{self, descriptor::Descriptors},
+        html, image, json, text, xml,
+    },
+};
 pub use leakpolicy::*;
 
+/// bounds the memory used by endpoints compiled after the policy is loaded, later ones are compiled for each request
+const MAX_LATE_ENDPOINTS: usize = 1024;
+
 lazy_static::lazy_static! {
-    static ref POLICY: ArcSwap<Option<(String, Policy)>> = {
+    static ref POLICY: ArcSwap<Option<CompiledPolicy>> = {
         ArcSwap::new(Arc::new(None))
     };
 }
 
-pub struct PolicyRef(Guard<Arc<Option<(String, Policy)>>>);
+/// A policy with its endpoints indexed, and the matcher states of each resolved endpoint configuration compiled
+pub struct CompiledPolicy {
+    policy_id: String,
+    policy: Policy,
+    grpc_descriptors: Arc<Descriptors>,
+    endpoint_index: EndpointIndex,
+    /// indices into `endpoints` by the globs matching a path
+    endpoint_indices: HashMap<Vec<GlobRef>, usize>,
+    /// endpoint configurations compiled when the policy is loaded
+    endpoints: Vec<Arc<CompiledEndpoint>>,
+    /// glob combinations not reached by `EndpointIndex::example_combinations`, compiled on first use
+    late_endpoints: RwLock<HashMap<Vec<GlobRef>, Arc<CompiledEndpoint>>>,
+}
+
+/// Everything needed to scan requests and responses for a given combination of matching endpoints
+pub struct CompiledEndpoint {
+    pub path_policy: PathPolicy,
+    pub request: BodyMatchers,
+    pub response: BodyMatchers,
+}
+
+impl CompiledEndpoint {
+    fn new(policy: &Policy, grpc_descriptors: &Arc<Descriptors>, globs: &[GlobRef]) -> Self {
+        let path_policy = policy.resolve_path_config(globs);
+        Self {
+            request: BodyMatchers::new(policy, grpc_descriptors, &path_policy, Direction::Request),
+            response: BodyMatchers::new(
+                policy,
+                grpc_descriptors,
+                &path_policy,
+                Direction::Response,
+            ),
+            path_policy,
+        }
+    }
+
+    pub fn matchers(&self, direction: Direction) -> &BodyMatchers {
+        match direction {
+            Direction::Request => &self.request,
+            Direction::Response => &self.response,
+        }
+    }
+}
+
+/// Matcher states of the categories scanned in one direction
+pub struct BodyMatchers {
+    pub html: html::HtmlMatchers,
+    pub json: json::JsonMatchers,
+    pub xml: xml::XmlMatchers,
+    pub text: MatcherState,
+    pub csv: csv::CsvMatchers,
+    pub form: form::FormMatchers,
+    pub grpc: grpc::GrpcMatchers,
+    pub image: image::ImageMatchers,
+}
+
+impl BodyMatchers {
+    pub fn new(
+        policy: &Policy,
+        grpc_descriptors: &Arc<Descriptors>,
+        path_policy: &PathPolicy,
+        direction: Direction,
+    ) -> Self {
+        let mut json = json::prepare_match_state(
+            policy,
+            &path_policy.configuration,
+            direction,
+            &ContentType::Json,
+        );
+        // an ndjson line is never buffered beyond what can be scanned of the body
+        if let Some(max_scanned_bytes) = path_policy.limits.max_scanned_bytes {
+            json.max_line_size = json.max_line_size.min(max_scanned_bytes);
+        }
+        Self {
+            html: html::prepare_match_state(policy, &path_policy.configuration, direction),
+            json,
+            xml: xml::prepare_match_state(policy, &path_policy.configuration, direction),
+            text: text::prepare_match_state(policy, &path_policy.configuration, direction),
+            csv: csv::prepare_match_state(policy, &path_policy.configuration, direction),
+            form: form::prepare_match_state(policy, &path_policy.configuration, direction),
+            grpc: grpc::prepare_match_state(
+                policy,
+                grpc_descriptors,
+                &path_policy.configuration,
+                direction,
+            ),
+            image: image::prepare_match_state(policy, &path_policy.configuration, direction),
+        }
+    }
+}
+
+impl CompiledPolicy {
+    fn new(policy_id: String, policy: Policy) -> Self {
+        let endpoint_index = EndpointIndex::new(&policy);
+        let grpc_descriptors = Arc::new(Descriptors::new(&policy));
+        let mut endpoint_indices = HashMap::new();
+        let mut endpoints = vec![];
+        for globs in endpoint_index.example_combinations(&policy) {
+            endpoints.push(Arc::new(CompiledEndpoint::new(
+                &policy,
+                &grpc_descriptors,
+                &globs,
+            )));
+            endpoint_indices.insert(globs, endpoints.len() - 1);
+        }
+        Self {
+            policy_id,
+            policy,
+            grpc_descriptors,
+            endpoint_index,
+            endpoint_indices,
+            endpoints,
+            late_endpoints: Default::default(),
+        }
+    }
+
+    /// returns the compiled endpoint configuration for `path`
+    pub fn endpoint(&self, path: &str) -> Arc<CompiledEndpoint> {
+        let globs = self.endpoint_index.matching_globs(&self.policy, path);
+        if let Some(index) = self.endpoint_indices.get(&globs) {
+            return self.endpoints[*index].clone();
+        }
+        if let Some(endpoint) = self.late_endpoints.read().unwrap().get(&globs) {
+            return endpoint.clone();
+        }
+
+        let endpoint = Arc::new(CompiledEndpoint::new(
+            &self.policy,
+            &self.grpc_descriptors,
+            &globs,
+        ));
+        let mut late_endpoints = self.late_endpoints.write().unwrap();
+        if late_endpoints.len() < MAX_LATE_ENDPOINTS {
+            late_endpoints.insert(globs, endpoint.clone());
+        }
+        endpoint
+    }
+}
+
+pub struct PolicyRef(Guard<Arc<Option<CompiledPolicy>>>);
 
 impl Deref for PolicyRef {
     type Target = Policy;
 
     fn deref(&self) -> &Self::Target {
-        &(&**self.0).as_ref().unwrap().1
+        &self.compiled().policy
     }
 }
 
 impl PolicyRef {
     #[allow(dead_code)]
     pub fn policy_id(&self) -> &str {
-        &*(&**self.0).as_ref().unwrap().0
+        &self.compiled().policy_id
+    }
+
+    pub fn compiled(&self) -> &CompiledPolicy {
+        (**self.0).as_ref().unwrap()
     }
 }
 
@@ -38,7 +197,7 @@ pub fn policy() -> Option<PolicyRef> {
 }
 
 pub fn update_policy(policy_id: String, policy: Policy) {
-    POLICY.store(Arc::new(Some((policy_id, policy))));
+    POLICY.store(Arc::new(Some(CompiledPolicy::new(policy_id, policy))));
 }
 
 pub fn evaluate_report_style(style: DataReportStyle, input: &str) -> Option<String> {
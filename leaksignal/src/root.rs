@@ -20,6 +20,7 @@ use crate::{
     },
     elapsed,
     http_response::HttpResponseContext,
+    parsers::grpc::descriptor::resolve_references,
     policy::{policy, update_policy},
     proto::{PingMessage, UpdatePolicyRequest, UpdatePolicyResponse},
     GIT_COMMIT,
@@ -110,7 +111,7 @@ impl Context for EnvoyRootContext {
         };
         warn!("received new leaksignal policy: {}", response.policy_id);
 
-        let policy: Policy = match parse_policy(&*response.policy) {
+        let mut policy: Policy = match parse_policy(&*response.policy) {
             Ok(x) => x,
             Err(e) => {
                 error!("failed to parse new policy: {:?}", e);
@@ -118,6 +119,7 @@ impl Context for EnvoyRootContext {
             }
         };
 
+        resolve_references(&mut policy, &response.descriptor_sets);
         self.do_policy_update(response.policy_id.clone(), policy);
     }
 
@@ -285,6 +287,22 @@ impl RootContext for EnvoyRootContext {
                 .expect("failed to parse proxy_wasm configuration"),
             None => Config::default(),
         };
+        if let Some(local_policy) = &parsed_config.local_policy {
+            let mut report = local_policy.validate();
+            if let Some(config) = self.get_plugin_configuration() {
+                report.locate(
+                    &String::from_utf8_lossy(&config[..plugin_configuration_size]),
+                    &["local_policy"],
+                );
+            }
+            for warning in &report.warnings {
+                warn!("local_policy warning: {warning}");
+            }
+            if !report.is_valid() {
+                error!("invalid local_policy, config change rejected:\n{report}");
+                return false;
+            }
+        }
         if let Some(previous_config) = Config::try_get() {
             if previous_config.mode() != parsed_config.mode() {
                 error!("cannot change mode of LeakSignal module without an Envoy restart, config change ignored.");